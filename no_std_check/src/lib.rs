@@ -0,0 +1,28 @@
+//! Uses the `raytracing` core from a `no_std` crate, so that anything in it that quietly needs
+//! `std` fails to build here.
+
+#![no_std]
+
+#[cfg(test)]
+mod tests {
+    use raytracing::hittable::Hittable;
+    use raytracing::material::Diffuse;
+    use raytracing::objects::Sphere;
+    use raytracing::ray::Ray;
+    use raytracing::utils::{ self, color, Real, Vec3 };
+
+    #[test]
+    fn rays_hit_a_sphere() {
+        let sphere = Sphere::new(Vec3::new(0.0, 0.0, -3.0), 1.0, Diffuse::new(color::mid_gray()));
+
+        let ray = Ray::new(Vec3::zeros(), Vec3::new(0.0, 0.0, -1.0));
+        let hit = sphere.hit(&ray, 0.001..Real::INFINITY).expect("the ray points at the sphere");
+        assert!((hit.t - 2.0).abs() < 1e-5);
+        assert!(hit.is_front);
+        assert!(utils::approx_eq(&hit.point.0, &Vec3::new(0.0, 0.0, -2.0), 1e-5));
+        assert!(utils::approx_eq(&hit.normal, &Vec3::new(0.0, 0.0, 1.0), 1e-5));
+
+        let miss = Ray::new(Vec3::zeros(), Vec3::new(0.0, 1.0, 0.0));
+        assert!(sphere.hit(&miss, 0.001..Real::INFINITY).is_none());
+    }
+}
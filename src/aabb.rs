@@ -0,0 +1,62 @@
+use core::ops::Range;
+
+use crate::utils::{ Point3, Real };
+use crate::ray::Ray;
+use crate::hittable::count_node_visit;
+use crate::prelude::*;
+
+/// Axis aligned bounding box.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aabb {
+    pub min: Point3,
+    pub max: Point3,
+}
+
+impl Aabb {
+    pub fn new(min: Point3, max: Point3) -> Aabb {
+        Aabb { min, max }
+    }
+
+    /// Smallest box containing both `self` and `other`.
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb::new(
+            nalgebra_glm::min2(&self.min, &other.min),
+            nalgebra_glm::max2(&self.max, &other.max),
+        )
+    }
+
+    /// The part of `bounds` during which the ray is inside of the box, if any.
+    pub fn hit(&self, ray: &Ray, bounds: Range<Real>) -> Option<Range<Real>> {
+        count_node_visit();
+
+        let mut t_min = bounds.start;
+        let mut t_max = bounds.end;
+
+        for axis in 0..3 {
+            let inv_dir = 1.0 / ray.dir[axis];
+            let mut t0 = (self.min[axis] - ray.origin.0[axis]) * inv_dir;
+            let mut t1 = (self.max[axis] - ray.origin.0[axis]) * inv_dir;
+
+            if inv_dir < 0.0 {
+                core::mem::swap(&mut t0, &mut t1);
+            }
+
+            t_min = t_min.max(t0);
+            t_max = t_max.min(t1);
+
+            if t_max < t_min {
+                return None;
+            }
+        }
+
+        Some(t_min..t_max)
+    }
+
+    pub fn center(&self) -> Point3 {
+        (self.min + self.max) / 2.0
+    }
+
+    pub fn size(&self) -> Point3 {
+        self.max - self.min
+    }
+}
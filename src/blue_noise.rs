@@ -0,0 +1,165 @@
+use std::sync::OnceLock;
+
+use rand::{ Rng, SeedableRng };
+use rand::rngs::StdRng;
+
+use crate::utils::Real;
+
+/// Width and height of the tile, which repeats seamlessly.
+const SIZE: usize = 64;
+/// Spread of the gaussian that measures how clustered the points are, in pixels.
+const SIGMA: Real = 1.5;
+/// Fraction of the pixels in the starting pattern.
+const INITIAL_DENSITY: Real = 0.1;
+
+/// A tile of two channels of blue noise: values spread evenly over `[0, 1)` whose neighbours are
+/// as different from each other as possible, so it has next to no low frequencies. Made with
+/// void and cluster, see Ulichney, "The void-and-cluster method for dither array generation".
+#[derive(Debug, Clone)]
+pub struct BlueNoise {
+    channels: [Vec<Real>; 2],
+}
+
+impl BlueNoise {
+    /// Noise generated from the same seed is the same.
+    pub fn new(seed: u64) -> BlueNoise {
+        let mut rng = StdRng::seed_from_u64(seed);
+        BlueNoise { channels: [void_and_cluster(&mut rng), void_and_cluster(&mut rng)] }
+    }
+
+    /// Tile shared by the renderers, generated on first use.
+    pub fn shared() -> &'static BlueNoise {
+        static SHARED: OnceLock<BlueNoise> = OnceLock::new();
+        SHARED.get_or_init(|| BlueNoise::new(0))
+    }
+
+    /// Both channels at pixel `(x, y)`, wrapping around the tile.
+    pub fn get(&self, x: u32, y: u32) -> (Real, Real) {
+        let i = (y as usize % SIZE) * SIZE + x as usize % SIZE;
+        (self.channels[0][i], self.channels[1][i])
+    }
+}
+
+/// One channel of the tile, in row order.
+fn void_and_cluster(rng: &mut impl Rng) -> Vec<Real> {
+    let mut field = EnergyField::new();
+
+    // Start from random points, then move the point in the tightest cluster to the largest void
+    // until that would move it right back.
+    let initial = (INITIAL_DENSITY * (SIZE * SIZE) as Real) as usize;
+    while field.count < initial {
+        let i = rng.gen_range(0..SIZE * SIZE);
+        if !field.points[i] {
+            field.toggle(i);
+        }
+    }
+    loop {
+        let cluster = field.tightest_cluster();
+        field.toggle(cluster);
+        let void = field.largest_void();
+        if void == cluster {
+            field.toggle(cluster);
+            break;
+        }
+        field.toggle(void);
+    }
+
+    let mut ranks = vec![0; SIZE * SIZE];
+
+    // Points of the starting pattern are ranked by taking them out, tightest cluster first.
+    let start = field.clone();
+    while field.count > 0 {
+        let cluster = field.tightest_cluster();
+        field.toggle(cluster);
+        ranks[cluster] = field.count;
+    }
+
+    // The rest are ranked by filling in the largest void, up to half of the pixels.
+    let mut field = start;
+    while field.count < SIZE * SIZE / 2 {
+        let void = field.largest_void();
+        ranks[void] = field.count;
+        field.toggle(void);
+    }
+
+    // From there the empty pixels are the minority, so the tightest cluster of them is filled in
+    // instead.
+    let mut empty = EnergyField::new();
+    for i in 0..SIZE * SIZE {
+        if !field.points[i] {
+            empty.toggle(i);
+        }
+    }
+    while empty.count > 0 {
+        let cluster = empty.tightest_cluster();
+        ranks[cluster] = SIZE * SIZE - empty.count;
+        empty.toggle(cluster);
+    }
+
+    ranks.into_iter()
+        .map(|rank| (rank as Real + 0.5) / (SIZE * SIZE) as Real)
+        .collect()
+}
+
+/// Points on the tile, along with how close every pixel is to them.
+#[derive(Clone)]
+struct EnergyField {
+    points: Vec<bool>,
+    count: usize,
+    /// Sum over the points of a gaussian of their distance to each pixel, wrapping around.
+    energy: Vec<Real>,
+    /// Gaussian of every offset between two pixels of the tile, wrapping around.
+    kernel: Vec<Real>,
+}
+
+impl EnergyField {
+    fn new() -> EnergyField {
+        let kernel = (0..SIZE * SIZE)
+            .map(|i| {
+                let wrapped = |d: usize| d.min(SIZE - d) as Real;
+                let (dx, dy) = (wrapped(i % SIZE), wrapped(i / SIZE));
+                (-(dx * dx + dy * dy) / (2.0 * SIGMA * SIGMA)).exp()
+            })
+            .collect();
+
+        EnergyField {
+            points: vec![false; SIZE * SIZE],
+            count: 0,
+            energy: vec![0.0; SIZE * SIZE],
+            kernel,
+        }
+    }
+
+    fn toggle(&mut self, i: usize) {
+        self.points[i] = !self.points[i];
+        let sign = if self.points[i] { 1.0 } else { -1.0 };
+        if self.points[i] { self.count += 1 } else { self.count -= 1 }
+
+        let (x, y) = (i % SIZE, i / SIZE);
+        for (j, energy) in self.energy.iter_mut().enumerate() {
+            let dx = (j % SIZE + SIZE - x) % SIZE;
+            let dy = (j / SIZE + SIZE - y) % SIZE;
+            *energy += sign * self.kernel[dy * SIZE + dx];
+        }
+    }
+
+    /// Point with the most energy.
+    fn tightest_cluster(&self) -> usize {
+        self.extreme(true, |a, b| a > b)
+    }
+
+    /// Empty pixel with the least energy.
+    fn largest_void(&self) -> usize {
+        self.extreme(false, |a, b| a < b)
+    }
+
+    fn extreme(&self, point: bool, better: impl Fn(Real, Real) -> bool) -> usize {
+        let mut best = None;
+        for (i, &energy) in self.energy.iter().enumerate() {
+            if self.points[i] == point && best.is_none_or(|best| better(energy, self.energy[best])) {
+                best = Some(i);
+            }
+        }
+        best.unwrap()
+    }
+}
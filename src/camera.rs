@@ -1,39 +1,161 @@
 
-use crate::utils::{ self, Point3, Vec3 };
+use rand::Rng;
+
+use crate::utils::{ self, color, Color, Point3, Vec3, Position, Direction, Real };
 use crate::ray::Ray;
+use crate::hittable::{ Hittable, SHUTTER };
+use crate::prelude::*;
+
+/// Which extent of the image a field of view angle spans.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FovAxis {
+    #[default]
+    Vertical,
+    Horizontal,
+    Diagonal,
+}
+
+/// A field of view angle, in degrees. Plain angles are vertical.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fov {
+    pub degrees: Real,
+    pub axis: FovAxis,
+}
+
+impl Fov {
+    pub fn vertical(degrees: Real) -> Fov {
+        Fov { degrees, axis: FovAxis::Vertical }
+    }
+
+    pub fn horizontal(degrees: Real) -> Fov {
+        Fov { degrees, axis: FovAxis::Horizontal }
+    }
+
+    pub fn diagonal(degrees: Real) -> Fov {
+        Fov { degrees, axis: FovAxis::Diagonal }
+    }
+
+    /// Viewport `(width, height)` at unit distance from the camera.
+    pub fn viewport(&self, aspect_ratio: Real) -> (Real, Real) {
+        let extent = 2.0 * (self.degrees.to_radians() / 2.0).tan();
+        let height = match self.axis {
+            FovAxis::Vertical   => extent,
+            FovAxis::Horizontal => extent / aspect_ratio,
+            FovAxis::Diagonal   => extent / (1.0 + aspect_ratio * aspect_ratio).sqrt(),
+        };
+        (aspect_ratio * height, height)
+    }
+}
+
+impl From<Real> for Fov {
+    fn from(degrees: Real) -> Fov {
+        Fov::vertical(degrees)
+    }
+}
 
 pub struct Camera {
-    origin: Point3,
-    horizontal: Vec3,
-    vertical: Vec3,
-    lower_left_corner: Point3,
-    u: Vec3,
-    v: Vec3,
-    w: Vec3,
-    lens_radius: f32,
+    origin: Position,
+    horizontal: Direction,
+    vertical: Direction,
+    lower_left_corner: Position,
+    u: Direction,
+    v: Direction,
+    w: Direction,
+    lens_radius: Real,
+    focus_dist: Real,
+    bokeh_gain: Real,
+    shutter_open: Real,
+    shutter_close: Real,
+    /// Fraction of the shutter interval each row is exposed for, with a rolling shutter.
+    rolling_shutter: Option<Real>,
 }
 
 impl Camera {
     pub fn new(
-        look_from: Vec3,
-        look_at: Vec3,
-        vup: Vec3,
-        vert_fov: f32,
-        aspect_ratio: f32,
-        aperture: f32,
-        focus_dist: f32,
+        look_from: impl Into<Position>,
+        look_at: impl Into<Position>,
+        vup: impl Into<Direction>,
+        fov: impl Into<Fov>,
+        aspect_ratio: Real,
+        aperture: Real,
+        focus_dist: Real,
     ) -> Camera {
+        let look_from = look_from.into();
+        let w = (look_from - look_at.into()).unit();
+        Camera::from_basis(look_from, w, vup.into(), fov.into(), aspect_ratio, aperture, focus_dist)
+    }
+
+    /// Like `new`, but looking along `direction` instead of towards a point.
+    ///
+    /// # Panics
+    ///
+    /// If `direction` is zero or parallel to `vup`.
+    pub fn from_direction(
+        look_from: impl Into<Position>,
+        direction: impl Into<Direction>,
+        vup: impl Into<Direction>,
+        fov: impl Into<Fov>,
+        aspect_ratio: Real,
+        aperture: Real,
+        focus_dist: Real,
+    ) -> Camera {
+        let direction = direction.into();
+        let vup = vup.into();
+        assert!(direction.magnitude_squared() > 0.0, "camera direction must not be zero");
+        assert!(
+            vup.cross(&direction).magnitude_squared() > 0.0,
+            "camera direction must not be parallel to `vup`"
+        );
+
+        let w = (-direction).unit();
+        Camera::from_basis(look_from.into(), w, vup, fov.into(), aspect_ratio, aperture, focus_dist)
+    }
+
+    /// A pinhole camera looking along `direction` at the center of `world`, just far enough that
+    /// its whole bounding sphere is in view. `None` if `world` is unbounded.
+    ///
+    /// # Panics
+    ///
+    /// If `direction` is zero.
+    pub fn frame_scene(
+        world: &impl Hittable,
+        fov: impl Into<Fov>,
+        aspect_ratio: Real,
+        direction: Vec3,
+    ) -> Option<Camera> {
+        let bounds = world.bounding_box(SHUTTER)?;
+        let center = bounds.center();
+        let radius = bounds.size().magnitude() / 2.0;
 
-        let theta = vert_fov.to_radians();
-        let h = (theta / 2.0).tan();
-        let viewport_height = 2.0 * h;
-        let viewport_width = aspect_ratio * viewport_height;
+        let fov = fov.into();
+        let (width, height) = fov.viewport(aspect_ratio);
+        // Tangent of the smallest half angle the image spans.
+        let tan = width.min(height) / 2.0;
+        // The sphere fits when its radius is seen under at most that angle.
+        let distance = radius * (1.0 + tan * tan).sqrt() / tan;
 
-        let w = (look_from - look_at).normalize();
-        let u = vup.cross(&w).normalize();
-        let v = w.cross(&u);
+        let direction = direction.normalize();
+        let vup = if direction.y.abs() < 0.999 { Vec3::y() } else { Vec3::z() };
+        let look_from = center - direction * distance;
+
+        Some(Camera::from_direction(look_from, direction, vup, fov, aspect_ratio, 0.0, distance))
+    }
+
+    /// Camera at `origin` looking along `-w`.
+    fn from_basis(
+        origin: Position,
+        w: Direction,
+        vup: Direction,
+        fov: Fov,
+        aspect_ratio: Real,
+        aperture: Real,
+        focus_dist: Real,
+    ) -> Camera {
+        let (viewport_width, viewport_height) = fov.viewport(aspect_ratio);
+
+        let u = Direction(vup.cross(&w).normalize());
+        let v = Direction(w.cross(&u));
 
-        let origin = look_from;
         let horizontal = u * viewport_width * focus_dist;
         let vertical = v * viewport_height * focus_dist;
         let lower_left_corner = origin - horizontal / 2.0 - vertical / 2.0 - w * focus_dist;
@@ -49,14 +171,292 @@ impl Camera {
             v,
             w,
             lens_radius,
+            focus_dist,
+            bokeh_gain: 1.0,
+            shutter_open: 0.0,
+            shutter_close: 0.0,
+            rolling_shutter: None,
+        }
+    }
+
+    /// Scale the highlights seen through the lens by up to `gain`, the more the further out of
+    /// focus they are. Only the part of a sample brighter than white is scaled, and only with a
+    /// non zero aperture. The gain is weighted by the circle of confusion of the surface relative
+    /// to the aperture, so highlights in focus keep their brightness, while the background and
+    /// those at half the focus distance or nearer get all of it. The blur discs keep their size,
+    /// which only depends on the aperture.
+    pub fn with_bokeh_gain(self, gain: Real) -> Camera {
+        Camera { bokeh_gain: gain, ..self }
+    }
+
+    /// Keep the shutter open from time `open` to `close`, blurring objects that move meanwhile.
+    /// Every ray is shot at time 0 by default.
+    ///
+    /// # Panics
+    ///
+    /// If the interval isn't within `hittable::SHUTTER`, which bounding boxes are built for.
+    pub fn with_shutter(self, open: Real, close: Real) -> Camera {
+        assert!(
+            SHUTTER.start <= open && open <= close && close <= SHUTTER.end,
+            "the shutter interval must be within {:?}", SHUTTER
+        );
+        Camera { shutter_open: open, shutter_close: close, ..self }
+    }
+
+    /// Instead of exposing the whole image at once, expose one row after the other across the
+    /// shutter interval, top to bottom, like the rolling shutter of most phone cameras. Each row is
+    /// exposed for `row_exposure` of the interval, so fast objects come out skewed and still
+    /// blurred. `None` exposes all rows over the whole interval again.
+    ///
+    /// # Panics
+    ///
+    /// If `row_exposure` isn't within `[0, 1]`.
+    pub fn with_rolling_shutter(self, row_exposure: Option<Real>) -> Camera {
+        if let Some(exposure) = row_exposure {
+            assert!((0.0..=1.0).contains(&exposure), "rows must be exposed for a fraction of the shutter interval");
         }
+        Camera { rolling_shutter: row_exposure, ..self }
     }
 
-    pub fn get_ray(&self, s: f32, t: f32) -> Ray {
-        let rand = utils::random_in_unit_disc() * self.lens_radius;
+    /// Apply the bokeh gain to a sample taken with a ray from `get_ray`, that first hit a surface
+    /// at `point`, or went to the background when `None`.
+    pub fn apply_bokeh_gain(&self, sample: Color, point: Option<&Point3>) -> Color {
+        if self.lens_radius == 0.0 || self.bokeh_gain == 1.0 {
+            return sample;
+        }
+
+        let luminance = color::luminance(sample);
+        if luminance <= 1.0 {
+            return sample;
+        }
+
+        let gain = 1.0 + (self.bokeh_gain - 1.0) * self.defocus(point);
+        // Keep the hue, scaling only the luminance above 1.
+        sample * ((1.0 + (luminance - 1.0) * gain) / luminance)
+    }
+
+    /// Diameter of the circle of confusion of `point` over that of the lens, up to 1, which is
+    /// what points infinitely far away get. A point at depth `d` is blurred into a disc
+    /// `aperture * |d - focus_dist| / d` wide on the plane in focus.
+    fn defocus(&self, point: Option<&Point3>) -> Real {
+        match point {
+            Some(point) => {
+                let depth = (point - self.origin.0).dot(&-self.w.0);
+                (1.0 - self.focus_dist / depth).abs().min(1.0)
+            }
+            None => 1.0,
+        }
+    }
+
+    /// Whether the camera basis is unusable, e.g. because `look_from == look_at`, `vup` is
+    /// parallel to the view direction or the focus distance is zero.
+    pub fn is_degenerate(&self) -> bool {
+        let points = [self.origin, self.lower_left_corner];
+        let directions = [self.horizontal, self.vertical, self.u, self.v, self.w];
+
+        points.iter().map(|p| p.0).chain(directions.iter().map(|d| d.0))
+            .any(|v| v.iter().any(|c| !c.is_finite()))
+            || self.horizontal.magnitude_squared() == 0.0
+            || self.vertical.magnitude_squared() == 0.0
+    }
+
+    /// Ray through the viewport at `(s, t)`, from a point on the lens sampled with `rng`.
+    pub fn get_ray(&self, s: Real, t: Real, rng: &mut impl Rng) -> Ray {
+        let lens = utils::random_in_unit_disc(rng);
+        self.ray_from_lens(s, t, lens).with_time(self.time_at(t, rng))
+    }
+
+    /// Same as `get_ray`, but from the point on the lens at `(lens_u, lens_v)` in the unit square,
+    /// mapped to the lens with `utils::unit_disc_point`.
+    pub fn get_ray_through_lens(&self, s: Real, t: Real, lens_u: Real, lens_v: Real) -> Ray {
+        let ray = self.ray_from_lens(s, t, utils::unit_disc_point(lens_u, lens_v));
+        ray.with_time(self.time_at(t, &mut utils::LocalRng))
+    }
+
+    /// Time at which to shoot a ray through the viewport at height `t`.
+    fn time_at(&self, t: Real, rng: &mut impl Rng) -> Real {
+        let duration = self.shutter_close - self.shutter_open;
+        if duration <= 0.0 {
+            return self.shutter_open;
+        }
+
+        match self.rolling_shutter {
+            // Rows are exposed from the top, where `t` is 1, with the bottom row closing along with
+            // the shutter.
+            Some(exposure) => {
+                let row_open = self.shutter_open + (1.0 - t.clamp(0.0, 1.0)) * duration * (1.0 - exposure);
+                row_open + rng.gen::<Real>() * duration * exposure
+            }
+            None => self.shutter_open + rng.gen::<Real>() * duration,
+        }
+    }
+
+    fn ray_from_lens(&self, s: Real, t: Real, lens: Vec3) -> Ray {
+        let rand = lens * self.lens_radius;
         let offset = self.u * rand.x + self.v * rand.y;
         // The point at the viewport
         let viewport_point = self.lower_left_corner + self.horizontal * s + self.vertical * t;
         Ray::new(self.origin + offset, viewport_point - self.origin - offset)
     }
 }
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    use crate::material::Diffuse;
+    use crate::objects::Sphere;
+
+    fn looking_down_z(aperture: Real) -> Camera {
+        Camera::new(Vec3::zeros(), Vec3::new(0.0, 0.0, -1.0), Vec3::y(), 40.0, 1.5, aperture, 5.0)
+    }
+
+    #[test]
+    fn square_viewports_are_square_for_any_fov_axis() {
+        let tan = (30.0 as Real).to_radians().tan();
+        for fov in [Fov::vertical(60.0), Fov::horizontal(60.0), Fov::diagonal(60.0)] {
+            let (width, height) = fov.viewport(1.0);
+            assert!((width - height).abs() < 1e-5, "{:?}: {} x {}", fov.axis, width, height);
+
+            let camera = Camera::new(Vec3::zeros(), -Vec3::z(), Vec3::y(), fov, 1.0, 0.0, 1.0);
+            let (width, height) = (camera.horizontal.magnitude(), camera.vertical.magnitude());
+            assert!((width - height).abs() < 1e-5, "{:?}: {} x {}", fov.axis, width, height);
+
+            // The angle spans the side, or the diagonal.
+            let span = if fov.axis == FovAxis::Diagonal { width * (2.0 as Real).sqrt() } else { width };
+            assert!((span - 2.0 * tan).abs() < 1e-5, "{:?}: {}", fov.axis, span);
+        }
+    }
+
+    #[test]
+    fn rays_depend_only_on_the_rng() {
+        let camera = looking_down_z(0.5);
+        let ray_with_seed = |seed: u64| camera.get_ray(0.3, 0.7, &mut StdRng::seed_from_u64(seed));
+
+        let (a, b) = (ray_with_seed(1), ray_with_seed(1));
+        assert_eq!((a.origin, a.dir, a.payload.time), (b.origin, b.dir, b.payload.time));
+        // Through a different point of the lens otherwise.
+        assert_ne!(a.origin, ray_with_seed(2).origin);
+    }
+
+    #[test]
+    fn from_direction_matches_looking_at_a_point() {
+        let look_from = Vec3::new(1.0, 2.0, 3.0);
+        let look_at = Vec3::new(-2.0, 0.5, -4.0);
+        let at = Camera::new(look_from, look_at, Vec3::y(), 50.0, 1.5, 0.4, 6.0);
+        // Only the direction matters, not how far along it the point is.
+        let along = Camera::from_direction(look_from, 3.0 * (look_at - look_from), Vec3::y(), 50.0, 1.5, 0.4, 6.0);
+
+        for &(s, t, lens_u, lens_v) in &[(0.5, 0.5, 0.5, 0.5), (0.0, 1.0, 0.1, 0.9), (0.8, 0.2, 0.7, 0.3)] {
+            let expected = at.get_ray_through_lens(s, t, lens_u, lens_v);
+            let ray = along.get_ray_through_lens(s, t, lens_u, lens_v);
+            assert!(utils::approx_eq(&ray.origin.0, &expected.origin.0, 1e-5));
+            assert!(utils::approx_eq(&ray.dir.0, &expected.dir.0, 1e-5));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "parallel")]
+    fn from_direction_rejects_looking_straight_up() {
+        Camera::from_direction(Vec3::zeros(), Vec3::new(0.0, 2.0, 0.0), Vec3::y(), 40.0, 1.0, 0.0, 1.0);
+    }
+
+    #[test]
+    fn framed_scenes_are_entirely_in_view() {
+        let gray = || Diffuse::new(color::mid_gray());
+        let world = vec![
+            Sphere::new(Vec3::new(-3.0, 0.0, 1.0), 0.5, gray()),
+            Sphere::new(Vec3::new(2.0, 4.0, -1.0), 1.5, gray()),
+            Sphere::new(Vec3::new(0.0, -1.0, 6.0), 0.25, gray()),
+        ];
+        let bounds = world.bounding_box(SHUTTER).unwrap();
+
+        let views = [
+            (Fov::vertical(40.0), 1.5, Vec3::new(0.0, 0.0, -1.0)),
+            (Fov::vertical(90.0), 0.5, Vec3::new(1.0, -0.5, 0.3)),
+            (Fov::horizontal(30.0), 2.0, Vec3::new(0.0, -1.0, 0.0)),
+        ];
+        for &(fov, aspect_ratio, direction) in &views {
+            let camera = Camera::frame_scene(&world, fov, aspect_ratio, direction).unwrap();
+            assert!(utils::approx_eq(&(camera.origin.0 + camera.focus_dist * direction.normalize()), &bounds.center(), 1e-3));
+
+            for i in 0..8 {
+                let pick = |bit: usize, min: Real, max: Real| if i & bit == 0 { min } else { max };
+                let corner = Vec3::new(
+                    pick(1, bounds.min.x, bounds.max.x),
+                    pick(2, bounds.min.y, bounds.max.y),
+                    pick(4, bounds.min.z, bounds.max.z),
+                );
+
+                // Where the line from the camera to the corner crosses the focus plane, as a
+                // fraction of the viewport.
+                let to_corner = corner - camera.origin.0;
+                let depth = -to_corner.dot(&camera.w.0);
+                assert!(depth > 0.0);
+                let on_plane = camera.origin.0 + to_corner * (camera.focus_dist / depth) - camera.lower_left_corner.0;
+                let s = on_plane.dot(&camera.horizontal.0) / camera.horizontal.magnitude_squared();
+                let t = on_plane.dot(&camera.vertical.0) / camera.vertical.magnitude_squared();
+                assert!((0.0..=1.0).contains(&s) && (0.0..=1.0).contains(&t), "corner {} at ({}, {})", i, s, t);
+            }
+        }
+    }
+
+    #[test]
+    fn rolling_shutters_expose_rows_one_after_the_other() {
+        utils::seed_thread_rng(3);
+        // Earliest and latest time of the rays through the row at height `t`.
+        let times = |camera: &Camera, t: Real| {
+            (0..200)
+                .map(|_| camera.get_ray(0.5, t, &mut utils::LocalRng).payload.time)
+                .fold((Real::INFINITY, Real::NEG_INFINITY), |(min, max), time| (min.min(time), max.max(time)))
+        };
+
+        // Each row gets a quarter of the interval, the top one first.
+        let rolling = looking_down_z(0.0).with_shutter(0.2, 0.6).with_rolling_shutter(Some(0.25));
+        let (top_min, top_max) = times(&rolling, 1.0);
+        assert!(top_min >= 0.2 && top_max <= 0.3);
+        assert!(top_max - top_min > 0.08);
+        let (bottom_min, bottom_max) = times(&rolling, 0.0);
+        assert!(bottom_min >= 0.5 && bottom_max <= 0.6);
+        assert!(bottom_max - bottom_min > 0.08);
+
+        // Without it, every row sees the whole interval.
+        let (min, max) = times(&looking_down_z(0.0).with_shutter(0.2, 0.6), 1.0);
+        assert!(min < 0.25 && max > 0.55);
+    }
+
+    #[test]
+    fn bokeh_gain_only_brightens_out_of_focus_highlights() {
+        let camera = looking_down_z(1.0).with_bokeh_gain(4.0);
+        let highlight = color::new(3.0, 3.0, 3.0);
+        let luminance = |point: Option<Point3>| color::luminance(camera.apply_bokeh_gain(highlight, point.as_ref()));
+
+        // In focus, then further and further out of it up to the background, which gets all of
+        // the gain.
+        assert!((luminance(Some(Point3::new(0.5, 0.0, -5.0))) - 3.0).abs() < 1e-4);
+        assert!((luminance(Some(Point3::new(0.0, 0.0, -7.5))) - 5.0).abs() < 1e-4);
+        assert!((luminance(Some(Point3::new(0.0, 0.0, -10.0))) - 6.0).abs() < 1e-4);
+        assert!((luminance(None) - 9.0).abs() < 1e-4);
+        // Nearer than the focus blurs faster.
+        assert!((luminance(Some(Point3::new(0.0, 0.0, -2.5))) - 9.0).abs() < 1e-4);
+
+        // Nothing brighter than white, or without a lens.
+        let gray = color::new(0.5, 0.5, 0.5);
+        assert_eq!(camera.apply_bokeh_gain(gray, None), gray);
+        assert_eq!(looking_down_z(0.0).with_bokeh_gain(4.0).apply_bokeh_gain(highlight, None), highlight);
+    }
+
+    #[test]
+    fn bokeh_gain_keeps_the_rays() {
+        let rays = |camera: Camera| {
+            utils::seed_thread_rng(1);
+            (0..16)
+                .map(|_| camera.get_ray(0.3, 0.6, &mut utils::LocalRng))
+                .map(|ray| (ray.origin.0, ray.dir.0))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(rays(looking_down_z(1.0)), rays(looking_down_z(1.0).with_bokeh_gain(8.0)));
+    }
+}
@@ -0,0 +1,153 @@
+use alloc::sync::Arc;
+
+use rand::Rng;
+
+use crate::texture::{ equirect_uv, ImageTexture };
+use crate::utils::{ color, consts, Color, Real, Vec3 };
+use crate::prelude::*;
+
+/// An environment map that can pick directions in proportion to how much light comes from them,
+/// so that a small bright sun isn't left to be found by chance.
+#[derive(Debug, Clone)]
+pub struct EnvironmentLight {
+    texture: Arc<ImageTexture>,
+    /// Cumulative weight of the rows, top to bottom, normalized to end at 1.
+    row_cdf: Vec<Real>,
+    /// Cumulative weight of the texels in each row, normalized to end at 1 in every row.
+    texel_cdf: Vec<Real>,
+    /// Density of each texel over the unit square of `(u, v)` coordinates.
+    texel_pdf: Vec<Real>,
+}
+
+impl EnvironmentLight {
+    pub fn new(texture: Arc<ImageTexture>) -> EnvironmentLight {
+        let (width, height) = (texture.width(), texture.height());
+
+        // Rows near the poles cover less solid angle, so they are weighted by `sin(theta)`.
+        let mut weights: Vec<Real> = (0..height)
+            .flat_map(|j| {
+                let sin_theta = ((j as Real + 0.5) / height as Real * consts::PI).sin();
+                let texture = &texture;
+                (0..width).map(move |i| color::luminance(texture.texel(i, j)).max(0.0) * sin_theta)
+            })
+            .collect();
+
+        // Fall back to sampling the sphere evenly when there is no light at all.
+        let total: Real = weights.iter().sum();
+        if total <= 0.0 || !total.is_finite() {
+            weights.iter_mut().for_each(|weight| *weight = 1.0);
+        }
+        let total: Real = weights.iter().sum();
+
+        let texel_pdf = weights.iter().map(|weight| weight / total * weights.len() as Real).collect();
+
+        let mut row_cdf = Vec::with_capacity(height);
+        let mut texel_cdf = Vec::with_capacity(width * height);
+        let mut rows_sum = 0.0;
+        for row in weights.chunks_exact(width.max(1)) {
+            let row_sum: Real = row.iter().sum();
+            rows_sum += row_sum;
+            row_cdf.push(rows_sum / total);
+
+            let mut sum = 0.0;
+            for weight in row {
+                sum += weight;
+                texel_cdf.push(if row_sum > 0.0 { sum / row_sum } else { 1.0 });
+            }
+        }
+
+        EnvironmentLight { texture, row_cdf, texel_cdf, texel_pdf }
+    }
+
+    pub fn texture(&self) -> &Arc<ImageTexture> {
+        &self.texture
+    }
+
+    /// Light arriving from direction `dir`.
+    pub fn radiance(&self, dir: &Vec3) -> Color {
+        self.texture.sample_dir(dir)
+    }
+
+    /// Pick a unit direction towards the environment, with its density over solid angle.
+    pub fn sample(&self, rng: &mut impl Rng) -> (Vec3, Real) {
+        let (width, height) = (self.texture.width(), self.texture.height());
+        if width == 0 || height == 0 {
+            return (Vec3::new(0.0, 1.0, 0.0), 0.0);
+        }
+
+        let j = pick(&self.row_cdf, rng.gen());
+        let i = pick(&self.texel_cdf[j * width..(j + 1) * width], rng.gen());
+
+        // Uniformly within the texel. Row 0 is at the top, where `v` is 1.
+        let u = (i as Real + rng.gen::<Real>()) / width as Real;
+        let v = 1.0 - (j as Real + rng.gen::<Real>()) / height as Real;
+
+        // Inverse of `equirect_uv`.
+        let theta = v * consts::PI;
+        let phi = u * consts::TAU - consts::PI;
+        let dir = Vec3::new(theta.sin() * phi.cos(), -theta.cos(), -theta.sin() * phi.sin());
+
+        (dir, self.pdf(&dir))
+    }
+
+    /// Density over solid angle with which `sample` picks the direction `dir`.
+    pub fn pdf(&self, dir: &Vec3) -> Real {
+        let (width, height) = (self.texture.width(), self.texture.height());
+        if width == 0 || height == 0 {
+            return 0.0;
+        }
+
+        let dir = dir.normalize();
+        // Not `1 - y^2`, which cancels out to 0 close to the poles.
+        let sin_theta = (dir.x * dir.x + dir.z * dir.z).sqrt();
+        if sin_theta == 0.0 {
+            return 0.0;
+        }
+
+        let (u, v) = equirect_uv(&dir);
+        let i = ((u * width as Real) as usize).min(width - 1);
+        let j = (((1.0 - v) * height as Real) as usize).min(height - 1);
+
+        // The map from `(u, v)` to the sphere stretches areas by `2 pi^2 sin(theta)`.
+        self.texel_pdf[j * width + i] / (2.0 * consts::PI * consts::PI * sin_theta)
+    }
+}
+
+/// Index of the first entry of `cdf` above `x`.
+fn pick(cdf: &[Real], x: Real) -> usize {
+    cdf.partition_point(|&c| c <= x).min(cdf.len() - 1)
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::utils;
+
+    #[test]
+    fn samples_cluster_around_the_brightest_texel() {
+        let (width, height) = (16, 8);
+        let (sun_i, sun_j) = (12, 2);
+        let texels = (0..width * height)
+            .map(|k| if k == sun_j * width + sun_i { color::new(1000.0, 1000.0, 1000.0) } else { color::new(0.1, 0.1, 0.1) })
+            .collect();
+        let light = EnvironmentLight::new(Arc::new(ImageTexture::new(width, height, texels)));
+
+        utils::seed_thread_rng(0);
+        let samples = 1000;
+        let mut towards_sun = 0;
+        for _ in 0..samples {
+            let (dir, pdf) = light.sample(&mut utils::LocalRng);
+            assert!((dir.magnitude() - 1.0).abs() < 1e-4);
+            assert!((pdf - light.pdf(&dir)).abs() <= 1e-3 * pdf, "{} != {}", pdf, light.pdf(&dir));
+
+            let (u, v) = equirect_uv(&dir);
+            let i = ((u * width as Real) as usize).min(width - 1);
+            let j = (((1.0 - v) * height as Real) as usize).min(height - 1);
+            if (i, j) == (sun_i, sun_j) {
+                towards_sun += 1;
+                assert_eq!(light.radiance(&dir).x, 1000.0);
+            }
+        }
+        assert!(towards_sun > samples * 9 / 10, "only {} of {} samples towards the sun", towards_sun, samples);
+    }
+}
@@ -0,0 +1,95 @@
+use std::io::{ self, Read, Seek, Write };
+
+use ::exr::prelude::{ AnyChannel, AnyChannels, Encoding, FlatSamples, Image, Layer, LayerAttributes };
+use ::exr::prelude::{ ReadChannels, ReadLayers, WritableImage };
+
+/// A named channel of an image, like `R` or `normal.X`, with one value per pixel in image order.
+/// Channels named `layer.channel` are grouped into layers by compositors.
+pub type Channel<'a> = (&'a str, &'a [f32]);
+
+/// Write the `channels` of a `width` by `height` image as a single part OpenEXR file of 32 bit
+/// floats, with lossless compression.
+///
+/// # Panics
+///
+/// If a channel doesn't have a value for every pixel.
+pub fn write(out: impl Write + Seek, width: usize, height: usize, channels: &[Channel]) -> io::Result<()> {
+    let channels = channels.iter()
+        .map(|&(name, values)| {
+            assert_eq!(values.len(), width * height, "channel {:?} has the wrong size", name);
+            AnyChannel::new(name, FlatSamples::F32(values.to_vec()))
+        })
+        .collect();
+    let layer = Layer::new(
+        (width, height),
+        LayerAttributes::default(),
+        Encoding::FAST_LOSSLESS,
+        AnyChannels::sort(channels),
+    );
+    Image::from_layer(layer).write().to_buffered(out).map_err(io_error)
+}
+
+/// An image read by `read`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExrImage {
+    pub width: usize,
+    pub height: usize,
+    /// Names and values of the channels, in alphabetical order.
+    pub channels: Vec<(String, Vec<f32>)>,
+}
+
+impl ExrImage {
+    pub fn channel(&self, name: &str) -> Option<&[f32]> {
+        self.channels.iter()
+            .find(|(channel, _)| channel == name)
+            .map(|(_, values)| values.as_slice())
+    }
+}
+
+/// Read the first layer of an OpenEXR file, with every channel converted to 32 bit floats.
+pub fn read(input: impl Read + Seek) -> io::Result<ExrImage> {
+    let image = ::exr::prelude::read()
+        .no_deep_data()
+        .largest_resolution_level()
+        .all_channels()
+        .first_valid_layer()
+        .all_attributes()
+        .from_buffered(input)
+        .map_err(io_error)?;
+
+    let layer = image.layer_data;
+    let channels = layer.channel_data.list.iter()
+        .map(|channel| (channel.name.to_string(), channel.sample_data.values_as_f32().collect()))
+        .collect();
+    Ok(ExrImage { width: layer.size.width(), height: layer.size.height(), channels })
+}
+
+fn io_error(err: ::exr::error::Error) -> io::Error {
+    match err {
+        ::exr::error::Error::Io(err) => err,
+        err => io::Error::new(io::ErrorKind::InvalidData, err.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn images_read_back_the_same() {
+        let (width, height) = (3, 2);
+        let red = [0.0, 0.5, 1.0, 1.5, 2.0, 2.5];
+        let depth = [1.0, 2.0, 3.0, 4.0, 5.0, f32::INFINITY];
+
+        let mut bytes = io::Cursor::new(Vec::new());
+        write(&mut bytes, width, height, &[("depth.Z", &depth), ("R", &red)]).unwrap();
+        bytes.set_position(0);
+        let image = read(bytes).unwrap();
+
+        assert_eq!((image.width, image.height), (width, height));
+        let names: Vec<_> = image.channels.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["R", "depth.Z"]);
+        assert_eq!(image.channel("R"), Some(&red[..]));
+        assert_eq!(image.channel("depth.Z"), Some(&depth[..]));
+    }
+}
@@ -0,0 +1,289 @@
+use core::ops::Range;
+
+use crate::aabb::Aabb;
+use crate::hittable::{ count_node_visit, Hittable, Hit, SceneStats, SHUTTER };
+use crate::ray::Ray;
+use crate::utils::{ Real, Vec3 };
+use crate::prelude::*;
+
+/// Cells per object the grid aims for.
+const CELLS_PER_OBJECT: Real = 2.0;
+const MAX_RESOLUTION: usize = 64;
+
+/// Splits the bounds of the scene into equally sized cells, each listing the objects that overlap
+/// it, which rays walk through in order. Works best when the objects are about the same size and
+/// spread evenly, otherwise see `KdTree`.
+pub struct UniformGrid<T> {
+    objects: Vec<T>,
+    bounds: Option<Aabb>,
+    /// Number of cells along each axis.
+    resolution: [usize; 3],
+    cell_size: Vec3,
+    /// Objects overlapping each cell, x varying fastest.
+    cells: Vec<Vec<usize>>,
+    /// Objects without a bounding box, tested against every ray.
+    unbounded: Vec<usize>,
+}
+
+impl<T: Hittable> UniformGrid<T> {
+    pub fn new(objects: Vec<T>) -> UniformGrid<T> {
+        UniformGrid::with_time_range(objects, SHUTTER)
+    }
+
+    /// Build a grid to trace rays shot during `time`, like `KdTree::with_time_range`.
+    pub fn with_time_range(objects: Vec<T>, time: Range<Real>) -> UniformGrid<T> {
+        let mut bounded = Vec::new();
+        let mut unbounded = Vec::new();
+
+        for (i, obj) in objects.iter().enumerate() {
+            match obj.bounding_box(time.clone()) {
+                Some(bbox) => bounded.push((i, bbox)),
+                None       => unbounded.push(i),
+            }
+        }
+
+        let bounds = bounded.iter()
+            .map(|(_, bbox)| *bbox)
+            .reduce(|a, b| a.union(&b));
+
+        let mut grid = UniformGrid {
+            objects,
+            bounds,
+            resolution: [1; 3],
+            cell_size: Vec3::zeros(),
+            cells: Vec::new(),
+            unbounded,
+        };
+
+        if let Some(bounds) = bounds {
+            // Flat scenes would have no volume, so every axis is given some thickness.
+            let size = bounds.size().map(|s| s.max(1e-4));
+            let cell_side = (size.x * size.y * size.z / (CELLS_PER_OBJECT * bounded.len() as Real)).cbrt();
+
+            for axis in 0..3 {
+                grid.resolution[axis] = ((size[axis] / cell_side).round() as usize).clamp(1, MAX_RESOLUTION);
+                grid.cell_size[axis] = size[axis] / grid.resolution[axis] as Real;
+            }
+
+            let [nx, ny, nz] = grid.resolution;
+            grid.cells = vec![Vec::new(); nx * ny * nz];
+
+            for (i, bbox) in bounded {
+                let min = grid.cell_of(&bbox.min);
+                let max = grid.cell_of(&bbox.max);
+                for z in min[2]..=max[2] {
+                    for y in min[1]..=max[1] {
+                        for x in min[0]..=max[0] {
+                            grid.cells[(z * ny + y) * nx + x].push(i);
+                        }
+                    }
+                }
+            }
+        }
+
+        grid
+    }
+
+    pub fn objects(&self) -> &[T] {
+        &self.objects
+    }
+
+    /// Cell containing `point`, clamped to the grid.
+    fn cell_of(&self, point: &Vec3) -> [usize; 3] {
+        let min = self.bounds.map_or(Vec3::zeros(), |bounds| bounds.min);
+        let mut cell = [0; 3];
+        for axis in 0..3 {
+            let i = ((point[axis] - min[axis]) / self.cell_size[axis]).floor();
+            cell[axis] = (i.max(0.0) as usize).min(self.resolution[axis] - 1);
+        }
+        cell
+    }
+
+    fn nearest_in(&self, indices: &[usize], ray: &Ray, bounds: Range<Real>) -> Option<Hit<'_>> {
+        indices.iter()
+            .filter_map(|&i| self.objects[i].hit(ray, bounds.clone()))
+            .min_by(Hit::cmp_nearest)
+    }
+
+    /// Call `visit` with the objects of every cell the ray goes through during `span`, nearest
+    /// first, along with the `t` at which the ray leaves the cell. Stops when `visit` returns
+    /// `true`.
+    fn walk(&self, ray: &Ray, span: Range<Real>, mut visit: impl FnMut(&[usize], Real) -> bool) {
+        let bounds = match self.bounds {
+            Some(bounds) => bounds,
+            None         => return,
+        };
+
+        let mut cell = self.cell_of(&ray.at(span.start).0);
+        let [nx, ny, _] = self.resolution;
+
+        // Per axis: which way the ray steps, the `t` of the next cell boundary it crosses, and the
+        // `t` it takes to cross a whole cell.
+        let mut step = [0isize; 3];
+        let mut t_next = [Real::INFINITY; 3];
+        let mut t_delta = [Real::INFINITY; 3];
+        for axis in 0..3 {
+            let dir = ray.dir[axis];
+            let origin = ray.origin.0[axis];
+            let cell_min = bounds.min[axis] + cell[axis] as Real * self.cell_size[axis];
+
+            if dir > 0.0 {
+                step[axis] = 1;
+                t_next[axis] = (cell_min + self.cell_size[axis] - origin) / dir;
+                t_delta[axis] = self.cell_size[axis] / dir;
+            } else if dir < 0.0 {
+                step[axis] = -1;
+                t_next[axis] = (cell_min - origin) / dir;
+                t_delta[axis] = -self.cell_size[axis] / dir;
+            }
+        }
+
+        loop {
+            let axis = (0..3).min_by(|&a, &b| t_next[a].total_cmp(&t_next[b])).unwrap();
+            let t_exit = t_next[axis].min(span.end);
+
+            count_node_visit();
+            if visit(&self.cells[(cell[2] * ny + cell[1]) * nx + cell[0]], t_exit) || t_exit >= span.end {
+                return;
+            }
+
+            let next = cell[axis] as isize + step[axis];
+            if next < 0 || next >= self.resolution[axis] as isize {
+                return;
+            }
+            cell[axis] = next as usize;
+            t_next[axis] += t_delta[axis];
+        }
+    }
+}
+
+impl<T: Hittable> Hittable for UniformGrid<T> {
+    fn hit(&self, ray: &Ray, bounds: Range<Real>) -> Option<Hit<'_>> {
+        let mut nearest = self.nearest_in(&self.unbounded, ray, bounds.clone());
+
+        if let Some(span) = self.bounds.and_then(|scene_bounds| scene_bounds.hit(ray, bounds.clone())) {
+            self.walk(ray, span, |indices, t_exit| {
+                // Objects overlap several cells, so a hit is only sure to be the nearest once
+                // the ray has walked past it.
+                if let Some(hit) = self.nearest_in(indices, ray, bounds.clone()) {
+                    if nearest.is_none_or(|nearest| hit.cmp_nearest(&nearest).is_lt()) {
+                        nearest = Some(hit);
+                    }
+                }
+                nearest.is_some_and(|hit| hit.t <= t_exit)
+            });
+        }
+
+        nearest
+    }
+
+    fn hit_any(&self, ray: &Ray, bounds: Range<Real>) -> bool {
+        let hits_unbounded = self.unbounded.iter()
+            .any(|&i| self.objects[i].hit_any(ray, bounds.clone()));
+
+        if hits_unbounded {
+            return true;
+        }
+
+        let span = match self.bounds.and_then(|scene_bounds| scene_bounds.hit(ray, bounds.clone())) {
+            Some(span) => span,
+            None       => return false,
+        };
+
+        let mut hit = false;
+        self.walk(ray, span, |indices, _| {
+            hit = indices.iter().any(|&i| self.objects[i].hit_any(ray, bounds.clone()));
+            hit
+        });
+        hit
+    }
+
+    /// Computed ahead of time over the interval the grid was built for.
+    fn bounding_box(&self, _time: Range<Real>) -> Option<Aabb> {
+        if self.unbounded.is_empty() {
+            self.bounds
+        } else {
+            None
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.objects.iter().all(Hittable::is_empty)
+    }
+
+    fn scene_stats(&self) -> SceneStats {
+        self.objects.scene_stats()
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::kdtree::KdTree;
+    use crate::material::CommonMat;
+    use crate::objects::Sphere;
+    use crate::render::random_scene;
+    use crate::utils;
+
+    /// The spheres of `random_scene`, without the ground, and rays through them from random points
+    /// above the ground, seeded.
+    fn sphere_grid_and_rays(rays: usize) -> (Vec<Sphere<CommonMat>>, Vec<Ray>) {
+        utils::seed_thread_rng(5);
+        let spheres: Vec<_> = random_scene().into_iter()
+            .filter(|sphere| sphere.center.y >= 0.0)
+            .collect();
+        let point = || Vec3::new(
+            24.0 * utils::random::<Real>() - 12.0,
+            2.0 * utils::random::<Real>(),
+            24.0 * utils::random::<Real>() - 12.0,
+        );
+        let rays = (0..rays).map(|_| Ray::new(point(), point() - point())).collect();
+        (spheres, rays)
+    }
+
+    #[test]
+    fn grid_hits_match_a_list() {
+        let (spheres, rays) = sphere_grid_and_rays(1000);
+        let grid = UniformGrid::new(spheres.clone());
+
+        let mut hits = 0;
+        for ray in &rays {
+            for bounds in [0.001..Real::INFINITY, 0.001..0.5] {
+                let expected = spheres.hit(ray, bounds.clone());
+                let hit = grid.hit(ray, bounds.clone());
+                assert_eq!(hit.as_ref().map(|hit| hit.t), expected.as_ref().map(|hit| hit.t), "{:?}", ray);
+                assert_eq!(grid.hit_any(ray, bounds.clone()), expected.is_some(), "{:?} in {:?}", ray, bounds);
+                hits += expected.is_some() as usize;
+            }
+        }
+        assert!(hits > 100, "only {} hits", hits);
+
+        // With the ground, which doesn't fit the grid at all, the nearest hits are still right.
+        let world = random_scene();
+        let grid = UniformGrid::new(world.clone());
+        for ray in &rays {
+            let t = |hit: Option<Hit>| hit.map(|hit| hit.t);
+            assert_eq!(t(grid.hit(ray, 0.001..Real::INFINITY)), t(world.hit(ray, 0.001..Real::INFINITY)), "{:?}", ray);
+        }
+    }
+
+    /// Run with `cargo test --release -- --ignored --nocapture grid_benchmark`.
+    #[test]
+    #[ignore]
+    fn grid_benchmark() {
+        use std::time::Instant;
+
+        let (spheres, rays) = sphere_grid_and_rays(200_000);
+        let time = |name: &str, world: &dyn Hittable| {
+            let start = Instant::now();
+            let hits = rays.iter()
+                .filter(|ray| world.hit(ray, 0.001..Real::INFINITY).is_some())
+                .count();
+            eprintln!("{}: {:?} ({} hits)", name, start.elapsed(), hits);
+        };
+
+        time("grid", &UniformGrid::new(spheres.clone()));
+        time("kd-tree", &KdTree::new(spheres.clone()));
+        time("list", &spheres);
+    }
+}
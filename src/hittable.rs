@@ -1,32 +1,296 @@
-use std::ops::Range;
-use std::sync::Arc;
-use rayon::prelude::*;
+use core::cmp::Ordering;
+use core::fmt;
+use core::ops::Range;
+#[cfg(feature = "std")]
+use std::cell::Cell;
+use alloc::sync::Arc;
 
-use crate::utils::{ Point3, Vec3 };
+use crate::utils::{ self, color, Point3, Vec3, Position, Direction, Real };
 use crate::ray::Ray;
-use crate::material::Scatter;
+use crate::material::{ Eval, Material, Scatter };
+use crate::aabb::Aabb;
+use crate::prelude::*;
+
+/// Interval of time during which rays are shot, so bounding boxes computed ahead of time must
+/// cover it.
+pub const SHUTTER: Range<Real> = 0.0..1.0;
+
+/// What a world is made of, for `RenderStats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SceneStats {
+    /// Primitives, and other objects that don't count what they are made of.
+    pub objects: u64,
+    /// How many of the `objects` are triangles.
+    pub triangles: u64,
+    /// Levels of the deepest acceleration structure, 0 without any.
+    pub tree_depth: u64,
+}
+
+impl SceneStats {
+    /// A single object.
+    pub const OBJECT: SceneStats = SceneStats { objects: 1, triangles: 0, tree_depth: 0 };
+
+    /// Stats of two sets of objects put together.
+    pub fn merge(self, other: SceneStats) -> SceneStats {
+        SceneStats {
+            objects: self.objects + other.objects,
+            triangles: self.triangles + other.triangles,
+            tree_depth: self.tree_depth.max(other.tree_depth),
+        }
+    }
+}
+
+/// Work done looking for hits, for tuning acceleration structures.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TraversalCounts {
+    /// Bounding boxes tested, and nodes or cells of acceleration structures visited.
+    pub node_visits: u64,
+    /// Rays tested against primitives, like spheres or triangles.
+    pub primitive_tests: u64,
+}
+
+#[cfg(feature = "std")]
+thread_local! {
+    /// Counts of this thread, only kept between `start_traversal_counts` and
+    /// `finish_traversal_counts`, so that the counters cost next to nothing otherwise.
+    static TRAVERSAL: Cell<Option<TraversalCounts>> = const { Cell::new(None) };
+}
+
+#[inline]
+pub fn count_node_visit() {
+    #[cfg(feature = "std")]
+    TRAVERSAL.with(|counts| {
+        if let Some(mut current) = counts.get() {
+            current.node_visits += 1;
+            counts.set(Some(current));
+        }
+    });
+}
+
+#[inline]
+pub fn count_primitive_test() {
+    #[cfg(feature = "std")]
+    TRAVERSAL.with(|counts| {
+        if let Some(mut current) = counts.get() {
+            current.primitive_tests += 1;
+            counts.set(Some(current));
+        }
+    });
+}
+
+/// Start counting the work done on this thread, from zero. Without `std` there is nowhere to keep
+/// counts per thread, so nothing is counted.
+pub fn start_traversal_counts() {
+    #[cfg(feature = "std")]
+    TRAVERSAL.with(|counts| counts.set(Some(TraversalCounts::default())));
+}
+
+/// Stop counting, with the work done on this thread since `start_traversal_counts`.
+pub fn finish_traversal_counts() -> TraversalCounts {
+    #[cfg(feature = "std")]
+    return TRAVERSAL.with(Cell::take).unwrap_or_default();
+    #[cfg(not(feature = "std"))]
+    return TraversalCounts::default();
+}
 
 pub trait Hittable {
-    fn hit(&self, ray: &Ray, bounds: Range<f32>) -> Option<Hit>;
+    fn hit(&self, ray: &Ray, bounds: Range<Real>) -> Option<Hit<'_>>;
+
+    /// Whether the ray hits anything at all within `bounds`. This is all shadow rays need, so
+    /// implementors should skip finding the nearest hit and shading it when they can.
+    fn hit_any(&self, ray: &Ray, bounds: Range<Real>) -> bool {
+        self.hit(ray, bounds).is_some()
+    }
+
+    /// Push every intersection with the ray within `bounds` to `hits`, nearest first. `Csg` needs
+    /// them to know where the ray is inside of a solid, so solids should override this, since by
+    /// default it only gives the nearest hit.
+    fn hit_all<'a>(&'a self, ray: &Ray, bounds: Range<Real>, hits: &mut Vec<Hit<'a>>) {
+        hits.extend(self.hit(ray, bounds));
+    }
+
+    /// Box enclosing the whole object at every instant of `time`, `None` if it is unbounded.
+    fn bounding_box(&self, _time: Range<Real>) -> Option<Aabb> {
+        None
+    }
+
+    /// Whether there is nothing in this object that could ever be hit.
+    fn is_empty(&self) -> bool {
+        false
+    }
+
+    /// Counts of what the object is made of. Collections and acceleration structures should
+    /// override this to add up their objects.
+    fn scene_stats(&self) -> SceneStats {
+        SceneStats::OBJECT
+    }
 }
 
-impl<'a, T: Hittable> Hittable for &'a T {
+impl<T: Hittable> Hittable for &T {
     #[inline]
-    fn hit(&self, ray: &Ray, bounds: Range<f32>) -> Option<Hit> {
+    fn hit(&self, ray: &Ray, bounds: Range<Real>) -> Option<Hit<'_>> {
         (*self).hit(ray, bounds)
     }
+
+    #[inline]
+    fn hit_any(&self, ray: &Ray, bounds: Range<Real>) -> bool {
+        (*self).hit_any(ray, bounds)
+    }
+
+    #[inline]
+    fn hit_all<'a>(&'a self, ray: &Ray, bounds: Range<Real>, hits: &mut Vec<Hit<'a>>) {
+        (*self).hit_all(ray, bounds, hits)
+    }
+
+    #[inline]
+    fn bounding_box(&self, time: Range<Real>) -> Option<Aabb> {
+        (*self).bounding_box(time)
+    }
+
+    #[inline]
+    fn is_empty(&self) -> bool {
+        T::is_empty(*self)
+    }
+
+    #[inline]
+    fn scene_stats(&self) -> SceneStats {
+        T::scene_stats(*self)
+    }
 }
 
-#[derive(Debug, Clone, Copy)]
-pub struct Hit {
-    pub point: Point3,
-    pub normal: Vec3,
-    pub t: f32,
-    pub scatter: Option<Scatter>,
+/// Lets a world be shared between scenes, e.g. between the frames of an animation, without
+/// cloning it.
+impl<T: Hittable + ?Sized> Hittable for Arc<T> {
+    #[inline]
+    fn hit(&self, ray: &Ray, bounds: Range<Real>) -> Option<Hit<'_>> {
+        (**self).hit(ray, bounds)
+    }
+
+    #[inline]
+    fn hit_any(&self, ray: &Ray, bounds: Range<Real>) -> bool {
+        (**self).hit_any(ray, bounds)
+    }
+
+    #[inline]
+    fn hit_all<'a>(&'a self, ray: &Ray, bounds: Range<Real>, hits: &mut Vec<Hit<'a>>) {
+        (**self).hit_all(ray, bounds, hits)
+    }
+
+    #[inline]
+    fn bounding_box(&self, time: Range<Real>) -> Option<Aabb> {
+        (**self).bounding_box(time)
+    }
+
+    #[inline]
+    fn is_empty(&self) -> bool {
+        (**self).is_empty()
+    }
+
+    #[inline]
+    fn scene_stats(&self) -> SceneStats {
+        (**self).scene_stats()
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Hit<'a> {
+    pub point: Position,
+    pub normal: Direction,
+    /// Unit vectors on the surface, making a right handed orthonormal frame with `normal`.
+    /// Surfaces with texture coordinates point `tangent` where `u` grows, others pick one that is
+    /// consistent across the surface where they can, see `with_tangent`.
+    pub tangent: Direction,
+    pub bitangent: Direction,
+    pub t: Real,
+    /// Whether the ray hit the outside of the surface. `normal` always faces the ray regardless.
+    pub is_front: bool,
+    /// Material of the surface that was hit. Shading is left to `scatter`, so that it only
+    /// happens for the nearest hit.
+    pub material: &'a dyn Material,
+}
+
+impl<'a> Hit<'a> {
+    pub fn new(
+        point: impl Into<Position>,
+        normal: impl Into<Direction>,
+        t: Real,
+        is_front: bool,
+        material: &'a dyn Material,
+    ) -> Hit<'a> {
+        Hit::along(point, normal, Vec3::zeros(), t, is_front, material)
+    }
+
+    /// Like `new`, with the tangent along `direction` projected onto the surface.
+    pub fn along(
+        point: impl Into<Position>,
+        normal: impl Into<Direction>,
+        direction: Vec3,
+        t: Real,
+        is_front: bool,
+        material: &'a dyn Material,
+    ) -> Hit<'a> {
+        let normal = normal.into();
+        let (tangent, bitangent) = utils::tangent_frame(&normal.0, &direction);
+        Hit {
+            point: point.into(),
+            normal,
+            tangent: tangent.into(),
+            bitangent: bitangent.into(),
+            t,
+            is_front,
+            material,
+        }
+    }
+
+    /// Same hit, with the tangent along `direction` projected onto the surface. Also used to fix up
+    /// the frame after changing `normal`.
+    #[inline]
+    pub fn with_tangent(self, direction: Vec3) -> Hit<'a> {
+        let (tangent, bitangent) = utils::tangent_frame(&self.normal.0, &direction);
+        Hit { tangent: tangent.into(), bitangent: bitangent.into(), ..self }
+    }
+
+    /// Shade the hit, for the `ray` that produced it. Negative attenuation from a misbehaving
+    /// material is clamped to zero, so that it can't take light away from the rest of the path.
+    #[inline]
+    pub fn scatter(&self, ray: &Ray) -> Option<Scatter> {
+        self.material.scatter(ray, self)
+            .map(|s| Scatter { attenuation: color::nonneg(s.attenuation), ..s })
+    }
+
+    /// How the surface reflects light arriving from `dir` back along `ray`, see `Material::eval`.
+    /// Negative values are clamped like in `scatter`.
+    #[inline]
+    pub fn eval(&self, ray: &Ray, dir: &Vec3) -> Option<Eval> {
+        self.material.eval(ray, self, dir)
+            .map(|eval| Eval { value: color::nonneg(eval.value), ..eval })
+    }
+
+    /// Like `scatter`, but with the material's deterministic `preview`.
+    #[inline]
+    pub fn preview(&self, ray: &Ray) -> Option<Scatter> {
+        self.material.preview(ray, self)
+            .map(|s| Scatter { attenuation: color::nonneg(s.attenuation), ..s })
+    }
+
+    /// Order hits by distance along the ray. Hits at exactly the same distance, like coincident
+    /// surfaces, prefer the front face. Used with `min_by`, which keeps the first of equal hits,
+    /// any remaining tie goes to whichever object comes first.
+    pub fn cmp_nearest(&self, other: &Hit<'_>) -> Ordering {
+        self.t.total_cmp(&other.t)
+            .then_with(|| other.is_front.cmp(&self.is_front))
+    }
 }
 
-impl Hit {
-    pub fn new(point: Point3, normal: Vec3, t: f32, scatter: Option<Scatter>) -> Hit {
-        Hit { point, normal, t, scatter }
+impl fmt::Debug for Hit<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Hit")
+            .field("point", &self.point)
+            .field("normal", &self.normal)
+            .field("tangent", &self.tangent)
+            .field("bitangent", &self.bitangent)
+            .field("t", &self.t)
+            .field("is_front", &self.is_front)
+            .finish_non_exhaustive()
     }
 }
@@ -0,0 +1,283 @@
+use core::ops::Range;
+
+use crate::aabb::Aabb;
+use crate::hittable::{ count_node_visit, Hittable, Hit, SceneStats, SHUTTER };
+use crate::par;
+use crate::ray::Ray;
+use crate::utils::Real;
+use crate::prelude::*;
+
+/// Nodes with at most this many objects are never split.
+const MAX_LEAF_OBJECTS: usize = 4;
+const MAX_DEPTH: usize = 24;
+/// Nodes with fewer objects build both halves on the current thread, below this handing them off
+/// to other threads costs more than it saves.
+const MIN_PARALLEL_OBJECTS: usize = 4096;
+
+/// Spatial subdivision where every node splits its space in two at the median of the centers of
+/// its objects, cycling through the x, y and z axes. Objects that straddle the split are kept in
+/// both halves.
+pub struct KdTree<T> {
+    objects: Vec<T>,
+    root: Option<KdNode>,
+    bounds: Option<Aabb>,
+    /// Objects without a bounding box, tested against every ray.
+    unbounded: Vec<usize>,
+}
+
+enum KdNode {
+    Leaf(Vec<usize>),
+    Split {
+        axis: usize,
+        at: Real,
+        below: Box<KdNode>,
+        above: Box<KdNode>,
+    },
+}
+
+impl<T: Hittable> KdTree<T> {
+    pub fn new(objects: Vec<T>) -> KdTree<T> {
+        KdTree::with_time_range(objects, SHUTTER)
+    }
+
+    /// Build a tree to trace rays shot during `time`, which is what the bounding boxes of moving
+    /// objects have to cover.
+    pub fn with_time_range(objects: Vec<T>, time: Range<Real>) -> KdTree<T> {
+        let mut bounded = Vec::new();
+        let mut unbounded = Vec::new();
+
+        for (i, obj) in objects.iter().enumerate() {
+            match obj.bounding_box(time.clone()) {
+                Some(bbox) => bounded.push((i, bbox)),
+                None       => unbounded.push(i),
+            }
+        }
+
+        let bounds = bounded.iter()
+            .map(|(_, bbox)| *bbox)
+            .reduce(|a, b| a.union(&b));
+
+        let root = bounds.map(|bounds| KdNode::build(bounded, &bounds, 0));
+
+        KdTree { objects, root, bounds, unbounded }
+    }
+
+    pub fn objects(&self) -> &[T] {
+        &self.objects
+    }
+
+    fn nearest_in(&self, indices: &[usize], ray: &Ray, bounds: Range<Real>) -> Option<Hit<'_>> {
+        indices.iter()
+            .filter_map(|&i| self.objects[i].hit(ray, bounds.clone()))
+            .min_by(Hit::cmp_nearest)
+    }
+
+    fn hit_node(&self, node: &KdNode, ray: &Ray, bounds: Range<Real>, span: Range<Real>) -> Option<Hit<'_>> {
+        count_node_visit();
+        match node {
+            KdNode::Leaf(indices) => self.nearest_in(indices, ray, bounds),
+
+            KdNode::Split { axis, at, below, above } => {
+                let (near, far, t_split) = crossing(*axis, *at, below, above, ray);
+
+                if t_split.is_nan() || t_split <= 0.0 || t_split > span.end {
+                    self.hit_node(near, ray, bounds, span)
+                } else if t_split < span.start {
+                    self.hit_node(far, ray, bounds, span)
+                } else {
+                    let near_hit = self.hit_node(near, ray, bounds.clone(), span.start..t_split);
+
+                    // Objects may straddle the split, so a hit in the near half could still be
+                    // behind something in the far half.
+                    match near_hit {
+                        Some(hit) if hit.t <= t_split => Some(hit),
+                        near_hit => {
+                            let far_hit = self.hit_node(far, ray, bounds, t_split..span.end);
+                            nearest(near_hit, far_hit)
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn hit_any_node(&self, node: &KdNode, ray: &Ray, bounds: Range<Real>, span: Range<Real>) -> bool {
+        count_node_visit();
+        match node {
+            KdNode::Leaf(indices) => {
+                indices.iter().any(|&i| self.objects[i].hit_any(ray, bounds.clone()))
+            }
+
+            KdNode::Split { axis, at, below, above } => {
+                let (near, far, t_split) = crossing(*axis, *at, below, above, ray);
+
+                if t_split.is_nan() || t_split <= 0.0 || t_split > span.end {
+                    self.hit_any_node(near, ray, bounds, span)
+                } else if t_split < span.start {
+                    self.hit_any_node(far, ray, bounds, span)
+                } else {
+                    self.hit_any_node(near, ray, bounds.clone(), span.start..t_split)
+                        || self.hit_any_node(far, ray, bounds, t_split..span.end)
+                }
+            }
+        }
+    }
+}
+
+/// The children of a split in the order the ray goes through them, and the `t` at which it
+/// crosses the split.
+fn crossing<'a>(axis: usize, at: Real, below: &'a KdNode, above: &'a KdNode, ray: &Ray) -> (&'a KdNode, &'a KdNode, Real) {
+    let origin = ray.origin.0[axis];
+    let dir = ray.dir[axis];
+    let (near, far) = if origin < at || (origin == at && dir <= 0.0) {
+        (below, above)
+    } else {
+        (above, below)
+    };
+    (near, far, (at - origin) / dir)
+}
+
+impl KdNode {
+    /// Levels of splits below this node, 1 for a leaf.
+    fn depth(&self) -> usize {
+        match self {
+            KdNode::Leaf(_) => 1,
+            KdNode::Split { below, above, .. } => 1 + below.depth().max(above.depth()),
+        }
+    }
+
+    fn build(objects: Vec<(usize, Aabb)>, bounds: &Aabb, depth: usize) -> KdNode {
+        if objects.len() <= MAX_LEAF_OBJECTS || depth >= MAX_DEPTH {
+            return KdNode::Leaf(objects.into_iter().map(|(i, _)| i).collect());
+        }
+
+        let axis = depth % 3;
+
+        let mut centers: Vec<Real> = objects.iter().map(|(_, bbox)| bbox.center()[axis]).collect();
+        centers.sort_by(|a, b| a.partial_cmp(b).unwrap_or(core::cmp::Ordering::Equal));
+        let at = centers[centers.len() / 2];
+
+        let below: Vec<_> = objects.iter().copied().filter(|(_, bbox)| bbox.min[axis] <= at).collect();
+        let above: Vec<_> = objects.iter().copied().filter(|(_, bbox)| bbox.max[axis] >= at).collect();
+
+        // Splitting doesn't separate anything, every object straddles the plane.
+        if below.len() == objects.len() && above.len() == objects.len() {
+            return KdNode::Leaf(objects.into_iter().map(|(i, _)| i).collect());
+        }
+
+        let mut below_bounds = *bounds;
+        below_bounds.max[axis] = at;
+        let mut above_bounds = *bounds;
+        above_bounds.min[axis] = at;
+
+        let build_below = || KdNode::build(below, &below_bounds, depth + 1);
+        let build_above = || KdNode::build(above, &above_bounds, depth + 1);
+        let (below, above) = if objects.len() >= MIN_PARALLEL_OBJECTS {
+            par::join(build_below, build_above)
+        } else {
+            (build_below(), build_above())
+        };
+
+        KdNode::Split { axis, at, below: Box::new(below), above: Box::new(above) }
+    }
+}
+
+fn nearest<'a>(a: Option<Hit<'a>>, b: Option<Hit<'a>>) -> Option<Hit<'a>> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(if b.cmp_nearest(&a).is_lt() { b } else { a }),
+        (a, b)             => a.or(b),
+    }
+}
+
+impl<T: Hittable> Hittable for KdTree<T> {
+    fn hit(&self, ray: &Ray, bounds: Range<Real>) -> Option<Hit<'_>> {
+        let unbounded_hit = self.nearest_in(&self.unbounded, ray, bounds.clone());
+
+        let tree_hit = self.root.as_ref().zip(self.bounds).and_then(|(root, scene_bounds)| {
+            let span = scene_bounds.hit(ray, bounds.clone())?;
+            self.hit_node(root, ray, bounds, span)
+        });
+
+        nearest(tree_hit, unbounded_hit)
+    }
+
+    fn hit_any(&self, ray: &Ray, bounds: Range<Real>) -> bool {
+        let hits_unbounded = self.unbounded.iter()
+            .any(|&i| self.objects[i].hit_any(ray, bounds.clone()));
+
+        hits_unbounded || self.root.as_ref().zip(self.bounds).is_some_and(|(root, scene_bounds)| {
+            scene_bounds.hit(ray, bounds.clone())
+                .is_some_and(|span| self.hit_any_node(root, ray, bounds, span))
+        })
+    }
+
+    /// Computed ahead of time over the interval the tree was built for.
+    fn bounding_box(&self, _time: Range<Real>) -> Option<Aabb> {
+        if self.unbounded.is_empty() {
+            self.bounds
+        } else {
+            None
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.objects.iter().all(Hittable::is_empty)
+    }
+
+    fn scene_stats(&self) -> SceneStats {
+        let objects = self.objects.scene_stats();
+        let depth = self.root.as_ref().map_or(0, KdNode::depth) as u64;
+        SceneStats { tree_depth: objects.tree_depth.max(depth), ..objects }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::material::Diffuse;
+    use crate::objects::Sphere;
+    use crate::utils::{ self, color, Vec3 };
+
+    /// Spheres in a 10 unit cube, and rays through it from random points, seeded.
+    fn random_spheres_and_rays(spheres: usize) -> (Vec<Sphere<Diffuse>>, Vec<Ray>) {
+        utils::seed_thread_rng(11);
+        let point = || Vec3::new(utils::random(), utils::random(), utils::random()) * 10.0;
+        let spheres = (0..spheres)
+            .map(|_| Sphere::new(point(), 0.05 + 0.3 * utils::random::<Real>(), Diffuse::new(color::mid_gray())))
+            .collect();
+        let rays = (0..500).map(|_| Ray::new(point(), point() - point())).collect();
+        (spheres, rays)
+    }
+
+    #[test]
+    fn tree_hits_match_a_list() {
+        let (spheres, rays) = random_spheres_and_rays(1000);
+        let tree = KdTree::new(spheres.clone());
+
+        let mut hits = 0;
+        for ray in &rays {
+            // Shadow rays only look as far as the light.
+            for bounds in [0.001..Real::INFINITY, 0.001..0.5] {
+                let expected = spheres.hit(ray, bounds.clone());
+                let hit = tree.hit(ray, bounds.clone());
+                assert_eq!(hit.as_ref().map(|hit| hit.t), expected.as_ref().map(|hit| hit.t), "{:?}", ray);
+                assert_eq!(tree.hit_any(ray, bounds.clone()), expected.is_some(), "{:?} in {:?}", ray, bounds);
+                hits += expected.is_some() as usize;
+            }
+        }
+        assert!(hits > 100, "only {} hits", hits);
+    }
+
+    #[test]
+    fn parallel_build_matches_a_single_thread() {
+        let (spheres, rays) = random_spheres_and_rays(2 * MIN_PARALLEL_OBJECTS);
+        let build = |threads| par::in_thread_pool(Some(threads), || KdTree::new(spheres.clone()));
+        let (single, parallel) = (build(1), build(8));
+
+        assert_eq!(single.scene_stats(), parallel.scene_stats());
+        for ray in &rays {
+            let t = |tree: &KdTree<_>| tree.hit(ray, 0.001..Real::INFINITY).map(|hit| hit.t);
+            assert_eq!(t(&single), t(&parallel), "{:?}", ray);
+        }
+    }
+}
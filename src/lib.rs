@@ -0,0 +1,47 @@
+//! A path tracer. The scene description and ray tracing core only need `alloc`, so with the `std`
+//! feature turned off they build for `no_std` targets; the renderers, image files and everything
+//! else that needs an operating system come with `std`.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+#![allow(dead_code)]
+#![allow(unused_imports)]
+
+extern crate alloc;
+
+pub mod utils;
+pub mod aabb;
+pub mod ray;
+pub mod objects;
+pub mod material;
+pub mod hittable;
+pub mod kdtree;
+pub mod grid;
+pub mod camera;
+pub mod texture;
+pub mod par;
+pub mod environment;
+pub mod light;
+
+#[cfg(feature = "std")]
+pub mod render;
+#[cfg(feature = "std")]
+pub mod testing;
+#[cfg(feature = "std")]
+pub mod blue_noise;
+#[cfg(feature = "std")]
+pub mod exr;
+#[cfg(feature = "std")]
+pub mod watch;
+#[cfg(feature = "std")]
+pub mod point_cloud;
+#[cfg(feature = "std")]
+pub mod mesh;
+
+/// What the core modules take from the `std` prelude, brought in from `alloc` and `num-traits`
+/// when there is no `std`.
+mod prelude {
+    #[cfg(not(feature = "std"))]
+    pub use alloc::{ boxed::Box, format, string::{ String, ToString }, vec, vec::Vec };
+    #[cfg(not(feature = "std"))]
+    pub use num_traits::Float;
+}
@@ -0,0 +1,176 @@
+use rand::Rng;
+
+use crate::ray::Ray;
+use crate::utils::{ self, consts, Color, Point3, Real, Vec3 };
+use crate::prelude::*;
+
+/// Something light leaves from, for tracers that follow light from its source instead of from
+/// the camera, like a photon mapper.
+pub trait Light {
+    /// Pick a ray leaving the light, with a unit direction, along with the power it carries: the
+    /// intensity towards it over the density it was picked with.
+    fn emit_photon(&self, rng: &mut impl Rng) -> (Ray, Color);
+}
+
+/// A point light that only shines within a cone, fading out smoothly between an inner and an
+/// outer angle. Being a point, rays never hit it, it only lights diffuse surfaces directly.
+#[derive(Debug, Clone, Copy)]
+pub struct SpotLight {
+    pub position: Point3,
+    /// Unit direction along the axis of the cone.
+    pub direction: Vec3,
+    /// Radiant intensity inside of the inner cone.
+    pub intensity: Color,
+    cos_inner: Real,
+    cos_outer: Real,
+}
+
+impl SpotLight {
+    /// The cone angles are in degrees, measured from its axis.
+    ///
+    /// # Panics
+    ///
+    /// If `inner_angle` is larger than `outer_angle`.
+    pub fn new(
+        position: Point3,
+        direction: Vec3,
+        inner_angle: Real,
+        outer_angle: Real,
+        intensity: Color,
+    ) -> SpotLight {
+        assert!(inner_angle <= outer_angle, "the inner cone of a spot light must fit in the outer one");
+
+        SpotLight {
+            position,
+            direction: direction.normalize(),
+            intensity,
+            cos_inner: inner_angle.to_radians().cos(),
+            cos_outer: outer_angle.to_radians().cos(),
+        }
+    }
+
+    /// Radiant intensity emitted towards `point`.
+    pub fn intensity_towards(&self, point: &Point3) -> Color {
+        let cos_angle = (point - self.position).normalize().dot(&self.direction);
+
+        let falloff = if cos_angle >= self.cos_inner {
+            1.0
+        } else if cos_angle <= self.cos_outer {
+            0.0
+        } else {
+            let t = (cos_angle - self.cos_outer) / (self.cos_inner - self.cos_outer);
+            t * t * (3.0 - 2.0 * t)
+        };
+
+        self.intensity * falloff
+    }
+}
+
+impl Light for SpotLight {
+    /// Only picks directions inside of the outer cone, where there is any light.
+    fn emit_photon(&self, rng: &mut impl Rng) -> (Ray, Color) {
+        // Uniformly over the solid angle of the cone.
+        let cos_theta = 1.0 - rng.gen::<Real>() * (1.0 - self.cos_outer);
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = rng.gen::<Real>() * consts::TAU;
+
+        // Any frame around the axis will do.
+        let (u, v) = utils::tangent_frame(&self.direction, &Vec3::zeros());
+        let dir = self.direction * cos_theta + (u * phi.cos() + v * phi.sin()) * sin_theta;
+        let solid_angle = consts::TAU * (1.0 - self.cos_outer);
+
+        let target = self.position + dir;
+        (Ray::new(self.position, dir), self.intensity_towards(&target) * solid_angle)
+    }
+}
+
+/// A light shining equally in every direction from a point. Used by the preview lighting, where
+/// it lights every surface directly instead of being found by bouncing around.
+#[derive(Debug, Clone, Copy)]
+pub struct PointLight {
+    pub position: Point3,
+    pub color: Color,
+    /// Radiant intensity, as a multiple of `color`.
+    pub intensity: Real,
+}
+
+impl PointLight {
+    pub fn new(position: Point3, color: Color, intensity: Real) -> PointLight {
+        PointLight { position, color, intensity }
+    }
+}
+
+impl Light for PointLight {
+    fn emit_photon(&self, rng: &mut impl Rng) -> (Ray, Color) {
+        // Uniformly over the sphere.
+        let z = 1.0 - 2.0 * rng.gen::<Real>();
+        let r = (1.0 - z * z).max(0.0).sqrt();
+        let phi = rng.gen::<Real>() * consts::TAU;
+        let dir = Vec3::new(r * phi.cos(), r * phi.sin(), z);
+
+        (Ray::new(self.position, dir), self.color * self.intensity * 4.0 * consts::PI)
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::utils::{ self, color };
+
+    #[test]
+    fn point_light_photons_leave_the_light_in_every_direction() {
+        let light = PointLight::new(Vec3::new(1.0, 2.0, 3.0), color::white(), 2.0);
+        let mut rng = utils::LocalRng;
+        utils::seed_thread_rng(0);
+
+        let mut mean_dir = Vec3::zeros();
+        for _ in 0..1000 {
+            let (ray, power) = light.emit_photon(&mut rng);
+            assert_eq!(ray.origin.0, light.position);
+            assert!((ray.dir.magnitude() - 1.0).abs() < 1e-4);
+            assert_eq!(power, color::white() * 8.0 * consts::PI);
+            mean_dir += ray.dir.0 / 1000.0;
+        }
+        assert!(mean_dir.magnitude() < 0.1);
+    }
+
+    #[test]
+    fn spot_lights_fade_out_between_their_cones() {
+        let light = SpotLight::new(Vec3::new(0.0, 5.0, 0.0), Vec3::new(0.0, -2.0, 0.0), 20.0, 30.0, color::new(4.0, 2.0, 1.0));
+        // On the floor, at the given angle from the axis.
+        let towards = |degrees: Real| light.intensity_towards(&Vec3::new(5.0 * degrees.to_radians().tan(), 0.0, 0.0));
+
+        assert_eq!(towards(0.0), light.intensity);
+        assert_eq!(towards(19.0), light.intensity);
+        assert_eq!(towards(31.0), Color::zeros());
+        assert_eq!(light.intensity_towards(&Vec3::new(0.0, 10.0, 0.0)), Color::zeros());
+
+        let partial = towards(25.0).x / 4.0;
+        assert!(partial > 0.0 && partial < 1.0, "{}", partial);
+        // Darker the further out.
+        assert!(towards(22.0).x > towards(25.0).x && towards(25.0).x > towards(28.0).x);
+        assert!(utils::approx_eq(&towards(25.0), &(light.intensity * partial), 1e-5));
+    }
+
+    #[test]
+    fn spot_light_photons_stay_inside_the_cone() {
+        let down = Vec3::new(0.0, -1.0, 0.0);
+        let light = SpotLight::new(Vec3::new(0.0, 5.0, 0.0), down, 20.0, 30.0, color::white());
+        let mut rng = utils::LocalRng;
+        utils::seed_thread_rng(0);
+
+        for _ in 0..1000 {
+            let (ray, power) = light.emit_photon(&mut rng);
+            assert_eq!(ray.origin.0, light.position);
+            assert!(ray.dir.normalize().dot(&down) >= Real::to_radians(30.0).cos() - 1e-4);
+
+            // Full power inside of the inner cone, less towards the edge.
+            let solid_angle = consts::TAU * (1.0 - Real::to_radians(30.0).cos());
+            if ray.dir.normalize().dot(&down) > Real::to_radians(20.0).cos() {
+                assert!(utils::approx_eq(&power, &(color::white() * solid_angle), 1e-4));
+            } else {
+                assert!(power.max() <= solid_angle);
+            }
+        }
+    }
+}
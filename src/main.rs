@@ -1,21 +1,14 @@
-#![feature(slice_as_chunks)]
-#![feature(atomic_from_mut)]
 #![allow(dead_code)]
 #![allow(unused_imports)]
 
 use std::default::Default;
+use std::sync::Arc;
 
-mod utils;
-mod ray;
-mod objects;
-mod material;
-mod hittable;
-mod camera;
-mod render;
-
-use objects::{ Sphere, WorldBuilder };
-use camera::Camera;
-use render::{
+use raytracing::render;
+use raytracing::objects::{ Sphere, WorldBuilder };
+use raytracing::camera::Camera;
+use raytracing::texture::ImageTexture;
+use raytracing::render::{
     multi_thread_render,
     simple_multi_thread_render,
     single_thread_render,
@@ -26,11 +19,19 @@ use render::{
 fn main() {
     let aspect_ratio = 3.0 / 2.0;
 
-    let render = RenderBuilder::new()
+    let mut render_builder = RenderBuilder::new();
+    render_builder
         .with_ratio(aspect_ratio, 720)
         .with_samples(32)
-        .with_max_bounces(8)
-        .build();
+        .with_max_bounces(8);
+
+    // An equirectangular image (e.g. a studio `.hdr`) may be passed to light the scene with.
+    if let Some(path) = std::env::args().nth(1) {
+        let hdri = ImageTexture::open(&path).expect("failed to load the environment map");
+        render_builder.with_environment_light(Arc::new(hdri));
+    }
+
+    let render = render_builder.build().expect("inconsistent render settings");
 
     let look_from  = nalgebra_glm::vec3(13.0, 2.0, 3.0);
     let look_at    = nalgebra_glm::vec3(0.0, 0.0, 0.0);
@@ -58,7 +59,7 @@ fn main() {
         .add(Sphere::new(Point3::new( 0.0, -100.5, -1.0), 100.0, material_ground.clone()))
         .add(Sphere::new(Point3::new( 0.0,    0.0, -1.0),   0.5, material_center.clone()))
         .add(Sphere::new(Point3::new(-1.0,    0.0, -1.0),   0.5, material_left.clone()))
-        .add(Sphere::new(Point3::new(-1.0,    0.0, -1.0), -0.45, material_left.clone()))
+        .add(Sphere::inside_out(Point3::new(-1.0, 0.0, -1.0), 0.45, material_left.clone()))
         .add(Sphere::new(Point3::new( 1.0,    0.0, -1.0),   0.5, material_right.clone()))
         .build();
     */
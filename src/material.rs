@@ -1,19 +1,67 @@
-use std::default::Default;
+use alloc::collections::BTreeMap;
+use core::default::Default;
+use core::fmt;
+use alloc::sync::Arc;
 
-use rand::{ random, thread_rng };
 use nalgebra_glm::vec3;
 
-use crate::ray::Ray;
-use crate::utils::{ self, Color, Vec3, Point3, color };
+use crate::hittable::Hit;
+use crate::ray::{ Ray, Medium, MediumStack, MediumTransition };
+use crate::texture::Texture;
+use crate::utils::{ self, random, Color, Vec3, Point3, color, Real };
+use crate::prelude::*;
 
 pub trait Material {
-    fn scatter(&self, ray: &Ray, normal: Vec3, is_front: bool) -> Option<Scatter>;
+    fn scatter(&self, ray: &Ray, hit: &Hit) -> Option<Scatter>;
+
+    /// Same as `scatter`, but without drawing any random numbers, for noise free previews.
+    /// Blurry reflections are sharp, glass always refracts unless the light is totally
+    /// reflected, and diffuse surfaces scatter along the normal. Materials that aren't random to
+    /// begin with don't need to implement it.
+    fn preview(&self, ray: &Ray, hit: &Hit) -> Option<Scatter> {
+        self.scatter(ray, hit)
+    }
+
+    /// Light reflected back along `ray` from light arriving at `hit` from `dir`, for lighting
+    /// surfaces directly. `None` for surfaces that only scatter in a few exact directions, like
+    /// mirrors and glass, which no light sample can find. Materials with `ScatterEvent::Diffuse`
+    /// events should implement it, those are the ones that get lit directly.
+    fn eval(&self, _ray: &Ray, _hit: &Hit, _dir: &Vec3) -> Option<Eval> {
+        None
+    }
 }
 
-impl<'a, Mat: Material> Material for &'a Mat {
+impl<Mat: Material> Material for &Mat {
+    #[inline]
+    fn scatter(&self, ray: &Ray, hit: &Hit) -> Option<Scatter> {
+        Mat::scatter(*self, ray, hit)
+    }
+
+    #[inline]
+    fn preview(&self, ray: &Ray, hit: &Hit) -> Option<Scatter> {
+        Mat::preview(*self, ray, hit)
+    }
+
+    #[inline]
+    fn eval(&self, ray: &Ray, hit: &Hit, dir: &Vec3) -> Option<Eval> {
+        Mat::eval(*self, ray, hit, dir)
+    }
+}
+
+impl<Mat: Material + ?Sized> Material for Arc<Mat> {
+    #[inline]
+    fn scatter(&self, ray: &Ray, hit: &Hit) -> Option<Scatter> {
+        self.as_ref().scatter(ray, hit)
+    }
+
+    #[inline]
+    fn preview(&self, ray: &Ray, hit: &Hit) -> Option<Scatter> {
+        self.as_ref().preview(ray, hit)
+    }
+
     #[inline]
-    fn scatter(&self, ray: &Ray, normal: Vec3, is_front: bool) -> Option<Scatter> {
-        Mat::scatter(*self, ray, normal, is_front)
+    fn eval(&self, ray: &Ray, hit: &Hit, dir: &Vec3) -> Option<Eval> {
+        self.as_ref().eval(ray, hit, dir)
     }
 }
 
@@ -21,38 +69,117 @@ impl<'a, Mat: Material> Material for &'a Mat {
 pub struct Scatter {
     pub attenuation: Color,
     pub scattered: Vec3,
+    pub event: ScatterEvent,
+    /// Set when the scattered ray crosses into or out of a medium.
+    pub transition: Option<MediumTransition>,
+    /// Set by surfaces that both reflect and refract, so that renderers can follow both rays
+    /// instead of the single randomly chosen `scattered`.
+    pub split: Option<Split>,
 }
 
 impl Scatter {
-    pub fn new(attenuation: Color, scattered: Vec3) -> Self {
+    pub fn new(attenuation: Color, scattered: Vec3, event: ScatterEvent) -> Self {
         Self {
             attenuation,
             scattered,
+            event,
+            transition: None,
+            split: None,
         }
     }
+
+    pub fn with_transition(self, transition: MediumTransition) -> Self {
+        Self { transition: Some(transition), ..self }
+    }
+
+    pub fn with_split(self, split: Split) -> Self {
+        Self { split: Some(split), ..self }
+    }
+}
+
+/// How the ray left the surface.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScatterEvent {
+    /// Bounced off of the surface in a mirror like direction, possibly blurred.
+    Reflected,
+    /// Went through the surface, into or out of the object.
+    Transmitted,
+    /// Bounced off of the surface in a random direction.
+    Diffuse,
+}
+
+/// Both of the rays leaving a surface that reflects and refracts light.
+#[derive(Debug, Clone, Copy)]
+pub struct Split {
+    /// Fraction of the light that is reflected, the rest is refracted.
+    pub reflectance: Real,
+    pub reflected: Vec3,
+    pub refracted: Vec3,
+    /// Medium transition of the refracted ray.
+    pub transition: MediumTransition,
+}
+
+/// How a surface reflects the light coming from one direction, see `Material::eval`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Eval {
+    /// The BRDF times the cosine of the angle between the direction and the normal, what the
+    /// light from the direction is multiplied by.
+    pub value: Color,
+    /// Density over solid angle with which `scatter` picks the direction.
+    pub pdf: Real,
+}
+
+impl Eval {
+    /// Eval of a lambertian surface of the given `albedo`, for a direction at an angle with a
+    /// cosine of `cos_theta` to the normal.
+    fn lambertian(albedo: Color, cos_theta: Real) -> Option<Eval> {
+        let pdf = cos_theta.max(0.0) / utils::consts::PI;
+        Some(Eval { value: albedo * pdf, pdf })
+    }
 }
 
 
 #[derive(Debug, Clone)]
 pub struct Diffuse {
     pub albedo: Color,
+    /// When set, the albedo is taken from the texture instead.
+    pub texture: Option<Arc<dyn Texture + Send + Sync>>,
 }
 
 impl Diffuse {
     pub fn new(albedo: Color) -> Self {
-        Diffuse { albedo }
+        Diffuse { albedo, texture: None }
+    }
+
+    pub fn textured(texture: impl Texture + Send + Sync + 'static) -> Self {
+        Diffuse { albedo: color::white(), texture: Some(Arc::new(texture)) }
+    }
+
+    fn albedo_at(&self, point: &Point3) -> Color {
+        match &self.texture {
+            Some(texture) => texture.value(point),
+            None          => self.albedo,
+        }
     }
 }
 
 impl Material for Diffuse {
-    fn scatter(&self, _: &Ray, normal: Vec3, _: bool) -> Option<Scatter> {
-        let mut scatter_dir = normal + utils::random_unit();
+    fn scatter(&self, _: &Ray, hit: &Hit) -> Option<Scatter> {
+        let mut scatter_dir = hit.normal.0 + utils::random_unit();
 
         if (0.0..1e-8).contains(&scatter_dir.magnitude_squared()) {
-            scatter_dir = normal;
+            scatter_dir = hit.normal.0;
         }
 
-        Some(Scatter::new(self.albedo, scatter_dir))
+        Some(Scatter::new(self.albedo_at(&hit.point.0), scatter_dir, ScatterEvent::Diffuse))
+    }
+
+    fn preview(&self, _: &Ray, hit: &Hit) -> Option<Scatter> {
+        Some(Scatter::new(self.albedo_at(&hit.point.0), hit.normal.0, ScatterEvent::Diffuse))
+    }
+
+    fn eval(&self, _: &Ray, hit: &Hit, dir: &Vec3) -> Option<Eval> {
+        Eval::lambertian(self.albedo_at(&hit.point.0), dir.normalize().dot(&hit.normal))
     }
 }
 
@@ -62,29 +189,137 @@ impl Default for Diffuse {
     }
 }
 
+/// A rough matte surface, like clay, concrete or the moon, which unlike `Diffuse` gets brighter
+/// when both lit and seen at grazing angles.
+#[derive(Debug, Clone)]
+pub struct OrenNayar {
+    pub albedo: Color,
+    /// Standard deviation of the slope of the microfacets, in radians. At 0 this is the same as
+    /// `Diffuse`.
+    pub roughness: Real,
+}
+
+impl OrenNayar {
+    pub fn new(albedo: Color, roughness: Real) -> Self {
+        Self { albedo, roughness }
+    }
+
+    /// Oren-Nayar reflectance relative to a lambertian surface with the same albedo, for unit
+    /// vectors from the surface to the viewer and to the light.
+    fn reflectance(&self, normal: &Vec3, to_viewer: &Vec3, to_light: &Vec3) -> Real {
+        let sigma2 = self.roughness * self.roughness;
+        let a = 1.0 - 0.5 * sigma2 / (sigma2 + 0.33);
+        let b = 0.45 * sigma2 / (sigma2 + 0.09);
+
+        let cos_r = normal.dot(to_viewer).clamp(0.0, 1.0);
+        let cos_i = normal.dot(to_light).clamp(0.0, 1.0);
+        let sin_r = (1.0 - cos_r * cos_r).sqrt();
+        let sin_i = (1.0 - cos_i * cos_i).sqrt();
+
+        // Cosine of the angle between the two directions projected onto the surface, 0 when
+        // either of them is along the normal and has no azimuth.
+        let view_tangent = to_viewer - normal * cos_r;
+        let light_tangent = to_light - normal * cos_i;
+        let cos_phi = match (view_tangent.try_normalize(1e-6), light_tangent.try_normalize(1e-6)) {
+            (Some(view), Some(light)) => view.dot(&light).max(0.0),
+            _                         => 0.0,
+        };
+
+        // `sin(alpha) * tan(beta)`, with alpha the larger of the two angles to the normal and
+        // beta the smaller one.
+        let sin_alpha = sin_r.max(sin_i);
+        let tan_beta = sin_r.min(sin_i) / cos_r.max(cos_i).max(1e-6);
+
+        a + b * cos_phi * sin_alpha * tan_beta
+    }
+}
+
+impl Material for OrenNayar {
+    fn scatter(&self, ray: &Ray, hit: &Hit) -> Option<Scatter> {
+        let mut scatter_dir = hit.normal.0 + utils::random_unit();
+
+        if (0.0..1e-8).contains(&scatter_dir.magnitude_squared()) {
+            scatter_dir = hit.normal.0;
+        }
+
+        // Sampled by cosine like `Diffuse`, so only the difference to lambertian is left to weigh.
+        let reflectance = self.reflectance(&hit.normal.0, &-ray.dir.normalize(), &scatter_dir.normalize());
+        Some(Scatter::new(self.albedo * reflectance, scatter_dir, ScatterEvent::Diffuse))
+    }
+
+    fn preview(&self, ray: &Ray, hit: &Hit) -> Option<Scatter> {
+        let reflectance = self.reflectance(&hit.normal.0, &-ray.dir.normalize(), &hit.normal.0);
+        Some(Scatter::new(self.albedo * reflectance, hit.normal.0, ScatterEvent::Diffuse))
+    }
+
+    fn eval(&self, ray: &Ray, hit: &Hit, dir: &Vec3) -> Option<Eval> {
+        let dir = dir.normalize();
+        let reflectance = self.reflectance(&hit.normal.0, &-ray.dir.normalize(), &dir);
+        Eval::lambertian(self.albedo * reflectance, dir.dot(&hit.normal))
+    }
+}
+
 
 #[derive(Debug, Clone)]
 pub struct Metal {
     pub albedo: Color,
-    pub fuzzy: f32,
+    pub fuzzy: Real,
+    /// Brighten the reflection towards white at grazing angles, like real metals do.
+    pub fresnel: bool,
+    /// When set, `fuzzy` is taken from the luminance of the texture instead.
+    pub roughness: Option<Arc<dyn Texture + Send + Sync>>,
 }
 
 impl Metal {
-    pub fn new(albedo: Color, fuzzy: f32) -> Self {
-        Self { albedo, fuzzy }
+    pub fn new(albedo: Color, fuzzy: Real) -> Self {
+        Self { albedo, fuzzy, fresnel: false, roughness: None }
+    }
+
+    pub fn with_fresnel(self, fresnel: bool) -> Self {
+        Self { fresnel, ..self }
+    }
+
+    pub fn with_roughness(self, roughness: impl Texture + Send + Sync + 'static) -> Self {
+        Self { roughness: Some(Arc::new(roughness)), ..self }
+    }
+
+    /// How much the reflection is blurred at `point`.
+    pub fn fuzz_at(&self, point: &Point3) -> Real {
+        match &self.roughness {
+            Some(roughness) => color::luminance(roughness.value(point)),
+            None            => self.fuzzy,
+        }
+    }
+
+    /// Color of the reflection for light arriving at an angle with cosine `cos_theta` to the
+    /// normal. With `fresnel` this uses Schlick's approximation with the albedo as the
+    /// reflectance at normal incidence.
+    pub fn reflectance(&self, cos_theta: Real) -> Color {
+        if self.fresnel {
+            let weight = (1.0 - cos_theta.clamp(0.0, 1.0)).powi(5);
+            color::lerp(self.albedo, color::white(), weight)
+        } else {
+            self.albedo
+        }
     }
 }
 
 impl Material for Metal {
-    fn scatter(&self, ray: &Ray, normal: Vec3,  _: bool) -> Option<Scatter> {
-        let reflected = reflect(ray.dir, normal) + utils::random_unit() * self.fuzzy;
+    fn scatter(&self, ray: &Ray, hit: &Hit) -> Option<Scatter> {
+        let reflected = reflect(ray.dir.0, hit.normal.0) + utils::random_unit() * self.fuzz_at(&hit.point.0);
 
-        if reflected.dot(&normal) >= 0.0 {
-            Some(Scatter::new(self.albedo, reflected))
+        if reflected.dot(&hit.normal.0) >= 0.0 {
+            let cos_theta = (-ray.dir.normalize()).dot(&hit.normal.0);
+            Some(Scatter::new(self.reflectance(cos_theta), reflected, ScatterEvent::Reflected))
         } else {
             None
         }
     }
+
+    fn preview(&self, ray: &Ray, hit: &Hit) -> Option<Scatter> {
+        let cos_theta = (-ray.dir.normalize()).dot(&hit.normal.0);
+        Some(Scatter::new(self.reflectance(cos_theta), reflect(ray.dir.0, hit.normal.0), ScatterEvent::Reflected))
+    }
 }
 
 impl Default for Metal {
@@ -93,41 +328,250 @@ impl Default for Metal {
     }
 }
 
+/// Brushed metal, whose reflection is blurred differently along the grain left by brushing it
+/// than across it. The grain runs along the `tangent` of the hit, which follows `u` on surfaces
+/// with texture coordinates.
+#[derive(Debug, Clone)]
+pub struct AnisotropicMetal {
+    pub albedo: Color,
+    /// How much the reflection is blurred along the grain.
+    pub roughness_along: Real,
+    /// How much the reflection is blurred across the grain.
+    pub roughness_across: Real,
+}
+
+impl AnisotropicMetal {
+    pub fn new(albedo: Color, roughness_along: Real, roughness_across: Real) -> Self {
+        Self { albedo, roughness_along, roughness_across }
+    }
+}
+
+impl Material for AnisotropicMetal {
+    fn scatter(&self, ray: &Ray, hit: &Hit) -> Option<Scatter> {
+        let offset = utils::random_in_unit_disc(&mut utils::LocalRng);
+        let reflected = reflect(ray.dir.normalize(), hit.normal.0)
+            + hit.tangent.0 * (offset.x * self.roughness_along)
+            + hit.bitangent.0 * (offset.y * self.roughness_across);
+
+        if reflected.dot(&hit.normal.0) > 0.0 {
+            Some(Scatter::new(self.albedo, reflected, ScatterEvent::Reflected))
+        } else {
+            None
+        }
+    }
+
+    fn preview(&self, ray: &Ray, hit: &Hit) -> Option<Scatter> {
+        Some(Scatter::new(self.albedo, reflect(ray.dir.normalize(), hit.normal.0), ScatterEvent::Reflected))
+    }
+}
+
+/// A perfectly specular surface. Same as a `Metal` with no fuzz, but it doesn't draw any random
+/// numbers.
+#[derive(Debug, Clone)]
+pub struct Mirror {
+    pub albedo: Color,
+}
+
+impl Mirror {
+    pub fn new(albedo: Color) -> Self {
+        Self { albedo }
+    }
+}
+
+impl Material for Mirror {
+    #[inline]
+    fn scatter(&self, ray: &Ray, hit: &Hit) -> Option<Scatter> {
+        Some(Scatter::new(self.albedo, reflect(ray.dir.0, hit.normal.0), ScatterEvent::Reflected))
+    }
+}
+
+impl Default for Mirror {
+    fn default() -> Mirror {
+        Mirror::new(color::white())
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Dielectric {
-    pub ior: f32,
+    pub ior: Real,
+    /// Inside of overlapping dielectrics, only the surfaces of the one with the highest priority
+    /// are seen, see `Medium::priority`.
+    pub priority: u32,
 }
 
 impl Dielectric {
-    pub fn new(ior: f32) -> Self {
-        Self { ior }
+    pub fn new(ior: Real) -> Self {
+        Self { ior, priority: 0 }
+    }
+
+    pub fn with_priority(self, priority: u32) -> Self {
+        Self { priority, ..self }
+    }
+
+    pub fn medium(&self) -> Medium {
+        Medium { ior: self.ior, priority: self.priority }
+    }
+
+    /// Ratio between the indices of refraction of the medium the ray comes from and the one it
+    /// goes into, when hitting the surface of this dielectric from within `medium`.
+    pub fn ior_ratio(&self, medium: &MediumStack, is_front: bool) -> Real {
+        if is_front {
+            medium.ior() / self.ior
+        } else {
+            self.ior / medium.without(self.medium()).ior()
+        }
     }
 
     // Use Schlick's approximation for reflectance.
-    fn reflectance(cos: f32, ior_ratio: f32) -> f32 {
+    fn reflectance(cos: Real, ior_ratio: Real) -> Real {
         let r0 = ((1.0 - ior_ratio) / (1.0 + ior_ratio)).powi(2);
         r0 + (1.0 - r0) * (1.0 - cos).powi(5)
     }
 }
 
 impl Material for Dielectric {
-    fn scatter(&self, ray: &Ray, normal: Vec3, is_front: bool) -> Option<Scatter> {
-        let ior_ratio = if is_front { 1.0 / self.ior } else { self.ior };
+    fn scatter(&self, ray: &Ray, hit: &Hit) -> Option<Scatter> {
+        let transition = if hit.is_front {
+            MediumTransition::Enter(self.medium())
+        } else {
+            MediumTransition::Exit(self.medium())
+        };
 
-        let cos_theta = (-ray.dir).dot(&normal).min(1.0);
+        // Inside of a medium that takes precedence the surface isn't there, but the ray still
+        // has to keep track of crossing it.
+        if ray.payload.medium.overrides(self.medium()) {
+            let scatter = Scatter::new(color::white(), ray.dir.0, ScatterEvent::Transmitted);
+            return Some(scatter.with_transition(transition));
+        }
+
+        let ior_ratio = self.ior_ratio(&ray.payload.medium, hit.is_front);
+
+        // The angles must be computed from the unit direction, otherwise the TIR check below is
+        // wrong for rays that aren't normalized and `refract` gets called when it shouldn't.
+        let unit_dir = ray.dir.normalize();
+        let cos_theta = (-unit_dir).dot(&hit.normal.0).min(1.0);
         let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
 
-        // Total Internal Reflection
-        let tir = ior_ratio * sin_theta > 1.0;
+        // Total Internal Reflection, there is nothing to pick at random.
+        if ior_ratio * sin_theta > 1.0 {
+            let reflected = reflect(unit_dir, hit.normal.0);
+            return Some(Scatter::new(color::white(), reflected, ScatterEvent::Reflected));
+        }
+
         let reflectance = Dielectric::reflectance(cos_theta, ior_ratio);
 
-        let scattered = if tir || reflectance > random::<f32>() {
-            reflect(ray.dir.normalize(), normal)
+        let split = Split {
+            reflectance,
+            reflected: reflect(unit_dir, hit.normal.0),
+            refracted: refract(unit_dir, hit.normal.0, ior_ratio),
+            transition,
+        };
+
+        let scatter = if reflectance > random::<Real>() {
+            Scatter::new(color::white(), split.reflected, ScatterEvent::Reflected)
         } else {
-            refract(ray.dir.normalize(), normal, ior_ratio)
+            Scatter::new(color::white(), split.refracted, ScatterEvent::Transmitted)
+                .with_transition(transition)
         };
+        Some(scatter.with_split(split))
+    }
+
+    fn preview(&self, ray: &Ray, hit: &Hit) -> Option<Scatter> {
+        let scatter = self.scatter(ray, hit)?;
+        match scatter.split {
+            Some(split) => Some(
+                Scatter::new(color::white(), split.refracted, ScatterEvent::Transmitted)
+                    .with_transition(split.transition)
+                    .with_split(split)
+            ),
+            // Totally reflected, or passing through an overridden surface, neither of which is
+            // random.
+            None => Some(scatter),
+        }
+    }
+}
+
+/// Randomly behaves like `a` with probability `factor`, and like `b` otherwise. On average this
+/// mixes the two, e.g. a glossy coat over a diffuse base.
+#[derive(Debug, Clone)]
+pub struct Blend {
+    pub a: Box<CommonMat>,
+    pub b: Box<CommonMat>,
+    pub factor: Real,
+}
+
+impl Blend {
+    pub fn new(a: impl Into<CommonMat>, b: impl Into<CommonMat>, factor: Real) -> Self {
+        Blend { a: Box::new(a.into()), b: Box::new(b.into()), factor }
+    }
+}
+
+impl Material for Blend {
+    fn scatter(&self, ray: &Ray, hit: &Hit) -> Option<Scatter> {
+        if random::<Real>() < self.factor {
+            self.a.scatter(ray, hit)
+        } else {
+            self.b.scatter(ray, hit)
+        }
+    }
+
+    /// Looks like whichever of the two materials it mostly is.
+    fn preview(&self, ray: &Ray, hit: &Hit) -> Option<Scatter> {
+        if self.factor >= 0.5 {
+            self.a.preview(ray, hit)
+        } else {
+            self.b.preview(ray, hit)
+        }
+    }
+
+    /// Both materials weighted by how often `scatter` picks each, where one that can't be
+    /// evaluated adds nothing.
+    fn eval(&self, ray: &Ray, hit: &Hit, dir: &Vec3) -> Option<Eval> {
+        let a = self.a.eval(ray, hit, dir);
+        let b = self.b.eval(ray, hit, dir);
+        if a.is_none() && b.is_none() {
+            return None;
+        }
 
-        Some(Scatter::new(color::white(), scattered))
+        let zero = Eval { value: color::black(), pdf: 0.0 };
+        let (a, b) = (a.unwrap_or(zero), b.unwrap_or(zero));
+        Some(Eval {
+            value: a.value * self.factor + b.value * (1.0 - self.factor),
+            pdf: a.pdf * self.factor + b.pdf * (1.0 - self.factor),
+        })
+    }
+}
+
+/// Any material behind a pointer, for the ones `CommonMat` doesn't list.
+#[derive(Clone)]
+pub struct BoxMat(pub Arc<dyn Material + Send + Sync>);
+
+impl BoxMat {
+    pub fn new(material: impl Material + Send + Sync + 'static) -> Self {
+        BoxMat(Arc::new(material))
+    }
+}
+
+impl Material for BoxMat {
+    #[inline]
+    fn scatter(&self, ray: &Ray, hit: &Hit) -> Option<Scatter> {
+        self.0.scatter(ray, hit)
+    }
+
+    #[inline]
+    fn preview(&self, ray: &Ray, hit: &Hit) -> Option<Scatter> {
+        self.0.preview(ray, hit)
+    }
+
+    #[inline]
+    fn eval(&self, ray: &Ray, hit: &Hit, dir: &Vec3) -> Option<Eval> {
+        self.0.eval(ray, hit, dir)
+    }
+}
+
+impl fmt::Debug for BoxMat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("BoxMat(..)")
     }
 }
 
@@ -135,18 +579,59 @@ impl Material for Dielectric {
 #[derive(Debug, Clone)]
 pub enum CommonMat {
     Diffuse(Diffuse),
+    OrenNayar(OrenNayar),
     Metal(Metal),
+    AnisotropicMetal(AnisotropicMetal),
+    Mirror(Mirror),
     Dielectric(Dielectric),
+    Blend(Blend),
+    /// Custom or rarely used materials, at the cost of a dynamic call.
+    Boxed(BoxMat),
 }
 
 impl Material for CommonMat {
-    fn scatter(&self, ray: &Ray, normal: Vec3, is_front: bool) -> Option<Scatter> {
+    fn scatter(&self, ray: &Ray, hit: &Hit) -> Option<Scatter> {
+        use CommonMat::*;
+
+        match self {
+            Diffuse(mat)          => mat.scatter(ray, hit),
+            OrenNayar(mat)        => mat.scatter(ray, hit),
+            Metal(mat)            => mat.scatter(ray, hit),
+            AnisotropicMetal(mat) => mat.scatter(ray, hit),
+            Mirror(mat)           => mat.scatter(ray, hit),
+            Dielectric(mat)       => mat.scatter(ray, hit),
+            Blend(mat)            => mat.scatter(ray, hit),
+            Boxed(mat)            => mat.scatter(ray, hit),
+        }
+    }
+
+    fn preview(&self, ray: &Ray, hit: &Hit) -> Option<Scatter> {
+        use CommonMat::*;
+
+        match self {
+            Diffuse(mat)          => mat.preview(ray, hit),
+            OrenNayar(mat)        => mat.preview(ray, hit),
+            Metal(mat)            => mat.preview(ray, hit),
+            AnisotropicMetal(mat) => mat.preview(ray, hit),
+            Mirror(mat)           => mat.preview(ray, hit),
+            Dielectric(mat)       => mat.preview(ray, hit),
+            Blend(mat)            => mat.preview(ray, hit),
+            Boxed(mat)            => mat.preview(ray, hit),
+        }
+    }
+
+    fn eval(&self, ray: &Ray, hit: &Hit, dir: &Vec3) -> Option<Eval> {
         use CommonMat::*;
 
         match self {
-            Diffuse(mat)     => mat.scatter(ray, normal, is_front),
-            Metal(mat)       => mat.scatter(ray, normal, is_front),
-            Dielectric(mat) => mat.scatter(ray, normal, is_front),
+            Diffuse(mat)          => mat.eval(ray, hit, dir),
+            OrenNayar(mat)        => mat.eval(ray, hit, dir),
+            Metal(mat)            => mat.eval(ray, hit, dir),
+            AnisotropicMetal(mat) => mat.eval(ray, hit, dir),
+            Mirror(mat)           => mat.eval(ray, hit, dir),
+            Dielectric(mat)       => mat.eval(ray, hit, dir),
+            Blend(mat)            => mat.eval(ray, hit, dir),
+            Boxed(mat)            => mat.eval(ray, hit, dir),
         }
     }
 }
@@ -157,26 +642,450 @@ impl From<Diffuse> for CommonMat {
     }
 }
 
+impl From<OrenNayar> for CommonMat {
+    fn from(v: OrenNayar) -> CommonMat {
+        CommonMat::OrenNayar(v)
+    }
+}
+
 impl From<Metal> for CommonMat {
     fn from(v: Metal) -> CommonMat {
         CommonMat::Metal(v)
     }
 }
 
+impl From<AnisotropicMetal> for CommonMat {
+    fn from(v: AnisotropicMetal) -> CommonMat {
+        CommonMat::AnisotropicMetal(v)
+    }
+}
+
+impl From<Mirror> for CommonMat {
+    fn from(v: Mirror) -> CommonMat {
+        CommonMat::Mirror(v)
+    }
+}
+
 impl From<Dielectric> for CommonMat {
     fn from(v: Dielectric) -> CommonMat {
         CommonMat::Dielectric(v)
     }
 }
 
+impl From<Blend> for CommonMat {
+    fn from(v: Blend) -> CommonMat {
+        CommonMat::Blend(v)
+    }
+}
+
+impl From<BoxMat> for CommonMat {
+    fn from(v: BoxMat) -> CommonMat {
+        CommonMat::Boxed(v)
+    }
+}
+
+/// Materials shared by name, so that many objects can reference (and be edited through) the
+/// same definition.
+#[derive(Debug, Clone, Default)]
+pub struct MaterialRegistry {
+    materials: BTreeMap<String, Arc<CommonMat>>,
+}
+
+impl MaterialRegistry {
+    pub fn new() -> MaterialRegistry {
+        MaterialRegistry::default()
+    }
+
+    /// Register a material under `name`, replacing any previous one with the same name.
+    pub fn insert(
+        &mut self,
+        name: impl Into<String>,
+        material: impl Into<CommonMat>,
+    ) -> Arc<CommonMat> {
+        let material = Arc::new(material.into());
+        self.materials.insert(name.into(), material.clone());
+        material
+    }
+
+    pub fn get(&self, name: &str) -> Result<Arc<CommonMat>, UnknownMaterial> {
+        self.materials
+            .get(name)
+            .cloned()
+            .ok_or_else(|| UnknownMaterial(name.to_string()))
+    }
+}
+
+/// A material name that isn't in the `MaterialRegistry`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownMaterial(pub String);
+
+impl fmt::Display for UnknownMaterial {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unknown material `{}`", self.0)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for UnknownMaterial {}
+
 pub fn reflect(incident: Vec3, normal: Vec3) -> Vec3 {
     incident - normal * 2.0 * incident.dot(&normal)
 }
 
-fn refract(incident: Vec3, normal: Vec3, ior_ratio: f32) -> Vec3 {
+/// Refract a unit `incident` direction through a surface with unit `normal` (pointing against
+/// `incident`). The caller must have ruled out total internal reflection.
+fn refract(incident: Vec3, normal: Vec3, ior_ratio: Real) -> Vec3 {
     let cos_theta = (-incident).dot(&normal).min(1.0);
     let refracted_perp = (incident + normal * cos_theta) * ior_ratio;
-    let refracted_par  = -normal * (1.0 - refracted_perp.magnitude_squared()).abs().sqrt();
+    // Only clamp away rounding error, a meaningfully negative value here means TIR.
+    let refracted_par  = -normal * (1.0 - refracted_perp.magnitude_squared()).max(0.0).sqrt();
     refracted_perp + refracted_par
 }
 
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::ray::Background;
+
+    const EPS: Real = 1e-5;
+
+    fn assert_close(a: Vec3, b: Vec3) {
+        assert!(utils::approx_eq(&a, &b, EPS), "{:?} != {:?}", a, b);
+    }
+
+    /// Scatter `ray` off of `material` at its origin, on a surface with unit `normal`.
+    fn scatter_at(material: &impl Material, ray: &Ray, normal: Vec3, is_front: bool) -> Option<Scatter> {
+        material.scatter(ray, &Hit::new(ray.origin, normal, 0.0, is_front, material))
+    }
+
+    #[test]
+    fn reflect_mirrors_about_normal() {
+        let normal = vec3(0.0, 1.0, 0.0);
+        let incident = vec3(1.0, -1.0, 0.0).normalize();
+        assert_close(reflect(incident, normal), vec3(1.0, 1.0, 0.0).normalize());
+    }
+
+    #[test]
+    fn refract_normal_incidence_passes_straight_through() {
+        let normal = vec3(0.0, 1.0, 0.0);
+        let incident = vec3(0.0, -1.0, 0.0);
+        assert_close(refract(incident, normal, 1.0 / 1.5), incident);
+    }
+
+    #[test]
+    fn refract_follows_snells_law() {
+        let normal = vec3(0.0, 1.0, 0.0);
+        let incident = vec3(1.0, -1.0, 0.0).normalize();
+        let ior_ratio = 1.0 / 1.5;
+        let refracted = refract(incident, normal, ior_ratio);
+
+        assert!((refracted.magnitude() - 1.0).abs() < EPS);
+        // sin(theta') = ior_ratio * sin(theta)
+        assert!((refracted.x - ior_ratio * incident.x).abs() < EPS);
+        assert!(refracted.y < 0.0);
+    }
+
+    #[test]
+    fn refract_from_denser_medium_bends_away_from_normal() {
+        // Going from glass into air just below the critical angle.
+        let normal = vec3(0.0, 1.0, 0.0);
+        let ior_ratio: Real = 1.5;
+        let critical = (1.0 / ior_ratio).asin();
+        let theta = critical - 0.01;
+        let incident = vec3(theta.sin(), -theta.cos(), 0.0);
+        let refracted = refract(incident, normal, ior_ratio);
+
+        assert!((refracted.magnitude() - 1.0).abs() < 1e-4);
+        assert!(refracted.x > incident.x);
+        assert!(refracted.y < 0.0);
+    }
+
+    #[test]
+    fn dielectric_reflects_past_critical_angle() {
+        // Inside the glass, grazing the surface: always total internal reflection.
+        let normal = vec3(0.0, 1.0, 0.0);
+        let ray = Ray::new(vec3(0.0, 0.0, 0.0), vec3(4.0, -1.0, 0.0));
+
+        for _ in 0..32 {
+            let scatter = scatter_at(&Dielectric::new(1.5), &ray, normal, false).unwrap();
+            assert_close(scatter.scattered, reflect(ray.dir.normalize(), normal));
+        }
+    }
+
+    #[test]
+    fn scatter_events_tell_reflection_from_transmission() {
+        let normal = vec3(0.0, 1.0, 0.0);
+
+        // Grazing the surface from inside of the glass.
+        let grazing = Ray::new(vec3(0.0, 0.0, 0.0), vec3(4.0, -1.0, 0.0));
+        let scatter = scatter_at(&Dielectric::new(1.5), &grazing, normal, false).unwrap();
+        assert_eq!(scatter.event, ScatterEvent::Reflected);
+        assert!(scatter.split.is_none() && scatter.transition.is_none());
+
+        // Straight into it, where almost all of the light gets through.
+        let straight = Ray::new(vec3(0.0, 1.0, 0.0), vec3(0.0, -1.0, 0.0));
+        utils::seed_thread_rng(0);
+        let transmitted = (0..100)
+            .filter_map(|_| scatter_at(&Dielectric::new(1.5), &straight, normal, true))
+            .filter(|scatter| scatter.event == ScatterEvent::Transmitted)
+            .count();
+        assert!(transmitted > 80, "{}", transmitted);
+
+        let diffuse = scatter_at(&Diffuse::default(), &straight, normal, true).unwrap();
+        assert_eq!(diffuse.event, ScatterEvent::Diffuse);
+        let mirror = scatter_at(&Mirror::new(color::white()), &straight, normal, true).unwrap();
+        assert_eq!(mirror.event, ScatterEvent::Reflected);
+    }
+
+    #[test]
+    fn dielectric_handles_unnormalized_directions() {
+        // Same ray as above but scaled, this used to slip past the TIR check.
+        let normal = vec3(0.0, 1.0, 0.0);
+        let ray = Ray::new(vec3(0.0, 0.0, 0.0), vec3(4.0, -1.0, 0.0) * 10.0);
+
+        for _ in 0..32 {
+            let scatter = scatter_at(&Dielectric::new(1.5), &ray, normal, false).unwrap();
+            assert_close(scatter.scattered, reflect(ray.dir.normalize(), normal));
+        }
+    }
+
+    #[test]
+    fn dielectric_total_internal_reflection_draws_no_random_numbers() {
+        let normal = vec3(0.0, 1.0, 0.0);
+        let ray = Ray::new(vec3(0.0, 0.0, 0.0), vec3(4.0, -1.0, 0.0));
+
+        utils::seed_thread_rng(1);
+        scatter_at(&Dielectric::new(1.5), &ray, normal, false).unwrap();
+        let next = random::<Real>();
+
+        utils::seed_thread_rng(1);
+        assert_eq!(next, random::<Real>());
+    }
+
+    #[test]
+    fn dielectric_reflects_with_probability_reflectance() {
+        let normal = vec3(0.0, 1.0, 0.0);
+        let ray = Ray::new(vec3(0.0, 1.0, 0.0), vec3(1.0, -1.0, 0.0));
+        let glass = Dielectric::new(1.5);
+        let cos_theta = ray.dir.normalize().dot(&-normal);
+        let reflectance = Dielectric::reflectance(cos_theta, 1.0 / 1.5);
+
+        for seed in 0..32 {
+            utils::seed_thread_rng(seed);
+            let scatter = scatter_at(&glass, &ray, normal, true).unwrap();
+
+            utils::seed_thread_rng(seed);
+            let reflected = reflectance > random::<Real>();
+            assert_eq!(scatter.event == ScatterEvent::Reflected, reflected);
+        }
+    }
+
+    #[test]
+    fn dielectric_is_ignored_inside_higher_priority_medium() {
+        let normal = vec3(0.0, 1.0, 0.0);
+        let glass = Dielectric::new(1.5).with_priority(1);
+        let water = Dielectric::new(1.33);
+
+        let mut medium = MediumStack::default();
+        medium.push(glass.medium());
+        let ray = Ray::new(vec3(0.0, 1.0, 0.0), vec3(1.0, -1.0, 0.0)).with_medium(medium);
+
+        // Going into the water where it overlaps with the glass, and back out.
+        for (is_front, transition) in [
+            (true, MediumTransition::Enter(water.medium())),
+            (false, MediumTransition::Exit(water.medium())),
+        ] {
+            let scatter = scatter_at(&water, &ray, normal, is_front).unwrap();
+            assert_close(scatter.scattered, ray.dir.0);
+            assert_eq!(scatter.transition, Some(transition));
+        }
+
+        let inside_both = medium.apply(Some(MediumTransition::Enter(water.medium())));
+        assert_eq!(inside_both.ior(), 1.5);
+        assert_eq!(glass.ior_ratio(&inside_both, false), 1.5 / 1.33);
+    }
+
+    #[test]
+    fn dielectric_refracts_into_higher_priority_medium() {
+        let normal = vec3(0.0, 1.0, 0.0);
+        let glass = Dielectric::new(1.5).with_priority(1);
+        let water = Dielectric::new(1.33);
+
+        let mut medium = MediumStack::default();
+        medium.push(water.medium());
+        let ray = Ray::new(vec3(0.0, 1.0, 0.0), vec3(1.0, -1.0, 0.0)).with_medium(medium);
+
+        assert_eq!(glass.ior_ratio(&medium, true), 1.33 / 1.5);
+        let scatter = scatter_at(&glass, &ray, normal, true).unwrap();
+        assert!(scatter.split.is_some());
+    }
+
+    #[test]
+    fn fresnel_metal_brightens_at_grazing_angles() {
+        let normal = vec3(0.0, 1.0, 0.0);
+        let albedo = color::new(0.9, 0.6, 0.2);
+        let head_on = Ray::new(Vec3::zeros(), vec3(0.0, -1.0, 0.0));
+        let grazing = Ray::new(Vec3::zeros(), vec3(1.0, -0.05, 0.0));
+        let attenuation = |metal: &Metal, ray: &Ray| scatter_at(metal, ray, normal, true).unwrap().attenuation;
+
+        let fresnel = Metal::new(albedo, 0.0).with_fresnel(true);
+        assert_close(attenuation(&fresnel, &head_on), albedo);
+        let reflectance = color::luminance(attenuation(&fresnel, &grazing));
+        assert!(reflectance > color::luminance(albedo) + 0.1, "{}", reflectance);
+
+        // Without it the reflection is the albedo at any angle.
+        let constant = Metal::new(albedo, 0.0);
+        assert_close(attenuation(&constant, &head_on), albedo);
+        assert_close(attenuation(&constant, &grazing), albedo);
+    }
+
+    #[test]
+    fn roughness_texture_blurs_the_reflection_where_it_is_bright() {
+        /// Smooth for negative `x`, fully rough for positive `x`.
+        #[derive(Debug)]
+        struct Split;
+
+        impl Texture for Split {
+            fn value(&self, point: &Point3) -> Color {
+                if point.x < 0.0 { color::black() } else { color::white() }
+            }
+        }
+
+        let metal = Metal::new(color::white(), 0.0).with_roughness(Split);
+        let normal = vec3(0.0, 1.0, 0.0);
+        let mirrored = vec3(1.0, 1.0, 0.0);
+        // Largest angle between the reflections and the mirror direction, off a hit at `x`.
+        let spread = |x: Real| {
+            let ray = Ray::new(vec3(x, 0.0, 0.0), vec3(1.0, -1.0, 0.0));
+            (0..64)
+                .filter_map(|_| scatter_at(&metal, &ray, normal, true))
+                .map(|scatter| 1.0 - scatter.scattered.normalize().dot(&mirrored.normalize()))
+                .fold(0.0, Real::max)
+        };
+
+        utils::seed_thread_rng(0);
+        assert!(spread(-1.0) < EPS);
+        assert!(spread(1.0) > 0.1);
+    }
+
+    #[test]
+    fn mirror_reflects_the_same_way_every_time() {
+        let normal = vec3(0.0, 1.0, 0.0);
+        let ray = Ray::new(Vec3::zeros(), vec3(1.0, -1.0, 0.0));
+        let direction = |material: &dyn Material| {
+            material.scatter(&ray, &Hit::new(ray.origin, normal, 0.0, true, material)).unwrap().scattered
+        };
+
+        let mirror = Mirror::new(color::white());
+        assert_eq!(direction(&mirror), direction(&mirror));
+        assert_close(direction(&mirror), vec3(1.0, 1.0, 0.0));
+
+        utils::seed_thread_rng(0);
+        let metal = Metal::new(color::white(), 0.3);
+        assert_ne!(direction(&metal), direction(&metal));
+    }
+
+    #[test]
+    fn anisotropic_metal_blurs_more_across_the_grain() {
+        let normal = vec3(0.0, 1.0, 0.0);
+        let ray = Ray::new(vec3(0.0, 1.0, 0.0), vec3(0.0, -1.0, 0.0));
+        let metal = AnisotropicMetal::new(color::white(), 0.05, 0.5);
+
+        // Spread of the reflections along x and z, with the grain along `tangent`.
+        let spread = |tangent: Vec3| {
+            let hit = Hit::along(ray.origin, normal, tangent, 0.0, true, &metal);
+            utils::seed_thread_rng(0);
+            (0..1000)
+                .map(|_| metal.scatter(&ray, &hit).unwrap().scattered)
+                .fold((0.0, 0.0), |(x, z), scattered| (x + scattered.x.abs(), z + scattered.z.abs()))
+        };
+
+        let (along, across) = spread(vec3(1.0, 0.0, 0.0));
+        assert!(across > 5.0 * along);
+
+        // The grain turns with the tangent of the surface.
+        let (across, along) = spread(vec3(0.0, 0.0, 1.0));
+        assert!(across > 5.0 * along);
+    }
+
+    #[test]
+    fn blend_picks_a_with_probability_factor() {
+        let normal = vec3(0.0, 1.0, 0.0);
+        let ray = Ray::new(vec3(0.0, 1.0, 0.0), vec3(1.0, -1.0, 0.0));
+        let blend = Blend::new(Mirror::new(color::white()), Diffuse::default(), 0.3);
+
+        utils::seed_thread_rng(0);
+        let samples = 10_000;
+        let reflected = (0..samples)
+            .map(|_| scatter_at(&blend, &ray, normal, true).unwrap())
+            .filter(|scatter| scatter.event == ScatterEvent::Reflected)
+            .count();
+
+        assert!((reflected as Real / samples as Real - 0.3).abs() < 0.02);
+    }
+
+    #[test]
+    fn oren_nayar_is_brighter_than_lambert_at_grazing_angles() {
+        let normal = vec3(0.0, 1.0, 0.0);
+        let grazing = Real::to_radians(80.0);
+        let to_viewer = vec3(grazing.sin(), grazing.cos(), 0.0);
+        let to_light = vec3(grazing.sin(), grazing.cos(), 0.1).normalize();
+
+        let rough = OrenNayar::new(color::white(), 1.0);
+        assert!(rough.reflectance(&normal, &to_viewer, &to_light) > 1.0);
+        // Still darker than lambertian when lit from straight above.
+        assert!(rough.reflectance(&normal, &to_viewer, &normal) < 1.0);
+
+        let smooth = OrenNayar::new(color::white(), 0.0);
+        assert_eq!(smooth.reflectance(&normal, &to_viewer, &to_light), 1.0);
+
+        let ray = Ray::new(to_viewer, -to_viewer);
+        utils::seed_thread_rng(0);
+        for _ in 0..32 {
+            let scatter = scatter_at(&rough, &ray, normal, true).unwrap();
+            let reflectance = rough.reflectance(&normal, &to_viewer, &scatter.scattered.normalize());
+            assert_close(scatter.attenuation, color::white() * reflectance);
+        }
+    }
+
+    #[test]
+    fn common_mat_scatters_through_boxed_materials() {
+        /// Sends every ray straight back, tinted red.
+        struct Retroreflector;
+
+        impl Material for Retroreflector {
+            fn scatter(&self, ray: &Ray, _: &Hit) -> Option<Scatter> {
+                Some(Scatter::new(color::red(), -ray.dir.0, ScatterEvent::Reflected))
+            }
+        }
+
+        let normal = vec3(0.0, 1.0, 0.0);
+        let ray = Ray::new(vec3(0.0, 1.0, 0.0), vec3(1.0, -1.0, 0.0));
+        let material = CommonMat::from(BoxMat::new(Retroreflector));
+
+        let scatter = scatter_at(&material, &ray, normal, true).unwrap();
+        assert_close(scatter.scattered, vec3(-1.0, 1.0, 0.0));
+        assert_close(scatter.attenuation, color::red());
+        assert_eq!(scatter.event, ScatterEvent::Reflected);
+    }
+
+    #[test]
+    fn negative_attenuation_doesnt_darken_below_the_background() {
+        /// Sends every ray straight back, with a negative red channel.
+        struct Broken;
+
+        impl Material for Broken {
+            fn scatter(&self, ray: &Ray, _: &Hit) -> Option<Scatter> {
+                Some(Scatter::new(vec3(-0.5, 1.0, 1.0), -ray.dir.0, ScatterEvent::Reflected))
+            }
+        }
+
+        let world = vec![crate::objects::Sphere::new(Vec3::zeros(), 1.0, Broken)];
+        let background = Background::gradient(Vec3::y());
+        let ray = Ray::new(vec3(0.0, 0.0, 3.0), vec3(0.0, 0.0, -1.0));
+
+        let sky = background.color(&Ray::new(vec3(0.0, 0.0, 1.0), vec3(0.0, 0.0, 1.0)));
+        let color = ray.compute_color(&world, 4, &background);
+        assert_close(color, vec3(0.0, sky.y, sky.z));
+    }
+}
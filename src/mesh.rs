@@ -0,0 +1,228 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{ self, BufRead, BufReader };
+use std::path::Path;
+
+use crate::objects::{ vertex_normals, Triangle };
+use crate::utils::{ Point3, Real, Vec3 };
+
+/// A triangle mesh, as loaded from a Wavefront OBJ file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mesh {
+    pub positions: Vec<Point3>,
+    /// The normals of the file when all of its faces have them, otherwise one for every position,
+    /// averaged from the faces with `vertex_normals`.
+    pub normals: Vec<Vec3>,
+    /// Indices into `positions`, counter clockwise from the front.
+    pub faces: Vec<[usize; 3]>,
+    /// Indices into `normals` of the same corners as `faces`. Corners sharing a position keep
+    /// their own normals, so that hard edges stay hard.
+    pub normal_faces: Vec<[usize; 3]>,
+}
+
+impl Mesh {
+    pub fn open(path: impl AsRef<Path>) -> Result<Mesh, MeshError> {
+        read_obj(BufReader::new(File::open(path)?))
+    }
+
+    /// Smooth shaded triangles of the mesh, all of the same material.
+    pub fn triangles<Mat: Clone>(&self, material: Mat) -> Vec<Triangle<Mat>> {
+        self.faces.iter()
+            .zip(&self.normal_faces)
+            .map(|(&[a, b, c], &[na, nb, nc])| {
+                Triangle::new([self.positions[a], self.positions[b], self.positions[c]], material.clone())
+                    .with_normals([self.normals[na], self.normals[nb], self.normals[nc]])
+            })
+            .collect()
+    }
+}
+
+/// Mesh of the `v`, `vn` and `f` lines of an OBJ file. Faces with more than three vertices are
+/// split into a fan of triangles. Texture coordinates, groups and materials are ignored.
+pub fn read_obj(reader: impl BufRead) -> Result<Mesh, MeshError> {
+    let mut positions = Vec::new();
+    let mut file_normals = Vec::new();
+    // Corners of each triangle as indices of a position and maybe of a normal.
+    let mut corners: Vec<[(usize, Option<usize>); 3]> = Vec::new();
+
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("v") => positions.push(parse_vec3(i, words)?),
+            Some("vn") => file_normals.push(parse_vec3(i, words)?),
+            Some("f") => {
+                let face: Vec<(usize, Option<usize>)> = words
+                    .map(|corner| parse_corner(i, corner, positions.len(), file_normals.len()))
+                    .collect::<Result<_, _>>()?;
+                if face.len() < 3 {
+                    return Err(MeshError::parse(i, "faces need at least 3 vertices"));
+                }
+                for k in 1..face.len() - 1 {
+                    corners.push([face[0], face[k], face[k + 1]]);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    let faces: Vec<[usize; 3]> = corners.iter().map(|corner| corner.map(|(position, _)| position)).collect();
+    let has_normals = !corners.is_empty() && corners.iter().flatten().all(|(_, normal)| normal.is_some());
+    let (normals, normal_faces) = if has_normals {
+        let normal_faces = corners.iter().map(|corner| corner.map(|(_, normal)| normal.unwrap())).collect();
+        (file_normals, normal_faces)
+    } else {
+        (vertex_normals(&positions, &faces), faces.clone())
+    };
+
+    Ok(Mesh { positions, normals, faces, normal_faces })
+}
+
+fn parse_vec3<'a>(line: usize, words: impl Iterator<Item = &'a str>) -> Result<Vec3, MeshError> {
+    let values: Vec<Real> = words.take(3)
+        .map(|word| word.parse())
+        .collect::<Result<_, _>>()
+        .map_err(|_| MeshError::parse(line, "expected numbers"))?;
+    match values[..] {
+        [x, y, z] => Ok(Vec3::new(x, y, z)),
+        _ => Err(MeshError::parse(line, format!("expected 3 values, got {}", values.len()))),
+    }
+}
+
+/// Position and normal index of a face corner like `1`, `1/2`, `1//3` or `1/2/3`. OBJ indices
+/// start at 1, and negative ones count back from the last item read so far.
+fn parse_corner(
+    line: usize,
+    corner: &str,
+    positions: usize,
+    normals: usize,
+) -> Result<(usize, Option<usize>), MeshError> {
+    let index = |field: &str, count: usize| -> Result<usize, MeshError> {
+        let index: isize = field.parse()
+            .map_err(|_| MeshError::parse(line, format!("bad index {:?}", field)))?;
+        let resolved = if index < 0 { count as isize + index } else { index - 1 };
+        if (0..count as isize).contains(&resolved) {
+            Ok(resolved as usize)
+        } else {
+            Err(MeshError::parse(line, format!("index {} out of range", index)))
+        }
+    };
+
+    let mut fields = corner.split('/');
+    let position = index(fields.next().unwrap_or(""), positions)?;
+    let normal = match fields.nth(1) {
+        Some(field) if !field.is_empty() => Some(index(field, normals)?),
+        _ => None,
+    };
+    Ok((position, normal))
+}
+
+/// Why a mesh couldn't be loaded.
+#[derive(Debug)]
+pub enum MeshError {
+    Io(io::Error),
+    /// The file is malformed, at the line with this index.
+    Parse { line: usize, message: String },
+}
+
+impl MeshError {
+    fn parse(line: usize, message: impl Into<String>) -> MeshError {
+        MeshError::Parse { line, message: message.into() }
+    }
+}
+
+impl From<io::Error> for MeshError {
+    fn from(err: io::Error) -> MeshError {
+        MeshError::Io(err)
+    }
+}
+
+impl fmt::Display for MeshError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MeshError::Io(err) => write!(f, "{}", err),
+            MeshError::Parse { line, message } => write!(f, "line {}: {}", line + 1, message),
+        }
+    }
+}
+
+impl std::error::Error for MeshError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hittable::Hittable;
+    use crate::material::Diffuse;
+    use crate::ray::Ray;
+    use crate::utils::{ self, color };
+
+    #[test]
+    fn meshes_keep_their_normals_or_get_averaged_ones() {
+        // A unit square split in two, with normals tilted out to the sides.
+        let obj = "\
+# square
+v 0 0 0
+v 1 0 0
+v 1 1 0
+v 0 1 0
+vn -1 -1 1
+vn 1 -1 1
+vn 1 1 1
+vn -1 1 1
+f 1//1 2//2 3//3 4//4
+";
+        let mesh = read_obj(obj.as_bytes()).unwrap();
+        assert_eq!(mesh.faces, [[0, 1, 2], [0, 2, 3]]);
+        assert!(utils::approx_eq(&mesh.normals[1], &Vec3::new(1.0, -1.0, 1.0), 1e-6));
+
+        // Near the diagonal, the sideways tilts of the corners mostly cancel out.
+        let triangles = mesh.triangles(Diffuse::new(color::mid_gray()));
+        let ray = Ray::new(Vec3::new(0.5, 0.4, 1.0), Vec3::new(0.0, 0.0, -1.0));
+        let hit = triangles.hit(&ray, 0.001..Real::INFINITY).unwrap();
+        assert!(utils::approx_eq(&hit.normal, &Vec3::new(0.0, -0.2, 1.0).normalize(), 1e-5));
+
+        // Without normals, a flat mesh gets the normal of its faces everywhere, negative indices
+        // included.
+        let flat = read_obj("v 0 0 0\nv 1 0 0\nv 0 1 0\nv 5 5 5\nf -4 -3 -2\n".as_bytes()).unwrap();
+        for normal in &flat.normals[..3] {
+            assert!(utils::approx_eq(normal, &Vec3::z(), 1e-6));
+        }
+        // The stray vertex isn't on any face.
+        assert_eq!(flat.normals[3], Vec3::zeros());
+
+        assert!(matches!(read_obj("v 0 0 0\nf 1 2 3\n".as_bytes()), Err(MeshError::Parse { line: 1, .. })));
+    }
+
+    #[test]
+    fn faces_sharing_an_edge_keep_their_own_normals() {
+        // Two faces of a cube meeting at the edge from (1, 0, 0) to (1, 1, 0), exported with one
+        // normal per face.
+        let obj = "\
+v 0 0 0
+v 1 0 0
+v 1 1 0
+v 0 1 0
+v 1 0 -1
+v 1 1 -1
+vn 0 0 1
+vn 1 0 0
+f 1//1 2//1 3//1 4//1
+f 2//2 5//2 6//2 3//2
+";
+        let mesh = read_obj(obj.as_bytes()).unwrap();
+        assert_eq!(mesh.faces[2], [1, 4, 5]);
+        assert_eq!(mesh.normal_faces, [[0, 0, 0], [0, 0, 0], [1, 1, 1], [1, 1, 1]]);
+
+        // Each face is flat and lit from its own side, right up to the shared edge.
+        let triangles = mesh.triangles(Diffuse::new(color::mid_gray()));
+        let front = Ray::new(Vec3::new(0.99, 0.5, 1.0), Vec3::new(0.0, 0.0, -1.0));
+        let hit = triangles.hit(&front, 0.001..Real::INFINITY).unwrap();
+        assert!(hit.is_front);
+        assert!(utils::approx_eq(&hit.normal, &Vec3::z(), 1e-6));
+
+        let side = Ray::new(Vec3::new(2.0, 0.5, -0.01), Vec3::new(-1.0, 0.0, 0.0));
+        let hit = triangles.hit(&side, 0.001..Real::INFINITY).unwrap();
+        assert!(hit.is_front);
+        assert!(utils::approx_eq(&hit.normal, &Vec3::x(), 1e-6));
+    }
+}
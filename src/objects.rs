@@ -1,134 +1,1881 @@
-use std::ops::Range;
+use core::ops::{ Index, Range };
+use alloc::sync::Arc;
 
-use rayon::prelude::*;
-
-use crate::utils::{ Vec3, Point3, Color };
-use crate::hittable::{ Hittable, Hit };
-use crate::material::Material;
+use crate::utils::{ Vec3, Point3, Color, Real };
+use crate::hittable::{ count_primitive_test, Hittable, Hit, SceneStats, SHUTTER };
+use crate::material::{ Material, Diffuse, Metal, Dielectric, CommonMat, MaterialRegistry, UnknownMaterial };
 use crate::ray::Ray;
+use crate::aabb::Aabb;
+use crate::kdtree::KdTree;
+use crate::grid::UniformGrid;
+use crate::texture::equirect_uv;
+use crate::prelude::*;
 
 #[derive(Debug, Clone)]
 pub struct Sphere<Mat> {
     pub center: Point3,
-    pub radius: f32,
+    /// Always positive, see `inward` for spheres that are turned inside out.
+    radius: Real,
+    /// `radius * radius` and `1 / radius`, which every hit needs.
+    radius_sq: Real,
+    inv_radius: Real,
+    /// Whether the outside of the surface faces the center, see `inside_out`.
+    pub inward: bool,
     pub material: Mat,
 }
 
 impl<Mat> Sphere<Mat> {
-    pub fn new(center: Point3, radius: f32, material: Mat) -> Self {
-        Self { center, radius, material }
+    /// A sphere facing out. Use `inside_out` for one facing its center.
+    ///
+    /// # Panics
+    ///
+    /// If `radius` isn't positive.
+    pub fn new(center: Point3, radius: Real, material: Mat) -> Self {
+        assert!(radius > 0.0, "the radius of a sphere must be positive, use `inside_out` to turn it inward");
+        Self::oriented(center, radius, false, material)
     }
-}
 
-impl<Mat: Material> Hittable for Sphere<Mat> {
-    fn hit(&self, ray: &Ray, bounds: Range<f32>) -> Option<Hit> {
-        let oc = ray.origin - self.center;
+    /// A sphere whose normals point to its center, so that its inside is treated as the outside.
+    /// Put inside of a larger sphere of the same material, it makes the larger one hollow.
+    ///
+    /// # Panics
+    ///
+    /// If `radius` isn't positive.
+    pub fn inside_out(center: Point3, radius: Real, material: Mat) -> Self {
+        assert!(radius > 0.0, "the radius of an inside out sphere must be positive");
+        Self::oriented(center, radius, true, material)
+    }
+
+    fn oriented(center: Point3, radius: Real, inward: bool, material: Mat) -> Self {
+        Self { center, radius, radius_sq: radius * radius, inv_radius: 1.0 / radius, inward, material }
+    }
+
+    /// Always positive, see `inward` for spheres that are turned inside out.
+    pub fn radius(&self) -> Real {
+        self.radius
+    }
+
+    /// Texture coordinates of `point` on the surface, mapped like `texture::equirect_uv`. These
+    /// are always within `[0, 1]`, including at the poles and along the seam at `-x`. Points off
+    /// of the surface get the coordinates of the surface point in their direction.
+    pub fn uv(&self, point: &Point3) -> (Real, Real) {
+        let dir = (point - self.center).try_normalize(0.0).unwrap_or_else(Vec3::y);
+        equirect_uv(&dir)
+    }
+
+    /// Values of `t` at which the ray enters and leaves the sphere, if it goes through it. Rays
+    /// that only graze the surface miss, since they would enter and leave at the same point.
+    fn roots(&self, ray: &Ray) -> Option<(Real, Real)> {
+        count_primitive_test();
+
+        let oc = ray.origin.0 - self.center;
         let a = ray.dir.magnitude_squared();
-        let half_b = oc.dot(&ray.dir);
-        let c = oc.magnitude_squared() - self.radius * self.radius;
+        // A degenerate scatter can leave the ray without a direction, which goes nowhere.
+        if a == 0.0 {
+            return None;
+        }
+        let half_b = oc.dot(&ray.dir.0);
+        let c = oc.magnitude_squared() - self.radius_sq;
         let discriminant = half_b * half_b - a * c;
 
         if discriminant > 0.0 {
             let disc_sqrt = discriminant.sqrt();
-            let mut t = (-half_b - disc_sqrt) / a;
+            Some(((-half_b - disc_sqrt) / a, (-half_b + disc_sqrt) / a))
+        } else {
+            None
+        }
+    }
+
+    /// Distance along the ray to the nearest intersection within `bounds`, if any.
+    fn intersect(&self, ray: &Ray, bounds: &Range<Real>) -> Option<Real> {
+        let (enter, exit) = self.roots(ray)?;
+
+        // When the ray starts inside (or on the surface, going in), the entry is out of bounds
+        // and the hit is the exit, which `hit` then reports from the back with the normal flipped
+        // inward.
+        [enter, exit].iter().copied().find(|t| bounds.contains(t))
+    }
+}
+
+impl<Mat: Material> Sphere<Mat> {
+    fn hit_at(&self, ray: &Ray, t: Real) -> Hit<'_> {
+        let hit_point = ray.at(t);
+        let local = hit_point.0 - self.center;
+        let mut outward_normal = local * self.inv_radius;
+        if self.inward {
+            outward_normal = -outward_normal;
+        }
+
+        let (normal, is_front) = if ray.dir.dot(&outward_normal) < 0.0 {
+            (outward_normal, true)
+        } else {
+            (-outward_normal, false)
+        };
+
+        Hit::along(hit_point, normal, sphere_dpdu(&local), t, is_front, &self.material)
+    }
+}
+
+/// Direction `u` of `Sphere::uv` grows in at `point`, relative to the center. Zero at the poles,
+/// where `u` is undefined.
+#[inline]
+fn sphere_dpdu(point: &Vec3) -> Vec3 {
+    Vec3::new(point.z, 0.0, -point.x)
+}
+
+impl Sphere<Dielectric> {
+    /// A glass shell of the given thickness. The inner sphere is inside out, so that it behaves
+    /// like a bubble of air inside the outer sphere.
+    pub fn hollow_glass(
+        center: Point3,
+        outer_radius: Real,
+        thickness: Real,
+        ior: Real,
+    ) -> [Sphere<Dielectric>; 2] {
+        assert!(
+            0.0 < thickness && thickness < outer_radius,
+            "the thickness of a hollow sphere must be in (0, outer_radius)"
+        );
+
+        [
+            Sphere::new(center, outer_radius, Dielectric::new(ior)),
+            Sphere::inside_out(center, outer_radius - thickness, Dielectric::new(ior)),
+        ]
+    }
+}
 
-            if !bounds.contains(&t) {
-                t = (-half_b + disc_sqrt) / a;
+impl<Mat: Material> Hittable for Sphere<Mat> {
+    fn hit(&self, ray: &Ray, bounds: Range<Real>) -> Option<Hit<'_>> {
+        let t = self.intersect(ray, &bounds)?;
+        Some(self.hit_at(ray, t))
+    }
 
-                if !bounds.contains(&t) {
-                    return None;
+    fn hit_all<'a>(&'a self, ray: &Ray, bounds: Range<Real>, hits: &mut Vec<Hit<'a>>) {
+        if let Some((enter, exit)) = self.roots(ray) {
+            for t in [enter, exit] {
+                if bounds.contains(&t) {
+                    hits.push(self.hit_at(ray, t));
                 }
             }
+        }
+    }
+
+    #[inline]
+    fn hit_any(&self, ray: &Ray, bounds: Range<Real>) -> bool {
+        self.intersect(ray, &bounds).is_some()
+    }
 
-            let hit_point = ray.at(t);
-            let outward_normal = (hit_point - self.center) / self.radius;
+    fn bounding_box(&self, _time: Range<Real>) -> Option<Aabb> {
+        let extent = Vec3::repeat(self.radius);
+        Some(Aabb::new(self.center - extent, self.center + extent))
+    }
+}
 
-            let (normal, is_front) = if ray.dir.dot(&outward_normal) < 0.0 {
-                (outward_normal, true)
-            } else {
-                (-outward_normal, false)
-            };
+/// A sphere moving in a straight line, from `start` at time 0 to `end` at time 1. Rays see it
+/// where it is at their `payload.time`.
+#[derive(Debug, Clone)]
+pub struct MovingSphere<Mat> {
+    pub start: Point3,
+    pub end: Point3,
+    pub radius: Real,
+    pub material: Mat,
+}
 
-            let scatter = self.material.scatter(ray, normal, is_front);
-            Some(Hit::new(hit_point, normal, t, scatter))
+impl<Mat> MovingSphere<Mat> {
+    /// # Panics
+    ///
+    /// If `radius` isn't positive.
+    pub fn new(start: Point3, end: Point3, radius: Real, material: Mat) -> Self {
+        assert!(radius > 0.0, "the radius of a sphere must be positive");
+        Self { start, end, radius, material }
+    }
+
+    pub fn center(&self, time: Real) -> Point3 {
+        self.start + (self.end - self.start) * time
+    }
+
+    /// The sphere as it is at `time`, with no material.
+    fn at(&self, time: Real) -> Sphere<()> {
+        Sphere::oriented(self.center(time), self.radius, false, ())
+    }
+}
+
+impl<Mat: Material> Hittable for MovingSphere<Mat> {
+    fn hit(&self, ray: &Ray, bounds: Range<Real>) -> Option<Hit<'_>> {
+        let sphere = self.at(ray.payload.time);
+        let t = sphere.intersect(ray, &bounds)?;
+
+        let hit_point = ray.at(t);
+        let local = hit_point.0 - sphere.center;
+        let outward_normal = local / self.radius;
+
+        let (normal, is_front) = if ray.dir.dot(&outward_normal) < 0.0 {
+            (outward_normal, true)
         } else {
-            None
-        }
+            (-outward_normal, false)
+        };
+
+        Some(Hit::along(hit_point, normal, sphere_dpdu(&local), t, is_front, &self.material))
+    }
+
+    #[inline]
+    fn hit_any(&self, ray: &Ray, bounds: Range<Real>) -> bool {
+        self.at(ray.payload.time).intersect(ray, &bounds).is_some()
+    }
+
+    fn bounding_box(&self, time: Range<Real>) -> Option<Aabb> {
+        // Moving in a straight line, it's within the boxes at both ends of the interval.
+        let extent = Vec3::repeat(self.radius);
+        let box_at = |time| {
+            let center = self.center(time);
+            Aabb::new(center - extent, center + extent)
+        };
+        Some(box_at(time.start).union(&box_at(time.end)))
     }
 }
 
-pub type BoxHittable = Box<dyn Hittable + Send + Sync>;
+/// A sphere scaled independently along each axis.
+#[derive(Debug, Clone)]
+pub struct Ellipsoid<Mat> {
+    pub center: Point3,
+    pub radii: Vec3,
+    pub material: Mat,
+}
+
+impl<Mat> Ellipsoid<Mat> {
+    pub fn new(center: Point3, radii: Vec3, material: Mat) -> Self {
+        Self { center, radii, material }
+    }
+
+    /// Distance along the ray to the nearest intersection within `bounds`, if any. Scaling the
+    /// ray into the space where the ellipsoid is the unit sphere doesn't change `t`.
+    fn intersect(&self, ray: &Ray, bounds: &Range<Real>) -> Option<Real> {
+        let origin = (ray.origin.0 - self.center).component_div(&self.radii);
+        let dir = ray.dir.component_div(&self.radii);
+        Sphere::new(Point3::zeros(), 1.0, ()).intersect(&Ray::new(origin, dir), bounds)
+    }
+}
+
+impl<Mat: Material> Hittable for Ellipsoid<Mat> {
+    fn hit(&self, ray: &Ray, bounds: Range<Real>) -> Option<Hit<'_>> {
+        let t = self.intersect(ray, &bounds)?;
+
+        let hit_point = ray.at(t);
+        // The normal of the unit sphere is the local hit point, which is transformed back with
+        // the inverse transpose of the scaling.
+        let local_point = (hit_point.0 - self.center).component_div(&self.radii);
+        let outward_normal = local_point.component_div(&self.radii).normalize();
+        // Tangents are scaled like points.
+        let tangent = sphere_dpdu(&local_point).component_mul(&self.radii);
+
+        let (normal, is_front) = if ray.dir.dot(&outward_normal) < 0.0 {
+            (outward_normal, true)
+        } else {
+            (-outward_normal, false)
+        };
+
+        Some(Hit::along(hit_point, normal, tangent, t, is_front, &self.material))
+    }
 
-impl Hittable for Box<dyn Hittable + Send + Sync> {
     #[inline]
-    fn hit(&self, ray: &Ray, bounds: Range<f32>) -> Option<Hit> {
-        self.as_ref().hit(ray, bounds)
+    fn hit_any(&self, ray: &Ray, bounds: Range<Real>) -> bool {
+        self.intersect(ray, &bounds).is_some()
+    }
+
+    fn bounding_box(&self, _time: Range<Real>) -> Option<Aabb> {
+        let extent = self.radii.abs();
+        Some(Aabb::new(self.center - extent, self.center + extent))
     }
 }
 
-impl<T: Hittable> Hittable for Vec<T> {
-    fn hit(&self, ray: &Ray, bounds: Range<f32>) -> Option<Hit> {
-        self.iter()
-            .filter_map(|hittable| hittable.hit(ray, bounds.clone()))
-            .min_by(|a, b| a.t.partial_cmp(&b.t).unwrap_or(std::cmp::Ordering::Greater))
+/// A flat, round surface. Visible from both sides, with `normal` being the front.
+#[derive(Debug, Clone)]
+pub struct Disk<Mat> {
+    pub center: Point3,
+    pub normal: Vec3,
+    pub radius: Real,
+    pub material: Mat,
+}
+
+impl<Mat> Disk<Mat> {
+    pub fn new(center: Point3, normal: Vec3, radius: Real, material: Mat) -> Self {
+        Self { center, normal: normal.normalize(), radius, material }
+    }
+
+    /// Distance along the ray to where it crosses the disk within `bounds`, if it does.
+    fn intersect(&self, ray: &Ray, bounds: &Range<Real>) -> Option<Real> {
+        count_primitive_test();
+
+        let denom = self.normal.dot(&ray.dir.0);
+        if denom == 0.0 {
+            return None;
+        }
+
+        let t = self.normal.dot(&(self.center - ray.origin.0)) / denom;
+        if !bounds.contains(&t) {
+            return None;
+        }
+
+        let from_center = ray.at(t).0 - self.center;
+        (from_center.magnitude_squared() <= self.radius * self.radius).then_some(t)
     }
 }
 
-#[derive(Default)]
-pub struct BoxedHitList {
-    pub objects: Vec<BoxHittable>,
+impl<Mat: Material> Hittable for Disk<Mat> {
+    fn hit(&self, ray: &Ray, bounds: Range<Real>) -> Option<Hit<'_>> {
+        let t = self.intersect(ray, &bounds)?;
+
+        let (normal, is_front) = if ray.dir.dot(&self.normal) < 0.0 {
+            (self.normal, true)
+        } else {
+            (-self.normal, false)
+        };
+
+        Some(Hit::new(ray.at(t), normal, t, is_front, &self.material))
+    }
+
+    #[inline]
+    fn hit_any(&self, ray: &Ray, bounds: Range<Real>) -> bool {
+        self.intersect(ray, &bounds).is_some()
+    }
+
+    fn bounding_box(&self, _time: Range<Real>) -> Option<Aabb> {
+        // How far the rim reaches along each axis. Padded, so that axis aligned disks don't get a
+        // box with no thickness.
+        let extent = self.normal
+            .map(|n| self.radius.abs() * (1.0 - n * n).max(0.0).sqrt())
+            .add_scalar(1e-4);
+        Some(Aabb::new(self.center - extent, self.center + extent))
+    }
 }
 
-impl BoxedHitList {
+/// A triangle, visible from both sides. Its front is the side the vertices are counter clockwise
+/// from, or the side the vertex normals point to when they are set.
+#[derive(Debug, Clone)]
+pub struct Triangle<Mat> {
+    pub vertices: [Point3; 3],
+    /// Normals at each vertex, interpolated across the triangle for smooth shading. Flat shaded
+    /// when `None`.
+    pub normals: Option<[Vec3; 3]>,
+    pub material: Mat,
+}
+
+impl<Mat> Triangle<Mat> {
+    pub fn new(vertices: [Point3; 3], material: Mat) -> Self {
+        Self { vertices, normals: None, material }
+    }
+
+    /// Same triangle, smooth shaded with the given vertex normals. Zero normals, like those of
+    /// `vertex_normals` where the faces around a vertex cancel out, are replaced by the normal of
+    /// the triangle.
+    pub fn with_normals(self, normals: [Vec3; 3]) -> Self {
+        let [p0, p1, p2] = self.vertices;
+        let face = (p1 - p0).cross(&(p2 - p0)).normalize();
+        Self { normals: Some(normals.map(|n| n.try_normalize(0.0).unwrap_or(face))), ..self }
+    }
+
+    /// Distance along the ray to the intersection within `bounds`, if any, with the barycentric
+    /// coordinates of the second and third vertices at that point (Möller–Trumbore).
+    fn intersect(&self, ray: &Ray, bounds: &Range<Real>) -> Option<(Real, Real, Real)> {
+        count_primitive_test();
+
+        let [p0, p1, p2] = self.vertices;
+        let edge1 = p1 - p0;
+        let edge2 = p2 - p0;
+
+        let p = ray.dir.cross(&edge2);
+        let det = edge1.dot(&p);
+        if det == 0.0 {
+            return None;
+        }
+
+        let to_origin = ray.origin.0 - p0;
+        let u = to_origin.dot(&p) / det;
+        if !(0.0..=1.0).contains(&u) {
+            return None;
+        }
+
+        let q = to_origin.cross(&edge1);
+        let v = ray.dir.dot(&q) / det;
+        if v < 0.0 || u + v > 1.0 {
+            return None;
+        }
+
+        let t = edge2.dot(&q) / det;
+        bounds.contains(&t).then_some((t, u, v))
+    }
+}
+
+impl<Mat: Material> Hittable for Triangle<Mat> {
+    fn hit(&self, ray: &Ray, bounds: Range<Real>) -> Option<Hit<'_>> {
+        let (t, u, v) = self.intersect(ray, &bounds)?;
+
+        let [p0, p1, p2] = self.vertices;
+        let mut geometric = (p1 - p0).cross(&(p2 - p0));
+        let outward_normal = match self.normals {
+            Some([n0, n1, n2]) => {
+                // Opposite vertex normals can cancel out, then the triangle is flat there.
+                let normal = (n0 * (1.0 - u - v) + n1 * u + n2 * v)
+                    .try_normalize(0.0)
+                    .unwrap_or_else(|| geometric.normalize());
+                // The vertex normals decide which side is the front.
+                if geometric.dot(&normal) < 0.0 {
+                    geometric = -geometric;
+                }
+                normal
+            }
+            None => geometric.normalize(),
+        };
+
+        let (normal, is_front) = if ray.dir.dot(&geometric) < 0.0 {
+            (outward_normal, true)
+        } else {
+            (-outward_normal, false)
+        };
+
+        // Along the first edge.
+        Some(Hit::along(ray.at(t), normal, p1 - p0, t, is_front, &self.material))
+    }
+
     #[inline]
-    pub fn new(objects: Vec<BoxHittable>) -> BoxedHitList {
-        BoxedHitList { objects }
+    fn hit_any(&self, ray: &Ray, bounds: Range<Real>) -> bool {
+        self.intersect(ray, &bounds).is_some()
     }
 
-    pub fn empty() -> BoxedHitList {
-        BoxedHitList::default()
+    fn bounding_box(&self, _time: Range<Real>) -> Option<Aabb> {
+        let [p0, p1, p2] = self.vertices;
+        // Padded like `Disk`, for triangles lying in an axis aligned plane.
+        let padding = Vec3::repeat(1e-4);
+        let min = nalgebra_glm::min2(&nalgebra_glm::min2(&p0, &p1), &p2);
+        let max = nalgebra_glm::max2(&nalgebra_glm::max2(&p0, &p1), &p2);
+        Some(Aabb::new(min - padding, max + padding))
     }
 
-    pub fn add(&mut self, object: impl Hittable + Send + Sync + 'static) {
-        self.objects.push(Box::new(object));
+    fn scene_stats(&self) -> SceneStats {
+        SceneStats { triangles: 1, ..SceneStats::OBJECT }
     }
 }
 
-impl Hittable for BoxedHitList {
+/// Normal of every vertex of a mesh, averaging the normals of the faces around it weighted by
+/// their area. For meshes that don't come with their own normals. Vertices on no face, or whose
+/// faces cancel out, get a zero normal.
+pub fn vertex_normals(positions: &[Point3], faces: &[[usize; 3]]) -> Vec<Vec3> {
+    let mut normals = vec![Vec3::zeros(); positions.len()];
+    for &[a, b, c] in faces {
+        // Twice the area of the face, along its normal.
+        let normal = (positions[b] - positions[a]).cross(&(positions[c] - positions[a]));
+        for i in [a, b, c] {
+            normals[i] += normal;
+        }
+    }
+
+    for normal in &mut normals {
+        *normal = normal.try_normalize(0.0).unwrap_or_else(Vec3::zeros);
+    }
+    normals
+}
+
+/// A box aligned with the axes.
+#[derive(Debug, Clone)]
+pub struct Cuboid<Mat> {
+    pub min: Point3,
+    pub max: Point3,
+    pub material: Mat,
+}
+
+impl<Mat> Cuboid<Mat> {
+    /// The box between two opposite corners, in any order.
+    pub fn new(a: Point3, b: Point3, material: Mat) -> Self {
+        Self { min: nalgebra_glm::min2(&a, &b), max: nalgebra_glm::max2(&a, &b), material }
+    }
+
+    /// Values of `t` at which the ray enters and leaves the box, if it goes through it.
+    fn roots(&self, ray: &Ray) -> Option<(Real, Real)> {
+        count_primitive_test();
+
+        Aabb::new(self.min, self.max)
+            .hit(ray, Real::NEG_INFINITY..Real::INFINITY)
+            .map(|span| (span.start, span.end))
+    }
+}
+
+impl<Mat: Material> Cuboid<Mat> {
+    fn hit_at(&self, ray: &Ray, t: Real) -> Hit<'_> {
+        let hit_point = ray.at(t);
+
+        // The face that was hit is the one the point is closest to, relative to the size of the
+        // box along each axis.
+        let center = (self.min + self.max) / 2.0;
+        let local = (hit_point.0 - center).component_div(&(self.max - self.min));
+        let axis = local.iamax();
+        let mut outward_normal = Vec3::zeros();
+        outward_normal[axis] = local[axis].signum();
+
+        // Along the edges of the face that follow the axis of the normal.
+        let mut tangent = Vec3::zeros();
+        tangent[(axis + 1) % 3] = 1.0;
+
+        let (normal, is_front) = if ray.dir.dot(&outward_normal) < 0.0 {
+            (outward_normal, true)
+        } else {
+            (-outward_normal, false)
+        };
+
+        Hit::along(hit_point, normal, tangent, t, is_front, &self.material)
+    }
+}
+
+impl<Mat: Material> Hittable for Cuboid<Mat> {
+    fn hit(&self, ray: &Ray, bounds: Range<Real>) -> Option<Hit<'_>> {
+        let (enter, exit) = self.roots(ray)?;
+        let t = [enter, exit].iter().copied().find(|t| bounds.contains(t))?;
+        Some(self.hit_at(ray, t))
+    }
+
+    fn hit_all<'a>(&'a self, ray: &Ray, bounds: Range<Real>, hits: &mut Vec<Hit<'a>>) {
+        if let Some((enter, exit)) = self.roots(ray) {
+            for t in [enter, exit] {
+                if bounds.contains(&t) {
+                    hits.push(self.hit_at(ray, t));
+                }
+            }
+        }
+    }
+
+    fn bounding_box(&self, _time: Range<Real>) -> Option<Aabb> {
+        Some(Aabb::new(self.min, self.max))
+    }
+}
+
+/// Turns an object inside out: every hit it reports has the opposite face, so its outward normal
+/// points the other way. Like every `Hit::normal`, the normal still faces the ray. Used to pick
+/// which side of a one sided surface faces the scene.
+#[derive(Debug, Clone)]
+pub struct FlipNormals<H>(pub H);
+
+impl<H: Hittable> Hittable for FlipNormals<H> {
+    fn hit(&self, ray: &Ray, bounds: Range<Real>) -> Option<Hit<'_>> {
+        self.0.hit(ray, bounds).map(|hit| Hit { is_front: !hit.is_front, ..hit })
+    }
+
+    fn hit_all<'a>(&'a self, ray: &Ray, bounds: Range<Real>, hits: &mut Vec<Hit<'a>>) {
+        let start = hits.len();
+        self.0.hit_all(ray, bounds, hits);
+        for hit in &mut hits[start..] {
+            hit.is_front = !hit.is_front;
+        }
+    }
+
     #[inline]
-    fn hit(&self, ray: &Ray, bounds: Range<f32>) -> Option<Hit> {
-        self.objects.hit(ray, bounds)
+    fn hit_any(&self, ray: &Ray, bounds: Range<Real>) -> bool {
+        self.0.hit_any(ray, bounds)
+    }
+
+    #[inline]
+    fn bounding_box(&self, time: Range<Real>) -> Option<Aabb> {
+        self.0.bounding_box(time)
+    }
+
+    #[inline]
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    #[inline]
+    fn scene_stats(&self) -> SceneStats {
+        self.0.scene_stats()
     }
 }
 
-pub struct WorldBuilder<T> {
-    objects: Vec<T>,
+pub type Mat4 = nalgebra_glm::TMat4<Real>;
+pub type Mat3 = nalgebra_glm::TMat3<Real>;
+
+/// An object moved, rotated, scaled or sheared by an affine transform. Rays are brought into the
+/// space of the object to be intersected, and its hits are brought back out. Built up from
+/// `Transform::new`, as in `Transform::new(sphere).scale(factors).translate(offset)`.
+#[derive(Debug, Clone)]
+pub struct Transform<H> {
+    pub inner: H,
+    to_local: Mat4,
+    to_world: Mat4,
+    /// Takes normals out of the space of the object, the inverse transpose of `to_world`.
+    normal_mat: Mat3,
 }
 
-impl<T> WorldBuilder<T> {
-    pub fn build(&mut self) -> Vec<T> {
-        std::mem::take(&mut self.objects)
+impl<H> Transform<H> {
+    /// Leave `inner` where it is, for the other transforms to be composed onto.
+    pub fn new(inner: H) -> Self {
+        Transform {
+            inner,
+            to_local: Mat4::identity(),
+            to_world: Mat4::identity(),
+            normal_mat: Mat3::identity(),
+        }
     }
 
-    pub fn add(&mut self, object: T) -> &mut Self {
-        self.objects.push(object);
-        self
+    /// Apply `transform` after the ones so far.
+    ///
+    /// # Panics
+    ///
+    /// If `transform` can't be inverted, like a scale by 0.
+    pub fn compose(self, transform: Mat4) -> Self {
+        let inverse = transform.try_inverse().expect("transforms must be invertible");
+        let to_local = self.to_local * inverse;
+        Transform {
+            to_world: transform * self.to_world,
+            normal_mat: nalgebra_glm::mat4_to_mat3(&to_local).transpose(),
+            to_local,
+            ..self
+        }
+    }
+
+    pub fn translate(self, offset: Vec3) -> Self {
+        self.compose(nalgebra_glm::translation(&offset))
+    }
+
+    /// Rotate by `degrees` around `axis`, counterclockwise when looking down the axis.
+    pub fn rotate(self, axis: Vec3, degrees: Real) -> Self {
+        self.compose(nalgebra_glm::rotation(degrees.to_radians(), &axis))
+    }
+
+    /// Scale by `factors` along each axis, which may differ.
+    pub fn scale(self, factors: Vec3) -> Self {
+        self.compose(nalgebra_glm::scaling(&factors))
+    }
+
+    /// The ray in the space of the object. The direction isn't normalized, so that `t` is the
+    /// same in both spaces.
+    fn to_local(&self, ray: &Ray) -> Ray {
+        let origin = (self.to_local * ray.origin.0.push(1.0)).xyz();
+        let dir = nalgebra_glm::mat4_to_mat3(&self.to_local) * ray.dir.0;
+        Ray::new(origin, dir).with_payload(ray.payload)
+    }
+
+    fn to_world<'a>(&self, ray: &Ray, hit: Hit<'a>) -> Hit<'a> {
+        let hit = Hit {
+            point: ray.at(hit.t),
+            normal: (self.normal_mat * hit.normal.0).normalize().into(),
+            ..hit
+        };
+        // Tangents are transformed like directions, unlike normals.
+        hit.with_tangent(nalgebra_glm::mat4_to_mat3(&self.to_world) * hit.tangent.0)
     }
 }
 
-impl WorldBuilder<Box<dyn Hittable + Send + Sync + 'static>> {
-    pub fn into_boxed_list(&mut self) -> BoxedHitList {
-        BoxedHitList::new(std::mem::take(&mut self.objects))
+impl<H: Hittable> Hittable for Transform<H> {
+    fn hit(&self, ray: &Ray, bounds: Range<Real>) -> Option<Hit<'_>> {
+        let hit = self.inner.hit(&self.to_local(ray), bounds)?;
+        Some(self.to_world(ray, hit))
     }
 
-    pub fn add_boxed(&mut self, object: impl Hittable + Send + Sync + 'static) -> &mut Self {
-        self.objects.push(Box::new(object));
-        self
+    fn hit_all<'a>(&'a self, ray: &Ray, bounds: Range<Real>, hits: &mut Vec<Hit<'a>>) {
+        let start = hits.len();
+        self.inner.hit_all(&self.to_local(ray), bounds, hits);
+        for hit in &mut hits[start..] {
+            *hit = self.to_world(ray, *hit);
+        }
+    }
+
+    #[inline]
+    fn hit_any(&self, ray: &Ray, bounds: Range<Real>) -> bool {
+        self.inner.hit_any(&self.to_local(ray), bounds)
+    }
+
+    /// Box around the corners of the box of `inner`, once transformed.
+    fn bounding_box(&self, time: Range<Real>) -> Option<Aabb> {
+        let local = self.inner.bounding_box(time)?;
+        (0..8)
+            .map(|corner| {
+                let pick = |axis: usize| if corner & (1 << axis) == 0 { local.min[axis] } else { local.max[axis] };
+                let point = Vec3::new(pick(0), pick(1), pick(2));
+                let point = (self.to_world * point.push(1.0)).xyz();
+                Aabb::new(point, point)
+            })
+            .reduce(|a, b| a.union(&b))
+    }
+
+    #[inline]
+    fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    #[inline]
+    fn scene_stats(&self) -> SceneStats {
+        self.inner.scene_stats()
     }
 }
 
-impl<T> Default for WorldBuilder<T> {
-    fn default() -> WorldBuilder<T> {
-        WorldBuilder { objects: Vec::new() }
+/// How `Csg` combines two solids.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CsgOp {
+    /// Inside of either.
+    Union,
+    /// Inside of both.
+    Intersection,
+    /// Inside of the first but not the second.
+    Difference,
+}
+
+impl CsgOp {
+    fn contains(self, in_a: bool, in_b: bool) -> bool {
+        match self {
+            CsgOp::Union        => in_a || in_b,
+            CsgOp::Intersection => in_a && in_b,
+            CsgOp::Difference   => in_a && !in_b,
+        }
+    }
+}
+
+/// Constructive solid geometry, a solid made by combining two others. Both have to be closed and
+/// report all of their hits in `hit_all`, with `is_front` set where rays go into them. A solid
+/// turned inside out with `FlipNormals` is everything outside of it. Every surface keeps its own
+/// material, e.g. the inside of a hole has the material of what was subtracted.
+#[derive(Debug, Clone)]
+pub struct Csg<A, B> {
+    pub op: CsgOp,
+    pub a: A,
+    pub b: B,
+}
+
+impl<A, B> Csg<A, B> {
+    pub fn new(op: CsgOp, a: A, b: B) -> Self {
+        Self { op, a, b }
+    }
+
+    pub fn union(a: A, b: B) -> Self {
+        Csg::new(CsgOp::Union, a, b)
+    }
+
+    pub fn intersection(a: A, b: B) -> Self {
+        Csg::new(CsgOp::Intersection, a, b)
+    }
+
+    /// `a` with `b` cut out of it.
+    pub fn difference(a: A, b: B) -> Self {
+        Csg::new(CsgOp::Difference, a, b)
+    }
+}
+
+impl<A: Hittable, B: Hittable> Hittable for Csg<A, B> {
+    fn hit(&self, ray: &Ray, bounds: Range<Real>) -> Option<Hit<'_>> {
+        let mut hits = Vec::new();
+        self.hit_all(ray, bounds, &mut hits);
+        hits.into_iter().next()
+    }
+
+    fn hit_all<'a>(&'a self, ray: &Ray, bounds: Range<Real>, hits: &mut Vec<Hit<'a>>) {
+        // Whether the ray is inside of each solid at the start of `bounds` depends on the hits
+        // before it, so those are needed too.
+        let mut a_hits = Vec::new();
+        let mut b_hits = Vec::new();
+        self.a.hit_all(ray, Real::NEG_INFINITY..bounds.end, &mut a_hits);
+        self.b.hit_all(ray, Real::NEG_INFINITY..bounds.end, &mut b_hits);
+
+        // Rays start out inside of a solid whose first hit leaves it, like one in `FlipNormals`.
+        let starts_inside = |hits: &[Hit]| hits.first().is_some_and(|hit| !hit.is_front);
+        let (mut in_a, mut in_b) = (starts_inside(&a_hits), starts_inside(&b_hits));
+        let (mut a_hits, mut b_hits) = (a_hits.into_iter().peekable(), b_hits.into_iter().peekable());
+        loop {
+            let from_a = match (a_hits.peek(), b_hits.peek()) {
+                (Some(a), Some(b)) => a.t <= b.t,
+                (Some(_), None)    => true,
+                (None, Some(_))    => false,
+                (None, None)       => break,
+            };
+
+            let inside_before = self.op.contains(in_a, in_b);
+            let hit = if from_a {
+                let hit = a_hits.next().unwrap();
+                in_a = hit.is_front;
+                hit
+            } else {
+                let hit = b_hits.next().unwrap();
+                in_b = hit.is_front;
+                hit
+            };
+            let inside_after = self.op.contains(in_a, in_b);
+
+            if inside_before != inside_after && bounds.contains(&hit.t) {
+                hits.push(Hit { is_front: inside_after, ..hit });
+            }
+        }
+    }
+
+    fn bounding_box(&self, time: Range<Real>) -> Option<Aabb> {
+        match self.op {
+            CsgOp::Union => {
+                let a = self.a.bounding_box(time.clone())?;
+                let b = self.b.bounding_box(time)?;
+                Some(a.union(&b))
+            }
+            CsgOp::Intersection | CsgOp::Difference => self.a.bounding_box(time),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        match self.op {
+            CsgOp::Union => self.a.is_empty() && self.b.is_empty(),
+            _            => self.a.is_empty(),
+        }
+    }
+
+    fn scene_stats(&self) -> SceneStats {
+        self.a.scene_stats().merge(self.b.scene_stats())
+    }
+}
+
+pub type BoxHittable = Box<dyn Hittable + Send + Sync>;
+
+impl Hittable for Box<dyn Hittable + Send + Sync> {
+    #[inline]
+    fn hit(&self, ray: &Ray, bounds: Range<Real>) -> Option<Hit<'_>> {
+        self.as_ref().hit(ray, bounds)
+    }
+
+    #[inline]
+    fn hit_any(&self, ray: &Ray, bounds: Range<Real>) -> bool {
+        self.as_ref().hit_any(ray, bounds)
+    }
+
+    #[inline]
+    fn hit_all<'a>(&'a self, ray: &Ray, bounds: Range<Real>, hits: &mut Vec<Hit<'a>>) {
+        self.as_ref().hit_all(ray, bounds, hits)
+    }
+
+    #[inline]
+    fn bounding_box(&self, time: Range<Real>) -> Option<Aabb> {
+        self.as_ref().bounding_box(time)
+    }
+
+    #[inline]
+    fn is_empty(&self) -> bool {
+        self.as_ref().is_empty()
+    }
+
+    #[inline]
+    fn scene_stats(&self) -> SceneStats {
+        self.as_ref().scene_stats()
+    }
+}
+
+impl<T: Hittable> Hittable for Vec<T> {
+    /// The nearest hit, ties broken as in `Hit::cmp_nearest` so coincident surfaces resolve the
+    /// same way on every render.
+    fn hit(&self, ray: &Ray, bounds: Range<Real>) -> Option<Hit<'_>> {
+        self.iter()
+            .filter_map(|hittable| hittable.hit(ray, bounds.clone()))
+            .min_by(Hit::cmp_nearest)
+    }
+
+    fn hit_any(&self, ray: &Ray, bounds: Range<Real>) -> bool {
+        self.iter().any(|hittable| hittable.hit_any(ray, bounds.clone()))
+    }
+
+    fn bounding_box(&self, time: Range<Real>) -> Option<Aabb> {
+        let mut objects = self.iter();
+        let first = objects.next()?.bounding_box(time.clone())?;
+        objects.try_fold(first, |acc, obj| Some(acc.union(&obj.bounding_box(time.clone())?)))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.iter().all(Hittable::is_empty)
+    }
+
+    fn scene_stats(&self) -> SceneStats {
+        self.iter().map(Hittable::scene_stats).fold(SceneStats::default(), SceneStats::merge)
+    }
+}
+
+#[derive(Default)]
+pub struct BoxedHitList {
+    objects: Vec<BoxHittable>,
+    /// Union of the bounding boxes of `objects`.
+    bounds: Option<Aabb>,
+}
+
+impl BoxedHitList {
+    #[inline]
+    pub fn new(objects: Vec<BoxHittable>) -> BoxedHitList {
+        let bounds = objects.bounding_box(SHUTTER);
+        BoxedHitList { objects, bounds }
+    }
+
+    pub fn empty() -> BoxedHitList {
+        BoxedHitList::default()
+    }
+
+    pub fn add(&mut self, object: impl Hittable + Send + Sync + 'static) {
+        self.bounds = if self.objects.is_empty() {
+            object.bounding_box(SHUTTER)
+        } else {
+            self.bounds.zip(object.bounding_box(SHUTTER)).map(|(a, b)| a.union(&b))
+        };
+        self.objects.push(Box::new(object));
+    }
+
+    /// Move all objects of `other` into this list.
+    pub fn merge(mut self, other: BoxedHitList) -> BoxedHitList {
+        self.bounds = if self.objects.is_empty() {
+            other.bounds
+        } else if other.objects.is_empty() {
+            self.bounds
+        } else {
+            self.bounds.zip(other.bounds).map(|(a, b)| a.union(&b))
+        };
+        self.objects.extend(other.objects);
+        self
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.objects.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.objects.is_empty()
+    }
+
+    /// Union of the bounding boxes of all objects, `None` if any of them is unbounded.
+    #[inline]
+    pub fn bounds(&self) -> Option<Aabb> {
+        self.bounds
+    }
+
+    #[inline]
+    pub fn objects(&self) -> &[BoxHittable] {
+        &self.objects
+    }
+
+    /// Give `f` access to the objects to move, add or remove them. The cached bounds are brought
+    /// up to date afterwards.
+    pub fn modify<R>(&mut self, f: impl FnOnce(&mut Vec<BoxHittable>) -> R) -> R {
+        let result = f(&mut self.objects);
+        self.bounds = self.objects.bounding_box(SHUTTER);
+        result
+    }
+
+    /// Whether the ray gets anywhere near the objects, so that rays going off into the background
+    /// don't have to be tested against every one of them.
+    #[inline]
+    fn may_hit(&self, ray: &Ray, bounds: &Range<Real>) -> bool {
+        self.bounds.is_none_or(|aabb| aabb.hit(ray, bounds.clone()).is_some())
+    }
+
+    #[inline]
+    pub fn iter(&self) -> core::slice::Iter<'_, BoxHittable> {
+        self.objects.iter()
+    }
+}
+
+impl Index<usize> for BoxedHitList {
+    type Output = BoxHittable;
+
+    #[inline]
+    fn index(&self, index: usize) -> &BoxHittable {
+        &self.objects[index]
+    }
+}
+
+impl IntoIterator for BoxedHitList {
+    type Item = BoxHittable;
+    type IntoIter = alloc::vec::IntoIter<BoxHittable>;
+
+    #[inline]
+    fn into_iter(self) -> Self::IntoIter {
+        self.objects.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a BoxedHitList {
+    type Item = &'a BoxHittable;
+    type IntoIter = core::slice::Iter<'a, BoxHittable>;
+
+    #[inline]
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl Hittable for BoxedHitList {
+    #[inline]
+    fn hit(&self, ray: &Ray, bounds: Range<Real>) -> Option<Hit<'_>> {
+        if !self.may_hit(ray, &bounds) {
+            return None;
+        }
+        self.objects.hit(ray, bounds)
+    }
+
+    #[inline]
+    fn hit_any(&self, ray: &Ray, bounds: Range<Real>) -> bool {
+        self.may_hit(ray, &bounds) && self.objects.hit_any(ray, bounds)
+    }
+
+    /// Computed over the whole `SHUTTER` ahead of time.
+    #[inline]
+    fn bounding_box(&self, _time: Range<Real>) -> Option<Aabb> {
+        self.bounds
+    }
+
+    #[inline]
+    fn is_empty(&self) -> bool {
+        Hittable::is_empty(&self.objects)
+    }
+
+    #[inline]
+    fn scene_stats(&self) -> SceneStats {
+        self.objects.scene_stats()
+    }
+}
+
+pub struct WorldBuilder<T> {
+    objects: Vec<T>,
+}
+
+impl<T> WorldBuilder<T> {
+    pub fn build(&mut self) -> Vec<T> {
+        core::mem::take(&mut self.objects)
+    }
+
+    /// Build the world to be shared between scenes, see `Hittable for Arc`.
+    pub fn build_arc(&mut self) -> Arc<Vec<T>> {
+        Arc::new(self.build())
+    }
+
+    pub fn add(&mut self, object: T) -> &mut Self {
+        self.objects.push(object);
+        self
+    }
+}
+
+impl<T: Hittable> WorldBuilder<T> {
+    /// Build the world into a k-d tree, which is a lot faster than a list for large scenes.
+    pub fn build_kdtree(&mut self) -> KdTree<T> {
+        KdTree::new(self.build())
+    }
+
+    /// Build the world into a uniform grid, which can beat a k-d tree when the objects are
+    /// evenly spread and about the same size.
+    pub fn build_grid(&mut self) -> UniformGrid<T> {
+        UniformGrid::new(self.build())
+    }
+}
+
+/// Radius of the sphere that stands in for a ground plane. Big enough that it looks flat near
+/// the origin.
+const GROUND_RADIUS: Real = 1000.0;
+
+impl WorldBuilder<Sphere<CommonMat>> {
+    /// Add a ground whose top is at height `y`.
+    pub fn with_ground_plane(&mut self, y: Real, material: impl Into<CommonMat>) -> &mut Self {
+        let center = Point3::new(0.0, y - GROUND_RADIUS, 0.0);
+        self.add(Sphere::new(center, GROUND_RADIUS, material.into()))
+    }
+
+    /// Add a sphere whose material is picked next, as in `world.sphere(center, 0.5).glass(1.5)`.
+    pub fn sphere(&mut self, center: Point3, radius: Real) -> MaterialSlot<'_> {
+        MaterialSlot { world: self, center, radius }
+    }
+}
+
+/// A sphere waiting for its material, see `WorldBuilder::sphere`.
+#[must_use = "the sphere is only added once its material is picked"]
+pub struct MaterialSlot<'a> {
+    world: &'a mut WorldBuilder<Sphere<CommonMat>>,
+    center: Point3,
+    radius: Real,
+}
+
+impl<'a> MaterialSlot<'a> {
+    pub fn material(self, material: impl Into<CommonMat>) -> &'a mut WorldBuilder<Sphere<CommonMat>> {
+        self.world.add(Sphere::new(self.center, self.radius, material.into()))
+    }
+
+    pub fn diffuse(self, albedo: Color) -> &'a mut WorldBuilder<Sphere<CommonMat>> {
+        self.material(Diffuse::new(albedo))
+    }
+
+    pub fn metal(self, albedo: Color, fuzz: Real) -> &'a mut WorldBuilder<Sphere<CommonMat>> {
+        self.material(Metal::new(albedo, fuzz))
+    }
+
+    pub fn glass(self, ior: Real) -> &'a mut WorldBuilder<Sphere<CommonMat>> {
+        self.material(Dielectric::new(ior))
+    }
+}
+
+impl WorldBuilder<Sphere<String>> {
+    /// Replace the material names of the spheres with the materials they refer to. Spheres that
+    /// use the same name share the same material.
+    pub fn resolve(
+        &mut self,
+        registry: &MaterialRegistry,
+    ) -> Result<Vec<Sphere<Arc<CommonMat>>>, UnknownMaterial> {
+        self.build()
+            .into_iter()
+            .map(|Sphere { center, radius, radius_sq, inv_radius, inward, material }| {
+                let material = registry.get(&material)?;
+                Ok(Sphere { center, radius, radius_sq, inv_radius, inward, material })
+            })
+            .collect()
+    }
+}
+
+impl WorldBuilder<Box<dyn Hittable + Send + Sync + 'static>> {
+    #[allow(clippy::wrong_self_convention)]
+    pub fn into_boxed_list(&mut self) -> BoxedHitList {
+        BoxedHitList::new(core::mem::take(&mut self.objects))
+    }
+
+    pub fn add_boxed(&mut self, object: impl Hittable + Send + Sync + 'static) -> &mut Self {
+        self.objects.push(Box::new(object));
+        self
+    }
+}
+
+impl<T> Default for WorldBuilder<T> {
+    fn default() -> WorldBuilder<T> {
+        WorldBuilder { objects: Vec::new() }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::hittable::{ self, TraversalCounts };
+    use crate::utils::{ self, color };
+    use std::sync::atomic::{ AtomicUsize, Ordering };
+
+    fn unit_sphere() -> Sphere<Diffuse> {
+        unit_sphere_at(Point3::zeros())
+    }
+
+    fn unit_sphere_at(center: Point3) -> Sphere<Diffuse> {
+        Sphere::new(center, 1.0, Diffuse::new(color::mid_gray()))
+    }
+
+    /// Distance and outward normal of the nearest hit, computed from the radius alone like before
+    /// `Sphere` cached `radius_sq` and `inv_radius`.
+    fn uncached_hit(center: Point3, radius: Real, ray: &Ray, bounds: Range<Real>) -> Option<(Real, Vec3)> {
+        count_primitive_test();
+
+        let oc = ray.origin.0 - center;
+        let a = ray.dir.magnitude_squared();
+        if a == 0.0 {
+            return None;
+        }
+        let half_b = oc.dot(&ray.dir.0);
+        let c = oc.magnitude_squared() - radius * radius;
+        let discriminant = half_b * half_b - a * c;
+        if discriminant <= 0.0 {
+            return None;
+        }
+
+        let disc_sqrt = discriminant.sqrt();
+        let t = [(-half_b - disc_sqrt) / a, (-half_b + disc_sqrt) / a]
+            .iter()
+            .copied()
+            .find(|t| bounds.contains(t))?;
+        Some((t, (ray.at(t).0 - center) / radius))
+    }
+
+    /// A thousand spheres in a 10 unit cube, and rays through it from random points, seeded.
+    fn random_spheres_and_rays() -> (Vec<Sphere<Diffuse>>, Vec<Ray>) {
+        utils::seed_thread_rng(7);
+        let point = || Vec3::new(utils::random(), utils::random(), utils::random()) * 10.0;
+        let spheres = (0..1000)
+            .map(|_| Sphere::new(point(), 0.1 + utils::random::<Real>(), Diffuse::new(color::mid_gray())))
+            .collect();
+        let rays = (0..300).map(|_| Ray::new(point(), point() - point())).collect();
+        (spheres, rays)
+    }
+
+    #[test]
+    fn cached_radius_hits_match_the_direct_computation() {
+        let (spheres, rays) = random_spheres_and_rays();
+
+        let mut hits = 0;
+        for sphere in &spheres {
+            for ray in &rays {
+                let expected = uncached_hit(sphere.center, sphere.radius(), ray, 0.001..Real::INFINITY);
+                let hit = sphere.hit(ray, 0.001..Real::INFINITY);
+                assert_eq!(hit.is_some(), expected.is_some());
+
+                if let (Some(hit), Some((t, outward))) = (hit, expected) {
+                    assert!((hit.t - t).abs() <= 1e-5 * t.abs().max(1.0), "{} != {}", hit.t, t);
+                    let normal = if hit.is_front { hit.normal.0 } else { -hit.normal.0 };
+                    assert!(utils::approx_eq(&normal, &outward, 1e-5), "{:?} != {:?}", normal, outward);
+                    hits += 1;
+                }
+            }
+        }
+        assert!(hits > 1000);
+    }
+
+    /// Run with `cargo test --release -- --ignored --nocapture sphere_hit_benchmark`.
+    #[test]
+    #[ignore]
+    fn sphere_hit_benchmark() {
+        use std::time::Instant;
+
+        let (spheres, rays) = random_spheres_and_rays();
+        let passes = 50;
+
+        // Same work as `uncached_hit`, without building a `Hit`.
+        let cached_hit = |sphere: &Sphere<Diffuse>, ray: &Ray| {
+            let t = sphere.intersect(ray, &(0.001..Real::INFINITY))?;
+            Some((t, (ray.at(t).0 - sphere.center) * sphere.inv_radius))
+        };
+
+        let start = Instant::now();
+        let mut cached = 0.0;
+        for _ in 0..passes {
+            for ray in &rays {
+                cached += spheres.iter()
+                    .filter_map(|sphere| cached_hit(sphere, ray))
+                    .map(|(_, normal)| normal.x)
+                    .sum::<Real>();
+            }
+        }
+        let cached_time = start.elapsed();
+
+        let start = Instant::now();
+        let mut uncached = 0.0;
+        for _ in 0..passes {
+            for ray in &rays {
+                uncached += spheres.iter()
+                    .filter_map(|sphere| uncached_hit(sphere.center, sphere.radius(), ray, 0.001..Real::INFINITY))
+                    .map(|(_, normal)| normal.x)
+                    .sum::<Real>();
+            }
+        }
+        let uncached_time = start.elapsed();
+
+        eprintln!("cached: {:?}, uncached: {:?} ({} and {})", cached_time, uncached_time, cached, uncached);
+    }
+
+    fn assert_orthonormal_frame(hit: &Hit) {
+        let (t, b, n) = (hit.tangent.0, hit.bitangent.0, hit.normal.0);
+        for v in [t, b, n] {
+            assert!((v.magnitude() - 1.0).abs() < 1e-5, "{:?} isn't a unit vector", v);
+        }
+        assert!(t.dot(&b).abs() < 1e-5 && t.dot(&n).abs() < 1e-5 && b.dot(&n).abs() < 1e-5);
+        assert!(utils::approx_eq(&t.cross(&b), &n, 1e-5), "{:?} is left handed", hit);
+    }
+
+    #[test]
+    fn spheres_resolved_from_the_same_name_share_the_material() {
+        let mut registry = MaterialRegistry::new();
+        let gold = registry.insert("gold", Metal::new(color::new(1.0, 0.8, 0.3), 0.1));
+        registry.insert("chalk", Diffuse::new(color::white()));
+
+        let spheres = WorldBuilder::default()
+            .add(Sphere::new(Point3::new(-2.0, 0.0, 0.0), 1.0, "gold".to_string()))
+            .add(Sphere::new(Point3::new(0.0, 0.0, 0.0), 1.0, "chalk".to_string()))
+            .add(Sphere::new(Point3::new(2.0, 0.0, 0.0), 1.0, "gold".to_string()))
+            .resolve(&registry)
+            .unwrap();
+        assert!(Arc::ptr_eq(&spheres[0].material, &spheres[2].material));
+        assert!(Arc::ptr_eq(&spheres[0].material, &gold));
+        assert!(!Arc::ptr_eq(&spheres[0].material, &spheres[1].material));
+
+        let unknown = WorldBuilder::default()
+            .add(Sphere::new(Point3::zeros(), 1.0, "gold".to_string()))
+            .add(Sphere::new(Point3::zeros(), 1.0, "brass".to_string()))
+            .resolve(&registry);
+        assert_eq!(unknown.err(), Some(UnknownMaterial("brass".to_string())));
+    }
+
+    #[test]
+    fn round_ellipsoids_are_spheres() {
+        let center = Point3::new(1.0, 0.0, 0.0);
+        let sphere = Sphere::new(center, 2.0, Diffuse::new(color::mid_gray()));
+        let ellipsoid = Ellipsoid::new(center, Vec3::new(2.0, 2.0, 2.0), Diffuse::new(color::mid_gray()));
+
+        let rays = [
+            Ray::new(Vec3::new(-5.0, 0.5, 0.0), Vec3::new(1.0, 0.0, 0.0)),
+            Ray::new(Vec3::new(4.0, 5.0, 3.0), Vec3::new(-1.0, -2.0, -1.0)),
+            Ray::new(center, Vec3::new(0.0, 0.0, 1.0)),
+            Ray::new(Vec3::new(-5.0, 3.0, 0.0), Vec3::new(1.0, 0.0, 0.0)),
+        ];
+        for ray in &rays {
+            let bounds = 0.001..Real::INFINITY;
+            match (sphere.hit(ray, bounds.clone()), ellipsoid.hit(ray, bounds)) {
+                (Some(a), Some(b)) => {
+                    assert!((a.t - b.t).abs() < 1e-4, "{} != {}", a.t, b.t);
+                    assert!(utils::approx_eq(&a.normal, &b.normal, 1e-4), "{:?} != {:?}", a.normal, b.normal);
+                    assert_eq!(a.is_front, b.is_front);
+                }
+                (None, None) => {}
+                (a, b) => panic!("{:?} hit the sphere: {}, the ellipsoid: {}", ray, a.is_some(), b.is_some()),
+            }
+        }
+    }
+
+    #[test]
+    fn squashed_ellipsoids_are_hit_where_expected() {
+        let ellipsoid = Ellipsoid::new(Point3::zeros(), Vec3::new(2.0, 0.5, 1.0), Diffuse::new(color::mid_gray()));
+        let hit_at = |origin: Vec3, dir: Vec3| ellipsoid.hit(&Ray::new(origin, dir), 0.001..Real::INFINITY).unwrap();
+
+        // Along the axes the surface is a radius away.
+        assert!((hit_at(Vec3::new(0.0, 5.0, 0.0), -Vec3::y()).t - 4.5).abs() < 1e-4);
+        assert!((hit_at(Vec3::new(-5.0, 0.0, 0.0), Vec3::x()).t - 3.0).abs() < 1e-4);
+        assert!((hit_at(Vec3::new(0.0, 0.0, 5.0), -Vec3::z()).t - 4.0).abs() < 1e-4);
+
+        // Down onto (1.2, 0.4, 0), where the normal is the gradient (x / a², y / b², z / c²) and
+        // not the direction from the center.
+        let hit = hit_at(Vec3::new(1.2, 5.0, 0.0), -Vec3::y());
+        assert!((hit.t - 4.6).abs() < 1e-4, "{}", hit.t);
+        let expected = Vec3::new(1.2 / 4.0, 0.4 / 0.25, 0.0).normalize();
+        assert!(utils::approx_eq(&hit.normal, &expected, 1e-4), "{:?} != {:?}", hit.normal, expected);
+        assert!(hit.is_front);
+    }
+
+    #[test]
+    fn sphere_tangent_frame_is_orthonormal_and_follows_u() {
+        let sphere = unit_sphere();
+
+        let ray = Ray::new(Vec3::new(2.0, 3.0, 4.0), Vec3::new(-2.0, -3.0, -4.0));
+        let hit = sphere.hit(&ray, 0.001..Real::INFINITY).unwrap();
+        assert_orthonormal_frame(&hit);
+
+        // A small step along the tangent grows `u`.
+        let (u, _) = sphere.uv(&hit.point.0);
+        let (u_next, _) = sphere.uv(&(hit.point.0 + hit.tangent.0 * 1e-2).normalize());
+        assert!(u_next > u, "{} <= {}", u_next, u);
+
+        // Still a frame at the poles, where `u` is undefined, and from the inside.
+        let pole = Ray::new(Vec3::new(0.0, 5.0, 0.0), Vec3::new(0.0, -1.0, 0.0));
+        assert_orthonormal_frame(&sphere.hit(&pole, 0.001..Real::INFINITY).unwrap());
+        let inside = Ray::new(Vec3::zeros(), Vec3::new(1.0, 0.5, 0.0));
+        assert_orthonormal_frame(&sphere.hit(&inside, 0.001..Real::INFINITY).unwrap());
+    }
+
+    #[test]
+    fn tiny_shapes_have_the_same_tangents_as_large_ones() {
+        let ray = Ray::new(Vec3::new(2.0, 3.0, 4.0), Vec3::new(-2.0, -3.0, -4.0));
+        let sphere = unit_sphere();
+        let large = sphere.hit(&ray, 0.001..Real::INFINITY).unwrap();
+        let tiny_sphere = Sphere::new(Point3::zeros(), 1e-5, Diffuse::new(color::mid_gray()));
+        let tiny_ray = Ray::new(ray.origin.0 * 1e-5, ray.dir.0 * 1e-5);
+        let tiny = tiny_sphere.hit(&tiny_ray, 0.001..Real::INFINITY).unwrap();
+        assert!(utils::approx_eq(&tiny.tangent, &large.tangent, 1e-4), "{:?} != {:?}", tiny.tangent, large.tangent);
+
+        // Along the first edge, which is shorter than the fallback threshold used to be.
+        let triangle = Triangle::new(
+            [Point3::zeros(), Point3::new(0.0, 1e-5, 0.0), Point3::new(-1e-5, 0.0, 0.0)],
+            Diffuse::new(color::mid_gray()),
+        );
+        let ray = Ray::new(Vec3::new(-2e-6, 2e-6, 1.0), -Vec3::z());
+        let hit = triangle.hit(&ray, 0.001..Real::INFINITY).unwrap();
+        assert!(utils::approx_eq(&hit.tangent, &Vec3::y(), 1e-4), "{:?}", hit.tangent);
+    }
+
+    #[test]
+    fn smooth_triangles_interpolate_their_vertex_normals() {
+        let normals = [Vec3::new(-1.0, 0.0, 1.0), Vec3::new(0.0, -1.0, 1.0), Vec3::new(1.0, 1.0, 1.0)];
+        let triangle = Triangle::new(
+            [Point3::new(0.0, 0.0, 0.0), Point3::new(1.0, 0.0, 0.0), Point3::new(0.0, 1.0, 0.0)],
+            Diffuse::new(color::mid_gray()),
+        )
+        .with_normals(normals);
+        let centroid = Point3::new(1.0, 1.0, 0.0) / 3.0;
+
+        let hit = triangle.hit(&Ray::new(centroid + Vec3::z(), -Vec3::z()), 0.001..Real::INFINITY).unwrap();
+        let average = normals.iter().map(|n| n.normalize()).sum::<Vec3>().normalize();
+        assert!(utils::approx_eq(&hit.normal, &average, 1e-5));
+        assert!(hit.is_front);
+
+        // Vertices without a normal of their own take the triangle's, instead of NaN.
+        let positions = [Point3::zeros(), Point3::x(), Point3::y(), Point3::new(5.0, 5.0, 5.0)];
+        let mesh_normals = vertex_normals(&positions, &[[0, 1, 2]]);
+        let triangle = Triangle::new([positions[0], positions[1], positions[3]], Diffuse::new(color::mid_gray()))
+            .with_normals([mesh_normals[0], mesh_normals[1], mesh_normals[3]]);
+        let face = (positions[1] - positions[0]).cross(&(positions[3] - positions[0])).normalize();
+        assert_eq!(triangle.normals.unwrap()[2], face);
+
+        // Where opposite vertex normals cancel out, the triangle is flat shaded.
+        let triangle = Triangle::new(
+            [Point3::new(0.0, 0.0, 0.0), Point3::new(1.0, 0.0, 0.0), Point3::new(0.0, 1.0, 0.0)],
+            Diffuse::new(color::mid_gray()),
+        )
+        .with_normals([Vec3::x(), -Vec3::x(), Vec3::z()]);
+        let hit = triangle.hit(&Ray::new(Vec3::new(0.5, 0.0, 1.0), -Vec3::z()), 0.001..Real::INFINITY).unwrap();
+        assert!(utils::approx_eq(&hit.normal, &Vec3::z(), 1e-5));
+    }
+
+    #[test]
+    fn tangent_frames_survive_transforms() {
+        let triangle = Triangle::new(
+            [Point3::new(0.0, 0.0, 0.0), Point3::new(1.0, 0.0, 0.0), Point3::new(0.0, 1.0, 0.0)],
+            Diffuse::new(color::mid_gray()),
+        );
+        let ray = Ray::new(Vec3::new(0.25, 0.25, 1.0), Vec3::new(0.0, 0.0, -1.0));
+        let hit = triangle.hit(&ray, 0.001..Real::INFINITY).unwrap();
+        assert_orthonormal_frame(&hit);
+        assert!(utils::approx_eq(&hit.tangent.0, &Vec3::x(), 1e-5));
+
+        let sheared = Transform::new(triangle)
+            .scale(Vec3::new(3.0, 1.0, 1.0))
+            .rotate(Vec3::new(1.0, 1.0, 0.0), 30.0);
+        let ray = Ray::new(Vec3::new(0.5, 0.5, 3.0), Vec3::new(0.0, 0.0, -1.0));
+        assert_orthonormal_frame(&sheared.hit(&ray, 0.001..Real::INFINITY).unwrap());
+    }
+
+    #[test]
+    fn sphere_uv_is_defined_at_the_poles_and_seam() {
+        let sphere = Sphere::new(Point3::new(1.0, 2.0, 3.0), 2.0, Diffuse::new(color::mid_gray()));
+        let uv = |offset: Vec3| {
+            let (u, v) = sphere.uv(&(sphere.center + offset));
+            assert!((0.0..=1.0).contains(&u) && (0.0..=1.0).contains(&v), "{:?} at {:?}", (u, v), offset);
+            (u, v)
+        };
+
+        assert_eq!(uv(Vec3::new(0.0, 2.0, 0.0)), (0.5, 1.0));
+        assert_eq!(uv(Vec3::new(-0.0, -2.0, -0.0)), (0.5, 0.0));
+        // Just past the surface, as hit points can be after rounding.
+        assert_eq!(uv(Vec3::new(0.0, 2.0 + 1e-5, 0.0)).1, 1.0);
+        assert_eq!(uv(Vec3::new(0.0, 1e-3, 0.0)).1, 1.0);
+
+        // Either edge of the texture along the seam, depending on the side it's approached from.
+        for z in [0.0, -0.0] {
+            let (u, v) = uv(Vec3::new(-2.0, 0.0, z));
+            assert!(u == 0.0 || u == 1.0, "{}", u);
+            assert_eq!(v, 0.5);
+        }
+        let (towards_z, _) = uv(Vec3::new(-2.0, 0.0, 1e-3));
+        let (away_from_z, _) = uv(Vec3::new(-2.0, 0.0, -1e-3));
+        assert!(towards_z < 0.01 && away_from_z > 0.99, "{} {}", towards_z, away_from_z);
+    }
+
+    #[test]
+    fn sphere_is_missed_by_tangent_rays() {
+        let sphere = unit_sphere();
+
+        let tangent = Ray::new(Vec3::new(-5.0, 1.0, 0.0), Vec3::new(1.0, 0.0, 0.0));
+        assert!(sphere.hit(&tangent, 0.001..Real::INFINITY).is_none());
+        assert!(!sphere.hit_any(&tangent, 0.001..Real::INFINITY));
+
+        let grazing = Ray::new(Vec3::new(-5.0, 0.999, 0.0), Vec3::new(1.0, 0.0, 0.0));
+        assert!(sphere.hit(&grazing, 0.001..Real::INFINITY).is_some());
+    }
+
+    #[test]
+    fn sphere_is_missed_by_rays_without_direction() {
+        let sphere = unit_sphere();
+
+        for origin in [Vec3::new(0.0, 0.0, 5.0), Vec3::zeros()] {
+            let ray = Ray::new(origin, Vec3::zeros());
+            assert!(sphere.hit(&ray, 0.001..Real::INFINITY).is_none());
+
+            let mut hits = Vec::new();
+            sphere.hit_all(&ray, 0.001..Real::INFINITY, &mut hits);
+            assert!(hits.is_empty());
+        }
+    }
+
+    #[test]
+    fn world_builder_attaches_materials_to_spheres() {
+        let world = WorldBuilder::default()
+            .sphere(Point3::new(-2.0, 0.0, 0.0), 1.0).diffuse(color::mid_gray())
+            .sphere(Point3::new(2.0, 0.0, 0.0), 0.5).glass(1.5)
+            .build();
+
+        assert_eq!(world.len(), 2);
+        assert!(matches!(world[0].material, CommonMat::Diffuse(_)));
+        assert!(matches!(world[1].material, CommonMat::Dielectric(_)));
+
+        let down = Vec3::new(0.0, -1.0, 0.0);
+        let left = world.hit(&Ray::new(Vec3::new(-2.0, 5.0, 0.0), down), 0.001..Real::INFINITY).unwrap();
+        assert_eq!((left.t, left.point.0), (4.0, Vec3::new(-2.0, 1.0, 0.0)));
+        let right = world.hit(&Ray::new(Vec3::new(2.0, 5.0, 0.0), down), 0.001..Real::INFINITY).unwrap();
+        assert_eq!((right.t, right.point.0), (4.5, Vec3::new(2.0, 0.5, 0.0)));
+    }
+
+    /// How many of a grid of rays at a sphere `offset` away from the origin, bounced off of it at a
+    /// grazing angle, hit the same sphere again. That's acne, a convex surface can't shadow itself.
+    fn self_intersections(offset: Real) -> usize {
+        let center = Point3::repeat(offset);
+        let sphere = Sphere::new(center, 1.0, Diffuse::new(color::mid_gray()));
+        let origin = center + Vec3::new(0.0, 0.0, 5.0);
+
+        (-5..=5)
+            .flat_map(|i| (-5..=5).map(move |j| (i, j)))
+            .filter(|&(i, j)| {
+                let ray = Ray::new(origin, Vec3::new(i as Real * 0.02, j as Real * 0.02, -1.0));
+                let hit = sphere.hit(&ray, 0.001..Real::INFINITY).unwrap();
+                let tangent = hit.normal.cross(&Vec3::y()).normalize();
+                let grazing = Ray::new(hit.point, hit.normal.0 * 0.05 + tangent);
+                sphere.hit(&grazing, 0.001..Real::INFINITY).is_some()
+            })
+            .count()
+    }
+
+    #[test]
+    fn far_from_the_origin_only_f64_is_free_of_acne() {
+        assert_eq!(self_intersections(0.0), 0);
+
+        // At this scale `f32` can't place hit points within the 0.001 offset of the surface.
+        let far = self_intersections(10_000.0);
+        if cfg!(feature = "f64") {
+            assert_eq!(far, 0);
+        } else {
+            assert!(far > 10, "only {} of the rays hit the sphere again", far);
+        }
+    }
+
+    #[test]
+    fn ground_plane_is_hit_at_its_height() {
+        let world = WorldBuilder::default()
+            .with_ground_plane(-0.5, Diffuse::new(color::mid_gray()))
+            .build();
+        assert_eq!(world.len(), 1);
+
+        // Anywhere near the origin, the ground is flat.
+        for x in [0.0, 3.0, -7.0] {
+            let hit = world.hit(&Ray::new(Vec3::new(x, 5.0, 0.0), -Vec3::y()), 0.001..Real::INFINITY).unwrap();
+            assert!((hit.point.0.y + 0.5).abs() < 0.05, "{:?}", hit.point);
+            assert!(hit.is_front && hit.normal.y > 0.99);
+        }
+    }
+
+    #[test]
+    fn inside_out_spheres_match_outward_ones_but_face_in() {
+        let outward = Sphere::new(Point3::new(1.0, 2.0, 3.0), 2.0, Diffuse::new(color::mid_gray()));
+        let inward = Sphere::inside_out(Point3::new(1.0, 2.0, 3.0), 2.0, Diffuse::new(color::mid_gray()));
+        assert_eq!((inward.radius(), inward.inward), (2.0, true));
+        assert_eq!(inward.bounding_box(SHUTTER), outward.bounding_box(SHUTTER));
+
+        let ray = Ray::new(Point3::new(1.0, 2.0, -5.0), Vec3::z());
+        let out_hit = outward.hit(&ray, 0.001..Real::INFINITY).unwrap();
+        let in_hit = inward.hit(&ray, 0.001..Real::INFINITY).unwrap();
+        assert_eq!((out_hit.t, out_hit.point.0), (in_hit.t, in_hit.point.0));
+        // Both normals face the ray, but only the outward sphere is hit on its front.
+        assert_eq!(out_hit.normal.0, in_hit.normal.0);
+        assert!(out_hit.is_front && !in_hit.is_front);
+    }
+
+    #[test]
+    fn hollow_glass_is_a_shell_around_an_inward_bubble() {
+        let [outer, inner] = Sphere::hollow_glass(Point3::new(1.0, 2.0, 3.0), 2.0, 0.25, 1.5);
+        assert_eq!((outer.radius(), outer.inward), (2.0, false));
+        assert_eq!((inner.radius(), inner.inward), (1.75, true));
+        assert_eq!((outer.center, outer.material.ior), (inner.center, inner.material.ior));
+
+        // A ray through the middle enters the glass, then leaves it into the bubble.
+        let ray = Ray::new(Point3::new(1.0, 2.0, -5.0), Vec3::z());
+        let outer_hit = outer.hit(&ray, 0.001..Real::INFINITY).unwrap();
+        let inner_hit = inner.hit(&ray, 0.001..Real::INFINITY).unwrap();
+        assert!(outer_hit.t < inner_hit.t);
+        assert!(outer_hit.is_front && !inner_hit.is_front);
+    }
+
+    #[test]
+    #[should_panic(expected = "inside_out")]
+    fn spheres_reject_negative_radii() {
+        Sphere::new(Point3::zeros(), -1.0, Diffuse::new(color::mid_gray()));
+    }
+
+    #[test]
+    #[should_panic(expected = "positive")]
+    fn moving_spheres_reject_a_zero_radius() {
+        MovingSphere::new(Point3::zeros(), Point3::x(), 0.0, Diffuse::new(color::mid_gray()));
+    }
+
+    #[test]
+    fn rays_from_inside_a_sphere_hit_its_back() {
+        let sphere = unit_sphere_at(Point3::new(1.0, 0.0, 0.0));
+
+        for origin in [Point3::new(1.0, 0.0, 0.0), Point3::new(0.5, 0.5, 0.0), Point3::new(0.0, 0.0, 0.0)] {
+            let dir = Vec3::new(1.0, 0.2, -0.3);
+            let hit = sphere.hit(&Ray::new(origin, dir), 0.001..Real::INFINITY).unwrap();
+            assert!(!hit.is_front, "from {:?}", origin);
+            assert!(hit.t > 0.0);
+            assert!(((hit.point.0 - sphere.center).magnitude() - 1.0).abs() < 1e-5);
+            // The normal points back in, towards the ray.
+            assert!(utils::approx_eq(&hit.normal, &(sphere.center - hit.point.0), 1e-5));
+        }
+
+        // From outside, the same sphere is hit on its front.
+        let hit = sphere.hit(&Ray::new(Point3::new(-3.0, 0.0, 0.0), Vec3::x()), 0.001..Real::INFINITY).unwrap();
+        assert!(hit.is_front);
+        assert_eq!(hit.t, 3.0);
+    }
+
+    #[test]
+    fn moving_spheres_are_boxed_along_their_whole_path() {
+        let sphere = MovingSphere::new(Point3::zeros(), Point3::new(2.0, 0.0, 0.0), 0.5, Diffuse::new(color::mid_gray()));
+        assert_eq!(
+            sphere.bounding_box(0.0..1.0),
+            Some(Aabb::new(Point3::new(-0.5, -0.5, -0.5), Point3::new(2.5, 0.5, 0.5))),
+        );
+        // Only the part of the path within the interval.
+        assert_eq!(
+            sphere.bounding_box(0.5..1.0),
+            Some(Aabb::new(Point3::new(0.5, -0.5, -0.5), Point3::new(2.5, 0.5, 0.5))),
+        );
+
+        // Where the sphere is depends on when the ray is shot.
+        let down = |x: Real, time: Real| Ray::new(Point3::new(x, 5.0, 0.0), -Vec3::y()).with_time(time);
+        assert!(sphere.hit(&down(0.0, 0.0), 0.001..Real::INFINITY).is_some());
+        assert!(sphere.hit(&down(2.0, 0.0), 0.001..Real::INFINITY).is_none());
+        assert!(sphere.hit(&down(2.0, 1.0), 0.001..Real::INFINITY).is_some());
+        // A tree built over the shutter still finds it at the end of its path.
+        let still = Point3::new(-5.0, 0.0, 0.0);
+        let tree = KdTree::new(vec![sphere.clone(), MovingSphere::new(still, still, 0.5, Diffuse::new(color::mid_gray()))]);
+        assert!(tree.hit(&down(2.0, 1.0), 0.001..Real::INFINITY).is_some());
+    }
+
+    #[test]
+    fn disk_is_hit_inside_its_rim() {
+        let normal = Vec3::new(1.0, 1.0, 0.0).normalize();
+        let disk = Disk::new(Point3::new(0.0, 1.0, 0.0), normal, 0.5, Diffuse::new(color::mid_gray()));
+        let towards = |target: Point3| {
+            let origin = Point3::new(0.0, 1.0, 0.0) + 3.0 * normal;
+            Ray::new(origin, target - origin)
+        };
+
+        let center = disk.hit(&towards(disk.center), 0.001..Real::INFINITY).unwrap();
+        assert!((center.t - 1.0).abs() < 1e-5);
+        assert!(utils::approx_eq(&center.normal, &normal, 1e-5));
+        assert!(center.is_front);
+
+        // Along the plane of the disk, just inside and just outside the rim.
+        let rim = normal.cross(&Vec3::z());
+        assert!(disk.hit(&towards(disk.center + 0.49 * rim), 0.001..Real::INFINITY).is_some());
+        assert!(disk.hit(&towards(disk.center + 0.51 * rim), 0.001..Real::INFINITY).is_none());
+        assert!(!disk.hit_any(&towards(disk.center + 0.51 * rim), 0.001..Real::INFINITY));
+
+        // From behind, the normal turns to face the ray.
+        let back = disk.hit(&Ray::new(disk.center - normal, normal), 0.001..Real::INFINITY).unwrap();
+        assert!(utils::approx_eq(&back.normal, &-normal, 1e-5));
+        assert!(!back.is_front);
+
+        let aabb = disk.bounding_box(SHUTTER).unwrap();
+        let half = 0.5 * (0.5 as Real).sqrt() + 1e-4;
+        assert!(utils::approx_eq(&aabb.min, &Vec3::new(-half, 1.0 - half, -0.5 - 1e-4), 1e-5));
+        assert!(utils::approx_eq(&aabb.max, &Vec3::new(half, 1.0 + half, 0.5 + 1e-4), 1e-5));
+    }
+
+    #[test]
+    fn coincident_surfaces_have_a_deterministic_winner() {
+        let disk = |normal: Vec3, albedo: Color| Disk::new(Point3::zeros(), normal, 1.0, Diffuse::new(albedo));
+        let ray = Ray::new(Point3::new(0.1, 2.0, 0.0), -Vec3::y());
+        let winner = |list: &Vec<Disk<Diffuse>>| {
+            list.hit(&ray, 0.001..Real::INFINITY).unwrap().preview(&ray).unwrap().attenuation
+        };
+
+        // Facing the same way, the first one always wins.
+        let same = vec![disk(Vec3::y(), color::red()), disk(Vec3::y(), color::blue())];
+        for _ in 0..10 {
+            assert_eq!(winner(&same), color::red());
+        }
+
+        // Facing opposite ways, the one facing the ray wins whichever comes first.
+        let front = disk(Vec3::y(), color::red());
+        let back = disk(-Vec3::y(), color::blue());
+        assert_eq!(winner(&vec![front.clone(), back.clone()]), color::red());
+        assert_eq!(winner(&vec![back, front]), color::red());
+    }
+
+    #[test]
+    fn flipped_objects_swap_faces_and_keep_normals_facing_the_ray() {
+        let disk = Disk::new(Point3::zeros(), Vec3::y(), 1.0, Diffuse::new(color::mid_gray()));
+        let flipped = FlipNormals(disk.clone());
+
+        for dir in [Vec3::new(0.1, -1.0, 0.0), Vec3::new(0.1, 1.0, 0.0)] {
+            let ray = Ray::new(-dir * 2.0, dir);
+            let hit = disk.hit(&ray, 0.001..Real::INFINITY).unwrap();
+            let flipped_hit = flipped.hit(&ray, 0.001..Real::INFINITY).unwrap();
+
+            assert_eq!(flipped_hit.is_front, !hit.is_front);
+            assert_eq!(flipped_hit.normal.0, hit.normal.0);
+            assert!(flipped_hit.normal.dot(&dir) < 0.0);
+            assert_orthonormal_frame(&flipped_hit);
+
+            // Light still bounces back to the side the ray came from.
+            let scattered = flipped_hit.scatter(&ray).unwrap().scattered;
+            assert!(scattered.dot(&flipped_hit.normal) > 0.0);
+        }
+    }
+
+    #[test]
+    fn sphere_minus_a_sphere_leaves_a_cavity() {
+        let gray = || Diffuse::new(color::mid_gray());
+        let shell = Csg::difference(Sphere::new(Point3::zeros(), 2.0, gray()), Sphere::new(Point3::zeros(), 1.0, gray()));
+        let spans = |world: &dyn Hittable, ray: &Ray| {
+            let mut hits = Vec::new();
+            world.hit_all(ray, 0.001..Real::INFINITY, &mut hits);
+            hits.iter().map(|hit| (hit.t, hit.is_front, hit.normal.0)).collect::<Vec<_>>()
+        };
+
+        // From inside of the cavity, the wall of the hole is the first thing hit, from its front.
+        let inside = Ray::new(Point3::zeros(), Vec3::x());
+        let hit = shell.hit(&inside, 0.001..Real::INFINITY).unwrap();
+        assert!((hit.t - 1.0).abs() < 1e-5, "{}", hit.t);
+        assert!(hit.is_front);
+        assert!(utils::approx_eq(&hit.normal, &-Vec3::x(), 1e-5));
+        let hits = spans(&shell, &inside);
+        assert_eq!(hits.len(), 2);
+        assert!((hits[1].0 - 2.0).abs() < 1e-5 && !hits[1].1);
+
+        // Straight through, in and out of the shell on both sides of the cavity.
+        let through = Ray::new(Point3::new(-3.0, 0.0, 0.0), Vec3::x());
+        let hits = spans(&shell, &through);
+        let ts: Vec<_> = hits.iter().map(|&(t, _, _)| t).collect();
+        let fronts: Vec<_> = hits.iter().map(|&(_, is_front, _)| is_front).collect();
+        assert!(utils::approx_eq(&Vec3::new(ts[0], ts[1], ts[2]), &Vec3::new(1.0, 2.0, 4.0), 1e-5), "{:?}", ts);
+        assert!((ts[3] - 5.0).abs() < 1e-5);
+        assert_eq!(fronts, [true, false, true, false]);
+        assert!(hits.iter().all(|&(_, _, normal)| normal.x < 0.0), "{:?}", hits);
+
+        // Keeping what is outside of a flipped sphere is the same as cutting it out.
+        let flipped = Csg::intersection(Sphere::new(Point3::zeros(), 2.0, gray()), FlipNormals(Sphere::new(Point3::zeros(), 1.0, gray())));
+        for ray in [inside, through] {
+            assert_eq!(spans(&flipped, &ray), spans(&shell, &ray));
+        }
+    }
+
+    #[test]
+    fn traversal_is_only_counted_when_asked() {
+        let spheres = vec![unit_sphere_at(Point3::zeros()), unit_sphere_at(Point3::new(5.0, 0.0, 0.0))];
+        let ray = Ray::new(Point3::new(0.0, 0.0, 3.0), -Vec3::z());
+
+        spheres.hit(&ray, 0.001..Real::INFINITY);
+        hittable::start_traversal_counts();
+        assert_eq!(hittable::finish_traversal_counts(), TraversalCounts::default());
+
+        hittable::start_traversal_counts();
+        spheres.hit(&ray, 0.001..Real::INFINITY);
+        let counts = hittable::finish_traversal_counts();
+        assert_eq!(counts.primitive_tests, 2);
+
+        // Nothing is left counting afterwards.
+        spheres.hit(&ray, 0.001..Real::INFINITY);
+        assert_eq!(hittable::finish_traversal_counts(), TraversalCounts::default());
+    }
+
+    #[test]
+    fn shadow_segments_are_only_occluded_by_what_lies_between() {
+        let spheres = vec![unit_sphere_at(Point3::zeros()), unit_sphere_at(Point3::new(5.0, 0.0, 0.0))];
+        let shadow_ray = |from: Point3, to: Point3| Ray::new(from, to - from);
+
+        // A light behind the first sphere is hidden from a point in front of it.
+        let occluded = shadow_ray(Point3::new(0.0, 0.0, 3.0), Point3::new(0.0, 0.0, -3.0));
+        assert!(spheres.hit_any(&occluded, 0.001..1.0));
+
+        // The same sphere lies beyond a light that's closer, and nothing is in the way sideways.
+        let short = shadow_ray(Point3::new(0.0, 0.0, 3.0), Point3::new(0.0, 0.0, 2.0));
+        assert!(!spheres.hit_any(&short, 0.001..1.0));
+        let clear = shadow_ray(Point3::new(0.0, 3.0, 0.0), Point3::new(5.0, 3.0, 0.0));
+        assert!(!spheres.hit_any(&clear, 0.001..1.0));
+
+        for ray in [occluded, short, clear] {
+            assert_eq!(spheres.hit_any(&ray, 0.001..1.0), spheres.hit(&ray, 0.001..1.0).is_some());
+        }
+    }
+
+    #[test]
+    fn merged_lists_keep_every_object_and_the_combined_bounds() {
+        let list = |xs: &[Real]| {
+            let mut list = BoxedHitList::empty();
+            for &x in xs {
+                list.add(unit_sphere_at(Point3::new(x, 0.0, 0.0)));
+            }
+            list
+        };
+
+        let merged = list(&[0.0, 1.0, 2.0]).merge(list(&[-5.0, 7.0]));
+        assert_eq!(merged.len(), 5);
+        assert_eq!(merged.bounds(), Some(Aabb::new(Point3::new(-6.0, -1.0, -1.0), Point3::new(8.0, 1.0, 1.0))));
+        assert_eq!(list(&[]).merge(list(&[3.0])).bounds(), list(&[3.0]).bounds());
+
+        let mut moved = list(&[0.0]);
+        moved.modify(|objects| objects[0] = Box::new(unit_sphere_at(Point3::new(0.0, 10.0, 0.0))));
+        assert_eq!(moved.bounds(), Some(Aabb::new(Point3::new(-1.0, 9.0, -1.0), Point3::new(1.0, 11.0, 1.0))));
+    }
+
+    #[test]
+    fn lists_can_be_iterated_and_indexed() {
+        let mut list = BoxedHitList::empty();
+        for x in [0.0, 3.0, 6.0] {
+            list.add(unit_sphere_at(Point3::new(x, 0.0, 0.0)));
+        }
+
+        let mut boxes = Vec::new();
+        for object in &list {
+            boxes.push(object.bounding_box(SHUTTER));
+        }
+        assert_eq!(boxes.len(), 3);
+
+        let middle = Aabb::new(Point3::new(2.0, -1.0, -1.0), Point3::new(4.0, 1.0, 1.0));
+        assert_eq!((boxes[1], list[1].bounding_box(SHUTTER)), (Some(middle), Some(middle)));
+        assert_eq!(list.into_iter().count(), 3);
+    }
+
+    /// A sphere that counts how often it's asked for a hit.
+    struct Counting(Sphere<Diffuse>, Arc<AtomicUsize>);
+
+    impl Hittable for Counting {
+        fn hit(&self, ray: &Ray, bounds: Range<Real>) -> Option<Hit<'_>> {
+            self.1.fetch_add(1, Ordering::Relaxed);
+            self.0.hit(ray, bounds)
+        }
+
+        fn bounding_box(&self, time: Range<Real>) -> Option<Aabb> {
+            self.0.bounding_box(time)
+        }
+    }
+
+    #[test]
+    fn lists_only_test_objects_the_ray_may_hit() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let mut list = BoxedHitList::empty();
+        list.add(Counting(unit_sphere(), calls.clone()));
+        let towards = |target: Point3| Ray::new(Point3::new(0.0, 0.0, 5.0), target - Point3::new(0.0, 0.0, 5.0));
+
+        // Off into the background, the object isn't even asked.
+        assert!(list.hit(&towards(Point3::new(10.0, 0.0, 0.0)), 0.001..Real::INFINITY).is_none());
+        assert!(!list.hit_any(&towards(Point3::new(10.0, 0.0, 0.0)), 0.001..Real::INFINITY));
+        assert_eq!(calls.load(Ordering::Relaxed), 0);
+
+        assert!(list.hit(&towards(Point3::zeros()), 0.001..Real::INFINITY).is_some());
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+
+        // Once moved, the object is found where it went and culled where it was.
+        list.modify(|objects| objects[0] = Box::new(Counting(unit_sphere_at(Point3::new(10.0, 0.0, 0.0)), calls.clone())));
+        assert!(list.hit(&towards(Point3::new(10.0, 0.0, 0.0)), 0.001..Real::INFINITY).is_some());
+        assert!(list.hit(&towards(Point3::zeros()), 0.001..Real::INFINITY).is_none());
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn transformed_sphere_is_an_ellipsoid() {
+        // Stretched to a radius of 2 along x, then turned so that it lies along y.
+        let ellipsoid = Transform::new(unit_sphere())
+            .scale(Vec3::new(2.0, 1.0, 1.0))
+            .rotate(Vec3::z(), 90.0)
+            .translate(Vec3::new(0.0, 0.0, -1.0));
+        let hit_from = |origin: Vec3, dir: Vec3| ellipsoid.hit(&Ray::new(origin, dir), 0.001..Real::INFINITY).unwrap();
+
+        let top = hit_from(Vec3::new(0.0, 5.0, -1.0), Vec3::new(0.0, -1.0, 0.0));
+        assert!((top.t - 3.0).abs() < 1e-4);
+        assert!(utils::approx_eq(&top.normal, &Vec3::y(), 1e-4));
+
+        let side = hit_from(Vec3::new(5.0, 0.0, -1.0), Vec3::new(-2.0, 0.0, 0.0));
+        assert!((side.t - 2.0).abs() < 1e-4);
+        assert!(utils::approx_eq(&side.normal, &Vec3::x(), 1e-4));
+
+        // Where x^2 + y^2 / 4 = 1 the normal goes along (x, y / 4).
+        let oblique = hit_from(Vec3::new(5.0, 1.0, -1.0), Vec3::new(-1.0, 0.0, 0.0));
+        let x = (0.75 as Real).sqrt();
+        assert!((oblique.t - (5.0 - x)).abs() < 1e-4);
+        assert!(utils::approx_eq(&oblique.point.0, &Vec3::new(x, 1.0, -1.0), 1e-4));
+        assert!((oblique.normal.magnitude() - 1.0).abs() < 1e-5);
+        assert!(utils::approx_eq(&oblique.normal, &Vec3::new(x, 0.25, 0.0).normalize(), 1e-4));
+
+        let bbox = ellipsoid.bounding_box(SHUTTER).unwrap();
+        assert!(utils::approx_eq(&bbox.min, &Vec3::new(-1.0, -2.0, -2.0), 1e-4));
+        assert!(utils::approx_eq(&bbox.max, &Vec3::new(1.0, 2.0, 0.0), 1e-4));
     }
 }
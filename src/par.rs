@@ -0,0 +1,102 @@
+//! Rayon's parallel iterators when the `parallel` feature is enabled. Without it, the same methods
+//! return plain sequential iterators, so the renderers don't need to be written twice.
+
+#[cfg(feature = "parallel")]
+pub use rayon::prelude::*;
+
+#[cfg(not(feature = "parallel"))]
+pub use sequential::*;
+
+#[cfg(not(feature = "parallel"))]
+mod sequential {
+    pub trait ParallelSliceMut<T> {
+        fn par_chunks_exact_mut(&mut self, chunk_size: usize) -> core::slice::ChunksExactMut<'_, T>;
+        fn par_iter_mut(&mut self) -> core::slice::IterMut<'_, T>;
+    }
+
+    impl<T> ParallelSliceMut<T> for [T] {
+        #[inline]
+        fn par_chunks_exact_mut(&mut self, chunk_size: usize) -> core::slice::ChunksExactMut<'_, T> {
+            self.chunks_exact_mut(chunk_size)
+        }
+
+        #[inline]
+        fn par_iter_mut(&mut self) -> core::slice::IterMut<'_, T> {
+            self.iter_mut()
+        }
+    }
+
+    pub trait ParallelBridge: Iterator + Sized {
+        #[inline]
+        fn par_bridge(self) -> Self {
+            self
+        }
+    }
+
+    impl<I: Iterator> ParallelBridge for I {}
+
+    pub trait IntoParallelIterator: IntoIterator + Sized {
+        #[inline]
+        fn into_par_iter(self) -> Self::IntoIter {
+            self.into_iter()
+        }
+    }
+
+    impl<I: IntoIterator> IntoParallelIterator for I {}
+}
+
+/// Run `a` and `b`, possibly in parallel, and return both results.
+#[cfg(feature = "parallel")]
+pub fn join<A: Send, B: Send>(a: impl FnOnce() -> A + Send, b: impl FnOnce() -> B + Send) -> (A, B) {
+    rayon::join(a, b)
+}
+
+#[cfg(not(feature = "parallel"))]
+pub fn join<A: Send, B: Send>(a: impl FnOnce() -> A + Send, b: impl FnOnce() -> B + Send) -> (A, B) {
+    (a(), b())
+}
+
+/// Run `op` in a dedicated pool of `threads` threads, or in rayon's global pool when `None`.
+#[cfg(feature = "parallel")]
+pub fn in_thread_pool<R: Send>(threads: Option<usize>, op: impl FnOnce() -> R + Send) -> R {
+    match threads {
+        Some(threads) => rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .expect("failed to build the render thread pool")
+            .install(op),
+        None => op(),
+    }
+}
+
+/// Without the `parallel` feature everything runs on the calling thread.
+#[cfg(not(feature = "parallel"))]
+pub fn in_thread_pool<R: Send>(_threads: Option<usize>, op: impl FnOnce() -> R + Send) -> R {
+    op()
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    // Run with and without the `parallel` feature, these check that the fallbacks do the same work.
+    #[test]
+    fn parallel_helpers_visit_every_item() {
+        let mut values: Vec<u32> = (0..100).collect();
+        values.par_iter_mut().for_each(|value| *value *= 2);
+        values.par_chunks_exact_mut(10).for_each(|chunk| chunk[0] = 1);
+        assert_eq!(values.iter().filter(|&&value| value == 1).count(), 10);
+        assert_eq!(values[11], 22);
+
+        let sum: u32 = in_thread_pool(Some(2), || (0..10u32).into_par_iter().sum());
+        assert_eq!(sum, 45);
+
+        let count = std::sync::atomic::AtomicUsize::new(0);
+        (0..7).par_bridge().for_each(|_| {
+            count.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        });
+        assert_eq!(count.into_inner(), 7);
+
+        assert_eq!(join(|| 1, || "two"), (1, "two"));
+    }
+}
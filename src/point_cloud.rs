@@ -0,0 +1,344 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{ self, BufRead, BufReader };
+use std::ops::Range;
+use std::path::Path;
+
+use crate::aabb::Aabb;
+use crate::hittable::{ Hittable, Hit, SceneStats };
+use crate::kdtree::KdTree;
+use crate::material::Diffuse;
+use crate::objects::Sphere;
+use crate::ray::Ray;
+use crate::utils::{ color, Color, Point3, Real };
+
+/// Points drawn as small diffuse spheres of their own color, for visualizing data. The spheres are
+/// kept in a `KdTree`, so it takes millions of points.
+pub struct PointCloud {
+    spheres: KdTree<Sphere<Diffuse>>,
+}
+
+impl PointCloud {
+    /// # Panics
+    ///
+    /// If `radius` is 0.
+    pub fn new(points: impl IntoIterator<Item = (Point3, Color)>, radius: Real) -> PointCloud {
+        let spheres = points.into_iter()
+            .map(|(point, color)| Sphere::new(point, radius, Diffuse::new(color)))
+            .collect();
+        PointCloud { spheres: KdTree::new(spheres) }
+    }
+
+    /// Load the points of a `.ply` or `.csv` file, picked by the extension, see `read_ply` and
+    /// `read_csv`.
+    pub fn open(path: impl AsRef<Path>, radius: Real) -> Result<PointCloud, PointCloudError> {
+        let path = path.as_ref();
+        let reader = BufReader::new(File::open(path)?);
+        let points = match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("ply") => read_ply(reader)?,
+            Some(ext) if ext.eq_ignore_ascii_case("csv") => read_csv(reader)?,
+            _ => return Err(PointCloudError::UnknownFormat),
+        };
+        Ok(PointCloud::new(points, radius))
+    }
+}
+
+impl Hittable for PointCloud {
+    #[inline]
+    fn hit(&self, ray: &Ray, bounds: Range<Real>) -> Option<Hit<'_>> {
+        self.spheres.hit(ray, bounds)
+    }
+
+    #[inline]
+    fn hit_any(&self, ray: &Ray, bounds: Range<Real>) -> bool {
+        self.spheres.hit_any(ray, bounds)
+    }
+
+    fn bounding_box(&self, time: Range<Real>) -> Option<Aabb> {
+        self.spheres.bounding_box(time)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.spheres.is_empty()
+    }
+
+    fn scene_stats(&self) -> SceneStats {
+        self.spheres.scene_stats()
+    }
+}
+
+/// Points of a CSV file with `x,y,z` or `x,y,z,r,g,b` on each line, with the color in `[0, 1]`.
+/// Points without a color are gray. Blank lines and a header line are skipped.
+pub fn read_csv(reader: impl BufRead) -> Result<Vec<(Point3, Color)>, PointCloudError> {
+    let mut points = Vec::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        let values: Result<Vec<Real>, _> = fields.iter().map(|field| field.parse()).collect();
+        let values = match values {
+            Ok(values) => values,
+            Err(_) if i == 0 => continue,
+            Err(_) => return Err(PointCloudError::parse(i, format!("expected numbers, got {:?}", line))),
+        };
+
+        let point = match values[..] {
+            [x, y, z]          => (Point3::new(x, y, z), color::mid_gray()),
+            [x, y, z, r, g, b] => (Point3::new(x, y, z), color::new(r, g, b)),
+            _ => return Err(PointCloudError::parse(i, format!("expected 3 or 6 values, got {}", values.len()))),
+        };
+        points.push(point);
+    }
+    Ok(points)
+}
+
+/// Points of the `vertex` element of an ASCII PLY file, with the colors of their `red`, `green`
+/// and `blue` properties if there are any. Colors stored as bytes are scaled to `[0, 1]`, points
+/// without a color are gray. Binary PLY files aren't supported.
+pub fn read_ply(reader: impl BufRead) -> Result<Vec<(Point3, Color)>, PointCloudError> {
+    let mut lines = reader.lines().enumerate();
+    let mut next_line = || -> Result<(usize, String), PointCloudError> {
+        match lines.next() {
+            Some((i, line)) => Ok((i, line?)),
+            None => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+        }
+    };
+
+    if next_line()?.1.trim() != "ply" {
+        return Err(PointCloudError::parse(0, "not a PLY file"));
+    }
+
+    // Elements in the order they come in the body, with their count and properties.
+    let mut elements: Vec<(String, usize, Vec<PlyProperty>)> = Vec::new();
+    loop {
+        let (i, line) = next_line()?;
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words[..] {
+            ["end_header"] => break,
+            ["format", "ascii", _] | ["comment", ..] | ["obj_info", ..] | [] => (),
+            ["format", format, _] => {
+                return Err(PointCloudError::parse(i, format!("unsupported PLY format {:?}", format)));
+            }
+            ["element", name, count] => {
+                let count = count.parse()
+                    .map_err(|_| PointCloudError::parse(i, format!("bad element count {:?}", count)))?;
+                elements.push((name.to_owned(), count, Vec::new()));
+            }
+            ["property", "list", ..] => match elements.last_mut() {
+                Some((_, _, properties)) => properties.push(PlyProperty { name: String::new(), is_byte: false }),
+                None => return Err(PointCloudError::parse(i, "property before any element")),
+            },
+            ["property", kind, name] => match elements.last_mut() {
+                Some((_, _, properties)) => properties.push(PlyProperty {
+                    name: name.to_owned(),
+                    is_byte: matches!(kind, "uchar" | "uint8" | "char" | "int8"),
+                }),
+                None => return Err(PointCloudError::parse(i, "property before any element")),
+            },
+            _ => return Err(PointCloudError::parse(i, format!("unexpected header line {:?}", line))),
+        }
+    }
+
+    let mut points = Vec::new();
+    for (name, count, properties) in &elements {
+        if name != "vertex" {
+            for _ in 0..*count {
+                next_line()?;
+            }
+            continue;
+        }
+
+        let find = |name: &str| properties.iter().position(|property| property.name == name);
+        let (x, y, z) = match (find("x"), find("y"), find("z")) {
+            (Some(x), Some(y), Some(z)) => (x, y, z),
+            _ => return Err(PointCloudError::parse(0, "vertices have no position")),
+        };
+        let rgb = find("red").zip(find("green")).zip(find("blue")).map(|((r, g), b)| [r, g, b]);
+
+        points.reserve(*count);
+        for _ in 0..*count {
+            let (i, line) = next_line()?;
+            let values: Vec<Real> = line.split_whitespace()
+                .map(|value| value.parse())
+                .collect::<Result<_, _>>()
+                .map_err(|_| PointCloudError::parse(i, format!("expected numbers, got {:?}", line)))?;
+            if values.len() != properties.len() {
+                return Err(PointCloudError::parse(i, format!("expected {} values, got {}", properties.len(), values.len())));
+            }
+
+            let point = Point3::new(values[x], values[y], values[z]);
+            let color = match rgb {
+                Some(channels) => {
+                    let channel = |c: usize| {
+                        let scale = if properties[c].is_byte { 255.0 } else { 1.0 };
+                        values[c] / scale
+                    };
+                    color::new(channel(channels[0]), channel(channels[1]), channel(channels[2]))
+                }
+                None => color::mid_gray(),
+            };
+            points.push((point, color));
+        }
+    }
+    Ok(points)
+}
+
+struct PlyProperty {
+    /// Empty for lists, which aren't used.
+    name: String,
+    is_byte: bool,
+}
+
+/// Why a point cloud couldn't be loaded.
+#[derive(Debug)]
+pub enum PointCloudError {
+    Io(io::Error),
+    /// The file is malformed, at the line with this index.
+    Parse { line: usize, message: String },
+    /// The extension of the file is neither `.ply` nor `.csv`.
+    UnknownFormat,
+}
+
+impl PointCloudError {
+    fn parse(line: usize, message: impl Into<String>) -> PointCloudError {
+        PointCloudError::Parse { line, message: message.into() }
+    }
+}
+
+impl From<io::Error> for PointCloudError {
+    fn from(err: io::Error) -> PointCloudError {
+        PointCloudError::Io(err)
+    }
+}
+
+impl fmt::Display for PointCloudError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PointCloudError::Io(err) => write!(f, "{}", err),
+            PointCloudError::Parse { line, message } => write!(f, "line {}: {}", line + 1, message),
+            PointCloudError::UnknownFormat => write!(f, "point clouds must be .ply or .csv files"),
+        }
+    }
+}
+
+impl std::error::Error for PointCloudError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hittable;
+    use crate::utils::{ self, Vec3 };
+
+    const PLY: &str = "\
+ply
+format ascii 1.0
+comment five points on the x axis
+element vertex 5
+property float x
+property float y
+property float z
+property uchar red
+property uchar green
+property uchar blue
+element face 0
+property list uchar int vertex_indices
+end_header
+0 0 0 255 0 0
+2 0 0 0 255 0
+4 0 0 0 0 255
+6 0 0 255 255 255
+8 0 0 0 0 0
+";
+
+    #[test]
+    fn loaded_points_are_spheres_of_their_color() {
+        let points = read_ply(PLY.as_bytes()).unwrap();
+        assert_eq!(points.len(), 5);
+        let cloud = PointCloud::new(points.iter().copied(), 0.25);
+
+        for (point, color) in points {
+            // Straight down onto the top of the sphere.
+            let ray = Ray::new(point + Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, -1.0, 0.0));
+            let hit = cloud.hit(&ray, 0.001..Real::INFINITY).unwrap();
+            assert!(utils::approx_eq(&hit.point.0, &(point + Vec3::new(0.0, 0.25, 0.0)), 1e-5));
+
+            let scatter = hit.scatter(&ray).unwrap();
+            assert!(utils::approx_eq(&scatter.attenuation, &color, 1e-5));
+        }
+
+        // Nothing in between the points.
+        let ray = Ray::new(Point3::new(1.0, 1.0, 0.0), Vec3::new(0.0, -1.0, 0.0));
+        assert!(cloud.hit(&ray, 0.001..Real::INFINITY).is_none());
+    }
+
+    #[test]
+    fn clouds_open_by_extension() {
+        let dir = std::env::temp_dir();
+        let file = |name: &str, contents: &str| {
+            let path = dir.join(format!("raytracing-cloud-{}-{}", std::process::id(), name));
+            std::fs::write(&path, contents).unwrap();
+            path
+        };
+        let hits_at = |cloud: &PointCloud, point: Point3| {
+            let ray = Ray::new(point + Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, -1.0, 0.0));
+            cloud.hit(&ray, 0.001..Real::INFINITY).is_some()
+        };
+
+        // Extensions are matched regardless of case.
+        let ply = file("points.PLY", PLY);
+        let csv = file("points.csv", "x,y,z\n0,0,0\n0,0,5\n");
+        let txt = file("points.txt", "0,0,0\n");
+        let from_ply = PointCloud::open(&ply, 0.25);
+        let from_csv = PointCloud::open(&csv, 0.25);
+        let from_txt = PointCloud::open(&txt, 0.25);
+        for path in [&ply, &csv, &txt] {
+            std::fs::remove_file(path).unwrap();
+        }
+
+        let from_ply = from_ply.unwrap();
+        assert_eq!(from_ply.scene_stats().objects, 5);
+        assert!(hits_at(&from_ply, Point3::new(8.0, 0.0, 0.0)));
+
+        let from_csv = from_csv.unwrap();
+        assert_eq!(from_csv.scene_stats().objects, 2);
+        assert!(hits_at(&from_csv, Point3::new(0.0, 0.0, 5.0)));
+        assert!(!hits_at(&from_csv, Point3::new(2.0, 0.0, 0.0)));
+
+        assert!(matches!(from_txt, Err(PointCloudError::UnknownFormat)));
+        assert!(matches!(PointCloud::open(&ply, 0.25), Err(PointCloudError::Io(_))));
+    }
+
+    #[test]
+    fn shadow_rays_only_test_nearby_points() {
+        let points = (0..8000).map(|i| {
+            let point = Point3::new((i % 20) as Real, (i / 20 % 20) as Real, (i / 400) as Real);
+            (point, color::mid_gray())
+        });
+        let cloud = PointCloud::new(points, 0.25);
+
+        // Towards the point in the corner of the grid.
+        let ray = Ray::new(Point3::new(-1.0, -1.0, -1.0), Vec3::new(1.0, 1.0, 1.0));
+        hittable::start_traversal_counts();
+        assert!(cloud.hit_any(&ray, 0.001..Real::INFINITY));
+        let counts = hittable::finish_traversal_counts();
+        assert!(counts.primitive_tests < 100, "{:?}", counts);
+    }
+
+    #[test]
+    fn csv_points_have_optional_colors() {
+        let csv = "x,y,z,r,g,b\n0,0,0,1,0.5,0\n\n1,2,3,0,0,1\n";
+        let points = read_csv(csv.as_bytes()).unwrap();
+        assert_eq!(points, [
+            (Point3::new(0.0, 0.0, 0.0), color::new(1.0, 0.5, 0.0)),
+            (Point3::new(1.0, 2.0, 3.0), color::new(0.0, 0.0, 1.0)),
+        ]);
+
+        let points = read_csv("1,2,3".as_bytes()).unwrap();
+        assert_eq!(points, [(Point3::new(1.0, 2.0, 3.0), color::mid_gray())]);
+
+        assert!(matches!(read_csv("1,2\n".as_bytes()), Err(PointCloudError::Parse { line: 0, .. })));
+    }
+}
@@ -1,61 +1,845 @@
-use rand::{ thread_rng, Rng };
+use alloc::sync::Arc;
 
-use crate::utils::{ self, Color, Vec3, Point3, color };
+use rand::Rng;
+
+use crate::utils::{ self, Color, Vec3, Point3, Position, Direction, color, Real };
 use crate::hittable::{ Hittable, Hit };
+use crate::texture::ImageTexture;
+use crate::environment::EnvironmentLight;
+use crate::light::{ PointLight, SpotLight };
+use crate::material::{ self, Scatter, ScatterEvent };
+use crate::prelude::*;
 
 #[derive(Debug, Clone, Copy)]
 pub struct Ray {
-    pub dir: Vec3,
-    pub origin: Point3,
+    pub dir: Direction,
+    pub origin: Position,
+    pub payload: RayPayload,
+}
+
+/// Everything `Ray::trace` found along a path.
+#[derive(Debug, Clone, Copy)]
+pub struct PathResult {
+    pub color: Color,
+    /// Number of times the path bounced off of a surface.
+    pub bounces: usize,
+    /// The first surface the ray hit, `None` when it went straight to the background.
+    pub first_hit: Option<FirstHit>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FirstHit {
+    pub point: Position,
+    /// Facing the ray, like `Hit::normal`.
+    pub normal: Direction,
+    /// Distance from the origin of the ray.
+    pub distance: Real,
+    /// Attenuation of the bounce off of the surface, black when it absorbed the ray.
+    pub albedo: Color,
+}
+
+impl FirstHit {
+    fn new(ray: &Ray, hit: &Hit, scatter: Option<&Scatter>) -> FirstHit {
+        FirstHit {
+            point: hit.point,
+            normal: hit.normal,
+            distance: hit.t * ray.dir.magnitude(),
+            albedo: scatter.map_or_else(color::black, |s| s.attenuation),
+        }
+    }
+}
+
+/// Everything about tracing a path other than the world it goes through.
+#[derive(Debug, Clone, Copy)]
+pub struct TraceConfig<'a> {
+    pub max_depth: usize,
+    pub background: &'a Background,
+    pub lights: &'a [SpotLight],
+    /// Camera rays ignore surfaces further than this distance, like past a far clipping plane.
+    /// Bounced and shadow rays still see everything.
+    pub max_t: Real,
+    /// Color of paths whose rays degenerated, e.g. to a zero or NaN direction.
+    pub error_color: Color,
+}
+
+/// State carried from bounce to bounce along a path, as opposed to the geometry of each ray.
+#[derive(Debug, Clone, Copy)]
+pub struct RayPayload {
+    /// When the ray was shot.
+    pub time: Real,
+    /// The dielectrics the ray is currently inside of.
+    pub medium: MediumStack,
+    /// How much of the light found along the ray makes it back to the camera.
+    pub throughput: Color,
+}
+
+impl RayPayload {
+    /// Payload of the ray scattered by `scatter`, with the same time.
+    pub fn scattered(&self, scatter: &Scatter) -> RayPayload {
+        RayPayload {
+            medium: self.medium.apply(scatter.transition),
+            throughput: self.throughput.component_mul(&scatter.attenuation),
+            ..*self
+        }
+    }
+}
+
+impl Default for RayPayload {
+    fn default() -> RayPayload {
+        RayPayload { time: 0.0, medium: MediumStack::default(), throughput: color::white() }
+    }
 }
 
 impl Ray {
-    /// Create a new ray.
-    pub fn new(origin: Point3, dir: Vec3) -> Ray {
-        Ray { dir, origin }
+    /// Create a new ray, travelling through vacuum.
+    pub fn new(origin: impl Into<Position>, dir: impl Into<Direction>) -> Ray {
+        Ray { dir: dir.into(), origin: origin.into(), payload: RayPayload::default() }
+    }
+
+    pub fn with_payload(self, payload: RayPayload) -> Ray {
+        Ray { payload, ..self }
+    }
+
+    /// Value of `t` at which the ray has travelled `distance`, since `dir` isn't always a unit
+    /// vector.
+    #[inline]
+    pub fn t_at_distance(&self, distance: Real) -> Real {
+        distance / self.dir.magnitude()
+    }
+
+    /// Upper bound of `t` for this ray after `bounces` bounces along its path. `max_t` is a
+    /// distance from the camera, so it only clips camera rays.
+    #[inline]
+    fn far_t(&self, max_t: Real, bounces: usize) -> Real {
+        if bounces == 0 { self.t_at_distance(max_t) } else { Real::INFINITY }
+    }
+
+    /// Same ray, but shot at `time`.
+    pub fn with_time(self, time: Real) -> Ray {
+        Ray { payload: RayPayload { time, ..self.payload }, ..self }
+    }
+
+    /// Same ray, but travelling through `medium`.
+    pub fn with_medium(self, medium: MediumStack) -> Ray {
+        Ray { payload: RayPayload { medium, ..self.payload }, ..self }
     }
 
     /// Get a reference to the ray's dir.
-    pub fn dir(&self) -> &Vec3 {
+    pub fn dir(&self) -> &Direction {
         &self.dir
     }
 
     /// Get a reference to the ray's origin.
-    pub fn origin(&self) -> &Point3 {
+    pub fn origin(&self) -> &Position {
         &self.origin
     }
 
-    pub fn at(&self, t: f32) -> Point3 {
+    pub fn at(&self, t: Real) -> Position {
         self.origin + self.dir * t
     }
 
-    pub fn compute_color(&self, world: impl Hittable, max_depth: usize) -> Color {
+    /// Whether the ray has no usable direction, which happens after a bad scatter.
+    pub fn is_degenerate(&self) -> bool {
+        !self.dir.iter().chain(self.origin.0.iter()).all(|c| c.is_finite())
+            || self.dir.magnitude_squared() == 0.0
+    }
+
+    pub fn compute_color(
+        &self,
+        world: impl Hittable,
+        max_depth: usize,
+        background: &Background,
+    ) -> Color {
+        self.compute_color_full(world, max_depth, background).color
+    }
+
+    /// Same as `compute_color`, but also with what the ray hit first and how many times it
+    /// bounced.
+    pub fn compute_color_full(
+        &self,
+        world: impl Hittable,
+        max_depth: usize,
+        background: &Background,
+    ) -> PathResult {
+        let config = TraceConfig {
+            max_depth,
+            background,
+            lights: &[],
+            max_t: Real::INFINITY,
+            error_color: color::magenta(),
+        };
+        self.trace(world, &config)
+    }
+
+    /// Trace the path of the ray through the world. Paths that degenerate along the way are
+    /// `config.error_color`, so they stand out.
+    ///
+    /// With a `Background::Light`, surfaces that `Material::eval` supports also sample the
+    /// environment directly, and the two ways of reaching it are combined with multiple importance
+    /// sampling. Those surfaces are always lit by the spot lights directly, since they can't be
+    /// reached any other way.
+    pub fn trace(&self, world: impl Hittable, config: &TraceConfig) -> PathResult {
+        let TraceConfig { max_depth, background, lights, max_t, error_color } = *config;
+        let light = background.light();
+
         let mut ray = *self;
-        let mut color = Color::new(1., 1., 1.);
+        let mut radiance = color::black();
+        // Density over solid angle of the last bounce, when it was diffuse and the environment
+        // was also sampled directly from there.
+        let mut bounce_pdf = None;
+        let mut bounces = 0;
+        let mut first_hit = None;
         for _ in 0..max_depth {
-            match world.hit(&ray, 0.001..f32::INFINITY) {
+            if ray.is_degenerate() {
+                return PathResult { color: error_color, bounces, first_hit };
+            }
+
+            let hit = match world.hit(&ray, 0.001..ray.far_t(max_t, bounces)) {
+                Some(hit) => hit,
                 None => {
-                    color.component_mul_assign(&self.bg_color());
-                    break;
+                    let weight = match (light, bounce_pdf) {
+                        (Some(light), Some(pdf)) => power_heuristic(pdf, light.pdf(&ray.dir)),
+                        _                        => 1.0,
+                    };
+                    radiance += ray.payload.throughput.component_mul(&background.color(&ray)) * weight;
+                    return PathResult { color: radiance, bounces, first_hit };
                 }
+            };
+            let s = hit.scatter(&ray);
+            first_hit.get_or_insert_with(|| FirstHit::new(&ray, &hit, s.as_ref()));
 
-                Some(Hit { scatter: None, .. }) => {
-                    color.component_mul_assign(&color::black());
-                    break;
+            let s = match s {
+                Some(s) => s,
+                None    => return PathResult { color: radiance, bounces, first_hit },
+            };
+
+            // Direct light is weighed by the surface towards the light, not by the attenuation of
+            // the bounce, which is for `s.scattered`.
+            let direct = spot_lights(&world, lights, &ray, &hit);
+            radiance += ray.payload.throughput.component_mul(&direct);
+
+            bounce_pdf = None;
+            if let Some(light) = light {
+                let direct = sample_light(&world, light, &ray, &hit);
+                radiance += ray.payload.throughput.component_mul(&direct);
+                // Mirror like bounces can't be found by sampling the light, and count in full.
+                if s.event == ScatterEvent::Diffuse {
+                    bounce_pdf = hit.eval(&ray, &s.scattered).map(|eval| eval.pdf);
                 }
+            }
 
-                Some(Hit { scatter: Some(s), point, .. }) => {
-                    ray = Ray::new(point, s.scattered);
-                    color.component_mul_assign(&s.attenuation);
+            let payload = ray.payload.scattered(&s);
+
+            ray = Ray::new(hit.point, s.scattered).with_payload(payload);
+            bounces += 1;
+        }
+        // Like before, paths that run out of bounces keep their throughput.
+        PathResult { color: radiance + ray.payload.throughput, bounces, first_hit }
+    }
+
+    /// Same as `trace`, but wherever a surface both reflects and refracts it follows both rays,
+    /// weighted by the reflectance, instead of picking one at random. Surfaces stop splitting once
+    /// `max_rays` rays were traced, and the paths go on like in `trace`.
+    pub fn trace_split(&self, world: impl Hittable, config: &TraceConfig, max_rays: usize) -> PathResult {
+        let TraceConfig { max_depth, background, lights, max_t, error_color } = *config;
+
+        // Rays still to be traced, with their depth.
+        let mut stack = vec![(*self, 0)];
+        let mut color = color::black();
+        let mut rays = 1;
+        let mut bounces = 0;
+        let mut first_hit = None;
+
+        while let Some((ray, depth)) = stack.pop() {
+            // Like in `trace`, a path that runs out of bounces keeps its throughput.
+            if depth == max_depth {
+                color += ray.payload.throughput;
+                continue;
+            }
+
+            if ray.is_degenerate() {
+                return PathResult { color: error_color, bounces, first_hit };
+            }
+
+            let hit = world.hit(&ray, 0.001..ray.far_t(max_t, depth))
+                .map(|hit| (hit, hit.scatter(&ray)));
+            if let (Some((hit, s)), 0) = (&hit, depth) {
+                first_hit = Some(FirstHit::new(&ray, hit, s.as_ref()));
+            }
+
+            match hit {
+                None => {
+                    color += ray.payload.throughput.component_mul(&background.color(&ray));
+                }
+
+                Some((_, None)) => (),
+
+                Some((hit, Some(s))) => {
+                    let point = hit.point;
+                    match s.split {
+                        Some(split) if rays + 2 <= max_rays => {
+                            let throughput = ray.payload.throughput.component_mul(&s.attenuation);
+
+                            let reflected = RayPayload {
+                                throughput: throughput * split.reflectance,
+                                ..ray.payload
+                            };
+                            let refracted = RayPayload {
+                                medium: ray.payload.medium.apply(Some(split.transition)),
+                                throughput: throughput * (1.0 - split.reflectance),
+                                ..ray.payload
+                            };
+
+                            stack.push((Ray::new(point, split.reflected).with_payload(reflected), depth + 1));
+                            stack.push((Ray::new(point, split.refracted).with_payload(refracted), depth + 1));
+                            rays += 2;
+                            bounces += 2;
+                        }
+
+                        _ => {
+                            let direct = spot_lights(&world, lights, &ray, &hit);
+                            color += ray.payload.throughput.component_mul(&direct);
+                            let payload = ray.payload.scattered(&s);
+
+                            stack.push((Ray::new(point, s.scattered).with_payload(payload), depth + 1));
+                            rays += 1;
+                            bounces += 1;
+                        }
+                    }
+                }
+            }
+        }
+        PathResult { color, bounces, first_hit }
+    }
+
+    /// Quick and noise free approximation of `trace`, for previews. Diffuse surfaces are only lit
+    /// directly by the point `lights`, plus a little of the background as ambient light, and
+    /// shiny ones get a highlight for each light and mirror the rest of the scene. Glass lets rays
+    /// straight through, without highlights. Materials scatter with `Material::preview`, so no
+    /// random numbers are drawn. Spot lights and environment sampling are ignored.
+    pub fn trace_preview(&self, world: impl Hittable, config: &TraceConfig, lights: &[PointLight]) -> PathResult {
+        // Fraction of the background, seen along the normal, lighting diffuse surfaces.
+        const AMBIENT: Real = 0.1;
+        const SHININESS: i32 = 32;
+
+        let TraceConfig { max_depth, background, max_t, error_color, .. } = *config;
+
+        let mut ray = *self;
+        let mut radiance = color::black();
+        let mut bounces = 0;
+        let mut first_hit = None;
+        for _ in 0..max_depth {
+            if ray.is_degenerate() {
+                return PathResult { color: error_color, bounces, first_hit };
+            }
+
+            let hit = match world.hit(&ray, 0.001..ray.far_t(max_t, bounces)) {
+                Some(hit) => hit,
+                None => {
+                    radiance += ray.payload.throughput.component_mul(&background.color(&ray));
+                    return PathResult { color: radiance, bounces, first_hit };
+                }
+            };
+            let s = hit.preview(&ray);
+            first_hit.get_or_insert_with(|| FirstHit::new(&ray, &hit, s.as_ref()));
+
+            let s = match s {
+                Some(s) => s,
+                None    => return PathResult { color: radiance, bounces, first_hit },
+            };
+            let throughput = ray.payload.throughput.component_mul(&s.attenuation);
+
+            let mut direct = color::black();
+            let lit = if s.event == ScatterEvent::Transmitted { &[][..] } else { lights };
+            for light in lit {
+                let to_light = light.position - hit.point.0;
+                let dir = to_light.normalize();
+                let cos_theta = dir.dot(&hit.normal);
+                // Like with spot lights, the shadow ray reaches the light at `t = 1`.
+                let shadow_ray = Ray::new(hit.point, to_light).with_time(ray.payload.time);
+                if cos_theta <= 0.0 || world.hit_any(&shadow_ray, 0.001..1.0) {
+                    continue;
                 }
+
+                let shading = match s.event {
+                    ScatterEvent::Diffuse => cos_theta / utils::consts::PI,
+                    _                     => s.scattered.normalize().dot(&dir).max(0.0).powi(SHININESS),
+                };
+                direct += light.color * (light.intensity * shading / to_light.magnitude_squared());
             }
+            radiance += throughput.component_mul(&direct);
+
+            if s.event == ScatterEvent::Diffuse {
+                let ambient = background.color(&Ray::new(hit.point, hit.normal)) * AMBIENT;
+                radiance += throughput.component_mul(&ambient);
+                return PathResult { color: radiance, bounces: bounces + 1, first_hit };
+            }
+
+            ray = Ray::new(hit.point, s.scattered).with_payload(ray.payload.scattered(&s));
+            bounces += 1;
+        }
+        PathResult { color: radiance, bounces, first_hit }
+    }
+
+    /// Ambient occlusion: shade the first surface the ray hits by the fraction of `samples` rays,
+    /// spread around its normal like diffuse bounces, that get further than `radius` from it
+    /// without hitting anything. Materials are ignored, and rays that hit nothing are white.
+    pub fn trace_ao(&self, world: impl Hittable, config: &TraceConfig, samples: usize, radius: Real) -> PathResult {
+        if self.is_degenerate() {
+            return PathResult { color: config.error_color, bounces: 0, first_hit: None };
+        }
+
+        let hit = match world.hit(self, 0.001..self.t_at_distance(config.max_t)) {
+            Some(hit) => hit,
+            None      => return PathResult { color: color::white(), bounces: 0, first_hit: None },
+        };
+
+        let escaped = (0..samples)
+            .filter(|_| {
+                let mut dir = hit.normal.0 + utils::random_unit();
+                if dir.magnitude_squared() < 1e-8 {
+                    dir = hit.normal.0;
+                }
+                // A unit direction, so `t` is the distance.
+                let ray = Ray::new(hit.point, dir.normalize()).with_time(self.payload.time);
+                !world.hit_any(&ray, 0.001..radius)
+            })
+            .count();
+
+        let visibility = if samples == 0 { 1.0 } else { escaped as Real / samples as Real };
+        PathResult {
+            color: color::white() * visibility,
+            bounces: 1,
+            // Materials are ignored, as if everything was white.
+            first_hit: Some(FirstHit { albedo: color::white(), ..FirstHit::new(self, &hit, None) }),
         }
-        color
     }
 
     pub fn bg_color(&self) -> Color {
+        self.gradient_color(&Vec3::y())
+    }
+
+    /// Sky gradient going from white when looking against `axis` to light blue along it. A zero
+    /// `axis` has no direction, so the gradient goes along y like in `bg_color`.
+    pub fn gradient_color(&self, axis: &Vec3) -> Color {
         let dir = self.dir.normalize();
-        let t = dir.y / 2.0 + 0.5;
+        let axis = axis.try_normalize(0.0).unwrap_or_else(Vec3::y);
+        let t = dir.dot(&axis) / 2.0 + 0.5;
         color::lerp(nalgebra_glm::vec3(1.0, 1.0, 1.0), nalgebra_glm::vec3(0.5, 0.7, 1.0), t)
     }
 }
+
+/// Light reflected along `ray` by the surface it hit, coming straight from the environment and
+/// weighted against finding it by bouncing off of the surface. Black for surfaces without an
+/// `eval`.
+fn sample_light(world: impl Hittable, light: &EnvironmentLight, ray: &Ray, hit: &Hit) -> Color {
+    let (dir, light_pdf) = light.sample(&mut utils::LocalRng);
+    let eval = match hit.eval(ray, &dir) {
+        Some(eval) => eval,
+        None       => return color::black(),
+    };
+    let shadow_ray = Ray::new(hit.point, dir).with_time(ray.payload.time);
+    if light_pdf <= 0.0 || dir.dot(&hit.normal) <= 0.0 || world.hit_any(&shadow_ray, 0.001..Real::INFINITY) {
+        return color::black();
+    }
+
+    light.radiance(&dir).component_mul(&eval.value) * (power_heuristic(light_pdf, eval.pdf) / light_pdf)
+}
+
+/// Light reflected along `ray` by the surface it hit, coming straight from the spot lights. Black
+/// for surfaces without an `eval`, like in `sample_light`.
+fn spot_lights(world: impl Hittable, lights: &[SpotLight], ray: &Ray, hit: &Hit) -> Color {
+    let mut direct = color::black();
+    for light in lights {
+        let to_light = light.position - hit.point.0;
+        let eval = match hit.eval(ray, &to_light) {
+            Some(eval) => eval,
+            None       => return color::black(),
+        };
+        // The shadow ray reaches the light at `t = 1`.
+        let shadow_ray = Ray::new(hit.point, to_light).with_time(ray.payload.time);
+        if to_light.dot(&hit.normal) <= 0.0 || world.hit_any(&shadow_ray, 0.001..1.0) {
+            continue;
+        }
+
+        // The light falls off with the square of the distance.
+        let falloff = 1.0 / to_light.magnitude_squared();
+        direct += light.intensity_towards(&hit.point.0).component_mul(&eval.value) * falloff;
+    }
+    direct
+}
+
+/// Weight of a sample drawn with density `pdf` over one drawn with density `other_pdf`.
+fn power_heuristic(pdf: Real, other_pdf: Real) -> Real {
+    let (pdf, other_pdf) = (pdf * pdf, other_pdf * other_pdf);
+    if pdf + other_pdf == 0.0 { 0.0 } else { pdf / (pdf + other_pdf) }
+}
+
+/// A transparent medium a ray can be inside of.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Medium {
+    pub ior: Real,
+    /// Where media overlap, the one with the highest priority fills the overlap, and the
+    /// surfaces of the others are ignored inside of it.
+    pub priority: u32,
+}
+
+/// How a scattered ray moves between media.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MediumTransition {
+    /// The ray went into the medium.
+    Enter(Medium),
+    /// The ray left the medium.
+    Exit(Medium),
+}
+
+const MAX_NESTED_MEDIA: usize = 4;
+
+/// The (possibly overlapping) media a ray is inside of, in the order they were entered. Nesting
+/// deeper than `MAX_NESTED_MEDIA` levels is ignored.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MediumStack {
+    media: [Option<Medium>; MAX_NESTED_MEDIA],
+    len: usize,
+}
+
+impl MediumStack {
+    /// Index of refraction of the medium the ray is in, which is the one with the highest
+    /// priority, or the last one entered among those. Vacuum when outside of everything.
+    pub fn ior(&self) -> Real {
+        self.top().map_or(1.0, |medium| medium.ior)
+    }
+
+    /// Whether the ray is inside of a medium other than `medium` with a higher priority, so that
+    /// the surfaces of `medium` should be ignored.
+    pub fn overrides(&self, medium: Medium) -> bool {
+        self.without(medium).top().is_some_and(|top| top.priority > medium.priority)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn top(&self) -> Option<Medium> {
+        self.media[..self.len].iter()
+            .flatten()
+            .copied()
+            .max_by_key(|medium| medium.priority)
+    }
+
+    pub fn push(&mut self, medium: Medium) {
+        if self.len < MAX_NESTED_MEDIA {
+            self.media[self.len] = Some(medium);
+            self.len += 1;
+        }
+    }
+
+    /// Remove the innermost instance of `medium`. Objects may overlap, so it isn't necessarily
+    /// the last one entered.
+    pub fn remove(&mut self, medium: Medium) {
+        if let Some(i) = self.media[..self.len].iter().rposition(|&other| other == Some(medium)) {
+            self.media.copy_within(i + 1..self.len, i);
+            self.len -= 1;
+            self.media[self.len] = None;
+        }
+    }
+
+    /// The media after leaving `medium`.
+    pub fn without(mut self, medium: Medium) -> MediumStack {
+        self.remove(medium);
+        self
+    }
+
+    pub fn apply(mut self, transition: Option<MediumTransition>) -> MediumStack {
+        match transition {
+            Some(MediumTransition::Enter(medium)) => self.push(medium),
+            Some(MediumTransition::Exit(medium))  => self.remove(medium),
+            None => (),
+        }
+        self
+    }
+}
+
+/// What a ray sees when it doesn't hit anything.
+#[derive(Debug, Clone)]
+pub enum Background {
+    /// The sky gradient from white at the bottom to light blue at the top, with `axis` pointing
+    /// up. Tilting it tilts the horizon.
+    Gradient { axis: Vec3 },
+    /// An equirectangular environment map, usually an HDR image.
+    Environment(Arc<ImageTexture>),
+    /// An environment map that diffuse surfaces also sample directly, which is a lot less noisy
+    /// for maps with small bright spots like the sun.
+    Light(Arc<EnvironmentLight>),
+}
+
+impl Background {
+    pub fn color(&self, ray: &Ray) -> Color {
+        match self {
+            Background::Gradient { axis } => ray.gradient_color(axis),
+            Background::Environment(env)  => env.sample_dir(&ray.dir),
+            Background::Light(light)      => light.radiance(&ray.dir),
+        }
+    }
+
+    /// Gradient with the horizon tilted so that `axis` is up, or the usual one for a zero `axis`.
+    pub fn gradient(axis: Vec3) -> Background {
+        Background::Gradient { axis }
+    }
+
+    /// The environment to sample directly, if any.
+    pub fn light(&self) -> Option<&EnvironmentLight> {
+        match self {
+            Background::Light(light) => Some(light),
+            _                        => None,
+        }
+    }
+}
+
+impl Default for Background {
+    fn default() -> Background {
+        Background::gradient(Vec3::y())
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::material::{ Blend, Diffuse, Material, Metal, Mirror, OrenNayar };
+    use crate::objects::{ BoxHittable, Disk, Sphere };
+    use std::sync::atomic::{ AtomicUsize, Ordering };
+
+    fn unit_sphere() -> Vec<Sphere<Diffuse>> {
+        vec![Sphere::new(Point3::zeros(), 1.0, Diffuse::new(color::mid_gray()))]
+    }
+
+    #[test]
+    fn default_payload_is_vacuum_at_time_zero() {
+        let ray = Ray::new(Point3::new(0.0, 0.0, 3.0), Vec3::new(0.0, 0.0, -1.0));
+        assert_eq!(ray.payload.time, 0.0);
+        assert!(ray.payload.medium.is_empty());
+        assert_eq!(ray.payload.medium.ior(), 1.0);
+        assert_eq!(ray.payload.throughput, color::white());
+
+        // Tracing with an explicit default payload is the same as without one.
+        let trace = |ray: Ray| {
+            utils::seed_thread_rng(4);
+            ray.compute_color_full(unit_sphere(), 8, &Background::default())
+        };
+        let plain = trace(ray);
+        let explicit = trace(ray.with_payload(RayPayload::default()));
+        assert_eq!(plain.color, explicit.color);
+        assert_eq!(plain.bounces, explicit.bounces);
+
+        // Bounces only scale the throughput.
+        let scatter = Scatter::new(color::new(0.5, 0.25, 1.0), Vec3::y(), ScatterEvent::Diffuse);
+        let payload = ray.with_time(0.5).payload.scattered(&scatter);
+        assert_eq!(payload.time, 0.5);
+        assert!(payload.medium.is_empty());
+        assert_eq!(payload.throughput, color::new(0.5, 0.25, 1.0));
+    }
+
+    #[test]
+    fn spot_lights_light_diffuse_surfaces_in_their_cone() {
+        let floor = Disk::new(Point3::zeros(), Vec3::y(), 100.0, Diffuse::new(color::new(0.5, 0.5, 0.5)));
+        let lights = [SpotLight::new(Point3::new(0.0, 4.0, 0.0), -Vec3::y(), 20.0, 30.0, color::new(8.0, 8.0, 8.0))];
+        let black = Background::Environment(Arc::new(ImageTexture::new(1, 1, vec![color::black()])));
+        let config = TraceConfig { max_depth: 8, background: &black, lights: &lights, max_t: Real::INFINITY, error_color: color::magenta() };
+        // Straight down onto the floor at `x`, with the bounces going off into the black sky.
+        let lit = |x: Real| {
+            utils::seed_thread_rng(2);
+            Ray::new(Point3::new(x, 1.0, 0.0), -Vec3::y()).trace(&floor, &config).color.x
+        };
+
+        let under = 0.5 * 8.0 / (utils::consts::PI * 16.0);
+        assert!((lit(0.0) - under).abs() < 1e-5);
+        let between = lit(4.0 * Real::to_radians(25.0).tan());
+        assert!(between > 0.0 && between < 0.9 * under, "{}", between);
+        assert_eq!(lit(4.0 * Real::to_radians(31.0).tan()), 0.0);
+    }
+
+    #[test]
+    fn direct_light_is_weighed_towards_the_light() {
+        // A rough floor seen at a grazing angle, lit from straight above, with nothing else to
+        // bounce off of.
+        let rough = OrenNayar::new(color::new(0.5, 0.5, 0.5), 1.0);
+        let floor = Disk::new(Point3::zeros(), Vec3::y(), 100.0, rough);
+        let lights = [SpotLight::new(Point3::new(0.0, 4.0, 0.0), -Vec3::y(), 20.0, 30.0, color::new(8.0, 8.0, 8.0))];
+        let black = Background::Environment(Arc::new(ImageTexture::new(1, 1, vec![color::black()])));
+        let config = TraceConfig { max_depth: 8, background: &black, lights: &lights, max_t: Real::INFINITY, error_color: color::magenta() };
+        let ray = Ray::new(Point3::new(-4.0, 1.0, 0.0), Vec3::new(4.0, -1.0, 0.0));
+
+        let hit = floor.hit(&ray, 0.001..Real::INFINITY).unwrap();
+        let expected = hit.eval(&ray, &Vec3::y()).unwrap().value * 8.0 / 16.0;
+        // No matter which way the bounce went.
+        for seed in 0..8 {
+            utils::seed_thread_rng(seed);
+            let color = ray.trace(&floor, &config).color;
+            assert!(utils::approx_eq(&color, &expected, 1e-5), "{:?} != {:?}", color, expected);
+        }
+
+        // Half mirror, half diffuse under a white sky: all of the mirror's share comes back, and
+        // the albedo of the diffuse one. The same with the sky sampled directly or not.
+        let blend = Blend::new(Mirror::new(color::white()), Diffuse::new(color::new(0.5, 0.5, 0.5)), 0.5);
+        let floor = Disk::new(Point3::zeros(), Vec3::y(), 100.0, blend);
+        let white = Arc::new(ImageTexture::new(2, 1, vec![color::white(); 2]));
+        let mean = |background: &Background| {
+            let config = TraceConfig { lights: &[], background, ..config };
+            utils::seed_thread_rng(3);
+            let samples = 4000;
+            (0..samples).map(|_| ray.trace(&floor, &config).color.x).sum::<Real>() / samples as Real
+        };
+        for background in [Background::Environment(white.clone()), Background::Light(Arc::new(EnvironmentLight::new(white)))] {
+            let mean = mean(&background);
+            assert!((mean - 0.75).abs() < 0.02, "{} with {:?}", mean, background);
+        }
+    }
+
+    #[test]
+    fn degenerate_rays_are_the_error_color() {
+        /// Scatters every ray into a zero direction.
+        struct Stuck;
+
+        impl Material for Stuck {
+            fn scatter(&self, _: &Ray, _: &Hit) -> Option<Scatter> {
+                Some(Scatter::new(color::white(), Vec3::zeros(), ScatterEvent::Reflected))
+            }
+        }
+
+        let error_color = color::new(0.1, 0.2, 0.3);
+        let sky = Background::default();
+        let config = TraceConfig { max_depth: 4, background: &sky, lights: &[], max_t: Real::INFINITY, error_color };
+        let world = unit_sphere();
+
+        let ray = Ray::new(Point3::new(0.0, 0.0, 3.0), Vec3::zeros());
+        assert_eq!(ray.trace(&world, &config).color, error_color);
+        assert_eq!(ray.trace_split(&world, &config, 8).color, error_color);
+        assert_eq!(ray.trace_preview(&world, &config, &[]).color, error_color);
+        assert_eq!(ray.trace_ao(&world, &config, 4, 1.0).color, error_color);
+
+        // Also when the path only degenerates after a bounce.
+        let world = vec![Sphere::new(Point3::zeros(), 1.0, Stuck)];
+        let ray = Ray::new(Point3::new(0.0, 0.0, 3.0), -Vec3::z());
+        assert_eq!(ray.trace(&world, &config).color, error_color);
+    }
+
+    #[test]
+    fn only_the_nearest_hit_is_shaded() {
+        /// Absorbs everything, counting how often it's asked to scatter.
+        struct Counting(Arc<AtomicUsize>);
+
+        impl Material for Counting {
+            fn scatter(&self, _: &Ray, _: &Hit) -> Option<Scatter> {
+                self.0.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+
+        let (near, far) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        // The occluded sphere comes first, so that it's tested first too.
+        let world = vec![
+            Sphere::new(Point3::new(0.0, 0.0, -5.0), 1.0, Counting(far.clone())),
+            Sphere::new(Point3::zeros(), 1.0, Counting(near.clone())),
+        ];
+        let ray = Ray::new(Point3::new(0.0, 0.0, 3.0), -Vec3::z());
+        assert_eq!(ray.compute_color(&world, 4, &Background::default()), color::black());
+        assert_eq!((near.load(Ordering::Relaxed), far.load(Ordering::Relaxed)), (1, 0));
+    }
+
+    #[test]
+    fn max_t_clips_camera_rays_only() {
+        let gray = || Diffuse::new(color::mid_gray());
+        let far_wall = Disk::new(Point3::new(0.0, 0.0, -10.0), Vec3::z(), 100.0, gray());
+        let sky = Background::default();
+        let config = |max_depth: usize, max_t: Real| TraceConfig {
+            max_depth,
+            background: &sky,
+            lights: &[],
+            max_t,
+            error_color: color::magenta(),
+        };
+        let trace_to_wall = |max_t: Real| {
+            let config = config(1, max_t);
+            Ray::new(Point3::zeros(), Vec3::new(0.0, 0.0, -2.0)).trace(&far_wall, &config)
+        };
+        assert_eq!(trace_to_wall(10.5).first_hit.unwrap().distance, 10.0);
+        assert!(trace_to_wall(9.5).first_hit.is_none());
+
+        // A mirror in front of the camera sees the wall past `max_t` behind it.
+        let mirror = Disk::new(Point3::new(0.0, 0.0, 5.0), -Vec3::z(), 100.0, Metal::new(color::white(), 0.0));
+        let world = vec![Box::new(far_wall) as BoxHittable, Box::new(mirror)];
+        let path = Ray::new(Point3::zeros(), Vec3::z()).trace(&world, &config(2, 6.0));
+        assert_eq!(path.first_hit.unwrap().distance, 5.0);
+        assert_eq!(path.bounces, 2);
+    }
+
+    #[test]
+    fn ambient_occlusion_is_darker_near_a_wall() {
+        // The floor meets a wall along the z axis.
+        let gray = || Diffuse::new(color::mid_gray());
+        let floor = Disk::new(Point3::zeros(), Vec3::y(), 100.0, gray());
+        let wall = Disk::new(Point3::zeros(), Vec3::x(), 100.0, gray());
+        let world = vec![Box::new(floor) as BoxHittable, Box::new(wall)];
+        let sky = Background::default();
+        let config = TraceConfig {
+            max_depth: 1,
+            background: &sky,
+            lights: &[],
+            max_t: Real::INFINITY,
+            error_color: color::magenta(),
+        };
+
+        utils::seed_thread_rng(3);
+        let ao_at = |x: Real| {
+            let ray = Ray::new(Point3::new(x, 1.0, 0.0), -Vec3::y());
+            ray.trace_ao(&world, &config, 256, 0.5).color.x
+        };
+        let cornered = ao_at(0.05);
+        let exposed = ao_at(5.0);
+        assert_eq!(exposed, 1.0);
+        assert!(cornered < 0.8, "{} in the corner", cornered);
+        // The wall is out of reach from further than `radius`.
+        assert_eq!(ao_at(0.6), 1.0);
+
+        // Rays that miss everything are white, whatever the material.
+        let sky_ray = Ray::new(Point3::new(1.0, 1.0, 0.0), Vec3::y());
+        assert_eq!(sky_ray.trace_ao(&world, &config, 16, 0.5).color, color::white());
+    }
+
+    #[test]
+    fn gradient_without_an_axis_is_the_usual_sky() {
+        let zero = Background::gradient(Vec3::zeros());
+        let tilted = Background::gradient(Vec3::new(0.0, 0.0, 3.0));
+        for dir in [Vec3::y(), -Vec3::y(), Vec3::new(1.0, 0.5, -2.0)] {
+            let ray = Ray::new(Point3::zeros(), dir);
+            assert_eq!(zero.color(&ray), ray.bg_color());
+            assert!(!zero.color(&ray).iter().any(|c| c.is_nan()));
+        }
+
+        // Longer axes are the same as unit ones.
+        let along_z = Ray::new(Point3::zeros(), Vec3::z());
+        assert!(utils::approx_eq(&tilted.color(&along_z), &color::new(0.5, 0.7, 1.0), 1e-6));
+    }
+
+    #[test]
+    fn direct_hits_report_one_bounce_and_the_first_normal() {
+        let mirror = vec![Sphere::new(Point3::new(0.0, 0.0, -3.0), 1.0, Metal::new(color::white(), 0.0))];
+        let sky = Background::default();
+        // Off center, so that the reflection goes off into the sky.
+        let ray = Ray::new(Point3::zeros(), Vec3::new(0.0, 0.3, -3.0));
+
+        let path = ray.compute_color_full(&mirror, 10, &sky);
+        assert_eq!(path.bounces, 1);
+        let first = path.first_hit.unwrap();
+        assert!(utils::approx_eq(&first.normal, &(first.point.0 - Point3::new(0.0, 0.0, -3.0)), 1e-5));
+        assert!(first.normal.dot(&ray.dir) < 0.0);
+        assert!((first.distance - first.point.0.magnitude()).abs() < 1e-5);
+        assert_eq!(first.albedo, color::white());
+        assert_eq!(ray.compute_color(&mirror, 10, &sky), path.color);
+
+        // Straight to the sky.
+        let miss = Ray::new(Point3::zeros(), Vec3::y()).compute_color_full(&mirror, 10, &sky);
+        assert_eq!(miss.bounces, 0);
+        assert!(miss.first_hit.is_none());
+    }
+}
@@ -1,16 +1,30 @@
 use std::default::Default;
-use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::sync::atomic::{ AtomicBool, AtomicU64, AtomicUsize, Ordering };
 use std::convert::TryInto;
+use std::fmt;
+use std::ops::Range;
+use std::path::{ Path, PathBuf };
+use std::sync::{ Arc, Mutex };
+use std::time::{ Duration, Instant };
 
-use rayon::prelude::*;
-use rayon::iter;
-use rand::random;
+use rand::Rng;
+use rand::seq::SliceRandom;
+use image::{ GenericImage, Pixel };
+use image::buffer::ConvertBuffer;
 
+use crate::par::*;
 use crate::objects::{ BoxedHitList, Sphere };
 use crate::material::CommonMat;
-use crate::hittable::Hittable;
+use crate::hittable::{ self, Hittable, SceneStats, TraversalCounts };
 use crate::camera::Camera;
-use crate::utils::{ self, color, Color };
+use crate::ray::{ Background, TraceConfig };
+use crate::texture::ImageTexture;
+use crate::environment::EnvironmentLight;
+use crate::blue_noise::BlueNoise;
+use crate::exr;
+use crate::light::{ PointLight, SpotLight };
+use crate::utils::{ self, random, color, Color, Vec3, Real };
+use crate::utils::color::ToneMap;
 
 pub struct Scene<T> {
     pub world: T,
@@ -22,20 +36,238 @@ impl<T: Hittable + Send + Sync> Scene<T> {
     pub fn new(world: T, camera: Camera, config: Render) -> Self {
         Self { world, camera, config }
     }
+
+    /// Render the image in memory with `config.backend`, without encoding it. Like the free
+    /// functions, the setup mistakes `validate` finds are only warned about, unless there would
+    /// be no image at all. A cancelled render still hands back what it got to, see
+    /// `RenderError::Cancelled`.
+    pub fn render(self) -> Result<image::RgbImage, RenderError> {
+        let Render { width, height, .. } = self.config;
+        if width == 0 || height == 0 {
+            warn_invalid(&self);
+            return Err(RenderError::EmptyImage { width, height });
+        }
+
+        let (img, _) = render_scene(&self);
+        let img = img.convert();
+
+        if is_cancelled(&self.config) {
+            Err(RenderError::Cancelled { partial: img })
+        } else {
+            Ok(img)
+        }
+    }
+}
+
+impl<T: Hittable> Scene<T> {
+    /// Check the scene for common setup mistakes that would otherwise silently produce a black
+    /// or distorted image.
+    pub fn validate(&self) -> Result<(), Vec<SceneWarning>> {
+        let mut warnings = Vec::new();
+
+        if self.camera.is_degenerate() {
+            warnings.push(SceneWarning::DegenerateCamera);
+        }
+
+        if self.config.samples_per_pixel == 0 && self.config.sample_map.is_none() {
+            warnings.push(SceneWarning::NoSamples);
+        }
+
+        if self.config.max_bounces == 0 {
+            warnings.push(SceneWarning::NoBounces);
+        }
+
+        if self.world.is_empty() {
+            warnings.push(SceneWarning::EmptyWorld);
+        }
+
+        let Render { aspect_ratio, width, height, .. } = self.config;
+        if width == 0 || height == 0 {
+            warnings.push(SceneWarning::EmptyImage { width, height });
+        } else if !fits_aspect_ratio(aspect_ratio, width, height) {
+            warnings.push(SceneWarning::AspectRatioMismatch { aspect_ratio, width, height });
+        }
+
+        if let OutputFormat::Jpeg { quality, .. } = self.config.output {
+            if !(1..=100).contains(&quality) {
+                warnings.push(SceneWarning::InvalidJpegQuality(quality));
+            }
+
+            if self.config.alpha {
+                warnings.push(SceneWarning::AlphaWithoutPng);
+            }
+        }
+
+        if warnings.is_empty() {
+            Ok(())
+        } else {
+            Err(warnings)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SceneWarning {
+    DegenerateCamera,
+    NoSamples,
+    NoBounces,
+    EmptyWorld,
+    EmptyImage { width: usize, height: usize },
+    AspectRatioMismatch { aspect_ratio: Real, width: usize, height: usize },
+    InvalidJpegQuality(u8),
+    AlphaWithoutPng,
+}
+
+impl fmt::Display for SceneWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use SceneWarning::*;
+
+        match self {
+            DegenerateCamera => write!(
+                f,
+                "camera basis is degenerate (is `look_from == look_at` or `vup` parallel to the view direction?)"
+            ),
+            NoSamples  => write!(f, "`samples_per_pixel` is 0, no pixel will be sampled"),
+            NoBounces  => write!(f, "`max_bounces` is 0, every pixel will be white"),
+            EmptyWorld => write!(f, "the world has no objects"),
+            EmptyImage { width, height } => write!(f, "image has no pixels ({}x{})", width, height),
+            AspectRatioMismatch { aspect_ratio, width, height } => write!(
+                f,
+                "aspect ratio {} does not match the image dimensions {}x{}",
+                aspect_ratio, width, height
+            ),
+            InvalidJpegQuality(quality) => write!(f, "JPEG quality {} is not in 1..=100", quality),
+            AlphaWithoutPng => write!(f, "only PNG output keeps the alpha channel, the image will be opaque"),
+        }
+    }
+}
+
+/// Why `Scene::render` gave no image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RenderError {
+    EmptyImage { width: usize, height: usize },
+    /// `Render::cancel` was set before the render finished. `partial` is the full size image with
+    /// what was rendered so far, the rest left black.
+    Cancelled { partial: image::RgbImage },
+}
+
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RenderError::EmptyImage { width, height } => write!(f, "image has no pixels ({}x{})", width, height),
+            RenderError::Cancelled { .. } => write!(f, "render was cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for RenderError {}
+
+fn warn_invalid<T: Hittable>(scene: &Scene<T>) {
+    if let Err(warnings) = scene.validate() {
+        for warning in warnings {
+            eprintln!("[warning] {}", warning);
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Render {
-    pub aspect_ratio: f32,
+    pub aspect_ratio: Real,
     pub width: usize,
     pub height: usize,
     pub samples_per_pixel: usize,
     pub max_bounces: usize,
+    pub background: Background,
+    /// Spot lights, in addition to the light coming from the background.
+    pub lights: Vec<SpotLight>,
+    /// Far clipping distance: the camera doesn't see surfaces further away than this, e.g. to cut
+    /// away a distant enclosing sphere. Light bouncing around the scene still reaches them.
+    pub max_t: Real,
+    /// Base seed for the per pixel random number generators, a random one is picked when `None`.
+    pub seed: Option<u64>,
+    /// Index of the frame when rendering an animation. Mixed into `seed` so that the noise
+    /// changes from frame to frame instead of sitting still on top of the motion.
+    pub frame: u64,
+    /// Keep the same noise in every frame regardless of `frame`, which temporal denoisers
+    /// prefer.
+    pub static_noise: bool,
+    pub filter: PixelFilter,
+    pub output: OutputFormat,
+    /// Number of threads the parallel renderers use, rayon's global pool is used when `None`.
+    /// Ignored without the `parallel` feature.
+    pub threads: Option<usize>,
+    /// When set to `true` the renderers stop sampling new rows (or pixels) and output what has
+    /// been rendered so far, leaving the rest black.
+    pub cancel: Option<Arc<AtomicBool>>,
+    /// Samples brighter than this luminance are scaled down to it, trading a little energy for
+    /// fewer fireflies.
+    pub firefly_clamp: Option<Real>,
+    /// Render `samples_per_pixel` samples first, then take more samples on pixels at edges.
+    /// Supported by every `Backend` and `render_to_output`, not by `render_region` or
+    /// `strip_render`.
+    pub edge_supersampling: Option<EdgeSupersampling>,
+    /// Scale the image so that its (geometric) mean luminance is this, for scenes whose
+    /// brightness isn't known ahead of time. Supported by every `Backend` and `render_to_output`,
+    /// not by `render_region` or `strip_render`.
+    pub auto_exposure: Option<Real>,
+    /// Follow both the reflected and refracted rays at glass, up to this many rays per sample,
+    /// instead of randomly picking one of them. Less noisy, but each sample is slower.
+    pub fresnel_split: Option<usize>,
+    /// Color of the samples whose rays degenerated, e.g. to a zero or NaN direction.
+    pub error_color: Color,
+    /// Spread the samples of each pixel evenly over both the pixel and the lens, instead of
+    /// drawing them independently. Works best with a square number of samples.
+    pub stratified: bool,
+    /// How the samples are spread over each pixel when they aren't `stratified`.
+    pub jitter: Jitter,
+    /// Make the background transparent, with the alpha of each pixel being the fraction of its
+    /// camera rays that hit the world. Only kept with `OutputFormat::Png`, and dropped by
+    /// `Scene::render`, which gives an RGB image.
+    pub alpha: bool,
+    /// Render at this many times the width and height, and shrink the image back down by
+    /// averaging the radiance of each block of pixels. Every pixel of the larger image still gets
+    /// `samples_per_pixel` samples,
+    /// so this is usually paired with a single one. Supported by every `Backend` and
+    /// `render_to_output`, not by `render_region` or `strip_render`.
+    pub ssaa: Option<u32>,
+    /// Shade with `Ray::trace_preview` and these lights instead of path tracing, for quick
+    /// previews.
+    pub preview_lights: Option<Vec<PointLight>>,
+    /// Order in which `multi_thread_render` hands out tiles to the threads.
+    pub tile_order: TileOrder,
+    /// Shade with `Ray::trace_ao` instead of path tracing, to look at the geometry alone. Takes
+    /// precedence over `preview_lights`.
+    pub ambient_occlusion: Option<AmbientOcclusion>,
+    /// Blend the surfaces the camera rays hit towards a fog color with their distance. The
+    /// background is left as is.
+    pub fog: Option<Fog>,
+    /// How radiance brighter than the image can store is brought into range, after exposure.
+    pub tone_map: ToneMap,
+    /// Add blue noise of less than a step of the 8 bit output before rounding, which breaks up
+    /// the banding of smooth gradients like the sky.
+    pub dither: bool,
+    /// Renderer `Scene::render` uses.
+    pub backend: Backend,
+    /// When `progressive_render` stops adding passes, instead of at `samples_per_pixel`. The
+    /// other renderers always take `samples_per_pixel`.
+    pub stop: Option<StopCondition>,
+    /// Count the bounding boxes and primitives every camera ray is tested against, into
+    /// `RenderStats`.
+    pub traversal_stats: bool,
+    /// Also write an OpenEXR file here with the linear radiance (`R`, `G`, `B` and `A`) and, for
+    /// the first surface the camera rays hit, the `albedo`, `normal` and `depth` layers, for
+    /// denoisers and compositors. Depth is infinite where nothing was hit. Supported by every
+    /// `Backend` and `render_to_output`, not by `render_region` or `strip_render`.
+    pub exr_aovs: Option<PathBuf>,
+    /// Overrides `samples_per_pixel` pixel by pixel, to spend more samples on the noisy parts of
+    /// the image. `progressive_render` stops sampling each pixel at its count, even with a `stop`
+    /// condition.
+    pub sample_map: Option<SampleMap>,
 }
 
 impl Render {
     pub fn new(
-        aspect_ratio: f32,
+        aspect_ratio: Real,
         width: usize,
         height: usize,
         samples_per_pixel: usize,
@@ -47,17 +279,82 @@ impl Render {
             height,
             samples_per_pixel,
             max_bounces,
+            background: Background::default(),
+            lights: Vec::new(),
+            max_t: Real::INFINITY,
+            seed: None,
+            frame: 0,
+            static_noise: false,
+            filter: PixelFilter::default(),
+            output: OutputFormat::default(),
+            threads: None,
+            cancel: None,
+            firefly_clamp: None,
+            edge_supersampling: None,
+            auto_exposure: None,
+            fresnel_split: None,
+            error_color: color::magenta(),
+            stratified: false,
+            jitter: Jitter::default(),
+            alpha: false,
+            ssaa: None,
+            preview_lights: None,
+            tile_order: TileOrder::default(),
+            ambient_occlusion: None,
+            fog: None,
+            tone_map: ToneMap::default(),
+            dither: false,
+            backend: Backend::default(),
+            stop: None,
+            traversal_stats: false,
+            exr_aovs: None,
+            sample_map: None,
         }
     }
 
-    pub fn with_ratio(aspect_ratio: f32, height: usize) -> Self {
+    pub fn with_ratio(aspect_ratio: Real, height: usize) -> Self {
         Self {
             aspect_ratio,
-            width: (height as f32 * aspect_ratio).ceil() as usize,
+            width: (height as Real * aspect_ratio).ceil() as usize,
             height,
             ..Self::default()
         }
     }
+
+    /// Seed the pixels are seeded from, see `seed` and `frame`.
+    fn base_seed(&self) -> u64 {
+        let seed = self.seed.unwrap_or_else(rand::random);
+        if self.static_noise {
+            seed
+        } else {
+            // Spread consecutive frames over all of the bits, the pixel index is xored in later.
+            seed ^ self.frame.wrapping_mul(0x9e37_79b9_7f4a_7c15)
+        }
+    }
+
+    /// Samples to take in pixel `(x, y)` of the image, with `y` going down.
+    fn samples_at(&self, x: u32, y: u32) -> usize {
+        match &self.sample_map {
+            Some(map) => map.samples(x as usize, y as usize),
+            None      => self.samples_per_pixel,
+        }
+    }
+
+    /// Whether the whole image has to be kept in memory before it can be written out.
+    fn is_buffered(&self) -> bool {
+        self.edge_supersampling.is_some()
+            || self.auto_exposure.is_some()
+            || self.ssaa.is_some()
+            || self.exr_aovs.is_some()
+    }
+}
+
+/// Whether a `width` by `height` image has the given aspect ratio. `with_ratio` rounds the width
+/// up, so up to a pixel of difference is allowed. Counted in whole pixels, since a float
+/// comparison right at the one pixel boundary would depend on how the ratio rounds.
+fn fits_aspect_ratio(aspect_ratio: Real, width: usize, height: usize) -> bool {
+    let expected = (aspect_ratio * height as Real).round() as usize;
+    width.abs_diff(expected) <= 1
 }
 
 impl Default for Render {
@@ -68,29 +365,387 @@ impl Default for Render {
             height: 854,
             samples_per_pixel: 10,
             max_bounces: 5,
+            background: Background::default(),
+            lights: Vec::new(),
+            max_t: Real::INFINITY,
+            seed: None,
+            frame: 0,
+            static_noise: false,
+            filter: PixelFilter::default(),
+            output: OutputFormat::default(),
+            threads: None,
+            cancel: None,
+            firefly_clamp: None,
+            edge_supersampling: None,
+            auto_exposure: None,
+            fresnel_split: None,
+            error_color: color::magenta(),
+            stratified: false,
+            jitter: Jitter::default(),
+            alpha: false,
+            ssaa: None,
+            preview_lights: None,
+            tile_order: TileOrder::default(),
+            ambient_occlusion: None,
+            fog: None,
+            tone_map: ToneMap::default(),
+            dither: false,
+            backend: Backend::default(),
+            stop: None,
+            traversal_stats: false,
+            exr_aovs: None,
+            sample_map: None,
+        }
+    }
+}
+
+/// Renderers `Scene::render` can use, each matching one of the free functions. The options that
+/// need the whole image in memory, like `ssaa` or `auto_exposure`, work the same with all of
+/// them, on as many threads as the backend would use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Backend {
+    /// `multi_thread_render`.
+    #[default]
+    Tiled,
+    /// `simple_multi_thread_render`, one row per task.
+    Rows,
+    /// `single_thread_render`.
+    SingleThread,
+}
+
+/// How the samples of a pixel are spread over it, see `Render::jitter`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Jitter {
+    /// Independently and uniformly.
+    #[default]
+    Uniform,
+    /// Starting from a tile of blue noise, so that neighbouring pixels get different offsets and
+    /// the error at low sample counts looks like fine grain instead of moiré. The later samples
+    /// of a pixel follow a low discrepancy sequence from there.
+    BlueNoise,
+}
+
+impl Jitter {
+    /// Offset from the center of pixel `(x, y)` of its sample `i`.
+    fn pixel_offset(&self, x: u32, y: u32, i: usize, rng: &mut impl Rng) -> (Real, Real) {
+        match self {
+            Jitter::Uniform => (rng.gen::<Real>() - 0.5, rng.gen::<Real>() - 0.5),
+            Jitter::BlueNoise => {
+                // Steps of the R2 sequence, see Roberts, "The unreasonable effectiveness of
+                // quasirandom sequences".
+                const STEP: (Real, Real) = (0.754_877_7, 0.569_840_3);
+                let (u, v) = BlueNoise::shared().get(x, y);
+                let u = (u + i as Real * STEP.0).fract();
+                let v = (v + i as Real * STEP.1).fract();
+                (u - 0.5, v - 0.5)
+            }
+        }
+    }
+}
+
+/// Order in which the tiles of an image are rendered. Only changes what shows up first, every
+/// pixel comes out the same either way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TileOrder {
+    /// Row by row, from the top left.
+    #[default]
+    Scanline,
+    /// Going around the center tile in a growing square.
+    Spiral,
+    /// By the distance of the tile to the center of the image.
+    CenterOut,
+    /// Along a Z-order curve, which keeps consecutive tiles close together.
+    Morton,
+}
+
+impl TileOrder {
+    /// `(column, row)` of every tile of a grid of `columns` by `rows` tiles, in this order.
+    pub fn tiles(&self, columns: usize, rows: usize) -> Vec<(usize, usize)> {
+        let mut tiles: Vec<_> = (0..rows)
+            .flat_map(|row| (0..columns).map(move |column| (column, row)))
+            .collect();
+
+        match self {
+            TileOrder::Scanline => (),
+
+            TileOrder::Spiral => {
+                let mut spiral = Vec::with_capacity(tiles.len());
+                let (mut x, mut y) = ((columns as isize - 1) / 2, (rows as isize - 1) / 2);
+                let (mut dx, mut dy) = (1, 0);
+                let mut len = 1;
+                while spiral.len() < tiles.len() {
+                    // Two sides of every length, turning clockwise after each.
+                    for _ in 0..2 {
+                        for _ in 0..len {
+                            if (0..columns as isize).contains(&x) && (0..rows as isize).contains(&y) {
+                                spiral.push((x as usize, y as usize));
+                            }
+                            x += dx;
+                            y += dy;
+                        }
+                        (dx, dy) = (-dy, dx);
+                    }
+                    len += 1;
+                }
+                tiles = spiral;
+            }
+
+            TileOrder::CenterOut => {
+                let center = (columns as Real / 2.0, rows as Real / 2.0);
+                let distance = |&(column, row): &(usize, usize)| {
+                    let dx = column as Real + 0.5 - center.0;
+                    let dy = row as Real + 0.5 - center.1;
+                    dx * dx + dy * dy
+                };
+                tiles.sort_by(|a, b| distance(a).partial_cmp(&distance(b)).unwrap());
+            }
+
+            TileOrder::Morton => {
+                tiles.sort_by_key(|&(column, row)| interleave_bits(column as u32, row as u32));
+            }
+        }
+        tiles
+    }
+}
+
+/// Morton code of `(x, y)`, with the bits of `x` in the even positions and `y` in the odd ones.
+fn interleave_bits(x: u32, y: u32) -> u64 {
+    let spread = |v: u32| {
+        let mut v = v as u64;
+        v = (v | (v << 16)) & 0x0000_ffff_0000_ffff;
+        v = (v | (v << 8))  & 0x00ff_00ff_00ff_00ff;
+        v = (v | (v << 4))  & 0x0f0f_0f0f_0f0f_0f0f;
+        v = (v | (v << 2))  & 0x3333_3333_3333_3333;
+        v = (v | (v << 1))  & 0x5555_5555_5555_5555;
+        v
+    };
+    spread(x) | (spread(y) << 1)
+}
+
+/// How the samples taken inside of a pixel are weighted to reconstruct its final value.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum PixelFilter {
+    /// Every sample counts the same.
+    #[default]
+    Box,
+    /// Weight falls off linearly with the distance to the pixel center along each axis, down to
+    /// half on the edges of the pixel and a quarter in its corners.
+    Tent,
+    /// Weight falls off with a gaussian of the given standard deviation (in pixels). Deviations
+    /// under `MIN_GAUSSIAN_SIGMA` are treated as `MIN_GAUSSIAN_SIGMA`.
+    Gaussian { sigma: Real },
+}
+
+/// Narrowest `PixelFilter::Gaussian`. Any narrower and the samples near the corners of a pixel
+/// would weigh nothing, or even round down to 0 all together.
+pub const MIN_GAUSSIAN_SIGMA: Real = 0.1;
+
+impl PixelFilter {
+    /// Weight of a sample at offset `(dx, dy)` from the pixel center.
+    pub fn weight(&self, dx: Real, dy: Real) -> Real {
+        match *self {
+            PixelFilter::Box => 1.0,
+            PixelFilter::Tent => (1.0 - dx.abs()).max(0.0) * (1.0 - dy.abs()).max(0.0),
+            PixelFilter::Gaussian { sigma } => {
+                let sigma = sigma.max(MIN_GAUSSIAN_SIGMA);
+                (-(dx * dx + dy * dy) / (2.0 * sigma * sigma)).exp()
+            }
+        }
+    }
+}
+
+/// Second pass of a two pass render, that spends extra samples only on pixels where the first
+/// pass found an edge.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EdgeSupersampling {
+    /// Samples added to every edge pixel.
+    pub edge_samples: usize,
+    /// A pixel is on an edge when its luminance differs by more than this from any of its four
+    /// neighbours, after gamma correction.
+    pub threshold: Real,
+}
+
+/// Settings of the ambient occlusion render mode.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AmbientOcclusion {
+    /// Occlusion rays shot from every surface a camera ray hits.
+    pub samples: usize,
+    /// Only surfaces closer than this occlude.
+    pub radius: Real,
+}
+
+/// Number of samples to take in each pixel, see `Render::sample_map`. Called with the `x` and `y`
+/// of the pixel in the image, `y` going down.
+#[derive(Clone)]
+pub struct SampleMap(pub Arc<dyn Fn(usize, usize) -> usize + Send + Sync>);
+
+impl SampleMap {
+    pub fn new(map: impl Fn(usize, usize) -> usize + Send + Sync + 'static) -> Self {
+        SampleMap(Arc::new(map))
+    }
+
+    pub fn samples(&self, x: usize, y: usize) -> usize {
+        (self.0)(x, y)
+    }
+
+    /// The same map over an image `factor` times larger, for `Render::ssaa`.
+    fn scaled(&self, factor: usize) -> SampleMap {
+        let map = self.0.clone();
+        SampleMap::new(move |x, y| map(x / factor, y / factor))
+    }
+}
+
+impl fmt::Debug for SampleMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("SampleMap(..)")
+    }
+}
+
+/// Distance fog, see `Render::fog`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fog {
+    pub color: Color,
+    /// How quickly surfaces fade into the fog, the fraction of their color left at a distance `d`
+    /// being `exp(-density * d)`.
+    pub density: Real,
+}
+
+impl Fog {
+    /// Color of a surface of `color`, seen from `distance` away.
+    pub fn apply(&self, color: Color, distance: Real) -> Color {
+        color::lerp(self.color, color, (-self.density * distance).exp())
+    }
+}
+
+/// When to stop sampling, see `Render::stop`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopCondition {
+    /// Once every pixel has this many samples.
+    Samples(usize),
+    /// Once this long has passed, finishing the pass in progress. At least one pass is always
+    /// taken.
+    Time(Duration),
+    /// Whichever of the two comes first.
+    Either(usize, Duration),
+}
+
+impl StopCondition {
+    fn is_met(&self, samples: usize, elapsed: Duration) -> bool {
+        let out_of_time = |budget| samples > 0 && elapsed >= budget;
+        match *self {
+            StopCondition::Samples(max)        => samples >= max,
+            StopCondition::Time(budget)        => out_of_time(budget),
+            StopCondition::Either(max, budget) => samples >= max || out_of_time(budget),
+        }
+    }
+
+    /// Samples the next pass may take at most.
+    fn samples_left(&self, samples: usize) -> usize {
+        match *self {
+            StopCondition::Samples(max) | StopCondition::Either(max, _) => max.saturating_sub(samples),
+            StopCondition::Time(_) => usize::MAX,
+        }
+    }
+
+    /// How far along the render is, from 0 to 1.
+    fn progress(&self, samples: usize, elapsed: Duration) -> Real {
+        let by_samples = |max| samples as Real / max as Real;
+        let by_time = |budget: Duration| (elapsed.as_secs_f64() / budget.as_secs_f64()) as Real;
+        let progress = match *self {
+            StopCondition::Samples(max)        => by_samples(max),
+            StopCondition::Time(budget)        => by_time(budget),
+            StopCondition::Either(max, budget) => by_samples(max).max(by_time(budget)),
+        };
+        progress.min(1.0)
+    }
+}
+
+/// Format of the encoded image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    /// Lossy JPEG with `quality` in `1..=100`, and color kept at the resolution given by
+    /// `chroma`.
+    Jpeg { quality: u8, chroma: ChromaSubsampling },
+    /// Lossless PNG.
+    Png,
+}
+
+impl Default for OutputFormat {
+    fn default() -> OutputFormat {
+        OutputFormat::Jpeg { quality: 100, chroma: ChromaSubsampling::Full }
+    }
+}
+
+/// Resolution of the color (chroma) channels of a JPEG, relative to its brightness.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChromaSubsampling {
+    /// 4:4:4, color as sharp as brightness.
+    #[default]
+    Full,
+    /// 4:2:0, one color for each 2x2 block of pixels, for smaller files. The encoder always
+    /// stores color at full resolution, so the chroma of each block is averaged before encoding
+    /// instead, which leaves it the same detail to throw away.
+    Half,
+}
+
+/// Common image sizes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResolutionPreset {
+    /// 1280x720
+    Hd720,
+    /// 1920x1080
+    Hd1080,
+    /// 2560x1440
+    Qhd1440,
+    /// 3840x2160
+    Uhd4k,
+    /// 1024x1024
+    Square1k,
+}
+
+impl ResolutionPreset {
+    /// Width and height in pixels.
+    pub fn dimensions(&self) -> (usize, usize) {
+        match self {
+            ResolutionPreset::Hd720    => (1280, 720),
+            ResolutionPreset::Hd1080   => (1920, 1080),
+            ResolutionPreset::Qhd1440  => (2560, 1440),
+            ResolutionPreset::Uhd4k    => (3840, 2160),
+            ResolutionPreset::Square1k => (1024, 1024),
         }
     }
 }
 
+#[derive(Default)]
 pub struct RenderBuilder {
     render: Render,
+    /// Last aspect ratio given to `with_ratio` and dimensions given to `with_dimensions`, which
+    /// each overwrite the other's, so that `build` can tell when they disagree.
+    ratio: Option<Real>,
+    dimensions: Option<(usize, usize)>,
 }
 
 impl RenderBuilder {
     pub fn new() -> Self {
-        Self {
-            render: Render::default(),
-        }
+        Self::default()
     }
 
-    pub fn build(&mut self) -> Render {
-        self.render.clone()
+    /// Fails when both `with_ratio` and `with_dimensions` were used with different aspect ratios,
+    /// instead of silently stretching the image to whichever came last.
+    pub fn build(&mut self) -> Result<Render, BuildError> {
+        if let (Some(aspect_ratio), Some((width, height))) = (self.ratio, self.dimensions) {
+            if !fits_aspect_ratio(aspect_ratio, width, height) {
+                return Err(BuildError::ConflictingSize { aspect_ratio, width, height });
+            }
+        }
+        Ok(self.render.clone())
     }
 
-    pub fn with_ratio(&mut self, aspect_ratio: f32, height: usize) -> &mut Self {
-        self.render.width = (height as f32 * aspect_ratio).ceil() as usize;
+    pub fn with_ratio(&mut self, aspect_ratio: Real, height: usize) -> &mut Self {
+        self.render.width = (height as Real * aspect_ratio).ceil() as usize;
         self.render.height = height;
         self.render.aspect_ratio = aspect_ratio;
+        self.ratio = Some(aspect_ratio);
         self
     }
 
@@ -104,197 +759,1340 @@ impl RenderBuilder {
         self
     }
 
-    pub fn with_dimensions(&mut self, width: usize, heigth: usize) -> &mut Self {
-        self.render.width  = width;
-        self.render.height = heigth;
-        self.render.aspect_ratio = width as f32 / heigth as f32;
+    /// Make renders reproducible, every pixel draws its samples from a generator seeded with
+    /// `seed ^ pixel_index`. The pixels don't depend on each other, so every backend and thread
+    /// count gives the same image.
+    pub fn with_seed(&mut self, seed: u64) -> &mut Self {
+        self.render.seed = Some(seed);
         self
     }
-}
 
-pub fn multi_thread_render<T: Hittable + Send + Sync>(scene: Scene<T>) {
-    let Scene { world, camera, config } = scene;
-    let Render {
-        aspect_ratio: _,
-        width,
-        height,
-        samples_per_pixel,
-        max_bounces,
-    } = config;
+    pub fn with_frame(&mut self, frame: u64) -> &mut Self {
+        self.render.frame = frame;
+        self
+    }
 
-    let width = width as u32;
-    let height = height as u32;
+    pub fn with_static_noise(&mut self, static_noise: bool) -> &mut Self {
+        self.render.static_noise = static_noise;
+        self
+    }
 
-    let count = AtomicUsize::new(0);
+    /// # Panics
+    ///
+    /// If `filter` is `PixelFilter::Gaussian` with a `sigma` that isn't positive.
+    pub fn with_filter(&mut self, filter: PixelFilter) -> &mut Self {
+        if let PixelFilter::Gaussian { sigma } = filter {
+            assert!(sigma > 0.0, "gaussian filter needs a positive sigma, got {}", sigma);
+        }
+        self.render.filter = filter;
+        self
+    }
 
-    let mut img = image::RgbImage::new(width as u32, height as u32);
+    /// # Panics
+    ///
+    /// If `format` is `OutputFormat::Jpeg` with a quality outside of `1..=100`.
+    pub fn with_output(&mut self, format: OutputFormat) -> &mut Self {
+        if let OutputFormat::Jpeg { quality, .. } = format {
+            assert!((1..=100).contains(&quality), "JPEG quality must be in 1..=100, got {}", quality);
+        }
 
-    img
-        .par_chunks_exact_mut(3)
-        .enumerate()
-        .for_each(|(i, pixel)| {
-            let y = i as u32 / width;
-            let x = i as u32 % width;
+        self.render.output = format;
+        self
+    }
 
-            // Invert the y coordinate so higher of y go up.
-            let y = height - y as u32;
+    pub fn with_threads(&mut self, threads: usize) -> &mut Self {
+        self.render.threads = Some(threads);
+        self
+    }
 
-            let pixel = rgb_mut_ref(pixel.try_into().unwrap());
+    pub fn with_cancel_token(&mut self, cancel: Arc<AtomicBool>) -> &mut Self {
+        self.render.cancel = Some(cancel);
+        self
+    }
 
-            let mut color = color::black();
-            for _ in 0..samples_per_pixel {
-                let u = (x as f32 + random::<f32>()) / (width  as f32 - 1.0);
-                let v = (y as f32 + random::<f32>()) / (height as f32 - 1.0);
+    /// Take `base` samples in every pixel, and `edge_samples` more in pixels whose luminance
+    /// differs by more than `threshold` from a neighbour's.
+    pub fn with_edge_supersampling(&mut self, base: usize, edge_samples: usize, threshold: Real) -> &mut Self {
+        self.render.samples_per_pixel = base;
+        self.render.edge_supersampling = Some(EdgeSupersampling { edge_samples, threshold });
+        self
+    }
 
-                color += camera.get_ray(u, v).compute_color(&world, max_bounces);
-            }
+    pub fn with_alpha(&mut self, alpha: bool) -> &mut Self {
+        self.render.alpha = alpha;
+        self
+    }
 
-            let pixel_val = color / (samples_per_pixel as f32);
-            *pixel = utils::to_rgb(nalgebra_glm::sqrt(&pixel_val));
+    pub fn with_tone_map(&mut self, tone_map: ToneMap) -> &mut Self {
+        self.render.tone_map = tone_map;
+        self
+    }
 
-            let oldval = count.fetch_add(1, Ordering::SeqCst);
+    pub fn with_dither(&mut self, dither: bool) -> &mut Self {
+        self.render.dither = dither;
+        self
+    }
 
-            if oldval % 60 == 0 {
-                let percent = (oldval as f32 * 100.0) / (width * height as u32) as f32;
-                eprint!("\r[{:03.0}%] Rendering", percent);
-            }
-        });
+    /// Expose the image so that its mean luminance is `target`, e.g. 0.18 for middle gray.
+    pub fn with_auto_exposure(&mut self, target: Real) -> &mut Self {
+        self.render.auto_exposure = Some(target);
+        self
+    }
 
-    let stdout = std::io::stdout();
-    let mut stdout = stdout.lock();
-    let mut encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut stdout, 100);
+    pub fn with_exr_aovs(&mut self, path: impl Into<PathBuf>) -> &mut Self {
+        self.render.exr_aovs = Some(path.into());
+        self
+    }
 
-    encoder.encode_image(&img).unwrap();
+    /// Supersample every pixel with a `factor` by `factor` grid of pixels.
+    ///
+    /// # Panics
+    ///
+    /// If `factor` is 0.
+    pub fn with_ssaa(&mut self, factor: u32) -> &mut Self {
+        assert!(factor > 0, "the supersampling factor must be at least 1");
+        self.render.ssaa = Some(factor);
+        self
+    }
 
-    eprintln!("\nDone!");
-}
+    /// Light the scene with just `lights`, see `Ray::trace_preview`.
+    pub fn with_preview_lighting(&mut self, lights: Vec<PointLight>) -> &mut Self {
+        self.render.preview_lights = Some(lights);
+        self
+    }
 
-pub fn simple_multi_thread_render<T: Hittable + Send + Sync>(scene: Scene<T>) {
-    let Scene { world, camera, config } = scene;
-    let Render {
-        aspect_ratio: _,
-        width,
-        height,
-        samples_per_pixel,
-        max_bounces,
-    } = config;
-
-    let width = width as u32;
-    let height = height as u32;
-    let mut img = image::RgbImage::new(width as u32, height as u32);
+    /// Fade surfaces into `color` with their distance from the camera, see `Fog::density`.
+    pub fn with_fog(&mut self, color: Color, density: Real) -> &mut Self {
+        self.render.fog = Some(Fog { color, density });
+        self
+    }
 
-    let count = AtomicUsize::new(0);
+    /// Take `map(x, y)` samples in pixel `(x, y)` instead of `samples_per_pixel`, see
+    /// `Render::sample_map`.
+    pub fn with_sample_map(&mut self, map: impl Fn(usize, usize) -> usize + Send + Sync + 'static) -> &mut Self {
+        self.render.sample_map = Some(SampleMap::new(map));
+        self
+    }
 
-    let render_row = |y, row: &mut [u8]| {
-        // Invert the y coordinate so higher of y go up.
-        let y = height - y as u32;
+    /// Render ambient occlusion instead, with `samples` rays per camera ray that look for
+    /// surfaces up to `radius` away.
+    pub fn with_ao(&mut self, samples: usize, radius: Real) -> &mut Self {
+        self.render.ambient_occlusion = Some(AmbientOcclusion { samples, radius });
+        self
+    }
 
-        let row_iter = row
-            .as_chunks_mut().0 // &mut [[u8; 3]]
-            .iter_mut()        // impl Iterator<Item = &mut [u8; 3]>
-            .map(rgb_mut_ref); // impl Iterator<Item = &mut Rgb<u8>>
+    /// Have `progressive_render` stop on `stop` instead of at `samples_per_pixel`.
+    pub fn with_stop_condition(&mut self, stop: StopCondition) -> &mut Self {
+        self.render.stop = Some(stop);
+        self
+    }
 
-        for (x, pixel) in row_iter.enumerate() {
-            let mut color = color::black();
-            for _ in 0..samples_per_pixel {
-                let u = (x as f32 + random::<f32>()) / (width  as f32 - 1.0);
-                let v = (y as f32 + random::<f32>()) / (height as f32 - 1.0);
+    pub fn with_traversal_stats(&mut self, traversal_stats: bool) -> &mut Self {
+        self.render.traversal_stats = traversal_stats;
+        self
+    }
 
-                color += camera.get_ray(u, v).compute_color(&world, max_bounces);
-            }
+    pub fn with_backend(&mut self, backend: Backend) -> &mut Self {
+        self.render.backend = backend;
+        self
+    }
 
-            let pixel_val = color / (samples_per_pixel as f32);
-            *pixel = utils::to_rgb(nalgebra_glm::sqrt(&pixel_val));
+    pub fn with_tile_order(&mut self, order: TileOrder) -> &mut Self {
+        self.render.tile_order = order;
+        self
+    }
 
-            let oldval = count.fetch_add(1, Ordering::SeqCst);
-            if oldval % 60 == 0 {
-                let percent = (oldval as f32 * 100.0) / (width * height) as f32;
-                eprint!("\r[{:03.0}%] Rendering", percent);
-            }
-        }
-    };
+    pub fn with_jitter(&mut self, jitter: Jitter) -> &mut Self {
+        self.render.jitter = jitter;
+        self
+    }
 
-    img
-        .par_chunks_exact_mut(width as usize * 3)
-        .enumerate()
-        .for_each(|(y, row)| render_row(y, row));
+    pub fn with_stratified_sampling(&mut self, stratified: bool) -> &mut Self {
+        self.render.stratified = stratified;
+        self
+    }
 
-    let stdout = std::io::stdout();
-    let mut stdout = stdout.lock();
-    let mut encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut stdout, 100);
+    pub fn with_error_color(&mut self, error_color: Color) -> &mut Self {
+        self.render.error_color = error_color;
+        self
+    }
 
-    encoder.encode_image(&img).unwrap();
+    pub fn with_fresnel_split(&mut self, max_rays: usize) -> &mut Self {
+        self.render.fresnel_split = Some(max_rays);
+        self
+    }
 
-    eprintln!("\nDone!");
-}
+    pub fn with_firefly_clamp(&mut self, max_luminance: Real) -> &mut Self {
+        self.render.firefly_clamp = Some(max_luminance);
+        self
+    }
 
-pub fn single_thread_render<T: Hittable>(scene: Scene<T>) {
-    let Scene { world, camera, config } = scene;
-    let Render {
-        aspect_ratio: _,
-        width,
-        height,
-        samples_per_pixel,
-        max_bounces,
-    } = config;
-
-    let width = width as u32;
-    let height = height as u32;
-    let mut img = image::RgbImage::new(width as u32, height as u32);
+    pub fn with_background(&mut self, background: Background) -> &mut Self {
+        self.render.background = background;
+        self
+    }
 
-    let stdout = std::io::stdout();
-    let mut stdout = stdout.lock();
-    let mut encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut stdout, 100);
+    pub fn with_max_t(&mut self, max_t: Real) -> &mut Self {
+        self.render.max_t = max_t;
+        self
+    }
 
-    encoder.encode_image(&img).unwrap();
+    pub fn with_spot_light(&mut self, light: SpotLight) -> &mut Self {
+        self.render.lights.push(light);
+        self
+    }
 
-    let mut count = 0;
-    for (x, y, pixel) in img.enumerate_pixels_mut() {
-        let y = height - y;
-        let mut color = color::black();
+    /// Light the scene with an equirectangular environment map instead of the sky gradient.
+    pub fn with_background_image(&mut self, image: Arc<ImageTexture>) -> &mut Self {
+        self.with_background(Background::Environment(image))
+    }
 
-        for _ in 0..samples_per_pixel {
-            let u = (x as f32 + random::<f32>()) / (width  as f32 - 1.0);
-            let v = (y as f32 + random::<f32>()) / (height as f32 - 1.0);
-            color += camera.get_ray(u, v).compute_color(&world, max_bounces)
-        }
+    /// Same as `with_background_image`, but diffuse surfaces also sample the bright parts of the
+    /// map directly. Building the sampling tables takes a pass over the image.
+    pub fn with_environment_light(&mut self, image: Arc<ImageTexture>) -> &mut Self {
+        self.with_background(Background::Light(Arc::new(EnvironmentLight::new(image))))
+    }
 
-        let pixel_val = color / (samples_per_pixel as f32);
-        *pixel = utils::to_rgb(nalgebra_glm::sqrt(&pixel_val));
+    /// Set the dimensions, and the aspect ratio to match, from a preset.
+    pub fn with_resolution_preset(&mut self, preset: ResolutionPreset) -> &mut Self {
+        let (width, height) = preset.dimensions();
+        self.with_dimensions(width, height)
+    }
 
-        count += 1;
-        let percent = (count as f32 * 100.0) / (width * height) as f32;
-        eprint!("\r[{:03.0}%] Rendering", percent);
+    pub fn with_dimensions(&mut self, width: usize, heigth: usize) -> &mut Self {
+        self.render.width  = width;
+        self.render.height = heigth;
+        self.render.aspect_ratio = width as Real / heigth as Real;
+        self.dimensions = Some((width, heigth));
+        self
     }
+}
 
-    eprintln!("\nDone!");
+/// Why `RenderBuilder::build` gave no config.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BuildError {
+    /// `with_ratio` and `with_dimensions` were both used, with these different aspect ratios.
+    ConflictingSize { aspect_ratio: Real, width: usize, height: usize },
 }
 
-fn rgb_mut_ref<T: image::Primitive>(data: &mut [T; 3]) -> &mut image::Rgb<T> {
-    // Safety: image::Rgb is repr(C) so it is transparent to the underlying data.
-    unsafe {
-        std::mem::transmute(data)
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BuildError::ConflictingSize { aspect_ratio, width, height } => write!(
+                f,
+                "aspect ratio {} set with `with_ratio` does not match the dimensions {}x{} set with `with_dimensions`",
+                aspect_ratio, width, height
+            ),
+        }
     }
 }
 
-pub fn random_scene() -> Vec<Sphere<CommonMat>> {
-    use crate::objects::{ WorldBuilder, Sphere };
-    use crate::material::{ Dielectric, Diffuse, Metal };
-    use crate::utils::{ Vec3, Point3 };
+impl std::error::Error for BuildError {}
 
-    let mut world_builder = WorldBuilder::default();
+/// Counters gathered while rendering, shared between all of the render threads.
+#[derive(Debug, Default)]
+pub struct RenderStats {
+    camera_rays: AtomicU64,
+    bounces: AtomicU64,
+    node_visits: AtomicU64,
+    primitive_tests: AtomicU64,
+    max_node_visits: AtomicU64,
+    max_primitive_tests: AtomicU64,
+    scene: Mutex<SceneStats>,
+}
 
-    let ground_material = Diffuse::new(Color::new(0.5, 0.5, 0.5));
-    world_builder.add(Sphere::new(Point3::new(0.0, -1000.0, 0.0), 1000.0, ground_material.into()));
+impl RenderStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-    for a in -11..11 {
-        for b in -11..11 {
-            let choose_mat = random::<f32>();
-            let center = nalgebra_glm::vec3(
-                a as f32 + 0.9 * random::<f32>(),
-                0.2,
-                b as f32 + 0.9 * random::<f32>(),
-            );
+    /// Empty counters, along with the counts of what `world` is made of.
+    pub fn for_world(world: &impl Hittable) -> Self {
+        RenderStats { scene: Mutex::new(world.scene_stats()), ..Self::default() }
+    }
+
+    /// What the world is made of, all 0 unless created with `for_world`.
+    pub fn scene(&self) -> SceneStats {
+        *self.scene.lock().unwrap()
+    }
+
+    /// Number of rays shot from the camera, one per sample.
+    pub fn camera_rays(&self) -> u64 {
+        self.camera_rays.load(Ordering::Relaxed)
+    }
+
+    /// Number of scattered rays, across all samples.
+    pub fn bounces(&self) -> u64 {
+        self.bounces.load(Ordering::Relaxed)
+    }
+
+    /// Total number of rays traced through the world.
+    pub fn rays(&self) -> u64 {
+        self.camera_rays() + self.bounces()
+    }
+
+    pub fn average_bounces(&self) -> Real {
+        match self.camera_rays() {
+            0 => 0.0,
+            n => self.bounces() as Real / n as Real,
+        }
+    }
+
+    /// Bounding boxes and acceleration structure nodes visited, summed over the paths of all
+    /// camera rays. Only counted with `Render::traversal_stats`.
+    pub fn node_visits(&self) -> u64 {
+        self.node_visits.load(Ordering::Relaxed)
+    }
+
+    /// Primitives tested for hits, summed over the paths of all camera rays. Only counted with
+    /// `Render::traversal_stats`.
+    pub fn primitive_tests(&self) -> u64 {
+        self.primitive_tests.load(Ordering::Relaxed)
+    }
+
+    /// Most node visits along the path of a single camera ray.
+    pub fn max_node_visits(&self) -> u64 {
+        self.max_node_visits.load(Ordering::Relaxed)
+    }
+
+    /// Most primitive tests along the path of a single camera ray.
+    pub fn max_primitive_tests(&self) -> u64 {
+        self.max_primitive_tests.load(Ordering::Relaxed)
+    }
+
+    fn record_traversal(&self, counts: TraversalCounts) {
+        self.node_visits.fetch_add(counts.node_visits, Ordering::Relaxed);
+        self.primitive_tests.fetch_add(counts.primitive_tests, Ordering::Relaxed);
+        self.max_node_visits.fetch_max(counts.node_visits, Ordering::Relaxed);
+        self.max_primitive_tests.fetch_max(counts.primitive_tests, Ordering::Relaxed);
+    }
+
+    fn record(&self, camera_rays: u64, bounces: u64) {
+        self.camera_rays.fetch_add(camera_rays, Ordering::Relaxed);
+        self.bounces.fetch_add(bounces, Ordering::Relaxed);
+    }
+}
+
+impl fmt::Display for RenderStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} rays ({} from the camera), {:.2} bounces on average",
+            self.rays(), self.camera_rays(), self.average_bounces(),
+        )?;
+        let scene = self.scene();
+        if scene.objects > 0 {
+            write!(
+                f,
+                ", {} objects ({} triangles) in trees up to {} deep",
+                scene.objects, scene.triangles, scene.tree_depth,
+            )?;
+        }
+        if self.node_visits() > 0 || self.primitive_tests() > 0 {
+            let per_ray = |n: u64| n as Real / self.camera_rays().max(1) as Real;
+            write!(
+                f,
+                ", {:.1} node visits (at most {}) and {:.1} primitive tests (at most {}) per camera ray",
+                per_ray(self.node_visits()), self.max_node_visits(),
+                per_ray(self.primitive_tests()), self.max_primitive_tests(),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Width and height of the tiles `multi_thread_render` splits the image into.
+const TILE_SIZE: usize = 32;
+
+pub fn multi_thread_render<T: Hittable + Send + Sync>(scene: Scene<T>) -> RenderStats {
+    render_to_stdout(scene, Backend::Tiled)
+}
+
+pub fn simple_multi_thread_render<T: Hittable + Send + Sync>(scene: Scene<T>) -> RenderStats {
+    render_to_stdout(scene, Backend::Rows)
+}
+
+pub fn single_thread_render<T: Hittable + Send + Sync>(scene: Scene<T>) -> RenderStats {
+    render_to_stdout(scene, Backend::SingleThread)
+}
+
+/// Render with `backend` and write the encoded image to stdout.
+fn render_to_stdout<T: Hittable + Send + Sync>(mut scene: Scene<T>, backend: Backend) -> RenderStats {
+    scene.config.backend = backend;
+    let (img, stats) = render_scene(&scene);
+    write_rgba_image(&img, &scene.config);
+    stats
+}
+
+/// What `Scene::render` and the free functions share: warn about setup mistakes, render with
+/// `config.backend` and report how it went.
+fn render_scene<T: Hittable + Send + Sync>(scene: &Scene<T>) -> (image::RgbaImage, RenderStats) {
+    warn_invalid(scene);
+    let stats = RenderStats::for_world(&scene.world);
+    let img = match scene.config.backend {
+        Backend::Tiled        => render_tiled(scene, &stats),
+        Backend::Rows         => render_rows(scene, &stats),
+        Backend::SingleThread => render_single(scene, &stats),
+    };
+    report(&scene.config, &stats);
+    (img, stats)
+}
+
+/// Backend of `multi_thread_render`.
+fn render_tiled<T: Hittable + Send + Sync>(scene: &Scene<T>, stats: &RenderStats) -> image::RgbaImage {
+    let Scene { world, camera, config } = scene;
+
+    let base_seed = config.base_seed();
+
+    let width = config.width as u32;
+    let height = config.height as u32;
+
+    if config.is_buffered() {
+        return in_thread_pool(config.threads, || {
+            render_buffered(world, camera, config, stats, base_seed)
+        }).image;
+    }
+
+    let count = AtomicUsize::new(0);
+
+    let columns = (width as usize).div_ceil(TILE_SIZE);
+    let rows = (height as usize).div_ceil(TILE_SIZE);
+    let tiles = config.tile_order.tiles(columns, rows);
+    let shared_img = Mutex::new(image::RgbaImage::new(width, height));
+
+    in_thread_pool(config.threads, || {
+        // Bridging hands out the tiles in order, as threads become free.
+        tiles.into_iter().par_bridge().for_each(|(column, row)| {
+            if is_cancelled(config) {
+                return;
+            }
+
+            let (x0, y0) = ((column * TILE_SIZE) as u32, (row * TILE_SIZE) as u32);
+            let tile_width = TILE_SIZE.min(width as usize - x0 as usize) as u32;
+            let tile_height = TILE_SIZE.min(height as usize - y0 as usize) as u32;
+
+            let tile = image::RgbaImage::from_fn(tile_width, tile_height, |x, y| {
+                render_pixel(world, camera, config, stats, base_seed, x0 + x, y0 + y)
+            });
+            shared_img.lock().unwrap().copy_from(&tile, x0, y0).unwrap();
+
+            let oldval = count.fetch_add((tile_width * tile_height) as usize, Ordering::SeqCst);
+            let percent = (oldval as Real * 100.0) / (width * height) as Real;
+            eprint!("\r[{:03.0}%] Rendering", percent);
+        });
+    });
+
+    shared_img.into_inner().unwrap()
+}
+
+/// Backend of `simple_multi_thread_render`.
+fn render_rows<T: Hittable + Send + Sync>(scene: &Scene<T>, stats: &RenderStats) -> image::RgbaImage {
+    let Scene { world, camera, config } = scene;
+
+    let base_seed = config.base_seed();
+
+    let width = config.width as u32;
+    let height = config.height as u32;
+
+    if config.is_buffered() {
+        return in_thread_pool(config.threads, || {
+            render_buffered(world, camera, config, stats, base_seed)
+        }).image;
+    }
+
+    let mut img = image::RgbaImage::new(width, height);
+
+    let count = AtomicUsize::new(0);
+
+    let render_row = |y, row: &mut [u8]| {
+        if is_cancelled(config) {
+            return;
+        }
+
+        let row_iter = row
+            .as_chunks_mut().0  // &mut [[u8; 4]]
+            .iter_mut()         // impl Iterator<Item = &mut [u8; 4]>
+            .map(rgba_mut_ref); // impl Iterator<Item = &mut Rgba<u8>>
+
+        for (x, pixel) in row_iter.enumerate() {
+            *pixel = render_pixel(world, camera, config, stats, base_seed, x as u32, y as u32);
+
+            let oldval = count.fetch_add(1, Ordering::SeqCst);
+            if oldval.is_multiple_of(60) {
+                let percent = (oldval as Real * 100.0) / (width * height) as Real;
+                eprint!("\r[{:03.0}%] Rendering", percent);
+            }
+        }
+    };
+
+    in_thread_pool(config.threads, || {
+        img
+            .par_chunks_exact_mut(width as usize * 4)
+            .enumerate()
+            .for_each(|(y, row)| render_row(y, row));
+    });
+
+    img
+}
+
+/// Backend of `single_thread_render`.
+fn render_single<T: Hittable + Sync>(scene: &Scene<T>, stats: &RenderStats) -> image::RgbaImage {
+    let Scene { world, camera, config } = scene;
+
+    let base_seed = config.base_seed();
+
+    let width = config.width as u32;
+    let height = config.height as u32;
+
+    if config.is_buffered() {
+        return in_thread_pool(Some(1), || render_buffered(world, camera, config, stats, base_seed)).image;
+    }
+
+    let mut img = image::RgbaImage::new(width, height);
+
+    let mut count = 0;
+    for (x, y, pixel) in img.enumerate_pixels_mut() {
+        if x == 0 && is_cancelled(config) {
+            break;
+        }
+
+        *pixel = render_pixel(world, camera, config, stats, base_seed, x, y);
+
+        count += 1;
+        let percent = (count as Real * 100.0) / (width * height) as Real;
+        eprint!("\r[{:03.0}%] Rendering", percent);
+    }
+
+    img
+}
+
+/// End the progress line and print how the render went.
+fn report(config: &Render, stats: &RenderStats) {
+    if is_cancelled(config) {
+        eprintln!("\nCancelled!");
+    } else {
+        eprintln!("\nDone!");
+    }
+    eprintln!("{}", stats);
+}
+
+/// A rectangle of pixels in image coordinates, `y` going down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Rect {
+    pub fn new(x: usize, y: usize, width: usize, height: usize) -> Rect {
+        Rect { x, y, width, height }
+    }
+}
+
+/// An image rendered in memory, along with how many samples went into each of its pixels.
+#[derive(Debug, Clone)]
+pub struct RenderOutput {
+    pub image: image::RgbaImage,
+    /// Number of samples taken in each pixel, in image order.
+    pub samples: Vec<usize>,
+}
+
+impl RenderOutput {
+    /// Image of where samples were spent, going from black in the pixels with the fewest samples
+    /// through red to yellow in the ones with the most. All black when every pixel got the same.
+    pub fn sample_heatmap(&self) -> image::RgbImage {
+        let min = self.samples.iter().copied().min().unwrap_or(0);
+        let max = self.samples.iter().copied().max().unwrap_or(0);
+        let range = (max - min).max(1) as Real;
+
+        let mut img = image::RgbImage::new(self.image.width(), self.image.height());
+        for (pixel, &samples) in img.pixels_mut().zip(&self.samples) {
+            let heat = ((samples - min) as Real / range * 510.0) as u16;
+            *pixel = image::Rgb([heat.min(255) as u8, heat.saturating_sub(255) as u8, 0]);
+        }
+        img
+    }
+}
+
+/// Render the whole image in memory as tightly packed RGBA bytes, like the browser's `ImageData`
+/// expects. Alpha is opaque unless `Render::alpha` is set. Nothing is encoded or written to
+/// stdout.
+pub fn render_to_rgba<T: Hittable + Send + Sync>(scene: Scene<T>) -> Vec<u8> {
+    render_to_output(scene).image.into_raw()
+}
+
+/// Render the whole image in memory, keeping track of the samples taken in each pixel.
+pub fn render_to_output<T: Hittable + Send + Sync>(scene: Scene<T>) -> RenderOutput {
+    warn_invalid(&scene);
+
+    if scene.config.is_buffered() {
+        let Scene { world, camera, config } = &scene;
+        let base_seed = config.base_seed();
+        let stats = RenderStats::new();
+        in_thread_pool(config.threads, || render_buffered(world, camera, config, &stats, base_seed))
+    } else {
+        let Render { width, height, .. } = scene.config;
+        let samples = (0..height as u32)
+            .flat_map(|y| (0..width as u32).map(move |x| (x, y)))
+            .map(|(x, y)| scene.config.samples_at(x, y))
+            .collect();
+        RenderOutput { image: render_region(&scene, Rect::new(0, 0, width, height)), samples }
+    }
+}
+
+/// Render only the pixels inside of `region`. Every pixel is seeded from its index in the full
+/// image, so with a fixed seed the result matches the same region of `multi_thread_render`.
+///
+/// # Panics
+///
+/// If `region` doesn't fit in the image.
+pub fn render_region<T: Hittable + Send + Sync>(scene: &Scene<T>, region: Rect) -> image::RgbaImage {
+    render_region_with_stats(scene, region, &RenderStats::new())
+}
+
+fn render_region_with_stats<T: Hittable + Send + Sync>(
+    scene: &Scene<T>,
+    region: Rect,
+    stats: &RenderStats,
+) -> image::RgbaImage {
+    render_seeded_region(scene, region, stats, scene.config.base_seed())
+}
+
+/// Same as `render_region_with_stats`, with the seed already picked, so that regions rendered
+/// separately without a `seed` in the config still fit together.
+fn render_seeded_region<T: Hittable + Send + Sync>(
+    scene: &Scene<T>,
+    region: Rect,
+    stats: &RenderStats,
+    base_seed: u64,
+) -> image::RgbaImage {
+    let Scene { world, camera, config } = scene;
+
+    assert!(
+        region.x + region.width <= config.width && region.y + region.height <= config.height,
+        "region {:?} is outside of the {}x{} image", region, config.width, config.height
+    );
+
+    let mut img = image::RgbaImage::new(region.width as u32, region.height as u32);
+
+    if region.width == 0 {
+        return img;
+    }
+
+    in_thread_pool(config.threads, || {
+        img
+            .par_chunks_exact_mut(region.width * 4)
+            .enumerate()
+            .for_each(|(row, pixels)| {
+                if is_cancelled(config) {
+                    return;
+                }
+
+                let y = (region.y + row) as u32;
+                let pixels = pixels.as_chunks_mut().0.iter_mut().map(rgba_mut_ref);
+
+                for (col, pixel) in pixels.enumerate() {
+                    let x = (region.x + col) as u32;
+                    *pixel = render_pixel(world, camera, config, stats, base_seed, x, y);
+                }
+            });
+    });
+
+    img
+}
+
+/// Render the image `strip_height` rows at a time, writing each strip to stdout as soon as it is
+/// done, so that only one strip is ever in memory. Always written as binary PPM, whatever
+/// `config.output` says, since it can be encoded a row at a time. Like `render_region`, the
+/// options that need the whole image in memory are ignored, and the image matches the one from
+/// `multi_thread_render` without them.
+///
+/// # Errors
+///
+/// If writing to stdout fails, e.g. because it was piped into a program that exited early.
+///
+/// # Panics
+///
+/// If `strip_height` is 0.
+pub fn strip_render<T: Hittable + Send + Sync>(scene: Scene<T>, strip_height: usize) -> std::io::Result<RenderStats> {
+    warn_invalid(&scene);
+    let stats = RenderStats::for_world(&scene.world);
+    let stdout = std::io::stdout();
+    // Picked once, a random seed for every strip would leave seams between them.
+    let base_seed = scene.config.base_seed();
+    write_strips(&scene, strip_height, &stats, base_seed, stdout.lock())?;
+    report(&scene.config, &stats);
+    Ok(stats)
+}
+
+fn write_strips<T: Hittable + Send + Sync>(
+    scene: &Scene<T>,
+    strip_height: usize,
+    stats: &RenderStats,
+    base_seed: u64,
+    mut out: impl std::io::Write,
+) -> std::io::Result<()> {
+    assert!(strip_height > 0, "strips must be at least a row high");
+
+    let Render { width, height, .. } = scene.config;
+    write!(out, "P6\n{} {}\n255\n", width, height)?;
+
+    for y in (0..height).step_by(strip_height) {
+        let strip = Rect::new(0, y, width, strip_height.min(height - y));
+        let img: image::RgbImage = render_seeded_region(scene, strip, stats, base_seed).convert();
+        out.write_all(&img)?;
+
+        let percent = (y + strip.height) as Real * 100.0 / height as Real;
+        eprint!("\r[{:03.0}%] Rendering", percent);
+    }
+
+    out.flush()
+}
+
+/// Dither of pixel `(x, y)` of the image for `utils::encode_rgb`, when `config.dither` is set.
+fn dither_at(config: &Render, x: u32, y: u32) -> Option<Real> {
+    config.dither.then(|| BlueNoise::shared().get(x, y).0)
+}
+
+/// Render pixel `(x, y)` of the image, with `y` going down, drawing samples from a generator
+/// seeded with the pixel index.
+fn render_pixel<T: Hittable>(
+    world: &T,
+    camera: &Camera,
+    config: &Render,
+    stats: &RenderStats,
+    base_seed: u64,
+    x: u32,
+    y: u32,
+) -> image::Rgba<u8> {
+    let index = y as u64 * config.width as u64 + x as u64;
+    utils::seed_thread_rng(base_seed ^ index);
+    let dither = dither_at(config, x, y);
+    let samples = config.samples_at(x, y);
+
+    // Invert the y coordinate so higher of y go up.
+    let y = config.height as u32 - y;
+
+    let sum = accumulate_samples(world, camera, config, stats, x, y, 0..samples);
+    utils::encode_rgba(sum.color(), sum.alpha(), 1.0, config.tone_map, dither)
+}
+
+/// Render every pixel with `config.samples_per_pixel` samples into a buffer, which is then
+/// post processed as a whole: pixels on edges get more samples with `config.edge_supersampling`,
+/// the image is shrunk down with `config.ssaa` and exposed with `config.auto_exposure`.
+fn render_buffered<T: Hittable + Sync>(
+    world: &T,
+    camera: &Camera,
+    config: &Render,
+    stats: &RenderStats,
+    base_seed: u64,
+) -> RenderOutput {
+    let (sums, samples) = buffered_sums(world, camera, config, stats, base_seed);
+
+    if let Some(path) = &config.exr_aovs {
+        if let Err(err) = write_exr_aovs(path, &sums, config.width, config.height) {
+            eprintln!("\n[warning] failed to write {}: {}", path.display(), err);
+        }
+    }
+
+    let exposure = config.auto_exposure.map_or(1.0, |target| exposure_scale(&sums, target));
+
+    let mut image = image::RgbaImage::new(config.width as u32, config.height as u32);
+    for ((x, y, pixel), sum) in image.enumerate_pixels_mut().zip(sums) {
+        let dither = dither_at(config, x, y);
+        *pixel = utils::encode_rgba(sum.color(), sum.alpha(), exposure, config.tone_map, dither);
+    }
+    RenderOutput { image, samples }
+}
+
+/// Weighted sums of the samples of every pixel of `render_buffered`, and how many samples went
+/// into each, in image order. With `config.ssaa`, they are rendered larger and added up in
+/// blocks, which box filters the linear radiance.
+fn buffered_sums<T: Hittable + Sync>(
+    world: &T,
+    camera: &Camera,
+    config: &Render,
+    stats: &RenderStats,
+    base_seed: u64,
+) -> (Vec<PixelSum>, Vec<usize>) {
+    if let Some(factor) = config.ssaa {
+        let large = Render {
+            width: config.width * factor as usize,
+            height: config.height * factor as usize,
+            ssaa: None,
+            sample_map: config.sample_map.as_ref().map(|map| map.scaled(factor as usize)),
+            // Written at the final size by `render_buffered`.
+            exr_aovs: None,
+            ..config.clone()
+        };
+        let (sums, samples) = buffered_sums(world, camera, &large, stats, base_seed);
+        return (
+            downsample(&sums, config.width, config.height, factor as usize),
+            downsample(&samples, config.width, config.height, factor as usize),
+        );
+    }
+
+    let width = config.width;
+    let height = config.height;
+
+    // Weighted sums of the samples of each pixel, in image order.
+    let mut sums: Vec<PixelSum> = (0..width * height)
+        .into_par_iter()
+        .map(|i| {
+            if is_cancelled(config) {
+                return PixelSum::default();
+            }
+
+            let (x, y) = ((i % width) as u32, (i / width) as u32);
+            utils::seed_thread_rng(base_seed ^ i as u64);
+            let samples = config.samples_at(x, y);
+            accumulate_samples(world, camera, config, stats, x, height as u32 - y, 0..samples)
+        })
+        .collect();
+
+    let mut samples: Vec<usize> = (0..width * height)
+        .map(|i| config.samples_at((i % width) as u32, (i / width) as u32))
+        .collect();
+
+    if let Some(edges) = config.edge_supersampling {
+        let luminance: Vec<Real> = sums.iter()
+            .map(|sum| color::luminance(color::linear_to_srgb(sum.color())))
+            .collect();
+
+        let is_edge = |i: usize| {
+            let (x, y) = (i % width, i / width);
+            let neighbours = [
+                (x > 0).then(|| i - 1),
+                (x + 1 < width).then(|| i + 1),
+                (y > 0).then(|| i - width),
+                (y + 1 < height).then(|| i + width),
+            ];
+            neighbours.iter()
+                .flatten()
+                .any(|&n| (luminance[n] - luminance[i]).abs() > edges.threshold)
+        };
+
+        sums.par_iter_mut()
+            .zip(samples.par_iter_mut())
+            .enumerate()
+            .filter(|&(i, _)| is_edge(i))
+            .for_each(|(i, (sum, samples))| {
+                if is_cancelled(config) {
+                    return;
+                }
+
+                let (x, y) = ((i % width) as u32, (i / width) as u32);
+                // Different seed than the first pass, so the new samples aren't the same ones again.
+                utils::seed_thread_rng(!(base_seed ^ i as u64));
+                let y = height as u32 - y;
+                *sum += accumulate_samples(world, camera, config, stats, x, y, *samples..*samples + edges.edge_samples);
+                *samples += edges.edge_samples;
+            });
+    }
+
+    (sums, samples)
+}
+
+/// Write the radiance of the pixels along with what their camera rays hit first, for
+/// `Render::exr_aovs`.
+fn write_exr_aovs(path: &Path, sums: &[PixelSum], width: usize, height: usize) -> std::io::Result<()> {
+    // A no-op unless `Real` is `f64`.
+    #[allow(clippy::unnecessary_cast)]
+    let channel = |value: &dyn Fn(&PixelSum) -> Real| -> Vec<f32> {
+        sums.iter().map(|sum| value(sum) as f32).collect()
+    };
+    let layers = [
+        ("R", channel(&|sum| sum.color().x)),
+        ("G", channel(&|sum| sum.color().y)),
+        ("B", channel(&|sum| sum.color().z)),
+        ("A", channel(&|sum| sum.alpha())),
+        ("albedo.R", channel(&|sum| sum.albedo().x)),
+        ("albedo.G", channel(&|sum| sum.albedo().y)),
+        ("albedo.B", channel(&|sum| sum.albedo().z)),
+        ("normal.X", channel(&|sum| sum.normal().x)),
+        ("normal.Y", channel(&|sum| sum.normal().y)),
+        ("normal.Z", channel(&|sum| sum.normal().z)),
+        ("depth.Z", channel(&|sum| sum.depth())),
+    ];
+    let channels: Vec<exr::Channel> = layers.iter().map(|(name, values)| (*name, values.as_slice())).collect();
+
+    let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    exr::write(file, width, height, &channels)
+}
+
+/// Options of `progressive_render`.
+#[derive(Debug, Clone)]
+pub struct ProgressiveOpts {
+    /// Samples taken in every pixel on each pass over the image.
+    pub samples_per_pass: usize,
+    /// Write a thumbnail of the image so far whenever at least this long has passed since the
+    /// last one, or never when `None`.
+    pub thumbnail_every: Option<Duration>,
+    /// Width and height of the thumbnails.
+    pub thumbnail_size: (u32, u32),
+    /// Where the thumbnails are written, as JPEG. Each one replaces the last.
+    pub thumbnail_path: PathBuf,
+}
+
+impl Default for ProgressiveOpts {
+    fn default() -> Self {
+        ProgressiveOpts {
+            samples_per_pass: 1,
+            thumbnail_every: None,
+            thumbnail_size: (160, 90),
+            thumbnail_path: PathBuf::from("preview.jpg"),
+        }
+    }
+}
+
+/// Render the whole image in memory in passes of `opts.samples_per_pass` samples per pixel, until
+/// `config.stop` is met or else every pixel has `config.samples_per_pixel` of them, writing
+/// thumbnails of the image so far along the way. With a `config.sample_map` pixels stop at their
+/// own count instead. Cancelling stops after the current pass. Edge supersampling isn't supported.
+pub fn progressive_render<T: Hittable + Send + Sync>(scene: Scene<T>, opts: &ProgressiveOpts) -> RenderOutput {
+    warn_invalid(&scene);
+
+    let Scene { world, camera, config } = scene;
+
+    let base_seed = config.base_seed();
+    let width = config.width;
+    let height = config.height;
+
+    let stats = RenderStats::new();
+    let mut sums = vec![PixelSum::default(); width * height];
+    // Samples each pixel is done at, which the whole image is with a single count.
+    let limits: Vec<usize> = (0..width * height)
+        .map(|i| config.samples_at((i % width) as u32, (i / width) as u32))
+        .collect();
+    let max_samples = limits.iter().copied().max().unwrap_or(0);
+    let mut samples = 0;
+    let mut pass = 0u64;
+    let stop = config.stop.unwrap_or(StopCondition::Samples(max_samples));
+    let start = Instant::now();
+    let mut last_thumbnail = start;
+
+    in_thread_pool(config.threads, || {
+        while !stop.is_met(samples, start.elapsed()) && !is_cancelled(&config) {
+            let pass_samples = opts.samples_per_pass.clamp(1, stop.samples_left(samples));
+
+            sums.par_iter_mut()
+                .zip(&limits)
+                .enumerate()
+                .for_each(|(i, (sum, &limit))| {
+                    let pixel_samples = samples..(samples + pass_samples).min(limit);
+                    if pixel_samples.is_empty() {
+                        return;
+                    }
+                    let (x, y) = ((i % width) as u32, (i / width) as u32);
+                    // Every pass needs different samples, so the pass goes in the high bits.
+                    utils::seed_thread_rng(base_seed ^ i as u64 ^ pass.rotate_right(16));
+                    let y = height as u32 - y;
+                    *sum += accumulate_samples(&world, &camera, &config, &stats, x, y, pixel_samples);
+                });
+
+            samples += pass_samples;
+            pass += 1;
+            eprint!("\r[{:03.0}%] Rendering", stop.progress(samples, start.elapsed()) * 100.0);
+
+            if opts.thumbnail_every.is_some_and(|every| last_thumbnail.elapsed() >= every) {
+                let exposure = config.auto_exposure.map_or(1.0, |target| exposure_scale(&sums, target));
+                let thumbnail = thumbnail(&sums, width, height, opts.thumbnail_size, exposure, config.tone_map);
+                if let Err(err) = thumbnail.save_with_format(&opts.thumbnail_path, image::ImageFormat::Jpeg) {
+                    eprintln!("\n[warning] failed to write the thumbnail: {}", err);
+                }
+                last_thumbnail = Instant::now();
+            }
+        }
+    });
+
+    if is_cancelled(&config) {
+        eprintln!("\nCancelled!");
+    } else {
+        eprintln!("\nDone!");
+    }
+    eprintln!("{}", stats);
+
+    let exposure = config.auto_exposure.map_or(1.0, |target| exposure_scale(&sums, target));
+    let mut image = image::RgbaImage::new(width as u32, height as u32);
+    for ((x, y, pixel), sum) in image.enumerate_pixels_mut().zip(&sums) {
+        let dither = dither_at(&config, x, y);
+        *pixel = utils::encode_rgba(sum.color(), sum.alpha(), exposure, config.tone_map, dither);
+    }
+    let samples = limits.iter().map(|&limit| limit.min(samples)).collect();
+    RenderOutput { image, samples }
+}
+
+/// Shrink the `width` by `height` image accumulated in `sums` down to `size`, averaging each box
+/// of pixels that falls in a thumbnail pixel.
+fn thumbnail(sums: &[PixelSum], width: usize, height: usize, size: (u32, u32), exposure: Real, tone_map: ToneMap) -> image::RgbImage {
+    let (thumb_width, thumb_height) = (size.0 as usize, size.1 as usize);
+
+    // Pixels `start..end` of the image along an axis of `len` pixels fall in thumbnail pixel `i`.
+    let span = |i: usize, len: usize, thumb_len: usize| {
+        let start = i * len / thumb_len;
+        start..((i + 1) * len / thumb_len).max(start + 1).min(len)
+    };
+
+    image::RgbImage::from_fn(size.0, size.1, |tx, ty| {
+        let mut total = color::black();
+        let mut count = 0;
+        for y in span(ty as usize, height, thumb_height) {
+            for x in span(tx as usize, width, thumb_width) {
+                total += sums[y * width + x].color();
+                count += 1;
+            }
+        }
+        let average = if count > 0 { total / count as Real } else { total };
+        utils::encode_rgb(average, exposure, tone_map, None)
+    })
+}
+
+/// Shrink a buffer `factor` times larger than `width` by `height` down to it, adding up each
+/// block of pixels.
+fn downsample<P: Copy + Default + std::ops::AddAssign>(large: &[P], width: usize, height: usize, factor: usize) -> Vec<P> {
+    let large_width = width * factor;
+    (0..width * height)
+        .map(|i| {
+            let (x, y) = ((i % width) * factor, (i / width) * factor);
+            let mut total = P::default();
+            for y in y..y + factor {
+                for &pixel in &large[y * large_width + x..y * large_width + x + factor] {
+                    total += pixel;
+                }
+            }
+            total
+        })
+        .collect()
+}
+
+/// Scale that brings the geometric mean luminance of the covered pixels to `target`. The
+/// geometric mean keeps a few very bright pixels from darkening the whole image.
+fn exposure_scale(sums: &[PixelSum], target: Real) -> Real {
+    // Keeps black pixels from taking the mean to 0.
+    const DELTA: Real = 1e-4;
+
+    let covered = sums.iter().filter(|sum| sum.coverage > 0.0);
+    let (log_sum, count) = covered.fold((0.0, 0), |(log_sum, count), sum| {
+        (log_sum + (DELTA + color::luminance(sum.color()).max(0.0)).ln(), count + 1)
+    });
+
+    if count == 0 {
+        return 1.0;
+    }
+
+    let mean = (log_sum / count as Real).exp();
+    target / mean
+}
+
+/// Weighted sum of the samples taken in a pixel.
+#[derive(Debug, Clone, Copy, Default)]
+struct PixelSum {
+    /// Sum of the samples whose camera ray hit the world, or of all of them without
+    /// `Render::alpha`.
+    color: Color,
+    /// Sum of the weights of all samples.
+    weight: Real,
+    /// Sum of the weights of the samples in `color`.
+    coverage: Real,
+    /// Sum of the weights of the samples whose camera ray hit the world, which also weigh the
+    /// sums of what it hit below.
+    hits: Real,
+    albedo: Color,
+    normal: Vec3,
+    depth: Real,
+}
+
+impl PixelSum {
+    /// Average of the samples in `color`, not blended with the background when it is
+    /// transparent.
+    fn color(&self) -> Color {
+        if self.coverage > 0.0 { self.color / self.coverage } else { color::black() }
+    }
+
+    fn alpha(&self) -> Real {
+        if self.weight > 0.0 { self.coverage / self.weight } else { 0.0 }
+    }
+
+    /// Average albedo of the surfaces the camera rays hit first.
+    fn albedo(&self) -> Color {
+        if self.hits > 0.0 { self.albedo / self.hits } else { color::black() }
+    }
+
+    /// Average normal of the surfaces the camera rays hit first, zero when they hit nothing.
+    fn normal(&self) -> Vec3 {
+        self.normal.try_normalize(0.0).unwrap_or_else(Vec3::zeros)
+    }
+
+    /// Average distance to the surfaces the camera rays hit first, infinite when they hit nothing.
+    fn depth(&self) -> Real {
+        if self.hits > 0.0 { self.depth / self.hits } else { Real::INFINITY }
+    }
+}
+
+impl std::ops::AddAssign for PixelSum {
+    fn add_assign(&mut self, other: PixelSum) {
+        self.color += other.color;
+        self.weight += other.weight;
+        self.coverage += other.coverage;
+        self.hits += other.hits;
+        self.albedo += other.albedo;
+        self.normal += other.normal;
+        self.depth += other.depth;
+    }
+}
+
+/// Weighted sum of the samples numbered `samples` of pixel `(x, y)`, with `y` going up. Renders
+/// that come back to a pixel for more samples carry on the numbering, so that the blue noise
+/// jitter doesn't repeat the same offsets.
+fn accumulate_samples<T: Hittable>(
+    world: &T,
+    camera: &Camera,
+    config: &Render,
+    stats: &RenderStats,
+    x: u32,
+    y: u32,
+    samples: Range<usize>,
+) -> PixelSum {
+    let width = config.width as Real;
+    let height = config.height as Real;
+
+    // Pixels are seeded through the thread local generator.
+    let mut rng = utils::LocalRng;
+
+    let strata = config.stratified.then(|| Strata::new(samples.len(), &mut rng));
+
+    let trace = TraceConfig {
+        max_depth: config.max_bounces,
+        background: &config.background,
+        lights: &config.lights,
+        max_t: config.max_t,
+        error_color: config.error_color,
+    };
+
+    // Sequential on purpose, the renderers already spread the pixels over the threads and nesting
+    // parallel work in here only adds scheduling overhead.
+    let mut sum = PixelSum::default();
+    let mut bounces = 0;
+    for (k, i) in samples.clone().enumerate() {
+        // Offset from the center of the pixel.
+        let (dx, dy) = match &strata {
+            Some(strata) => strata.pixel_offset(k, &mut rng),
+            None         => config.jitter.pixel_offset(x, y, i, &mut rng),
+        };
+        let weight = config.filter.weight(dx, dy);
+
+        // Every pixel covers an equal share of the view, so that renders of the same scene at
+        // different sizes line up. After flipping, `y` goes from 1 at the bottom row to `height`.
+        let u = (x as Real + 0.5 + dx) / width;
+        let v = (y as Real - 0.5 + dy) / height;
+
+        let ray = match &strata {
+            Some(strata) => {
+                let (lens_u, lens_v) = strata.lens_point(k, &mut rng);
+                camera.get_ray_through_lens(u, v, lens_u, lens_v)
+            }
+            None => camera.get_ray(u, v, &mut rng),
+        };
+        if config.traversal_stats {
+            hittable::start_traversal_counts();
+        }
+        let path = if let Some(ao) = config.ambient_occlusion {
+            ray.trace_ao(world, &trace, ao.samples, ao.radius)
+        } else if let Some(lights) = &config.preview_lights {
+            ray.trace_preview(world, &trace, lights)
+        } else if let Some(max_rays) = config.fresnel_split {
+            ray.trace_split(world, &trace, max_rays)
+        } else {
+            ray.trace(world, &trace)
+        };
+        if config.traversal_stats {
+            stats.record_traversal(hittable::finish_traversal_counts());
+        }
+        sum.weight += weight;
+        bounces += path.bounces;
+
+        if let Some(hit) = &path.first_hit {
+            sum.hits += weight;
+            sum.albedo += hit.albedo * weight;
+            sum.normal += hit.normal.0 * weight;
+            sum.depth += hit.distance * weight;
+        }
+
+        if config.alpha && path.first_hit.is_none() {
+            continue;
+        }
+        // Only along the camera ray, bounces see surfaces without fog.
+        let color = match (&config.fog, &path.first_hit) {
+            (Some(fog), Some(hit)) => fog.apply(path.color, hit.distance),
+            _                      => path.color,
+        };
+        let color = camera.apply_bokeh_gain(color, path.first_hit.as_ref().map(|hit| &hit.point.0));
+        sum.color += clamp_sample(color, config.firefly_clamp) * weight;
+        sum.coverage += weight;
+    }
+    stats.record(samples.len() as u64, bounces as u64);
+
+    sum
+}
+
+/// Splits both the pixel and the lens into a grid of cells, and pairs every pixel cell with a
+/// different lens cell, so that the samples of a pixel cover both evenly.
+struct Strata {
+    grid: usize,
+    /// Lens cell of each pixel cell.
+    lens_cells: Vec<usize>,
+}
+
+impl Strata {
+    fn new(samples: usize, rng: &mut impl Rng) -> Strata {
+        let grid = ((samples as Real).sqrt() as usize).max(1);
+        let mut lens_cells: Vec<usize> = (0..grid * grid).collect();
+        lens_cells.shuffle(rng);
+        Strata { grid, lens_cells }
+    }
+
+    /// Random point in `cell`, within the unit square.
+    fn point_in(&self, cell: usize, rng: &mut impl Rng) -> (Real, Real) {
+        let grid = self.grid as Real;
+        let i = (cell % self.grid) as Real;
+        let j = (cell / self.grid) as Real;
+        ((i + rng.gen::<Real>()) / grid, (j + rng.gen::<Real>()) / grid)
+    }
+
+    /// Offset from the pixel center of sample `i`.
+    fn pixel_offset(&self, i: usize, rng: &mut impl Rng) -> (Real, Real) {
+        let (x, y) = self.point_in(i % self.lens_cells.len(), rng);
+        (x - 0.5, y - 0.5)
+    }
+
+    /// Point on the lens of sample `i`, within the unit square.
+    fn lens_point(&self, i: usize, rng: &mut impl Rng) -> (Real, Real) {
+        self.point_in(self.lens_cells[i % self.lens_cells.len()], rng)
+    }
+}
+
+/// Drop samples that went NaN or infinite along the way, so a single degenerate scatter can't
+/// poison the whole pixel, and limit the luminance to `max_luminance` if given.
+fn clamp_sample(sample: Color, max_luminance: Option<Real>) -> Color {
+    if !sample.iter().all(|c| c.is_finite()) {
+        return color::black();
+    }
+
+    match max_luminance {
+        Some(max) if color::luminance(sample) > max => sample * (max / color::luminance(sample)),
+        _                                          => sample,
+    }
+}
+
+#[inline]
+fn is_cancelled(config: &Render) -> bool {
+    config.cancel.as_ref().is_some_and(|cancel| cancel.load(Ordering::Relaxed))
+}
+
+/// Like `write_image`, but keeping the alpha channel when the config asks for it and the format
+/// supports it.
+fn write_rgba_image(img: &image::RgbaImage, config: &Render) {
+    if config.alpha && config.output == OutputFormat::Png {
+        let stdout = std::io::stdout();
+        let encoder = image::codecs::png::PngEncoder::new(stdout.lock());
+        encoder.encode(img, img.width(), img.height(), image::ColorType::Rgba8).unwrap();
+    } else {
+        write_image(&img.convert(), &config.output);
+    }
+}
+
+/// Encode the image to stdout.
+fn write_image(img: &image::RgbImage, format: &OutputFormat) {
+    let stdout = std::io::stdout();
+    encode_image(img, format, stdout.lock()).unwrap();
+}
+
+fn encode_image(img: &image::RgbImage, format: &OutputFormat, mut out: impl std::io::Write) -> image::ImageResult<()> {
+    match *format {
+        OutputFormat::Jpeg { quality, chroma } => {
+            let mut encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut out, quality);
+            match chroma {
+                ChromaSubsampling::Full => encoder.encode_image(img),
+                ChromaSubsampling::Half => encoder.encode_image(&average_chroma(img)),
+            }
+        }
+
+        OutputFormat::Png => {
+            let encoder = image::codecs::png::PngEncoder::new(&mut out);
+            encoder.encode(img, img.width(), img.height(), image::ColorType::Rgb8)
+        }
+    }
+}
+
+/// Replace the chroma of every 2x2 block of pixels with its average, keeping the luma of each
+/// pixel, in the YCbCr space JPEG uses.
+fn average_chroma(img: &image::RgbImage) -> image::RgbImage {
+    let to_ycbcr = |image::Rgb([r, g, b]): image::Rgb<u8>| {
+        let (r, g, b) = (r as f32, g as f32, b as f32);
+        [
+            0.299 * r + 0.587 * g + 0.114 * b,
+            -0.168_736 * r - 0.331_264 * g + 0.5 * b,
+            0.5 * r - 0.418_688 * g - 0.081_312 * b,
+        ]
+    };
+    let to_rgb = |[y, cb, cr]: [f32; 3]| {
+        let channel = |value: f32| value.round().clamp(0.0, 255.0) as u8;
+        image::Rgb([
+            channel(y + 1.402 * cr),
+            channel(y - 0.344_136 * cb - 0.714_136 * cr),
+            channel(y + 1.772 * cb),
+        ])
+    };
+
+    let (width, height) = img.dimensions();
+    let mut out = img.clone();
+    for y0 in (0..height).step_by(2) {
+        for x0 in (0..width).step_by(2) {
+            let block: Vec<_> = (y0..(y0 + 2).min(height))
+                .flat_map(|y| (x0..(x0 + 2).min(width)).map(move |x| (x, y)))
+                .collect();
+            let (mut cb, mut cr) = (0.0, 0.0);
+            for &(x, y) in &block {
+                let [_, pixel_cb, pixel_cr] = to_ycbcr(*img.get_pixel(x, y));
+                cb += pixel_cb / block.len() as f32;
+                cr += pixel_cr / block.len() as f32;
+            }
+            for &(x, y) in &block {
+                let [luma, _, _] = to_ycbcr(*img.get_pixel(x, y));
+                out.put_pixel(x, y, to_rgb([luma, cb, cr]));
+            }
+        }
+    }
+    out
+}
+
+fn rgba_mut_ref<T: image::Primitive>(data: &mut [T; 4]) -> &mut image::Rgba<T> {
+    // Safety: image::Rgba is repr(C) so it is transparent to the underlying data.
+    unsafe {
+        std::mem::transmute(data)
+    }
+}
+
+pub fn random_scene() -> Vec<Sphere<CommonMat>> {
+    use crate::objects::{ WorldBuilder, Sphere };
+    use crate::material::{ Dielectric, Diffuse, Metal };
+    use crate::utils::{ Vec3, Point3 };
+
+    let mut world_builder = WorldBuilder::default();
+
+    let ground_material = Diffuse::new(Color::new(0.5, 0.5, 0.5));
+    world_builder.with_ground_plane(0.0, ground_material);
+
+    for a in -11..11 {
+        for b in -11..11 {
+            let choose_mat = random::<Real>();
+            let center = nalgebra_glm::vec3(
+                a as Real + 0.9 * random::<Real>(),
+                0.2,
+                b as Real + 0.9 * random::<Real>(),
+            );
 
             if (center - nalgebra_glm::vec3(4.0, 0.2, 0.0)).magnitude() > 0.9 {
                 if choose_mat < 0.8 {
@@ -305,7 +2103,7 @@ pub fn random_scene() -> Vec<Sphere<CommonMat>> {
                 } else if choose_mat < 0.95 {
                     // metal
                     let albedo = color::random();
-                    let fuzz = random::<f32>() * 0.5;
+                    let fuzz = random::<Real>() * 0.5;
                     let sphere_material = Metal::new(albedo, fuzz);
                     world_builder.add(Sphere::new(center, 0.2, sphere_material.into()));
                 } else {
@@ -326,5 +2124,1043 @@ pub fn random_scene() -> Vec<Sphere<CommonMat>> {
     let material3 = Metal::new(Color::new(0.7, 0.6, 0.5), 0.0);
     world_builder.add(Sphere::new(Point3::new(4.0, 1.0, 0.0), 1.0, material3.into()));
 
-    return world_builder.build();
+    world_builder.build()
+}
+
+/// A glass ball half submerged in water, to show refraction between nested dielectrics.
+pub fn submerged_glass_scene() -> Vec<Sphere<CommonMat>> {
+    use crate::objects::{ WorldBuilder, Sphere };
+    use crate::material::{ Dielectric, Diffuse };
+    use crate::utils::Point3;
+
+    let mut world_builder = WorldBuilder::default();
+
+    // The surface of the water is at y = 0.
+    let water = Dielectric::new(1.33);
+    world_builder.with_ground_plane(0.0, water);
+
+    let floor = Diffuse::new(Color::new(0.8, 0.7, 0.5));
+    world_builder.with_ground_plane(-2.0, floor);
+
+    let glass = Dielectric::new(1.5);
+    world_builder.add(Sphere::new(Point3::new(0.0, 0.0, 0.0), 1.0, glass.into()));
+
+    let marker = Diffuse::new(Color::new(0.7, 0.1, 0.1));
+    world_builder.add(Sphere::new(Point3::new(-1.0, -1.5, -2.0), 0.5, marker.into()));
+
+    world_builder.build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hittable::Hit;
+    use crate::kdtree::KdTree;
+    use crate::material::{ Blend, Dielectric, Diffuse, Material, Metal, Scatter, ScatterEvent };
+    use crate::objects::WorldBuilder;
+    use crate::ray::Ray;
+    use crate::testing;
+    use crate::utils::Vec3;
+
+    /// Average the radiance arriving at pixel `(x, y)`, with `y` going up, weighting every sample
+    /// by the configured pixel filter.
+    fn sample_pixel<T: Hittable>(
+        world: &T,
+        camera: &Camera,
+        config: &Render,
+        stats: &RenderStats,
+        x: u32,
+        y: u32,
+    ) -> Color {
+        let samples = config.samples_at(x, config.height as u32 - y);
+        accumulate_samples(world, camera, config, stats, x, y, 0..samples).color()
+    }
+
+    fn tiny_scene(backend: Backend) -> Scene<Vec<Sphere<Diffuse>>> {
+        let config = RenderBuilder::new()
+            .with_dimensions(8, 6)
+            .with_samples(1)
+            .with_seed(1)
+            .with_backend(backend)
+            .build()
+            .unwrap();
+        let camera = Camera::new(
+            Vec3::new(0.0, 0.0, 3.0),
+            Vec3::zeros(),
+            Vec3::y(),
+            40.0,
+            config.aspect_ratio,
+            0.0,
+            3.0,
+        );
+        let world = vec![Sphere::new(Vec3::zeros(), 1.0, Diffuse::new(color::mid_gray()))];
+        Scene::new(world, camera, config)
+    }
+
+    /// Luminance variance of each pixel in row `y` over different seeds, added up over the row.
+    fn row_variance<T: Hittable>(world: &T, camera: &Camera, config: &Render, y: u32) -> Real {
+        (0..config.width as u32).map(|x| {
+            let values: Vec<Real> = (0..64).map(|seed| {
+                utils::seed_thread_rng(seed);
+                color::luminance(sample_pixel(world, camera, config, &RenderStats::new(), x, y))
+            })
+            .collect();
+            let mean = values.iter().sum::<Real>() / values.len() as Real;
+            values.iter().map(|v| (v - mean).powi(2)).sum::<Real>() / values.len() as Real
+        })
+        .sum()
+    }
+
+    #[test]
+    fn kd_tree_tests_fewer_primitives_than_a_list() {
+        fn primitive_tests<T: Hittable + Send + Sync>(world: T) -> u64 {
+            let mut scene = tiny_scene(Backend::Tiled);
+            scene.config.traversal_stats = true;
+            let scene = Scene::new(world, scene.camera, scene.config);
+
+            let stats = RenderStats::new();
+            render_region_with_stats(&scene, Rect::new(0, 0, 8, 6), &stats);
+            assert!(stats.max_primitive_tests() <= stats.primitive_tests());
+            stats.primitive_tests()
+        }
+
+        let spheres: Vec<_> = (0..100)
+            .map(|i| {
+                let center = Vec3::new((i % 10) as Real * 0.2 - 0.9, (i / 10) as Real * 0.15 - 0.7, 0.0);
+                Sphere::new(center, 0.05, Diffuse::new(color::mid_gray()))
+            })
+            .collect();
+
+        let list = primitive_tests(spheres.clone());
+        let tree = primitive_tests(KdTree::new(spheres));
+        assert!(tree > 0);
+        assert!(tree * 5 < list, "{} tests with the kd-tree, {} without", tree, list);
+    }
+
+    #[test]
+    fn stats_count_the_objects_of_the_world() {
+        use crate::objects::Triangle;
+
+        utils::seed_thread_rng(5);
+        let spheres: Vec<_> = (0..100)
+            .map(|_| Vec3::new(random(), random(), random()) * 10.0)
+            .map(|center| Sphere::new(center, 0.1, Diffuse::new(color::mid_gray())))
+            .collect();
+        let triangle = |z: Real| Triangle::new(
+            [Vec3::new(0.0, 0.0, z), Vec3::new(1.0, 0.0, z), Vec3::new(0.0, 1.0, z)],
+            Diffuse::new(color::mid_gray()),
+        );
+
+        let mut world = BoxedHitList::empty();
+        world.add(KdTree::new(spheres));
+        world.add(vec![triangle(-1.0), triangle(-2.0)]);
+        world.add(Sphere::new(Vec3::new(0.0, -100.0, 0.0), 99.0, Diffuse::new(color::mid_gray())));
+
+        let stats = RenderStats::for_world(&world);
+        let scene = stats.scene();
+        assert_eq!((scene.objects, scene.triangles), (103, 2));
+        // 100 spheres in leaves of at most 4, split roughly in half at each level.
+        assert!((6..=10).contains(&scene.tree_depth), "{}", scene.tree_depth);
+        assert!(stats.to_string().contains("103 objects (2 triangles)"), "{}", stats);
+
+        assert_eq!(RenderStats::new().scene(), SceneStats::default());
+    }
+
+    #[test]
+    fn exr_aovs_have_the_depth_of_the_sphere() {
+        let path = std::env::temp_dir().join(format!("raytracing-aovs-{}.exr", std::process::id()));
+
+        let mut scene = tiny_scene(Backend::Tiled);
+        scene.config = RenderBuilder::new()
+            .with_dimensions(9, 7)
+            .with_samples(4)
+            .with_seed(1)
+            .with_exr_aovs(&path)
+            .build()
+            .unwrap();
+        let output = render_to_output(scene);
+        assert_eq!(output.image.dimensions(), (9, 7));
+
+        let file = std::fs::File::open(&path).unwrap();
+        let image = exr::read(file).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!((image.width, image.height), (9, 7));
+
+        let names: Vec<_> = image.channels.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, [
+            "A", "B", "G", "R",
+            "albedo.B", "albedo.G", "albedo.R",
+            "depth.Z",
+            "normal.X", "normal.Y", "normal.Z",
+        ]);
+
+        // The camera is 3 away from the center of the unit sphere.
+        let center = 3 * 9 + 4;
+        let depth = image.channel("depth.Z").unwrap();
+        assert!((depth[center] - 2.0).abs() < 1e-2, "{}", depth[center]);
+        assert_eq!(depth[0], f32::INFINITY);
+        assert!((image.channel("normal.Z").unwrap()[center] - 1.0).abs() < 1e-2);
+        assert_eq!(image.channel("albedo.G").unwrap()[center], 0.5);
+    }
+
+    #[test]
+    fn supersampled_exr_aovs_have_the_final_size() {
+        let path = std::env::temp_dir().join(format!("raytracing-ssaa-aovs-{}.exr", std::process::id()));
+
+        let mut scene = tiny_scene(Backend::Tiled);
+        scene.config = RenderBuilder::new()
+            .with_dimensions(9, 7)
+            .with_seed(1)
+            .with_ssaa(2)
+            .with_exr_aovs(&path)
+            .build()
+            .unwrap();
+        let output = render_to_output(scene);
+
+        let file = std::fs::File::open(&path).unwrap();
+        let image = exr::read(file).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!((image.width, image.height), (9, 7));
+
+        // Same radiance as the image, before encoding.
+        let center = 3 * 9 + 4;
+        let red = image.channel("R").unwrap()[center];
+        let encoded = utils::encode_rgb(color::new(red as Real, 0.0, 0.0), 1.0, ToneMap::default(), None);
+        assert_eq!(encoded.0[0], output.image.get_pixel(4, 3).0[0]);
+        assert!((image.channel("depth.Z").unwrap()[center] - 2.0).abs() < 1e-2);
+    }
+
+    #[test]
+    fn distant_surfaces_are_foggier() {
+        let fog = color::new(1.0, 0.0, 0.0);
+        let center_pixel = |distance: Real| {
+            let mut scene = tiny_scene(Backend::Tiled);
+            scene.config.samples_per_pixel = 16;
+            scene.config.fog = Some(Fog { color: fog, density: 0.2 });
+            scene.camera = Camera::new(
+                Vec3::new(0.0, 0.0, distance),
+                Vec3::zeros(),
+                Vec3::y(),
+                10.0,
+                scene.config.aspect_ratio,
+                0.0,
+                distance,
+            );
+            let img = render_region(&scene, Rect::new(0, 0, 8, 6));
+            let image::Rgba([r, g, b, _]) = *img.get_pixel(4, 3);
+            // Distance from the fog color.
+            (255 - r as i32) + g as i32 + b as i32
+        };
+
+        let (near, far) = (center_pixel(3.0), center_pixel(12.0));
+        assert!(far < near, "{} is not foggier than {}", far, near);
+    }
+
+    #[test]
+    fn builder_rejects_conflicting_ratio_and_dimensions() {
+        let err = RenderBuilder::new()
+            .with_ratio(16.0 / 9.0, 720)
+            .with_dimensions(800, 800)
+            .build()
+            .unwrap_err();
+        assert_eq!(err, BuildError::ConflictingSize { aspect_ratio: 16.0 / 9.0, width: 800, height: 800 });
+
+        // The same size both ways, up to the rounding of `with_ratio`.
+        let config = RenderBuilder::new()
+            .with_ratio(3.0 / 2.0, 101)
+            .with_dimensions(152, 101)
+            .build()
+            .unwrap();
+        assert_eq!((config.width, config.height), (152, 101));
+
+        // Exactly a pixel off, with either precision.
+        let config = RenderBuilder::new()
+            .with_ratio(4.0 / 3.0, 6)
+            .with_dimensions(9, 6)
+            .build()
+            .unwrap();
+        assert_eq!((config.width, config.height), (9, 6));
+    }
+
+    #[test]
+    fn resolution_presets_set_matching_dimensions_and_ratio() {
+        let config = RenderBuilder::new()
+            .with_resolution_preset(ResolutionPreset::Hd1080)
+            .build()
+            .unwrap();
+        assert_eq!((config.width, config.height), (1920, 1080));
+        assert_eq!(config.aspect_ratio, 16.0 / 9.0);
+
+        // Agrees with the same ratio given by hand.
+        RenderBuilder::new()
+            .with_ratio(16.0 / 9.0, 1080)
+            .with_resolution_preset(ResolutionPreset::Hd1080)
+            .build()
+            .unwrap();
+
+        let square = RenderBuilder::new()
+            .with_resolution_preset(ResolutionPreset::Square1k)
+            .build()
+            .unwrap();
+        assert_eq!((square.width, square.height, square.aspect_ratio), (1024, 1024, 1.0));
+    }
+
+    #[test]
+    fn sample_map_overrides_the_samples_of_each_pixel() {
+        let mut scene = tiny_scene(Backend::Tiled);
+        // 8 samples in the left half of the image, 1 in the right half.
+        scene.config.sample_map = Some(SampleMap::new(|x, _| if x < 4 { 8 } else { 1 }));
+
+        let stats = RenderStats::new();
+        render_region_with_stats(&scene, Rect::new(0, 0, 8, 6), &stats);
+        assert_eq!(stats.camera_rays(), (4 * 8 + 4) * 6);
+
+        let output = render_to_output(scene);
+        assert_eq!(output.samples[8 + 1], 8);
+        assert_eq!(output.samples[8 + 6], 1);
+        let heatmap = output.sample_heatmap();
+        assert_eq!(*heatmap.get_pixel(1, 1), image::Rgb([255, 255, 0]));
+        assert_eq!(*heatmap.get_pixel(6, 1), image::Rgb([0, 0, 0]));
+    }
+
+    #[test]
+    fn tiles_do_not_depend_on_the_thread_count() {
+        let render = |threads: usize| {
+            let mut scene = tiny_scene(Backend::Tiled);
+            // More than one tile in each direction.
+            scene.config.width = 2 * TILE_SIZE + 5;
+            scene.config.height = TILE_SIZE + 3;
+            scene.config.samples_per_pixel = 2;
+            scene.config.threads = Some(threads);
+            render_tiled(&scene, &RenderStats::new())
+        };
+
+        let single = render(1);
+        assert_eq!(single, render(4));
+        assert_eq!(single, render(7));
+    }
+
+    #[test]
+    fn spiral_order_starts_at_the_center_tile() {
+        let (columns, rows) = (5, 4);
+        let spiral = TileOrder::Spiral.tiles(columns, rows);
+        assert_eq!(spiral[0], (2, 1));
+        let position = |tile| spiral.iter().position(|&t| t == tile).unwrap();
+        for corner in [(0, 0), (4, 0), (0, 3), (4, 3)] {
+            assert!(position(corner) > position((2, 1)) + 8, "corner {:?} too early", corner);
+        }
+
+        // Every order covers each tile exactly once.
+        for order in [TileOrder::Scanline, TileOrder::Spiral, TileOrder::CenterOut, TileOrder::Morton] {
+            let mut tiles = order.tiles(columns, rows);
+            tiles.sort_unstable_by_key(|&(column, row)| (row, column));
+            assert_eq!(tiles, TileOrder::Scanline.tiles(columns, rows), "{:?}", order);
+        }
+
+        // Only the order changes, not the image.
+        let render = |order: TileOrder| {
+            let mut scene = tiny_scene(Backend::Tiled);
+            scene.config.width = 2 * TILE_SIZE + 5;
+            scene.config.height = TILE_SIZE + 3;
+            scene.config.tile_order = order;
+            render_tiled(&scene, &RenderStats::new())
+        };
+        assert_eq!(render(TileOrder::Spiral), render(TileOrder::Scanline));
+    }
+
+    #[test]
+    fn noise_changes_between_frames_unless_static() {
+        let render = |frame: u64, static_noise: bool| {
+            let mut scene = tiny_scene(Backend::Tiled);
+            scene.config.frame = frame;
+            scene.config.static_noise = static_noise;
+            render_to_output(scene).image
+        };
+
+        // Same scene and seed, only the frame changes.
+        assert_ne!(render(0, false), render(1, false));
+        assert_eq!(render(1, false), render(1, false));
+        assert_eq!(render(0, true), render(1, true));
+        assert_eq!(render(0, true), render(0, false));
+    }
+
+    #[test]
+    fn filter_weights_fall_off_away_from_the_pixel_center() {
+        assert_eq!(PixelFilter::Box.weight(0.5, -0.5), 1.0);
+
+        assert_eq!(PixelFilter::Tent.weight(0.0, 0.0), 1.0);
+        assert_eq!(PixelFilter::Tent.weight(0.5, 0.0), 0.5);
+        assert_eq!(PixelFilter::Tent.weight(-0.5, 0.5), 0.25);
+
+        let gaussian = PixelFilter::Gaussian { sigma: 0.5 };
+        assert_eq!(gaussian.weight(0.0, 0.0), 1.0);
+        assert!(gaussian.weight(0.25, 0.0) > gaussian.weight(0.5, 0.0));
+        assert_eq!(gaussian.weight(0.25, -0.1), gaussian.weight(-0.25, 0.1));
+
+        // Degenerate deviations are clamped, instead of giving NaN or 0 everywhere.
+        for sigma in [0.0, 1e-6] {
+            let gaussian = PixelFilter::Gaussian { sigma };
+            assert_eq!(gaussian.weight(0.0, 0.0), 1.0);
+            assert!(gaussian.weight(0.5, 0.5) > 0.0);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "positive sigma")]
+    fn builder_rejects_a_gaussian_filter_without_width() {
+        RenderBuilder::new().with_filter(PixelFilter::Gaussian { sigma: 0.0 });
+    }
+
+    #[test]
+    fn jpeg_size_follows_quality_and_chroma_subsampling() {
+        // Saturated noise, with as much color detail as there can be.
+        utils::seed_thread_rng(3);
+        let img = image::RgbImage::from_fn(64, 64, |_, _| {
+            image::Rgb([random::<u8>() & 0xf0, random::<u8>() & 0xf0, random::<u8>() & 0xf0])
+        });
+        let size = |quality: u8, chroma: ChromaSubsampling| {
+            let mut jpeg = Vec::new();
+            encode_image(&img, &OutputFormat::Jpeg { quality, chroma }, &mut jpeg).unwrap();
+            jpeg.len()
+        };
+
+        assert!(size(30, ChromaSubsampling::Full) < size(90, ChromaSubsampling::Full));
+        assert!(size(90, ChromaSubsampling::Half) < size(90, ChromaSubsampling::Full));
+
+        // Flat blocks have no chroma detail to lose.
+        let flat = image::RgbImage::from_fn(4, 4, |x, y| image::Rgb([200, 40 * (x / 2 + 2 * (y / 2)) as u8, 90]));
+        assert_eq!(average_chroma(&flat), flat);
+    }
+
+    #[test]
+    fn glass_under_water_refracts_relative_to_the_water() {
+        use crate::ray::{ Ray, RayPayload };
+
+        let world = submerged_glass_scene();
+        let mut ray = Ray::new(Vec3::new(0.3, 5.0, 0.0), Vec3::new(0.1, -1.0, 0.0));
+        let sin = |dir: &Vec3, normal: &Vec3| dir.normalize().cross(normal).magnitude();
+
+        // Into the glass from the air, into the water from the glass (whose surface is seen from
+        // inside of the sphere), and out of the glass into the water.
+        for expected in [1.0 / 1.5, 1.5 / 1.33, 1.5 / 1.33] {
+            let hit = world.hit(&ray, 0.001..Real::INFINITY).unwrap();
+            let split = hit.scatter(&ray).unwrap().split.unwrap();
+            let ratio = sin(&split.refracted, &hit.normal) / sin(&ray.dir, &hit.normal);
+            assert!((ratio - expected).abs() < 1e-3, "{} != {} at {:?}", ratio, expected, hit.point);
+
+            let payload = RayPayload { medium: ray.payload.medium.apply(Some(split.transition)), ..ray.payload };
+            ray = Ray::new(hit.point, split.refracted).with_payload(payload);
+        }
+
+        // Only the water is left by the time the ray reaches the floor.
+        let floor = world.hit(&ray, 0.001..Real::INFINITY).unwrap();
+        assert!((floor.point.0.y + 2.0).abs() < 1e-2, "{:?}", floor.point);
+        assert_eq!(ray.payload.medium.ior(), 1.33);
+    }
+
+    #[test]
+    fn render_gives_an_image_of_the_configured_size() {
+        for backend in [Backend::Tiled, Backend::Rows, Backend::SingleThread] {
+            let img = tiny_scene(backend).render().unwrap();
+            assert_eq!(img.dimensions(), (8, 6), "{:?}", backend);
+        }
+    }
+
+    #[test]
+    fn every_backend_supports_the_buffered_options() {
+        let render = |backend: Backend| {
+            let mut scene = tiny_scene(backend);
+            scene.config.ssaa = Some(2);
+            scene.config.auto_exposure = Some(0.18);
+            scene.render().unwrap()
+        };
+        let tiled = render(Backend::Tiled);
+        assert_eq!(tiled, render(Backend::Rows));
+        assert_eq!(tiled, render(Backend::SingleThread));
+        // Exposed to the target, unlike the plain render.
+        assert_ne!(tiled, tiny_scene(Backend::Tiled).render().unwrap());
+    }
+
+    #[test]
+    fn scene_renders_report_what_the_world_is_made_of() {
+        let scene = tiny_scene(Backend::Rows);
+        let (_, stats) = render_scene(&scene);
+        assert_eq!(stats.scene(), RenderStats::for_world(&scene.world).scene());
+        assert_eq!(stats.scene().objects, 1);
+    }
+
+    #[test]
+    fn every_backend_gives_the_same_image_for_a_seed() {
+        let render = |backend: Backend| {
+            let mut scene = tiny_scene(backend);
+            scene.config.samples_per_pixel = 4;
+            scene.render().unwrap()
+        };
+        let tiled = render(Backend::Tiled);
+        assert_eq!(tiled, render(Backend::Rows));
+        assert_eq!(tiled, render(Backend::SingleThread));
+    }
+
+    #[test]
+    fn tiled_render_matches_the_region_of_the_whole_image() {
+        // Without the `parallel` feature this goes through the sequential fallbacks.
+        let scene = tiny_scene(Backend::Tiled);
+        let tiled = render_tiled(&scene, &RenderStats::new());
+        assert_eq!(tiled, render_region(&scene, Rect::new(0, 0, 8, 6)));
+        assert!(tiled.pixels().any(|pixel| pixel.0[..3] != [0, 0, 0]));
+    }
+
+    #[test]
+    fn regions_match_the_same_pixels_of_the_whole_image() {
+        let scene = tiny_scene(Backend::Tiled);
+        let whole = render_region(&scene, Rect::new(0, 0, 8, 6));
+
+        let region = render_region(&scene, Rect::new(3, 2, 4, 3));
+        assert_eq!(region.dimensions(), (4, 3));
+        for (x, y, pixel) in region.enumerate_pixels() {
+            assert_eq!(pixel, whole.get_pixel(x + 3, y + 2), "at ({}, {})", x, y);
+        }
+    }
+
+    #[test]
+    fn rgba_bytes_are_tightly_packed_and_show_the_sphere() {
+        let rgba = render_to_rgba(tiny_scene(Backend::Tiled));
+        assert_eq!(rgba.len(), 8 * 6 * 4);
+
+        let pixel = |x: usize, y: usize| &rgba[(y * 8 + x) * 4..][..4];
+        // The sphere covers the center, the corners see the sky.
+        assert_ne!(pixel(4, 3)[..3], pixel(0, 0)[..3]);
+        assert!(rgba.chunks_exact(4).all(|pixel| pixel[3] == 255));
+    }
+
+    #[test]
+    fn previews_are_the_same_for_any_seed() {
+        // Every material that scatters randomly when path tracing, in front of a diffuse wall.
+        let render = |seed: u64| {
+            let config = RenderBuilder::new()
+                .with_dimensions(16, 12)
+                .with_samples(2)
+                .with_seed(seed)
+                .with_jitter(Jitter::BlueNoise)
+                .with_preview_lighting(vec![PointLight::new(Vec3::new(2.0, 3.0, 4.0), color::white(), 20.0)])
+                .build()
+                .unwrap();
+            let camera = tiny_scene(Backend::Tiled).camera;
+            let world: Vec<Sphere<CommonMat>> = vec![
+                Sphere::new(Vec3::new(-0.6, 0.4, 0.0), 0.4, Metal::new(color::mid_gray(), 0.5).into()),
+                Sphere::new(Vec3::new(0.6, 0.4, 0.0), 0.4, Dielectric::new(1.5).into()),
+                Sphere::new(
+                    Vec3::new(0.0, -0.4, 0.0),
+                    0.4,
+                    Blend::new(Diffuse::new(color::white()), Metal::new(color::white(), 1.0), 0.5).into(),
+                ),
+                Sphere::new(Vec3::new(0.0, 0.0, -101.0), 100.0, Diffuse::new(color::mid_gray()).into()),
+            ];
+            render_to_output(Scene::new(world, camera, config)).image
+        };
+
+        assert_eq!(render(1), render(2));
+    }
+
+    #[test]
+    fn heatmap_shows_the_extra_samples_on_edges() {
+        // A black sphere in front of a white background, so only its silhouette has contrast.
+        let render = |radius: Real| {
+            let config = RenderBuilder::new()
+                .with_dimensions(16, 12)
+                .with_seed(1)
+                .with_edge_supersampling(1, 8, 0.1)
+                .with_background_image(Arc::new(ImageTexture::new(1, 1, vec![color::white()])))
+                .build()
+                .unwrap();
+            let camera = tiny_scene(Backend::Tiled).camera;
+            let world: Vec<_> = (radius > 0.0)
+                .then(|| Sphere::new(Vec3::zeros(), radius, Diffuse::new(Color::zeros())))
+                .into_iter()
+                .collect();
+            render_to_output(Scene::new(world, camera, config))
+        };
+
+        let uniform = render(0.0);
+        assert!(uniform.samples.iter().all(|&samples| samples == 1));
+        assert!(uniform.sample_heatmap().pixels().all(|pixel| pixel.0 == [0, 0, 0]));
+
+        let edge = render(0.5);
+        let samples_at = |x: usize, y: usize| edge.samples[y * 16 + x];
+        assert_eq!(samples_at(8, 6), 1);
+        assert_eq!(samples_at(0, 0), 1);
+        let edges = edge.samples.iter().filter(|&&samples| samples == 9).count();
+        assert!(edges > 8 && edges < 16 * 12 / 2, "{} edge pixels", edges);
+
+        let heatmap = edge.sample_heatmap();
+        assert_eq!(heatmap.get_pixel(8, 6).0, [0, 0, 0]);
+        assert_eq!(heatmap.pixels().filter(|pixel| pixel.0 == [255, 255, 0]).count(), edges);
+    }
+
+    #[test]
+    fn alpha_is_opaque_on_the_sphere_and_transparent_around_it() {
+        let mut scene = tiny_scene(Backend::Tiled);
+        scene.config.alpha = true;
+        scene.config.samples_per_pixel = 4;
+        let image = render_to_output(scene).image;
+
+        assert_eq!(image.get_pixel(4, 3).0[3], 255);
+        for &(x, y) in &[(0, 0), (7, 0), (0, 5), (7, 5)] {
+            assert_eq!(image.get_pixel(x, y).0[3], 0, "corner ({}, {})", x, y);
+        }
+        // Some pixels on the rim are only partly covered.
+        assert!(image.pixels().any(|pixel| (1..255).contains(&pixel.0[3])));
+
+        // Without it, the background is opaque.
+        let image = render_to_output(tiny_scene(Backend::Tiled)).image;
+        assert!(image.pixels().all(|pixel| pixel.0[3] == 255));
+    }
+
+    #[test]
+    fn scenes_share_one_world() {
+        let mut builder = WorldBuilder::default();
+        builder.add(Sphere::new(Vec3::zeros(), 1.0, Diffuse::new(color::mid_gray())));
+        let world = builder.build_arc();
+
+        let with_world = |world: Arc<Vec<Sphere<Diffuse>>>| {
+            let Scene { camera, config, .. } = tiny_scene(Backend::Tiled);
+            Scene::new(world, camera, config)
+        };
+        let front = with_world(world.clone());
+        let back = with_world(world.clone());
+        assert_eq!(Arc::strong_count(&world), 3);
+        assert!(Arc::ptr_eq(&front.world, &back.world));
+
+        // The shared world renders like the one it was built from.
+        let expected = render_to_rgba(tiny_scene(Backend::Tiled));
+        assert_eq!(render_to_rgba(front), expected);
+        assert_eq!(Arc::strong_count(&world), 2);
+        assert_eq!(render_to_rgba(back), expected);
+        assert_eq!(Arc::strong_count(&world), 1);
+    }
+
+    #[test]
+    fn auto_exposure_evens_out_the_brightness_of_scenes() {
+        let mean_byte = |brightness: Real, auto_exposure: bool| {
+            let mut scene = tiny_scene(Backend::Tiled);
+            let sky = ImageTexture::new(1, 1, vec![color::white() * brightness]);
+            scene.config.background = Background::Environment(Arc::new(sky));
+            scene.config.samples_per_pixel = 4;
+            if auto_exposure {
+                scene.config.auto_exposure = Some(0.18);
+            }
+            let image = render_to_output(scene).image;
+            image.pixels().map(|pixel| pixel.0[1] as Real).sum::<Real>() / (8.0 * 6.0)
+        };
+
+        let (dim, bright) = (mean_byte(0.05, false), mean_byte(0.4, false));
+        assert!(bright > 1.5 * dim, "{} vs {}", dim, bright);
+
+        let (dim, bright) = (mean_byte(0.05, true), mean_byte(0.4, true));
+        assert!((bright - dim).abs() < 2.0, "{} vs {}", dim, bright);
+    }
+
+    /// A black ball against a white sky, so that the only gray pixels are along its edge. The
+    /// sampling is left to `builder`.
+    fn black_ball_on_white(builder: &mut RenderBuilder) -> Scene<Vec<Sphere<Diffuse>>> {
+        let config = builder
+            .with_dimensions(24, 18)
+            .with_seed(1)
+            .with_background(Background::Environment(Arc::new(ImageTexture::new(1, 1, vec![color::white()]))))
+            .build()
+            .unwrap();
+        let world = vec![Sphere::new(Vec3::zeros(), 1.0, Diffuse::new(color::black()))];
+        Scene::new(world, tiny_scene(Backend::Tiled).camera, config)
+    }
+
+    #[test]
+    fn filters_sharpen_edges_at_the_same_sample_count() {
+        // Stratified, so that what's left of the gray along the edge is down to the filter.
+        let render = |filter: PixelFilter| {
+            let mut builder = RenderBuilder::new();
+            builder.with_samples(64).with_filter(filter).with_stratified_sampling(true);
+            let scene = black_ball_on_white(&mut builder);
+            image::DynamicImage::ImageRgba8(render_to_output(scene).image).to_rgb8()
+        };
+        // How far the pixels are from either black or white, added up in linear space.
+        let gray = |img: &image::RgbImage| {
+            img.pixels()
+                .map(|pixel| color::srgb_to_linear(Vec3::repeat(pixel.0[1] as Real / 255.0)).x)
+                .map(|value| value.min(1.0 - value))
+                .sum::<Real>()
+        };
+
+        let boxed = gray(&render(PixelFilter::Box));
+        let tent = gray(&render(PixelFilter::Tent));
+        let gaussian = gray(&render(PixelFilter::Gaussian { sigma: 0.3 }));
+        assert!(tent < 0.95 * boxed, "{} vs {}", tent, boxed);
+        assert!(gaussian < tent, "{} vs {}", gaussian, tent);
+    }
+
+    #[test]
+    fn supersampling_approaches_a_reference_render() {
+        // A mirror ball reflecting the sky, so that the only error is along its edges.
+        let render = |samples: usize, ssaa: Option<u32>| {
+            let mut builder = RenderBuilder::new();
+            builder.with_dimensions(24, 18).with_samples(samples).with_seed(1);
+            if let Some(factor) = ssaa {
+                builder.with_ssaa(factor);
+            }
+            let world = vec![Sphere::new(Vec3::zeros(), 1.0, Metal::new(color::new(0.2, 0.2, 0.2), 0.0))];
+            let camera = tiny_scene(Backend::Tiled).camera;
+            let output = render_to_output(Scene::new(world, camera, builder.build().unwrap()));
+            (image::DynamicImage::ImageRgba8(output.image).to_rgb8(), output.samples)
+        };
+
+        let (reference, _) = render(256, None);
+        let (aliased, _) = render(1, None);
+        let (supersampled, samples) = render(1, Some(4));
+        assert_eq!(supersampled.dimensions(), (24, 18));
+        assert!(samples.iter().all(|&samples| samples == 16));
+
+        let aliased = testing::image_diff(&aliased, &reference).rmse;
+        let supersampled = testing::image_diff(&supersampled, &reference).rmse;
+        assert!(supersampled < 0.5 * aliased, "{} vs {}", supersampled, aliased);
+    }
+
+    #[test]
+    fn edge_supersampling_beats_uniform_samples_at_the_same_cost() {
+        let render = |scene: Scene<Vec<Sphere<Diffuse>>>| {
+            let output = render_to_output(scene);
+            (image::DynamicImage::ImageRgba8(output.image).to_rgb8(), output.samples.iter().sum::<usize>())
+        };
+        let (reference, _) = render(black_ball_on_white(RenderBuilder::new().with_samples(256)));
+        // A few samples in every pixel, and many more in those on the edge.
+        let mut builder = RenderBuilder::new();
+        builder.with_edge_supersampling(4, 60, 0.05);
+        let (edges, edge_total) = render(black_ball_on_white(&mut builder));
+
+        // As many samples spread evenly, rounded up in favour of the uniform render.
+        let samples = edge_total.div_ceil(24 * 18);
+        let (uniform, uniform_total) = render(black_ball_on_white(RenderBuilder::new().with_samples(samples)));
+        assert!(uniform_total >= edge_total, "{} < {}", uniform_total, edge_total);
+
+        let uniform = testing::image_diff(&uniform, &reference).rmse;
+        let edges = testing::image_diff(&edges, &reference).rmse;
+        assert!(edges < uniform, "{} vs {}", edges, uniform);
+    }
+
+    #[test]
+    fn cancelled_renders_return_the_partial_image() {
+        for backend in [Backend::Tiled, Backend::Rows, Backend::SingleThread] {
+            let mut scene = tiny_scene(backend);
+            scene.config.cancel = Some(Arc::new(AtomicBool::new(true)));
+            match scene.render() {
+                Err(RenderError::Cancelled { partial }) => assert_eq!(partial.dimensions(), (8, 6)),
+                other => panic!("{:?} wasn't cancelled: {:?}", backend, other.map(|img| img.dimensions())),
+            }
+        }
+    }
+
+    #[test]
+    fn broken_samples_dont_poison_the_pixel() {
+        /// Scatters in a NaN direction.
+        struct Broken;
+
+        impl Material for Broken {
+            fn scatter(&self, _: &Ray, _: &Hit) -> Option<Scatter> {
+                Some(Scatter::new(color::white(), Vec3::repeat(Real::NAN), ScatterEvent::Reflected))
+            }
+        }
+
+        let scene = tiny_scene(Backend::Tiled);
+        let world = vec![Sphere::new(Vec3::zeros(), 1.0, Broken)];
+        let pixel = sample_pixel(&world, &scene.camera, &scene.config, &RenderStats::new(), 4, 3);
+        assert!(pixel.iter().all(|c| c.is_finite()), "{:?}", pixel);
+
+        // A NaN in the environment map, seen both directly and off the sphere.
+        let mut config = scene.config.clone();
+        config.background = Background::Environment(Arc::new(ImageTexture::new(1, 1, vec![Vec3::repeat(Real::NAN)])));
+        for (x, y) in [(0, 0), (4, 3)] {
+            let pixel = sample_pixel(&scene.world, &scene.camera, &config, &RenderStats::new(), x, y);
+            assert!(pixel.iter().all(|c| c.is_finite()), "{:?} at ({}, {})", pixel, x, y);
+        }
+    }
+
+    #[test]
+    fn firefly_clamp_caps_the_luminance_of_samples() {
+        let mut scene = tiny_scene(Backend::Tiled);
+        scene.config.background = Background::Environment(Arc::new(ImageTexture::new(1, 1, vec![color::white() * 100.0])));
+        let luminance = |config: &Render, x, y| {
+            color::luminance(sample_pixel(&scene.world, &scene.camera, config, &RenderStats::new(), x, y))
+        };
+
+        // The sky in the corner, and the sphere it lights in the middle.
+        assert!(luminance(&scene.config, 0, 0) > 50.0);
+        assert!(luminance(&scene.config, 4, 3) > 10.0);
+
+        let mut clamped = scene.config.clone();
+        clamped.firefly_clamp = Some(2.0);
+        for (x, y) in [(0, 0), (4, 3)] {
+            let luminance = luminance(&clamped, x, y);
+            assert!(luminance <= 2.0 + 1e-4, "{} at ({}, {})", luminance, x, y);
+            assert!(luminance > 0.5, "{} at ({}, {})", luminance, x, y);
+        }
+    }
+
+    #[test]
+    fn stratified_lens_samples_are_less_noisy_out_of_focus() {
+        // A black sphere on white, far in front of the focus distance, through a wide lens.
+        let config = RenderBuilder::new()
+            .with_dimensions(16, 12)
+            .with_samples(16)
+            .with_background_image(Arc::new(ImageTexture::new(1, 1, vec![color::white()])))
+            .build()
+            .unwrap();
+        let camera = Camera::new(Vec3::new(0.0, 0.0, 3.0), Vec3::zeros(), Vec3::y(), 40.0, config.aspect_ratio, 1.0, 10.0);
+        let world = vec![Sphere::new(Vec3::zeros(), 0.5, Diffuse::new(Color::zeros()))];
+
+        // The middle row crosses the blurred edge.
+        let independent = row_variance(&world, &camera, &config, 6);
+        let stratified = row_variance(&world, &camera, &Render { stratified: true, ..config }, 6);
+        assert!(independent > 0.0);
+        assert!(stratified < 0.7 * independent, "{} vs {}", stratified, independent);
+    }
+
+    #[test]
+    fn fresnel_split_is_less_noisy_on_glass() {
+        // A glass sphere filling most of the view, in front of the sky gradient.
+        let config = RenderBuilder::new()
+            .with_dimensions(16, 12)
+            .with_samples(2)
+            .build()
+            .unwrap();
+        let camera = tiny_scene(Backend::Tiled).camera;
+        let world = vec![Sphere::new(Vec3::zeros(), 1.0, Dielectric::new(1.5))];
+
+        let stochastic = row_variance(&world, &camera, &config, 6);
+        let split = row_variance(&world, &camera, &Render { fresnel_split: Some(16), ..config }, 6);
+        assert!(stochastic > 0.0);
+        assert!(split < 0.5 * stochastic, "{} vs {}", split, stochastic);
+    }
+
+    /// What `validate` finds wrong with `scene`, nothing when it's valid.
+    fn warnings<T: Hittable>(scene: &Scene<T>) -> Vec<SceneWarning> {
+        scene.validate().err().unwrap_or_default()
+    }
+
+    #[test]
+    fn valid_scenes_have_no_warnings() {
+        assert_eq!(tiny_scene(Backend::Tiled).validate(), Ok(()));
+    }
+
+    #[test]
+    fn validate_warns_about_a_degenerate_camera() {
+        let mut scene = tiny_scene(Backend::Tiled);
+        scene.camera = Camera::new(Vec3::zeros(), Vec3::zeros(), Vec3::y(), 40.0, scene.config.aspect_ratio, 0.0, 3.0);
+        assert_eq!(warnings(&scene), [SceneWarning::DegenerateCamera]);
+    }
+
+    #[test]
+    fn validate_warns_about_zero_samples() {
+        let mut scene = tiny_scene(Backend::Tiled);
+        scene.config.samples_per_pixel = 0;
+        assert_eq!(warnings(&scene), [SceneWarning::NoSamples]);
+
+        // Unless a sample map decides instead.
+        scene.config.sample_map = Some(SampleMap::new(|_, _| 1));
+        assert_eq!(warnings(&scene), []);
+    }
+
+    #[test]
+    fn validate_warns_about_zero_bounces() {
+        let mut scene = tiny_scene(Backend::Tiled);
+        scene.config.max_bounces = 0;
+        assert_eq!(warnings(&scene), [SceneWarning::NoBounces]);
+    }
+
+    #[test]
+    fn validate_warns_about_an_empty_world() {
+        let mut scene = tiny_scene(Backend::Tiled);
+        scene.world.clear();
+        assert_eq!(warnings(&scene), [SceneWarning::EmptyWorld]);
+    }
+
+    #[test]
+    fn validate_warns_about_an_empty_image() {
+        let mut scene = tiny_scene(Backend::Tiled);
+        scene.config.height = 0;
+        assert_eq!(warnings(&scene), [SceneWarning::EmptyImage { width: 8, height: 0 }]);
+    }
+
+    #[test]
+    fn validate_warns_about_a_mismatched_aspect_ratio() {
+        let mut scene = tiny_scene(Backend::Tiled);
+        scene.config.width = 12;
+        let aspect_ratio = scene.config.aspect_ratio;
+        assert_eq!(warnings(&scene), [SceneWarning::AspectRatioMismatch { aspect_ratio, width: 12, height: 6 }]);
+
+        // A pixel off either way is just rounding, whatever the precision.
+        for width in [7, 9] {
+            scene.config.width = width;
+            assert_eq!(warnings(&scene), []);
+        }
+    }
+
+    #[test]
+    fn validate_warns_about_jpeg_settings() {
+        let mut scene = tiny_scene(Backend::Tiled);
+        scene.config.output = OutputFormat::Jpeg { quality: 0, chroma: ChromaSubsampling::Full };
+        assert_eq!(warnings(&scene), [SceneWarning::InvalidJpegQuality(0)]);
+
+        scene.config.output = OutputFormat::default();
+        scene.config.alpha = true;
+        assert_eq!(warnings(&scene), [SceneWarning::AlphaWithoutPng]);
+        scene.config.output = OutputFormat::Png;
+        assert_eq!(warnings(&scene), []);
+    }
+
+    #[test]
+    fn render_fails_without_pixels() {
+        let mut scene = tiny_scene(Backend::Tiled);
+        scene.config.width = 0;
+        assert_eq!(scene.render(), Err(RenderError::EmptyImage { width: 0, height: 6 }));
+    }
+
+    #[test]
+    fn progressive_render_stops_when_out_of_time() {
+        let mut scene = tiny_scene(Backend::Tiled);
+        let budget = Duration::from_millis(100);
+        scene.config.stop = Some(StopCondition::Time(budget));
+
+        let start = Instant::now();
+        let output = progressive_render(scene, &ProgressiveOpts::default());
+        assert!(start.elapsed() < budget + Duration::from_secs(10));
+
+        assert_eq!(output.image.dimensions(), (8, 6));
+        assert!(output.samples.iter().all(|&samples| samples > 0));
+        assert!(output.image.pixels().any(|pixel| pixel.0[..3] != [0, 0, 0]));
+    }
+
+    #[test]
+    fn progressive_render_follows_the_sample_map() {
+        let mut scene = tiny_scene(Backend::Tiled);
+        scene.config.samples_per_pixel = 1;
+        scene.config.sample_map = Some(SampleMap::new(|x, _| match x {
+            0..=3 => 6,
+            7     => 0,
+            _     => 2,
+        }));
+
+        let opts = ProgressiveOpts { samples_per_pass: 4, ..ProgressiveOpts::default() };
+        let output = progressive_render(scene, &opts);
+        assert_eq!(output.samples[8 + 1], 6);
+        assert_eq!(output.samples[8 + 6], 2);
+        // Pixels without samples are left empty.
+        assert_eq!(output.samples[8 + 7], 0);
+        assert_eq!(output.image.get_pixel(7, 1).0, [0, 0, 0, 0]);
+        assert_ne!(output.image.get_pixel(6, 1).0, [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn progressive_render_writes_thumbnails_of_the_requested_size() {
+        let path = std::env::temp_dir().join(format!("raytracing-thumbnail-{}.jpg", std::process::id()));
+        let opts = ProgressiveOpts {
+            thumbnail_every: Some(Duration::ZERO),
+            thumbnail_size: (4, 3),
+            thumbnail_path: path.clone(),
+            ..ProgressiveOpts::default()
+        };
+        let mut scene = tiny_scene(Backend::Tiled);
+        scene.config.samples_per_pixel = 2;
+        progressive_render(scene, &opts);
+
+        let thumbnail = image::open(&path).unwrap().to_rgb8();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(thumbnail.dimensions(), (4, 3));
+    }
+
+    #[test]
+    fn progressive_passes_carry_on_the_blue_noise_sequence() {
+        // A black sphere on white, so that only where the samples land matters.
+        let scene = || {
+            let config = RenderBuilder::new()
+                .with_dimensions(16, 12)
+                .with_samples(2)
+                .with_seed(1)
+                .with_jitter(Jitter::BlueNoise)
+                .with_background_image(Arc::new(ImageTexture::new(1, 1, vec![color::white()])))
+                .build()
+                .unwrap();
+            let camera = tiny_scene(Backend::Tiled).camera;
+            Scene::new(vec![Sphere::new(Vec3::zeros(), 0.5, Diffuse::new(Color::zeros()))], camera, config)
+        };
+
+        let opts = ProgressiveOpts { samples_per_pass: 1, ..ProgressiveOpts::default() };
+        let progressive = progressive_render(scene(), &opts).image;
+        assert_eq!(progressive, render_to_output(scene()).image);
+    }
+
+    #[test]
+    fn strips_match_the_whole_image() {
+        let scene = tiny_scene(Backend::Tiled);
+        let mut ppm = Vec::new();
+        write_strips(&scene, 4, &RenderStats::new(), scene.config.base_seed(), &mut ppm).unwrap();
+        let strips = image::load_from_memory_with_format(&ppm, image::ImageFormat::Pnm).unwrap().to_rgb8();
+
+        let whole: image::RgbImage = render_to_output(tiny_scene(Backend::Tiled)).image.convert();
+        assert_eq!(strips, whole);
+
+        // Without a seed, every strip still uses the one that was picked.
+        let mut unseeded = tiny_scene(Backend::Tiled);
+        unseeded.config.seed = None;
+        let mut ppm = Vec::new();
+        write_strips(&unseeded, 1, &RenderStats::new(), scene.config.base_seed(), &mut ppm).unwrap();
+        let strips = image::load_from_memory_with_format(&ppm, image::ImageFormat::Pnm).unwrap().to_rgb8();
+        assert_eq!(strips, whole);
+    }
+
+    #[test]
+    fn strips_report_write_errors() {
+        /// Like a pipe whose reader went away.
+        struct Closed;
+
+        impl std::io::Write for Closed {
+            fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+                Err(std::io::ErrorKind::BrokenPipe.into())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let scene = tiny_scene(Backend::Tiled);
+        let err = write_strips(&scene, 4, &RenderStats::new(), 0, Closed).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn blue_noise_jitter_has_less_low_frequency_error() {
+        // Stripes a little narrower than a pixel, which a regular grid turns into wide bands.
+        let stripes = |x: Real, _: Real| if (x / 0.99).fract() < 0.5 { 1.0 } else { 0.0 };
+        let size = 64;
+
+        // Error at 1 sample per pixel, averaged over blocks of 8 by 8 pixels to keep only the
+        // low frequencies.
+        let low_frequency_error = |jitter: Jitter| {
+            utils::seed_thread_rng(0);
+            let mut blocks = vec![0.0; (size / 8) * (size / 8)];
+            for y in 0..size {
+                for x in 0..size {
+                    let (cx, cy) = (x as Real + 0.5, y as Real + 0.5);
+                    let exact = (0..16 * 16)
+                        .map(|i| stripes(cx + ((i % 16) as Real + 0.5) / 16.0 - 0.5, cy + ((i / 16) as Real + 0.5) / 16.0 - 0.5))
+                        .sum::<Real>() / 256.0;
+                    let (dx, dy) = jitter.pixel_offset(x as u32, y as u32, 0, &mut utils::LocalRng);
+                    blocks[(y / 8) * (size / 8) + x / 8] += (stripes(cx + dx, cy + dy) - exact) / 64.0;
+                }
+            }
+            (blocks.iter().map(|e| e * e).sum::<Real>() / blocks.len() as Real).sqrt()
+        };
+
+        let uniform = low_frequency_error(Jitter::Uniform);
+        let blue_noise = low_frequency_error(Jitter::BlueNoise);
+        assert!(blue_noise < 0.5 * uniform, "{} vs {}", blue_noise, uniform);
+    }
 }
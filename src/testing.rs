@@ -0,0 +1,104 @@
+use image::{ Rgb, RgbImage };
+
+/// How much two renders of the same size differ.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiffReport {
+    width: u32,
+    height: u32,
+    /// Largest difference among the channels of each pixel, in image order.
+    diffs: Vec<u8>,
+    /// Largest difference of any channel in the image.
+    pub max_diff: u8,
+    /// Root mean square of the differences of all channels.
+    pub rmse: f64,
+}
+
+impl DiffReport {
+    /// Number of pixels with some channel differing by more than `threshold`.
+    pub fn pixels_over(&self, threshold: u8) -> usize {
+        self.diffs.iter().filter(|&&diff| diff > threshold).count()
+    }
+
+    pub fn is_identical(&self) -> bool {
+        self.max_diff == 0
+    }
+
+    /// Image of where the renders differ, going from black where they are the same through red
+    /// to yellow where they differ the most.
+    pub fn heatmap(&self) -> RgbImage {
+        let mut img = RgbImage::new(self.width, self.height);
+        for (pixel, &diff) in img.pixels_mut().zip(&self.diffs) {
+            let heat = diff as u16 * 2;
+            *pixel = Rgb([heat.min(255) as u8, heat.saturating_sub(255) as u8, 0]);
+        }
+        img
+    }
+}
+
+/// Compare two images pixel by pixel.
+///
+/// # Panics
+///
+/// If the images aren't the same size.
+pub fn image_diff(a: &RgbImage, b: &RgbImage) -> DiffReport {
+    assert_eq!(a.dimensions(), b.dimensions(), "can only compare images of the same size");
+
+    let mut squared_sum = 0.0;
+    let diffs: Vec<u8> = a.pixels()
+        .zip(b.pixels())
+        .map(|(Rgb(a), Rgb(b))| {
+            a.iter().zip(b).map(|(a, b)| {
+                let diff = a.abs_diff(*b);
+                squared_sum += (diff as f64).powi(2);
+                diff
+            })
+            .max()
+            .unwrap()
+        })
+        .collect();
+
+    let channels = diffs.len() * 3;
+    let rmse = if channels == 0 { 0.0 } else { (squared_sum / channels as f64).sqrt() };
+
+    DiffReport {
+        width: a.width(),
+        height: a.height(),
+        max_diff: diffs.iter().copied().max().unwrap_or(0),
+        diffs,
+        rmse,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gradient() -> RgbImage {
+        RgbImage::from_fn(16, 8, |x, y| Rgb([x as u8 * 16, y as u8 * 32, 128]))
+    }
+
+    #[test]
+    fn identical_images_have_no_difference() {
+        let report = image_diff(&gradient(), &gradient());
+        assert!(report.is_identical());
+        assert_eq!((report.max_diff, report.rmse, report.pixels_over(0)), (0, 0.0, 0));
+        assert!(report.heatmap().pixels().all(|pixel| *pixel == Rgb([0, 0, 0])));
+    }
+
+    #[test]
+    fn a_single_changed_pixel_is_found() {
+        let mut changed = gradient();
+        changed.put_pixel(5, 3, Rgb([80, 96, 228]));
+
+        let report = image_diff(&gradient(), &changed);
+        assert!(!report.is_identical());
+        assert_eq!(report.max_diff, 100);
+        assert_eq!(report.pixels_over(0), 1);
+        assert_eq!(report.pixels_over(100), 0);
+        assert!((report.rmse - (100.0f64.powi(2) / (16.0 * 8.0 * 3.0)).sqrt()).abs() < 1e-9);
+
+        let heatmap = report.heatmap();
+        assert_eq!(*heatmap.get_pixel(5, 3), Rgb([200, 0, 0]));
+        assert_eq!(*heatmap.get_pixel(4, 3), Rgb([0, 0, 0]));
+    }
+}
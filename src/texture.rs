@@ -0,0 +1,287 @@
+use core::fmt;
+#[cfg(feature = "std")]
+use std::fs::File;
+#[cfg(feature = "std")]
+use std::io::BufReader;
+#[cfg(feature = "std")]
+use std::path::Path;
+
+use rand::{ Rng, SeedableRng };
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+
+use crate::utils::{ self, color, Color, Vec3, Point3, Real };
+use crate::prelude::*;
+
+/// A color that varies across space.
+pub trait Texture: fmt::Debug {
+    fn value(&self, point: &Point3) -> Color;
+}
+
+/// An image that can be sampled with `(u, v)` coordinates in `[0, 1]`, with `v = 1` at the top
+/// row. Texels are stored as linear radiance.
+#[derive(Clone)]
+pub struct ImageTexture {
+    width: usize,
+    height: usize,
+    texels: Vec<Color>,
+}
+
+impl ImageTexture {
+    /// Create a texture from row-major linear texels, starting at the top row.
+    pub fn new(width: usize, height: usize, texels: Vec<Color>) -> Self {
+        assert_eq!(texels.len(), width * height, "texel count doesn't match the dimensions");
+        Self { width, height, texels }
+    }
+
+    /// Load a texture from disk. Radiance `.hdr` files are kept as is, any other format `image`
+    /// understands is assumed to be sRGB encoded and is linearized.
+    #[cfg(feature = "std")]
+    pub fn open(path: impl AsRef<Path>) -> image::ImageResult<Self> {
+        let path = path.as_ref();
+        let is_hdr = path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("hdr"));
+
+        if is_hdr {
+            let decoder = image::codecs::hdr::HdrDecoder::new(BufReader::new(File::open(path)?))?;
+            let meta = decoder.metadata();
+            let texels = decoder
+                .read_image_hdr()?
+                .into_iter()
+                .map(|image::Rgb([r, g, b])| Color::new(r as Real, g as Real, b as Real))
+                .collect();
+
+            Ok(Self::new(meta.width as usize, meta.height as usize, texels))
+        } else {
+            let img = image::open(path)?.to_rgb8();
+            let texels = img
+                .pixels()
+                .map(|image::Rgb([r, g, b])| {
+                    color::srgb_to_linear(Color::new(*r as Real, *g as Real, *b as Real) / 255.0)
+                })
+                .collect();
+
+            Ok(Self::new(img.width() as usize, img.height() as usize, texels))
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Texel at column `i` and row `j`, with row 0 at the top.
+    pub fn texel(&self, i: usize, j: usize) -> Color {
+        self.texels[j * self.width + i]
+    }
+
+    /// Nearest texel lookup, coordinates outside `[0, 1]` are clamped to the border.
+    pub fn sample(&self, u: Real, v: Real) -> Color {
+        if self.texels.is_empty() {
+            return Color::new(0.0, 0.0, 0.0);
+        }
+
+        let i = ((u.clamp(0.0, 1.0) * self.width as Real) as usize).min(self.width - 1);
+        let j = (((1.0 - v.clamp(0.0, 1.0)) * self.height as Real) as usize).min(self.height - 1);
+        self.texels[j * self.width + i]
+    }
+
+    /// Sample the texture as an equirectangular environment map in the given direction.
+    pub fn sample_dir(&self, dir: &Vec3) -> Color {
+        let (u, v) = equirect_uv(&dir.normalize());
+        self.sample(u, v)
+    }
+}
+
+impl fmt::Debug for ImageTexture {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ImageTexture")
+            .field("width", &self.width)
+            .field("height", &self.height)
+            .finish()
+    }
+}
+
+/// Map a unit direction to equirectangular `(u, v)` coordinates, both within `[0, 1]`. `u` goes
+/// around the `y` axis starting at `-x` and `v` goes from the bottom (`-y`) to the top (`+y`).
+/// At the poles, where any `u` would do, it is 0.5.
+pub fn equirect_uv(dir: &Vec3) -> (Real, Real) {
+    // Rounding can push `y` slightly out of `[-1, 1]` near the poles, where `acos` would be NaN.
+    let theta = (-dir.y).clamp(-1.0, 1.0).acos();
+    // `atan2(±0, ±0)` depends on the signs of the zeros, which would make the poles flip between
+    // the middle and the edge of the texture.
+    let u = if dir.x == 0.0 && dir.z == 0.0 {
+        0.5
+    } else {
+        ((-dir.z).atan2(dir.x) + utils::consts::PI) / utils::consts::TAU
+    };
+
+    (u, theta / utils::consts::PI)
+}
+
+const PERLIN_POINTS: usize = 256;
+
+/// Perlin gradient noise.
+#[derive(Debug, Clone)]
+pub struct Perlin {
+    gradients: Vec<Vec3>,
+    perm_x: Vec<usize>,
+    perm_y: Vec<usize>,
+    perm_z: Vec<usize>,
+}
+
+impl Perlin {
+    /// Noise generated from the same seed is the same everywhere.
+    pub fn new(seed: u64) -> Perlin {
+        let mut rng = StdRng::seed_from_u64(seed);
+
+        let gradients = (0..PERLIN_POINTS)
+            .map(|_| {
+                let v = Vec3::new(rng.gen(), rng.gen(), rng.gen()) * 2.0 - Vec3::repeat(1.0);
+                v.normalize()
+            })
+            .collect();
+
+        let mut permutation = || {
+            let mut perm: Vec<usize> = (0..PERLIN_POINTS).collect();
+            perm.shuffle(&mut rng);
+            perm
+        };
+
+        let perm_x = permutation();
+        let perm_y = permutation();
+        let perm_z = permutation();
+
+        Perlin { gradients, perm_x, perm_y, perm_z }
+    }
+
+    /// Smooth noise in `[-1, 1]`.
+    pub fn noise(&self, point: &Point3) -> Real {
+        let floor = point.map(Real::floor);
+        let frac = point - floor;
+        // Hermite smoothing, to get rid of grid artifacts.
+        let smooth = frac.map(|t| t * t * (3.0 - 2.0 * t));
+
+        let mut accum = 0.0;
+        for di in 0..2 {
+            for dj in 0..2 {
+                for dk in 0..2 {
+                    let i = (floor.x as i64 + di as i64) as usize & (PERLIN_POINTS - 1);
+                    let j = (floor.y as i64 + dj as i64) as usize & (PERLIN_POINTS - 1);
+                    let k = (floor.z as i64 + dk as i64) as usize & (PERLIN_POINTS - 1);
+                    let gradient = self.gradients[self.perm_x[i] ^ self.perm_y[j] ^ self.perm_z[k]];
+
+                    let corner = Vec3::new(di as Real, dj as Real, dk as Real);
+                    let weight = corner.zip_map(&smooth, |c, t| c * t + (1.0 - c) * (1.0 - t));
+                    accum += weight.x * weight.y * weight.z * gradient.dot(&(frac - corner));
+                }
+            }
+        }
+
+        accum
+    }
+
+    /// Sum of `depth` octaves of noise.
+    pub fn turbulence(&self, point: &Point3, depth: usize) -> Real {
+        let mut accum = 0.0;
+        let mut point = *point;
+        let mut weight = 1.0;
+
+        for _ in 0..depth {
+            accum += weight * self.noise(&point);
+            weight *= 0.5;
+            point *= 2.0;
+        }
+
+        accum.abs()
+    }
+}
+
+/// How a `NoiseTexture` turns noise into a color.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NoisePattern {
+    /// Plain noise mapped to gray levels.
+    Grayscale,
+    /// Stripes along `z` perturbed by turbulence, which looks like veined marble.
+    Marble,
+}
+
+#[derive(Debug, Clone)]
+pub struct NoiseTexture {
+    pub noise: Perlin,
+    /// Frequency of the noise, higher values give finer detail.
+    pub scale: Real,
+    pub pattern: NoisePattern,
+}
+
+impl NoiseTexture {
+    pub fn new(seed: u64, scale: Real, pattern: NoisePattern) -> NoiseTexture {
+        NoiseTexture { noise: Perlin::new(seed), scale, pattern }
+    }
+}
+
+impl Texture for NoiseTexture {
+    fn value(&self, point: &Point3) -> Color {
+        let point = point * self.scale;
+        let gray = match self.pattern {
+            NoisePattern::Grayscale => 0.5 * (1.0 + self.noise.noise(&point)),
+            NoisePattern::Marble => {
+                0.5 * (1.0 + (point.z + 10.0 * self.noise.turbulence(&point, 7)).sin())
+            }
+        };
+
+        utils::color::white() * gray
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    /// Points spread over a few noise cells, off the lattice.
+    fn sample_points() -> impl Iterator<Item = Point3> {
+        (0..64).map(|i| Point3::new(i as Real * 0.37, (i % 7) as Real * 0.91 - 2.0, (i % 5) as Real * 1.13))
+    }
+
+    #[test]
+    fn opposite_directions_sample_opposite_texels() {
+        let (red, blue) = (color::new(1.0, 0.0, 0.0), color::new(0.0, 0.0, 1.0));
+        let env = ImageTexture::new(2, 1, vec![red, blue]);
+
+        for dir in [Vec3::z(), Vec3::new(1.0, 0.3, 1.0), Vec3::new(-0.2, -0.9, 1.0)] {
+            assert_eq!(env.sample_dir(&dir), red, "{:?}", dir);
+            assert_eq!(env.sample_dir(&-dir), blue, "{:?}", -dir);
+        }
+    }
+
+    #[test]
+    fn noise_is_the_same_for_the_same_seed() {
+        for pattern in [NoisePattern::Grayscale, NoisePattern::Marble] {
+            let a = NoiseTexture::new(7, 4.0, pattern);
+            let b = NoiseTexture::new(7, 4.0, pattern);
+            let other = NoiseTexture::new(8, 4.0, pattern);
+            assert!(sample_points().all(|point| a.value(&point) == b.value(&point)));
+            assert!(sample_points().any(|point| a.value(&point) != other.value(&point)));
+        }
+    }
+
+    #[test]
+    fn noise_is_smooth() {
+        let perlin = Perlin::new(7);
+        let step = Vec3::new(1e-3, -1e-3, 1e-3);
+        for point in sample_points() {
+            let value = perlin.noise(&point);
+            assert!((-1.0..=1.0).contains(&value), "{}", value);
+            let difference = (perlin.noise(&(point + step)) - value).abs();
+            assert!(difference < 1e-2, "{} at {:?}", difference, point);
+        }
+
+        let texture = NoiseTexture::new(7, 1.0, NoisePattern::Grayscale);
+        for point in sample_points() {
+            let difference = (texture.value(&(point + step)) - texture.value(&point)).amax();
+            assert!(difference < 1e-2, "{} at {:?}", difference, point);
+        }
+    }
+}
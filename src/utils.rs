@@ -1,14 +1,274 @@
-use rand::random;
+use core::ops::{ Add, Deref, Div, Mul, Neg, Sub };
+#[cfg(feature = "std")]
+use std::cell::RefCell;
+#[cfg(not(feature = "std"))]
+use core::sync::atomic::{ AtomicU64, Ordering as AtomicOrdering };
 
-pub use nalgebra_glm::Vec3;
+use rand::{ Rng, RngCore, SeedableRng };
+use rand::distributions::{ Distribution, Standard };
+use rand::rngs::StdRng;
 
-pub type Color = nalgebra_glm::Vec3;
-pub type Point3 = nalgebra_glm::Vec3;
+use crate::prelude::*;
 
+/// Scalar type used throughout the renderer. Building with the `f64` feature trades some speed
+/// for precision, which helps against self intersection acne in scenes with large coordinates.
+#[cfg(not(feature = "f64"))]
+pub type Real = f32;
+#[cfg(feature = "f64")]
+pub type Real = f64;
 
-pub fn random_in_unit_disc() -> Vec3 {
-    let theta = random::<f32>() * std::f32::consts::TAU;
-    let rho   = random::<f32>();
+#[cfg(not(feature = "f64"))]
+pub use core::f32::consts;
+#[cfg(feature = "f64")]
+pub use core::f64::consts;
+
+pub type Vec3 = nalgebra_glm::TVec3<Real>;
+pub type Color = Vec3;
+pub type Point3 = Vec3;
+
+/// A point in space. Unlike `Point3`, only the operations that make sense for points are
+/// available: the difference of two positions is a `Direction` and a position can be offset by a
+/// `Direction`, but positions can't be added together or normalized.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Position(pub Vec3);
+
+/// A displacement or direction in space. Derefs to `Vec3` for things like `dot` or `magnitude`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Direction(pub Vec3);
+
+impl Position {
+    #[inline]
+    pub fn new(x: Real, y: Real, z: Real) -> Position {
+        Position(nalgebra_glm::vec3(x, y, z))
+    }
+}
+
+impl Direction {
+    #[inline]
+    pub fn new(x: Real, y: Real, z: Real) -> Direction {
+        Direction(nalgebra_glm::vec3(x, y, z))
+    }
+
+    /// Same direction with length 1.
+    #[inline]
+    pub fn unit(&self) -> Direction {
+        Direction(self.0.normalize())
+    }
+}
+
+impl Deref for Direction {
+    type Target = Vec3;
+
+    #[inline]
+    fn deref(&self) -> &Vec3 {
+        &self.0
+    }
+}
+
+impl From<Vec3> for Position {
+    #[inline]
+    fn from(v: Vec3) -> Position {
+        Position(v)
+    }
+}
+
+impl From<Vec3> for Direction {
+    #[inline]
+    fn from(v: Vec3) -> Direction {
+        Direction(v)
+    }
+}
+
+impl From<Position> for Vec3 {
+    #[inline]
+    fn from(p: Position) -> Vec3 {
+        p.0
+    }
+}
+
+impl From<Direction> for Vec3 {
+    #[inline]
+    fn from(d: Direction) -> Vec3 {
+        d.0
+    }
+}
+
+impl Sub for Position {
+    type Output = Direction;
+
+    #[inline]
+    fn sub(self, rhs: Position) -> Direction {
+        Direction(self.0 - rhs.0)
+    }
+}
+
+impl Add<Direction> for Position {
+    type Output = Position;
+
+    #[inline]
+    fn add(self, rhs: Direction) -> Position {
+        Position(self.0 + rhs.0)
+    }
+}
+
+impl Sub<Direction> for Position {
+    type Output = Position;
+
+    #[inline]
+    fn sub(self, rhs: Direction) -> Position {
+        Position(self.0 - rhs.0)
+    }
+}
+
+impl Add for Direction {
+    type Output = Direction;
+
+    #[inline]
+    fn add(self, rhs: Direction) -> Direction {
+        Direction(self.0 + rhs.0)
+    }
+}
+
+impl Sub for Direction {
+    type Output = Direction;
+
+    #[inline]
+    fn sub(self, rhs: Direction) -> Direction {
+        Direction(self.0 - rhs.0)
+    }
+}
+
+impl Mul<Real> for Direction {
+    type Output = Direction;
+
+    #[inline]
+    fn mul(self, rhs: Real) -> Direction {
+        Direction(self.0 * rhs)
+    }
+}
+
+impl Div<Real> for Direction {
+    type Output = Direction;
+
+    #[inline]
+    fn div(self, rhs: Real) -> Direction {
+        Direction(self.0 / rhs)
+    }
+}
+
+impl Neg for Direction {
+    type Output = Direction;
+
+    #[inline]
+    fn neg(self) -> Direction {
+        Direction(-self.0)
+    }
+}
+
+#[cfg(feature = "std")]
+thread_local! {
+    static RNG: RefCell<StdRng> = RefCell::new(StdRng::from_entropy());
+}
+
+/// Without `std` there are no thread locals, so every thread shares this SplitMix64 state
+/// instead. Each draw takes its own step with a single atomic add, so threads never get the same
+/// numbers, but they take turns and a reseeded sequence is only reproducible on one thread.
+#[cfg(not(feature = "std"))]
+static RNG: AtomicU64 = AtomicU64::new(0x853c_49e6_748f_ea9b);
+
+#[cfg(not(feature = "std"))]
+const SPLITMIX_GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+
+/// Next number from the shared generator, with SplitMix64's output function.
+#[cfg(not(feature = "std"))]
+fn shared_next_u64() -> u64 {
+    let mut z = RNG.fetch_add(SPLITMIX_GAMMA, AtomicOrdering::Relaxed).wrapping_add(SPLITMIX_GAMMA);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Reseed the random number generator of the current thread. Everything that is sampled on this
+/// thread afterwards (pixel jitter, lens and scatter directions) is reproducible. Without `std`
+/// this reseeds the generator all threads share.
+pub fn seed_thread_rng(seed: u64) {
+    #[cfg(feature = "std")]
+    RNG.with(|rng| *rng.borrow_mut() = StdRng::seed_from_u64(seed));
+    #[cfg(not(feature = "std"))]
+    RNG.store(seed, AtomicOrdering::Relaxed);
+}
+
+/// Handle to the reseedable thread local generator, for APIs that take an `Rng`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LocalRng;
+
+#[cfg(feature = "std")]
+impl RngCore for LocalRng {
+    #[inline]
+    fn next_u32(&mut self) -> u32 {
+        RNG.with(|rng| rng.borrow_mut().next_u32())
+    }
+
+    #[inline]
+    fn next_u64(&mut self) -> u64 {
+        RNG.with(|rng| rng.borrow_mut().next_u64())
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        RNG.with(|rng| rng.borrow_mut().fill_bytes(dest))
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        RNG.with(|rng| rng.borrow_mut().try_fill_bytes(dest))
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl RngCore for LocalRng {
+    #[inline]
+    fn next_u32(&mut self) -> u32 {
+        (shared_next_u64() >> 32) as u32
+    }
+
+    #[inline]
+    fn next_u64(&mut self) -> u64 {
+        shared_next_u64()
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(8) {
+            chunk.copy_from_slice(&shared_next_u64().to_le_bytes()[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+/// Same as `rand::random`, but draws from the reseedable thread local generator.
+#[inline]
+pub fn random<T>() -> T
+where
+    Standard: Distribution<T>,
+{
+    #[cfg(feature = "std")]
+    return RNG.with(|rng| rng.borrow_mut().gen());
+    #[cfg(not(feature = "std"))]
+    return LocalRng.gen();
+}
+
+pub fn random_in_unit_disc(rng: &mut impl Rng) -> Vec3 {
+    let u = rng.gen::<Real>();
+    let v = rng.gen::<Real>();
+    unit_disc_point(u, v)
+}
+
+/// Map `(u, v)` in the unit square to the unit disc, `u` going around and `v` going out.
+pub fn unit_disc_point(u: Real, v: Real) -> Vec3 {
+    let theta = u * consts::TAU;
+    let rho   = v;
     nalgebra_glm::vec3(
         rho * theta.cos(),
         rho * theta.sin(),
@@ -17,9 +277,9 @@ pub fn random_in_unit_disc() -> Vec3 {
 }
 
 pub fn random_in_unit_sphere() -> Vec3 {
-    let phi   = random::<f32>() * std::f32::consts::PI;
-    let theta = random::<f32>() * std::f32::consts::TAU;
-    let rho   = random::<f32>();
+    let phi   = random::<Real>() * consts::PI;
+    let theta = random::<Real>() * consts::TAU;
+    let rho   = random::<Real>();
     nalgebra_glm::vec3(
         rho * phi.sin() * theta.cos(),
         rho * phi.sin() * theta.sin(),
@@ -27,15 +287,73 @@ pub fn random_in_unit_sphere() -> Vec3 {
     )
 }
 
+/// Uniformly distributed on the unit sphere, which makes `normal + random_unit()` follow the
+/// cosine distribution of a lambertian surface.
 pub fn random_unit() -> Vec3 {
-    random_in_unit_sphere().normalize()
+    let z     = random::<Real>() * 2.0 - 1.0;
+    let theta = random::<Real>() * consts::TAU;
+    let r     = (1.0 - z * z).max(0.0).sqrt();
+    nalgebra_glm::vec3(r * theta.cos(), r * theta.sin(), z)
 }
 
-pub fn to_rgb(color: Color) -> image::Rgb<u8> {
-    let bytes = nalgebra_glm::try_convert(
-        nalgebra_glm::clamp(&color, 0.0, 0.999) * 256.0
-    ).unwrap_or(nalgebra_glm::vec3(255, 255, 255));
-    image::Rgb([bytes[0], bytes[1], bytes[2]])
+/// Unit vectors `(tangent, bitangent)` on a surface with unit `normal`, the tangent being
+/// `direction` projected onto the surface. Along with the normal they make a right handed
+/// orthonormal frame. When `direction` is along the normal any tangent will do. Only the way
+/// `direction` points matters, however short it is.
+pub fn tangent_frame(normal: &Vec3, direction: &Vec3) -> (Vec3, Vec3) {
+    let mut tangent = direction - normal * direction.dot(normal);
+    if tangent.magnitude_squared() <= 1e-8 * direction.magnitude_squared() {
+        let axis = if normal.x.abs() < 0.9 { Vec3::x() } else { Vec3::y() };
+        tangent = normal.cross(&axis);
+    }
+    let tangent = tangent.normalize();
+    (tangent, normal.cross(&tangent))
+}
+
+/// Whether every component of `a` is within `eps` of the one in `b`, for comparing directions or
+/// colors that went through float math. Never true when either has a NaN.
+pub fn approx_eq(a: &Vec3, b: &Vec3, eps: Real) -> bool {
+    a.iter().zip(b.iter()).all(|(a, b)| (a - b).abs() <= eps)
+}
+
+/// Turn linear radiance into sRGB bytes: scaled by `exposure`, brought into range by `tone_map`
+/// and gamma encoded. Every renderer writes its pixels through here, so they all agree.
+///
+/// With a `dither` in `[0, 1)`, channels are rounded up when their fraction of a step is above
+/// it instead of always being rounded down, which averages out to the exact value when the
+/// dither varies evenly from pixel to pixel.
+#[cfg(feature = "std")]
+pub fn encode_rgb(radiance: Color, exposure: Real, tone_map: color::ToneMap, dither: Option<Real>) -> image::Rgb<u8> {
+    let srgb = color::linear_to_srgb(tone_map.apply(radiance * exposure));
+    let to_byte = |c| match dither {
+        Some(dither) => dithered_byte(c, dither),
+        None         => to_byte(c),
+    };
+    image::Rgb([to_byte(srgb.x), to_byte(srgb.y), to_byte(srgb.z)])
+}
+
+/// Same as `encode_rgb`, with `alpha` stored as is.
+#[cfg(feature = "std")]
+pub fn encode_rgba(
+    radiance: Color,
+    alpha: Real,
+    exposure: Real,
+    tone_map: color::ToneMap,
+    dither: Option<Real>,
+) -> image::Rgba<u8> {
+    let image::Rgb([r, g, b]) = encode_rgb(radiance, exposure, tone_map, dither);
+    image::Rgba([r, g, b, to_byte(alpha)])
+}
+
+/// Quantize a channel in `0..=1` to the nearest of 256 steps. Same as `dithered_byte` with a
+/// dither of one half, so that dithering only adds noise. NaN goes to 0.
+fn to_byte(c: Real) -> u8 {
+    dithered_byte(c, 0.5)
+}
+
+/// Quantize a channel in `0..=1` to the step below or above it, depending on `dither`.
+fn dithered_byte(c: Real, dither: Real) -> u8 {
+    (c.clamp(0.0, 1.0) * 255.0 + dither).floor().clamp(0.0, 255.0) as u8
 }
 
 pub mod color {
@@ -72,17 +390,167 @@ pub mod color {
     }
 
     #[inline]
-    pub fn lerp(start: Color, end: Color, step: f32) -> Color {
+    pub fn magenta() -> Color {
+        nalgebra_glm::vec3(1.0, 0.0, 1.0)
+    }
+
+    #[inline]
+    pub fn lerp(start: Color, end: Color, step: Real) -> Color {
         start + (end - start) * step
     }
 
     #[inline]
     pub fn random() -> Color {
-        nalgebra_glm::vec3(rand::random(), rand::random(), rand::random())
+        nalgebra_glm::vec3(super::random(), super::random(), super::random())
     }
 
     #[inline]
-    pub fn new(r: f32, g: f32, b: f32) -> Color {
+    pub fn new(r: Real, g: Real, b: Real) -> Color {
         nalgebra_glm::vec3(r, g, b)
     }
+
+    /// Channel-wise maximum of two colors.
+    #[inline]
+    pub fn max(a: Color, b: Color) -> Color {
+        a.zip_map(&b, Real::max)
+    }
+
+    /// `color` with negative channels, which no light can have, set to zero.
+    #[inline]
+    pub fn nonneg(color: Color) -> Color {
+        max(color, black())
+    }
+
+    /// Relative luminance with Rec. 709 weights.
+    #[inline]
+    pub fn luminance(color: Color) -> Real {
+        0.2126 * color.x + 0.7152 * color.y + 0.0722 * color.z
+    }
+
+    /// Decode a color stored with the sRGB transfer function, as most images and color pickers do.
+    #[inline]
+    pub fn srgb_to_linear(color: Color) -> Color {
+        color.map(|c| if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) })
+    }
+
+    /// Inverse of `srgb_to_linear`, for writing out images.
+    #[inline]
+    pub fn linear_to_srgb(color: Color) -> Color {
+        color.map(|c| if c <= 0.0031308 { c * 12.92 } else { 1.055 * c.powf(1.0 / 2.4) - 0.055 })
+    }
+
+    /// How radiance is brought into the `0..=1` range an image can store.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub enum ToneMap {
+        /// Cut every channel off at 1, which clips bright highlights abruptly.
+        #[default]
+        Clamp,
+        /// `c / (1 + c)` per channel, rolling highlights off smoothly at the cost of some
+        /// contrast.
+        Reinhard,
+    }
+
+    impl ToneMap {
+        pub fn apply(self, color: Color) -> Color {
+            match self {
+                ToneMap::Clamp    => color.map(|c| c.clamp(0.0, 1.0)),
+                ToneMap::Reinhard => color.map(|c| c.max(0.0) / (1.0 + c.max(0.0))),
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use super::color::ToneMap;
+
+    #[test]
+    fn approx_eq_compares_every_component_within_eps() {
+        let a = Vec3::new(1.0, 2.0, 3.0);
+        assert!(approx_eq(&a, &a, 0.0));
+        // The bound is inclusive.
+        assert!(approx_eq(&a, &Vec3::new(1.5, 2.0, 2.5), 0.5));
+        assert!(!approx_eq(&a, &Vec3::new(1.5, 2.0, 2.5), 0.4));
+
+        // One component off is enough, whichever it is.
+        for axis in 0..3 {
+            let mut b = a;
+            b[axis] += 1.0;
+            assert!(!approx_eq(&a, &b, 0.5), "{:?}", b);
+            assert!(!approx_eq(&b, &a, 0.5), "{:?}", b);
+        }
+
+        let nan = Vec3::new(1.0, Real::NAN, 3.0);
+        assert!(!approx_eq(&nan, &nan, Real::INFINITY));
+        assert!(!approx_eq(&a, &nan, 1.0));
+    }
+
+    #[test]
+    fn encode_rgb_gamma_encodes_and_clips() {
+        let rgb = encode_rgb(color::new(0.0, 0.18, 0.5), 1.0, ToneMap::Clamp, None);
+        assert_eq!(rgb, image::Rgb([0, 118, 188]));
+
+        // Anything at or above 1 is white, and below 0 is black.
+        let rgb = encode_rgb(color::new(1.0, 40.0, -3.0), 1.0, ToneMap::Clamp, None);
+        assert_eq!(rgb, image::Rgb([255, 255, 0]));
+    }
+
+    #[test]
+    fn encode_rgb_applies_exposure_before_tone_mapping() {
+        let rgb = encode_rgb(color::new(0.25, 0.09, 0.005), 2.0, ToneMap::Clamp, None);
+        assert_eq!(rgb, image::Rgb([188, 118, 25]));
+    }
+
+    #[test]
+    fn reinhard_rolls_off_highlights() {
+        let rgb = encode_rgb(color::new(1.0, 3.0, 0.0), 1.0, ToneMap::Reinhard, None);
+        assert_eq!(rgb, image::Rgb([188, 225, 0]));
+
+        // Still short of white, unlike `ToneMap::Clamp`.
+        let image::Rgb([r, _, _]) = encode_rgb(color::new(100.0, 0.0, 0.0), 1.0, ToneMap::Reinhard, None);
+        assert!(r < 255);
+    }
+
+    #[test]
+    fn srgb_conversions_match_known_pairs() {
+        let pairs: [(Real, Real); 5] = [(0.0, 0.0), (0.02, 0.001_548), (0.5, 0.214_041), (0.735_357, 0.5), (1.0, 1.0)];
+        for &(srgb, linear) in &pairs {
+            assert!((color::srgb_to_linear(Vec3::repeat(srgb)).x - linear).abs() < 1e-5, "{} sRGB", srgb);
+            assert!((color::linear_to_srgb(Vec3::repeat(linear)).x - srgb).abs() < 1e-5, "{} linear", linear);
+        }
+
+        // Both pieces of the curve meet where they switch over, and the two directions undo each other.
+        for i in 0..=100 {
+            let c = Vec3::new(i as Real / 100.0, 0.040_45, 0.003_130_8);
+            assert!(approx_eq(&color::linear_to_srgb(color::srgb_to_linear(c)), &c, 1e-5));
+        }
+    }
+
+    #[test]
+    fn encode_rgba_keeps_alpha_linear() {
+        let rgba = encode_rgba(color::new(0.5, 0.5, 0.5), 0.5, 1.0, ToneMap::Clamp, None);
+        assert_eq!(rgba, image::Rgba([188, 188, 188, 128]));
+
+        // Rounded to the nearest step, like the colors.
+        let image::Rgba([_, _, _, a]) = encode_rgba(Vec3::zeros(), 0.1, 1.0, ToneMap::Clamp, None);
+        assert_eq!(a, 26);
+    }
+
+    #[test]
+    fn dithering_breaks_up_bands() {
+        // A dark gradient, a few steps of the output across 256 pixels.
+        let gradient = |x: u32| color::srgb_to_linear(Vec3::repeat(0.2 + 0.02 * x as Real / 256.0));
+        let encode = |x: u32, dither| encode_rgb(gradient(x), 1.0, ToneMap::Clamp, dither).0[0];
+
+        let changes = |bytes: &[u8]| bytes.windows(2).filter(|pair| pair[0] != pair[1]).count();
+        let flat: Vec<u8> = (0..256).map(|x| encode(x, None)).collect();
+        let dithered: Vec<u8> = (0..256).map(|x| encode(x, Some((x as Real * 0.618_034).fract()))).collect();
+        assert!(changes(&dithered) > 10 * changes(&flat));
+
+        // Still the same gradient on average, and the same levels as without dithering.
+        let mean = |bytes: &[u8]| bytes.iter().map(|&b| b as Real).sum::<Real>() / bytes.len() as Real;
+        assert!((mean(&dithered) - (0.21 * 255.0)).abs() < 0.5);
+        assert!((mean(&dithered) - mean(&flat)).abs() < 0.1, "{} vs {}", mean(&dithered), mean(&flat));
+    }
 }
@@ -0,0 +1,156 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{ Duration, SystemTime };
+
+use crate::hittable::Hittable;
+use crate::render::{ self, RenderOutput, Scene };
+
+/// How often `watch_and_render` checks whether the scene file changed.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Re-renders the scene described by a file whenever it changes, for iterating on a scene. The
+/// file is turned into a scene by a loader, which gets its contents.
+pub struct SceneWatcher<F> {
+    scene_path: PathBuf,
+    out_path: PathBuf,
+    load: F,
+    /// Modification time of the scene file as of the last reload.
+    modified: Option<SystemTime>,
+}
+
+impl<F, T, E> SceneWatcher<F>
+where
+    F: FnMut(&str) -> Result<Scene<T>, E>,
+    T: Hittable + Send + Sync,
+    E: fmt::Display,
+{
+    pub fn new(scene_path: impl Into<PathBuf>, out_path: impl Into<PathBuf>, load: F) -> Self {
+        SceneWatcher { scene_path: scene_path.into(), out_path: out_path.into(), load, modified: None }
+    }
+
+    /// Reload if the scene file was modified since the last reload, or was never loaded.
+    pub fn poll(&mut self) -> Option<Result<RenderOutput, ReloadError>> {
+        let modified = fs::metadata(&self.scene_path).and_then(|meta| meta.modified()).ok();
+        if modified.is_some() && modified == self.modified {
+            return None;
+        }
+        Some(self.reload())
+    }
+
+    /// Load the scene file, render it in memory with `render_to_output` and write the image to
+    /// the output path, with the format picked from its extension. Nothing is written when the
+    /// file can't be read or loaded.
+    pub fn reload(&mut self) -> Result<RenderOutput, ReloadError> {
+        self.modified = fs::metadata(&self.scene_path).and_then(|meta| meta.modified()).ok();
+
+        let text = fs::read_to_string(&self.scene_path).map_err(ReloadError::Read)?;
+        let scene = (self.load)(&text).map_err(|err| ReloadError::Load(err.to_string()))?;
+
+        let output = render::render_to_output(scene);
+        output.image.save(&self.out_path).map_err(ReloadError::Write)?;
+        Ok(output)
+    }
+}
+
+/// Why `SceneWatcher::reload` didn't render the scene.
+#[derive(Debug)]
+pub enum ReloadError {
+    Read(io::Error),
+    /// The loader failed, with its error message.
+    Load(String),
+    Write(image::ImageError),
+}
+
+impl fmt::Display for ReloadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReloadError::Read(err)  => write!(f, "failed to read the scene: {}", err),
+            ReloadError::Load(err)  => write!(f, "failed to load the scene: {}", err),
+            ReloadError::Write(err) => write!(f, "failed to write the image: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for ReloadError {}
+
+/// Render the scene at `scene_path` to `out_path`, then again every time the file changes, for
+/// ever. Scenes that fail to load are reported and skipped, keeping the last image.
+pub fn watch_and_render<F, T, E>(scene_path: impl Into<PathBuf>, out_path: impl Into<PathBuf>, load: F) -> !
+where
+    F: FnMut(&str) -> Result<Scene<T>, E>,
+    T: Hittable + Send + Sync,
+    E: fmt::Display,
+{
+    let mut watcher = SceneWatcher::new(scene_path, out_path, load);
+    loop {
+        match watcher.poll() {
+            Some(Ok(_))    => eprintln!("[watch] wrote {}", watcher.out_path.display()),
+            Some(Err(err)) => eprintln!("[watch] {}", err),
+            None           => (),
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::Camera;
+    use crate::material::Diffuse;
+    use crate::objects::Sphere;
+    use crate::render::RenderBuilder;
+    use crate::utils::{ color, Vec3 };
+
+    /// Loads files like `samples = 4` into a sphere in front of the camera.
+    fn load(text: &str) -> Result<Scene<Vec<Sphere<Diffuse>>>, String> {
+        let samples = text.trim()
+            .strip_prefix("samples = ")
+            .and_then(|count| count.parse().ok())
+            .ok_or_else(|| format!("expected `samples = <count>`, got {:?}", text))?;
+
+        let config = RenderBuilder::new()
+            .with_dimensions(4, 3)
+            .with_samples(samples)
+            .with_seed(1)
+            .build()
+            .map_err(|err| err.to_string())?;
+        let camera = Camera::new(
+            Vec3::new(0.0, 0.0, 3.0),
+            Vec3::zeros(),
+            Vec3::y(),
+            40.0,
+            config.aspect_ratio,
+            0.0,
+            3.0,
+        );
+        let world = vec![Sphere::new(Vec3::zeros(), 1.0, Diffuse::new(color::mid_gray()))];
+        Ok(Scene::new(world, camera, config))
+    }
+
+    #[test]
+    fn reloading_picks_up_edits() {
+        let dir = std::env::temp_dir().join(format!("raytracing-watch-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (scene_path, out_path) = (dir.join("scene.txt"), dir.join("out.png"));
+
+        fs::write(&scene_path, "samples = 2").unwrap();
+        let mut watcher = SceneWatcher::new(&scene_path, &out_path, load);
+        let output = watcher.poll().expect("the first poll always loads").unwrap();
+        assert_eq!(output.samples, vec![2; 12]);
+        assert!(out_path.exists());
+        assert!(watcher.poll().is_none());
+
+        fs::write(&scene_path, "samples = 5").unwrap();
+        let output = watcher.reload().unwrap();
+        assert_eq!(output.samples, vec![5; 12]);
+
+        // Broken scenes are skipped.
+        fs::write(&scene_path, "samples = lots").unwrap();
+        assert!(matches!(watcher.reload(), Err(ReloadError::Load(_))));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}